@@ -0,0 +1,241 @@
+// Throughput of the hot paths: LZSA, quantization and, on Windows, the WIC codec itself.
+
+use std::hint::black_box;
+
+use bmx_shell::bmx::palette::{nearest_index, VERA_DEFAULT};
+use bmx_shell::lzsa;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+const SIZES: [(u16, u16); 2] = [(320, 240), (640, 480)];
+
+// Horizontal runs with some noise, roughly what converted artwork looks like.
+fn indices(width: u16, height: u16) -> Vec<u8> {
+    (0..height as u32)
+        .flat_map(|y| {
+            (0..width as u32).map(move |x| {
+                let noise = (x.wrapping_mul(2654435761) ^ y.wrapping_mul(40503)) >> 29;
+                ((x / 8 + y / 4) as u8).wrapping_add(noise as u8)
+            })
+        })
+        .collect()
+}
+
+fn id((width, height): (u16, u16)) -> String {
+    format!("{width}x{height}")
+}
+
+fn lzsa(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lzsa");
+
+    for size in SIZES {
+        let data = indices(size.0, size.1);
+        let compressed = lzsa::compress(&data).unwrap();
+
+        group.throughput(Throughput::Bytes(data.len() as u64));
+        group.bench_with_input(BenchmarkId::new("compress", id(size)), &data, |b, data| {
+            b.iter(|| lzsa::compress(black_box(data)).unwrap())
+        });
+        group.bench_with_input(
+            BenchmarkId::new("decompress", id(size)),
+            &compressed,
+            |b, compressed| b.iter(|| lzsa::decompress(black_box(compressed), data.len()).unwrap()),
+        );
+    }
+
+    group.finish();
+}
+
+fn quantize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("quantize");
+
+    for size in SIZES {
+        let pixels = indices(size.0, size.1)
+            .into_iter()
+            .map(|index| {
+                let (r, g, b) = VERA_DEFAULT[index as usize].to_rgb();
+                (r ^ 0x18, g, b.wrapping_add(0x21))
+            })
+            .collect::<Vec<_>>();
+
+        group.throughput(Throughput::Elements(pixels.len() as u64));
+        group.bench_with_input(
+            BenchmarkId::new("vera_default", id(size)),
+            &pixels,
+            |b, pixels| {
+                b.iter(|| {
+                    pixels
+                        .iter()
+                        .map(|&rgb| nearest_index(&VERA_DEFAULT, rgb).unwrap() as u8)
+                        .collect::<Vec<_>>()
+                })
+            },
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("16_colors", id(size)),
+            &pixels,
+            |b, pixels| {
+                b.iter(|| {
+                    pixels
+                        .iter()
+                        .map(|&rgb| nearest_index(&VERA_DEFAULT[..16], rgb).unwrap() as u8)
+                        .collect::<Vec<_>>()
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+#[cfg(windows)]
+mod wic {
+    use std::hint::black_box;
+
+    use bmx_shell::bmx::palette::VERA_DEFAULT;
+    use bmx_shell::bmx::BmxImage;
+    use bmx_shell::com::wic::decoder::BitmapDecoder;
+    use bmx_shell::com::wic::encoder::BitmapEncoder;
+    use bmx_shell::com::wic::{bit_depth_to_pixel_format, create_imaging_factory};
+    use criterion::{BatchSize, BenchmarkId, Criterion, Throughput};
+    use windows::Win32::Graphics::Imaging::{
+        IWICBitmapDecoder, IWICBitmapEncoder, IWICBitmapFrameDecode, IWICBitmapFrameEncode,
+        WICBitmapEncoderNoCache, WICDecodeMetadataCacheOnDemand,
+    };
+    use windows::Win32::System::Com::{CoInitializeEx, COINIT_MULTITHREADED};
+    use windows::Win32::UI::Shell::SHCreateMemStream;
+    use windows_core::ComObject;
+
+    use super::{id, indices, SIZES};
+
+    fn image(width: u16, height: u16) -> BmxImage {
+        BmxImage::new(
+            width,
+            height,
+            8,
+            VERA_DEFAULT.to_vec(),
+            indices(width, height),
+        )
+        .unwrap()
+    }
+
+    fn frame_decode(bytes: &[u8]) -> IWICBitmapFrameDecode {
+        let stream = unsafe { SHCreateMemStream(Some(bytes)) }.unwrap();
+        let decoder: IWICBitmapDecoder = ComObject::new(BitmapDecoder::new()).into_interface();
+
+        unsafe {
+            decoder
+                .Initialize(&stream, WICDecodeMetadataCacheOnDemand)
+                .unwrap();
+            decoder.GetFrame(0).unwrap()
+        }
+    }
+
+    pub fn decode(c: &mut Criterion) {
+        unsafe { CoInitializeEx(None, COINIT_MULTITHREADED) }
+            .ok()
+            .unwrap();
+
+        let mut group = c.benchmark_group("decode");
+
+        for size in SIZES {
+            for compress in [false, true] {
+                let bytes = image(size.0, size.1).to_bytes(compress).unwrap();
+                let mut buffer = vec![0u8; size.0 as usize * size.1 as usize];
+                let name = if compress {
+                    "compressed"
+                } else {
+                    "uncompressed"
+                };
+
+                group.throughput(Throughput::Bytes(buffer.len() as u64));
+                group.bench_function(BenchmarkId::new(name, id(size)), |b| {
+                    b.iter_batched(
+                        || frame_decode(&bytes),
+                        |frame| unsafe {
+                            frame
+                                .CopyPixels(std::ptr::null(), size.0 as u32, &mut buffer)
+                                .unwrap();
+                            black_box(&buffer);
+                        },
+                        BatchSize::SmallInput,
+                    )
+                });
+            }
+        }
+
+        group.finish();
+    }
+
+    pub fn encode(c: &mut Criterion) {
+        unsafe { CoInitializeEx(None, COINIT_MULTITHREADED) }
+            .ok()
+            .unwrap();
+
+        let imaging_factory = create_imaging_factory().unwrap();
+        let mut group = c.benchmark_group("encode");
+
+        for size in SIZES {
+            let image = image(size.0, size.1);
+            let colors = image
+                .palette
+                .iter()
+                .map(|entry| entry.to_wic())
+                .collect::<Vec<_>>();
+
+            let frame_encode = || -> (IWICBitmapEncoder, IWICBitmapFrameEncode) {
+                let stream = unsafe { SHCreateMemStream(None) }.unwrap();
+                let encoder: IWICBitmapEncoder =
+                    ComObject::new(BitmapEncoder::new()).into_interface();
+
+                unsafe {
+                    encoder
+                        .Initialize(&stream, WICBitmapEncoderNoCache)
+                        .unwrap();
+
+                    let mut frame = None;
+                    encoder
+                        .CreateNewFrame(&mut frame, std::ptr::null_mut())
+                        .unwrap();
+                    let frame = frame.unwrap();
+
+                    frame.Initialize(None).unwrap();
+                    frame.SetSize(size.0 as u32, size.1 as u32).unwrap();
+
+                    let mut pixel_format = bit_depth_to_pixel_format(8).unwrap();
+                    frame.SetPixelFormat(&mut pixel_format).unwrap();
+
+                    let palette = imaging_factory.CreatePalette().unwrap();
+                    palette.InitializeCustom(&colors).unwrap();
+                    frame.SetPalette(&palette).unwrap();
+
+                    frame
+                        .WritePixels(size.1 as u32, size.0 as u32, &image.data)
+                        .unwrap();
+
+                    (encoder, frame)
+                }
+            };
+
+            group.throughput(Throughput::Bytes(image.data.len() as u64));
+            group.bench_function(BenchmarkId::new("commit", id(size)), |b| {
+                b.iter_batched(
+                    frame_encode,
+                    |(encoder, frame)| unsafe {
+                        frame.Commit().unwrap();
+                        encoder.Commit().unwrap();
+                    },
+                    BatchSize::SmallInput,
+                )
+            });
+        }
+
+        group.finish();
+    }
+}
+
+#[cfg(windows)]
+criterion_group!(benches, lzsa, quantize, wic::decode, wic::encode);
+#[cfg(not(windows))]
+criterion_group!(benches, lzsa, quantize);
+criterion_main!(benches);
@@ -0,0 +1,19 @@
+#![no_main]
+
+use bmx_shell::bmx::{FileHeader, Validation};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Some(bytes) = data.get(..FileHeader::SIZE) else {
+        let _ = FileHeader::from_bytes(data);
+        return;
+    };
+
+    for validation in [Validation::Lenient, Validation::Strict] {
+        if let Ok(header) = FileHeader::from_bytes_with(bytes, validation) {
+            let _ = header.validate();
+            let _ = header.check_integrity(&data[FileHeader::SIZE..]);
+            assert_eq!(header.to_bytes(), bytes);
+        }
+    }
+});
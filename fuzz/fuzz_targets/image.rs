@@ -0,0 +1,25 @@
+#![no_main]
+
+use bmx_shell::bmx::{palette, BmxImage, Truncation};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = palette::io::parse(data);
+
+    for truncation in [Truncation::Reject, Truncation::FillWithBorderColor] {
+        let Ok(image) = BmxImage::from_bytes_with(data, truncation) else {
+            continue;
+        };
+
+        let _ = image.integrity();
+
+        let width = image.header.width;
+        for (y, row) in image.rows().enumerate() {
+            assert_eq!(row.len(), width as usize);
+            let _ = image.pixel(0, y as u16);
+            let _ = image.pixel(width.saturating_sub(1), y as u16);
+        }
+
+        let _ = image.pixel(width, image.header.height);
+    }
+});
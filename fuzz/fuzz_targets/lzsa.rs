@@ -0,0 +1,25 @@
+#![no_main]
+
+use bmx_shell::lzsa;
+use libfuzzer_sys::fuzz_target;
+
+// Bounded so the fuzzer can't just ask for huge allocations.
+const MAX_OUTPUT_LEN: usize = 1 << 20;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&[low, high], input)) = data.split_first_chunk::<2>() else {
+        return;
+    };
+
+    let max_output_len = (u16::from_le_bytes([low, high]) as usize * 16).min(MAX_OUTPUT_LEN);
+
+    if let Ok(output) = lzsa::decompress(input, max_output_len) {
+        assert!(output.len() <= max_output_len);
+    }
+
+    if let Ok(compressed) = lzsa::compress(input) {
+        let decompressed =
+            lzsa::decompress(&compressed, input.len()).expect("compressed data should decompress");
+        assert_eq!(decompressed, input);
+    }
+});
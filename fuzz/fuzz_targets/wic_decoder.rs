@@ -0,0 +1,66 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// Pixel buffers above this are skipped; the point is the parsing, not the allocator.
+#[cfg(windows)]
+const MAX_PIXELS: u64 = 1 << 24;
+
+#[cfg(windows)]
+fn decode(data: &[u8]) -> windows::core::Result<()> {
+    use bmx_shell::com::wic::decoder::BitmapDecoder;
+    use windows::Win32::{
+        Graphics::Imaging::{IWICBitmapDecoder, WICDecodeMetadataCacheOnDemand, WICRect},
+        UI::Shell::SHCreateMemStream,
+    };
+    use windows_core::ComObject;
+
+    let stream =
+        unsafe { SHCreateMemStream(Some(data)) }.ok_or_else(windows::core::Error::from_win32)?;
+
+    let decoder: IWICBitmapDecoder = ComObject::new(BitmapDecoder::new()).into_interface();
+
+    unsafe {
+        decoder.Initialize(&stream, WICDecodeMetadataCacheOnDemand)?;
+
+        for index in 0..decoder.GetFrameCount()? {
+            let frame = decoder.GetFrame(index)?;
+
+            let (mut width, mut height) = (0, 0);
+            frame.GetSize(&mut width, &mut height)?;
+
+            if width as u64 * height as u64 > MAX_PIXELS {
+                continue;
+            }
+
+            let _ = frame.GetPixelFormat()?;
+
+            // Requested as 8bpp; also covers the partial rectangle path.
+            let stride = width;
+            let mut buffer = vec![0u8; stride as usize * height as usize];
+            frame.CopyPixels(std::ptr::null(), stride, &mut buffer)?;
+
+            let rect = WICRect {
+                X: (width / 3) as i32,
+                Y: (height / 3) as i32,
+                Width: (width / 2) as i32,
+                Height: (height / 2) as i32,
+            };
+            frame.CopyPixels(&rect, stride, &mut buffer)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(windows)]
+fuzz_target!(|data: &[u8]| {
+    use windows::Win32::System::Com::{CoInitializeEx, COINIT_MULTITHREADED};
+
+    // Already initialized after the first run, which is fine.
+    let _ = unsafe { CoInitializeEx(None, COINIT_MULTITHREADED) };
+    let _ = decode(data);
+});
+
+#[cfg(not(windows))]
+fuzz_target!(|_data: &[u8]| {});
@@ -0,0 +1,82 @@
+#pragma once
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef enum BmxStatus {
+    BMX_OK = 0,
+    BMX_NULL_POINTER = -1,
+    BMX_BUFFER_TOO_SMALL = -2,
+    BMX_INVALID_HEADER_SIZE = -3,
+    BMX_INVALID_FILE_ID = -4,
+    BMX_INVALID_VERSION = -5,
+    BMX_INVALID_BIT_DEPTH = -6,
+    BMX_INVALID_VERA_COLOR_DEPTH_REGISTER = -7,
+    BMX_BIT_DEPTH_MISMATCH = -8,
+    BMX_INVALID_DATA_START = -9,
+    BMX_INVALID_VERA_BORDER_COLOR = -10,
+    BMX_INVALID_PALETTE = -11,
+    BMX_INVALID_PIXEL_DATA_LENGTH = -12,
+    BMX_TRUNCATED = -13,
+    BMX_COMPRESSION = -14,
+    BMX_INVALID_PALETTE_LENGTH = -15,
+} BmxStatus;
+
+typedef struct BmxFileHeader {
+    uint8_t file_id[3];
+    uint8_t version;
+    uint8_t bit_depth;
+    uint8_t vera_color_depth_register;
+    uint16_t width;
+    uint16_t height;
+    uint8_t pal_used;
+    uint8_t pal_start;
+    uint16_t data_start;
+    int8_t compressed;
+    uint8_t vera_border_color;
+    uint8_t reserved[16];
+} BmxFileHeader;
+
+/* X16 palette entry: gggg_bbbb, 0000_rrrr */
+typedef struct BmxPaletteEntry {
+    uint8_t gb;
+    uint8_t r;
+} BmxPaletteEntry;
+
+BmxStatus bmx_parse_header(const uint8_t *data, size_t len, BmxFileHeader *header);
+
+/* header is validated like a parsed file; 0 if it is NULL or invalid. */
+size_t bmx_pixel_data_len(const BmxFileHeader *header);
+
+/* palette_len is the capacity of palette on input and the number of entries on output. */
+BmxStatus bmx_decode(
+    const uint8_t *data,
+    size_t len,
+    BmxFileHeader *header,
+    BmxPaletteEntry *palette,
+    size_t *palette_len,
+    uint8_t *pixels,
+    size_t pixels_len);
+
+/* written receives the encoded size, even if output is too small. */
+BmxStatus bmx_encode(
+    uint16_t width,
+    uint16_t height,
+    uint8_t bit_depth,
+    const BmxPaletteEntry *palette,
+    size_t palette_len,
+    const uint8_t *pixels,
+    size_t pixels_len,
+    bool compress,
+    uint8_t *output,
+    size_t output_len,
+    size_t *written);
+
+#ifdef __cplusplus
+}
+#endif
@@ -0,0 +1,561 @@
+use std::{fmt::Display, path::PathBuf, process::ExitCode};
+
+use bmx_shell::{
+    bmx::{
+        palette::io::{self as palette_io, PaletteFormat, PaletteIoError},
+        BmxImage, BmxImageError, FileHeader, FileHeaderError,
+    },
+    com::{
+        transcode::{TranscodeError, TranscodeOptions, TranscodeRequest},
+        wic::{com::CONTAINER_FORMAT, conformance, create_imaging_factory},
+    },
+    lzsa::{self, LzsaError},
+    registry::activation_manifest,
+    settings::Dithering,
+};
+use windows::{
+    core::{s, w, Owned, HRESULT, HSTRING, PWSTR},
+    Win32::{
+        Foundation::{FreeLibrary, GENERIC_READ, HANDLE, HLOCAL},
+        Graphics::Imaging::{
+            GUID_ContainerFormatPng, GUID_WICPixelFormat1bppIndexed,
+            GUID_WICPixelFormat2bppIndexed, GUID_WICPixelFormat4bppIndexed,
+            WICDecodeMetadataCacheOnDemand,
+        },
+        Security::{
+            Authorization::ConvertStringSidToSidW, DuplicateTokenEx, GetLengthSid,
+            SecurityImpersonation, SetTokenInformation, TokenIntegrityLevel, TokenPrimary, PSID,
+            SID_AND_ATTRIBUTES, TOKEN_ADJUST_DEFAULT, TOKEN_ASSIGN_PRIMARY, TOKEN_DUPLICATE,
+            TOKEN_MANDATORY_LABEL, TOKEN_QUERY,
+        },
+        System::{
+            Com::{CoInitializeEx, CoUninitialize, COINIT_MULTITHREADED},
+            LibraryLoader::{GetProcAddress, LoadLibraryW},
+            SystemServices::SE_GROUP_INTEGRITY,
+            Threading::{
+                CreateProcessAsUserW, GetCurrentProcess, GetExitCodeProcess, OpenProcessToken,
+                WaitForSingleObject, INFINITE, PROCESS_CREATION_FLAGS, PROCESS_INFORMATION,
+                STARTUPINFOW,
+            },
+        },
+    },
+};
+use windows_core::{PCSTR, PCWSTR};
+
+const USAGE: &str = "\
+Usage: bmx-tool <command> [arguments]
+
+Commands:
+    info <file.bmx>                             Print the parsed file header
+    to-png <input.bmx> <output.png>             Convert a BMX file to PNG
+    from-png <input> <output.bmx> [1|2|4|8]     Convert an image to BMX (default: 8 bpp)
+    compress <input.bmx> <output.bmx>           LZSA-compress the pixel data
+    decompress <input.bmx> <output.bmx>         Decompress LZSA-compressed pixel data
+    extract-palette <input.bmx> <output> [vera|jasc|gimp]
+                                                Write the palette to a file (default: vera)
+    apply-palette <input.bmx> <palette> <output.bmx>
+                                                Replace the palette from a VERA, JASC or GIMP file
+    decode <file.bmx>                           Decode through the registered WIC codec
+    decode-low-integrity <file.bmx>             Run decode in a low integrity process
+    conformance                                 Check the codec against the WIC conformance rules
+    manifest <output.manifest> [bmx_shell.dll]  Write a registration-free COM manifest
+    register [bmx_shell.dll]                    Register the shell extension
+    unregister [bmx_shell.dll]                  Unregister the shell extension";
+
+enum ToolError {
+    Usage,
+    Io(std::io::Error),
+    Win(windows::core::Error),
+    Transcode(TranscodeError),
+    Header(FileHeaderError),
+    Lzsa(LzsaError),
+    Image(BmxImageError),
+    Palette(PaletteIoError),
+    AlreadyCompressed,
+    NotCompressed,
+    TruncatedFile,
+    UnexpectedPixelDataSize,
+    CodecNotRegistered,
+    ChildFailed(u32),
+    ConformanceFailed(usize),
+}
+
+impl Display for ToolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ToolError::Usage => write!(f, "{}", USAGE),
+            ToolError::Io(err) => write!(f, "{}", err),
+            ToolError::Win(err) => write!(f, "{}", err),
+            ToolError::Transcode(err) => write!(f, "{}", err),
+            ToolError::Header(err) => write!(f, "{}", err),
+            ToolError::Lzsa(err) => write!(f, "{}", err),
+            ToolError::Image(err) => write!(f, "{}", err),
+            ToolError::Palette(err) => write!(f, "{}", err),
+            ToolError::AlreadyCompressed => write!(f, "File is already compressed"),
+            ToolError::NotCompressed => write!(f, "File is not compressed"),
+            ToolError::TruncatedFile => write!(f, "File is truncated"),
+            ToolError::UnexpectedPixelDataSize => {
+                write!(f, "Decompressed pixel data does not match image size")
+            }
+            ToolError::CodecNotRegistered => write!(f, "The BMX codec is not registered"),
+            ToolError::ChildFailed(code) => write!(f, "Child process exited with code {}", code),
+            ToolError::ConformanceFailed(count) => {
+                write!(f, "{} conformance checks failed", count)
+            }
+        }
+    }
+}
+
+impl From<std::io::Error> for ToolError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<windows::core::Error> for ToolError {
+    fn from(err: windows::core::Error) -> Self {
+        Self::Win(err)
+    }
+}
+
+impl From<TranscodeError> for ToolError {
+    fn from(err: TranscodeError) -> Self {
+        Self::Transcode(err)
+    }
+}
+
+impl From<FileHeaderError> for ToolError {
+    fn from(err: FileHeaderError) -> Self {
+        Self::Header(err)
+    }
+}
+
+impl From<LzsaError> for ToolError {
+    fn from(err: LzsaError) -> Self {
+        Self::Lzsa(err)
+    }
+}
+
+impl From<BmxImageError> for ToolError {
+    fn from(err: BmxImageError) -> Self {
+        Self::Image(err)
+    }
+}
+
+impl From<PaletteIoError> for ToolError {
+    fn from(err: PaletteIoError) -> Self {
+        Self::Palette(err)
+    }
+}
+
+struct BmxFile {
+    header: FileHeader,
+    palette: Vec<u8>,
+    data: Vec<u8>,
+}
+
+impl BmxFile {
+    fn read(path: &str) -> Result<Self, ToolError> {
+        let bytes = std::fs::read(path)?;
+
+        let header_size = std::mem::size_of::<FileHeader>();
+        let header = FileHeader::from_bytes(bytes.get(..header_size).unwrap_or(&bytes))?;
+
+        let data_start = header.data_start as usize;
+        if bytes.len() < data_start {
+            return Err(ToolError::TruncatedFile);
+        }
+
+        Ok(Self {
+            palette: bytes[header_size..data_start].to_vec(),
+            data: bytes[data_start..].to_vec(),
+            header,
+        })
+    }
+
+    fn write(&self, path: &str) -> Result<(), ToolError> {
+        std::fs::write(
+            path,
+            [&self.header.to_bytes()[..], &self.palette, &self.data].concat(),
+        )?;
+
+        Ok(())
+    }
+}
+
+fn info(path: &str) -> Result<(), ToolError> {
+    let file = BmxFile::read(path)?;
+    let header = &file.header;
+
+    println!("Version:                   {}", header.version);
+    println!(
+        "Size:                      {}x{}",
+        header.width, header.height
+    );
+    println!("Bit depth:                 {}", header.bit_depth);
+    println!(
+        "VERA color depth register: {}",
+        header.vera_color_depth_register
+    );
+    println!(
+        "Palette entries used:      {}",
+        header.palette_entry_count()
+    );
+    println!("Palette start:             {}", header.pal_start);
+    println!("Data start:                {}", header.data_start);
+    println!(
+        "Compressed:                {}",
+        if header.compressed != 0 { "yes" } else { "no" }
+    );
+    println!("VERA border color:         {}", header.vera_border_color);
+    if let Some((x, y)) = header.resolution() {
+        println!("Resolution:                {}x{} DPI", x, y);
+    }
+    println!(
+        "Integrity:                 {}",
+        header.check_integrity(&file.data)
+    );
+    println!("Pixel data size:           {}", file.data.len());
+
+    Ok(())
+}
+
+fn compress(input: &str, output: &str) -> Result<(), ToolError> {
+    let mut file = BmxFile::read(input)?;
+
+    if file.header.compressed != 0 {
+        return Err(ToolError::AlreadyCompressed);
+    }
+
+    let pixel_data_len = file.header.pixel_data_len();
+    if file.data.len() < pixel_data_len {
+        return Err(ToolError::TruncatedFile);
+    }
+
+    file.data = lzsa::compress(&file.data[..pixel_data_len])?;
+    file.header.compressed = 1;
+    file.write(output)
+}
+
+fn decompress(input: &str, output: &str) -> Result<(), ToolError> {
+    let mut file = BmxFile::read(input)?;
+
+    if file.header.compressed == 0 {
+        return Err(ToolError::NotCompressed);
+    }
+
+    let pixel_data_len = file.header.pixel_data_len();
+    file.data = lzsa::decompress(&file.data, pixel_data_len)?;
+
+    if file.data.len() != pixel_data_len {
+        return Err(ToolError::UnexpectedPixelDataSize);
+    }
+
+    file.header.compressed = 0;
+    file.write(output)
+}
+
+fn extract_palette(input: &str, output: &str, format: &str) -> Result<(), ToolError> {
+    let format = PaletteFormat::from_name(format).ok_or(ToolError::Usage)?;
+    let image = BmxImage::from_bytes(&std::fs::read(input)?)?;
+
+    palette_io::write(output, &image.palette, format)?;
+    Ok(())
+}
+
+fn apply_palette(input: &str, palette: &str, output: &str) -> Result<(), ToolError> {
+    let image = BmxImage::from_bytes(&std::fs::read(input)?)?;
+    let palette = palette_io::read(palette)?;
+
+    let mut result = BmxImage::new(
+        image.header.width,
+        image.header.height,
+        image.header.bit_depth,
+        palette,
+        image.data,
+    )?;
+
+    result.header.version = image.header.version;
+    result.header.pal_start = image.header.pal_start;
+    result.header.vera_border_color = image.header.vera_border_color;
+    result.header.reserved = image.header.reserved;
+
+    std::fs::write(output, result.to_bytes(image.header.compressed != 0)?)?;
+    Ok(())
+}
+
+fn to_png(input: &str, output: &str) -> Result<(), ToolError> {
+    TranscodeRequest::new(
+        PathBuf::from(input),
+        PathBuf::from(output),
+        GUID_ContainerFormatPng,
+    )
+    .run(&create_imaging_factory()?)?;
+
+    Ok(())
+}
+
+fn from_png(input: &str, output: &str, bit_depth: &str) -> Result<(), ToolError> {
+    let bit_depth = match bit_depth {
+        "1" => 1,
+        "2" => 2,
+        "4" => 4,
+        "8" => 8,
+        _ => return Err(ToolError::Usage),
+    };
+
+    TranscodeRequest {
+        options: TranscodeOptions {
+            bit_depth: Some(bit_depth),
+            dithering: Some(Dithering::ErrorDiffusion),
+            ..Default::default()
+        },
+        ..TranscodeRequest::new(
+            PathBuf::from(input),
+            PathBuf::from(output),
+            CONTAINER_FORMAT,
+        )
+    }
+    .run(&create_imaging_factory()?)?;
+
+    Ok(())
+}
+
+fn decode(path: &str) -> Result<(), ToolError> {
+    let imaging_factory = create_imaging_factory()?;
+
+    let decoder = unsafe {
+        imaging_factory.CreateDecoderFromFilename(
+            &HSTRING::from(path),
+            None,
+            GENERIC_READ,
+            WICDecodeMetadataCacheOnDemand,
+        )?
+    };
+
+    if unsafe { decoder.GetContainerFormat()? } != CONTAINER_FORMAT {
+        return Err(ToolError::CodecNotRegistered);
+    }
+
+    let frame = unsafe { decoder.GetFrame(0)? };
+
+    let (mut width, mut height) = (0, 0);
+    unsafe { frame.GetSize(&raw mut width, &raw mut height)? };
+
+    #[allow(non_upper_case_globals)]
+    let bit_depth = match unsafe { frame.GetPixelFormat()? } {
+        GUID_WICPixelFormat1bppIndexed => 1,
+        GUID_WICPixelFormat2bppIndexed => 2,
+        GUID_WICPixelFormat4bppIndexed => 4,
+        _ => 8,
+    };
+
+    let stride = (width * bit_depth).div_ceil(8);
+    let mut pixels = vec![0u8; (stride * height) as usize];
+    unsafe { frame.CopyPixels(std::ptr::null(), stride, &mut pixels)? };
+
+    println!("Decoded {}x{} at {} bpp", width, height, bit_depth);
+    Ok(())
+}
+
+fn decode_low_integrity(path: &str) -> Result<(), ToolError> {
+    const LOW_INTEGRITY: PCWSTR = w!("S-1-16-4096");
+
+    let token = unsafe {
+        let mut token = HANDLE::default();
+        OpenProcessToken(
+            GetCurrentProcess(),
+            TOKEN_DUPLICATE | TOKEN_QUERY | TOKEN_ADJUST_DEFAULT | TOKEN_ASSIGN_PRIMARY,
+            &raw mut token,
+        )?;
+        Owned::new(token)
+    };
+
+    let low_integrity_token = unsafe {
+        let mut low_integrity_token = HANDLE::default();
+        DuplicateTokenEx(
+            *token,
+            TOKEN_DUPLICATE | TOKEN_QUERY | TOKEN_ADJUST_DEFAULT | TOKEN_ASSIGN_PRIMARY,
+            None,
+            SecurityImpersonation,
+            TokenPrimary,
+            &raw mut low_integrity_token,
+        )?;
+        Owned::new(low_integrity_token)
+    };
+
+    let mut sid = PSID::default();
+    unsafe { ConvertStringSidToSidW(LOW_INTEGRITY, &raw mut sid)? };
+    let _sid = unsafe { Owned::new(HLOCAL(sid.0)) };
+
+    let label = TOKEN_MANDATORY_LABEL {
+        Label: SID_AND_ATTRIBUTES {
+            Sid: sid,
+            Attributes: SE_GROUP_INTEGRITY as u32,
+        },
+    };
+
+    unsafe {
+        SetTokenInformation(
+            *low_integrity_token,
+            TokenIntegrityLevel,
+            (&raw const label).cast(),
+            std::mem::size_of_val(&label) as u32 + GetLengthSid(sid),
+        )?;
+    }
+
+    let mut command_line = format!(
+        "\"{}\" decode \"{}\"",
+        std::env::current_exe()?.display(),
+        path
+    )
+    .encode_utf16()
+    .chain(std::iter::once(0))
+    .collect::<Vec<_>>();
+
+    let startup_info = STARTUPINFOW {
+        cb: std::mem::size_of::<STARTUPINFOW>() as u32,
+        ..Default::default()
+    };
+    let mut process_information = PROCESS_INFORMATION::default();
+
+    unsafe {
+        CreateProcessAsUserW(
+            *low_integrity_token,
+            PCWSTR::null(),
+            PWSTR::from_raw(command_line.as_mut_ptr()),
+            None,
+            None,
+            false,
+            PROCESS_CREATION_FLAGS(0),
+            None,
+            PCWSTR::null(),
+            &startup_info,
+            &raw mut process_information,
+        )?;
+    }
+
+    let process = unsafe { Owned::new(process_information.hProcess) };
+    let _thread = unsafe { Owned::new(process_information.hThread) };
+
+    let mut exit_code = 0;
+    unsafe {
+        WaitForSingleObject(*process, INFINITE);
+        GetExitCodeProcess(*process, &raw mut exit_code)?;
+    }
+
+    match exit_code {
+        0 => Ok(()),
+        code => Err(ToolError::ChildFailed(code)),
+    }
+}
+
+fn check_conformance() -> Result<(), ToolError> {
+    let results = conformance::run(&create_imaging_factory()?);
+
+    for result in &results {
+        println!("{}", result);
+    }
+
+    let failed = results
+        .iter()
+        .filter(|result| result.outcome.is_err())
+        .count();
+    println!(
+        "{} of {} checks passed",
+        results.len() - failed,
+        results.len()
+    );
+
+    if failed == 0 {
+        Ok(())
+    } else {
+        Err(ToolError::ConformanceFailed(failed))
+    }
+}
+
+fn write_manifest(output: &str, module_name: &str) -> Result<(), ToolError> {
+    std::fs::write(output, activation_manifest(module_name))?;
+    Ok(())
+}
+
+fn default_module_path() -> Result<PathBuf, ToolError> {
+    let executable = std::env::current_exe()?;
+    Ok(executable.with_file_name("bmx_shell.dll"))
+}
+
+fn call_module_export(module_path: Option<&str>, export: PCSTR) -> Result<(), ToolError> {
+    let module_path = match module_path {
+        Some(module_path) => PathBuf::from(module_path),
+        None => default_module_path()?,
+    };
+
+    let module = unsafe { LoadLibraryW(&HSTRING::from(module_path.as_os_str()))? };
+
+    let result = match unsafe { GetProcAddress(module, export) } {
+        Some(function) => {
+            let function: unsafe extern "system" fn() -> HRESULT =
+                unsafe { std::mem::transmute(function) };
+            unsafe { function() }.ok()
+        }
+        None => Err(windows::core::Error::from_win32()),
+    };
+
+    unsafe {
+        let _ = FreeLibrary(module);
+    }
+
+    Ok(result?)
+}
+
+fn run(args: &[&str]) -> Result<(), ToolError> {
+    match args {
+        ["info", path] => info(path),
+        ["to-png", input, output] => to_png(input, output),
+        ["from-png", input, output] => from_png(input, output, "8"),
+        ["from-png", input, output, bit_depth] => from_png(input, output, bit_depth),
+        ["compress", input, output] => compress(input, output),
+        ["decompress", input, output] => decompress(input, output),
+        ["extract-palette", input, output] => extract_palette(input, output, "vera"),
+        ["extract-palette", input, output, format] => extract_palette(input, output, format),
+        ["apply-palette", input, palette, output] => apply_palette(input, palette, output),
+        ["decode", path] => decode(path),
+        ["decode-low-integrity", path] => decode_low_integrity(path),
+        ["conformance"] => check_conformance(),
+        ["manifest", output] => write_manifest(output, "bmx_shell.dll"),
+        ["manifest", output, module_name] => write_manifest(output, module_name),
+        ["register"] => call_module_export(None, s!("DllRegisterServer")),
+        ["register", module_path] => call_module_export(Some(module_path), s!("DllRegisterServer")),
+        ["unregister"] => call_module_export(None, s!("DllUnregisterServer")),
+        ["unregister", module_path] => {
+            call_module_export(Some(module_path), s!("DllUnregisterServer"))
+        }
+        _ => Err(ToolError::Usage),
+    }
+}
+
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+
+    if let Err(err) = unsafe { CoInitializeEx(None, COINIT_MULTITHREADED) }.ok() {
+        eprintln!("bmx-tool: {}", err);
+        return ExitCode::FAILURE;
+    }
+
+    let result = run(&args);
+
+    unsafe { CoUninitialize() };
+
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(ToolError::Usage) => {
+            eprintln!("{}", USAGE);
+            ExitCode::from(2)
+        }
+        Err(err) => {
+            eprintln!("bmx-tool: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -1,7 +1,46 @@
 use std::{fmt::Display, num::NonZeroU8};
 
+use crate::{
+    crc32::crc32,
+    lzsa::{self, LzsaError},
+};
+
+pub mod error;
+pub mod limits;
+pub mod palette;
+pub mod raw;
+pub mod reader;
+
+pub use error::BmxError;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Validation {
+    #[default]
+    Strict,
+    Lenient,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Integrity {
+    Absent,
+    Ok,
+    Mismatch,
+}
+
+impl Display for Integrity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Integrity::Absent => write!(f, "Not present"),
+            Integrity::Ok => write!(f, "OK"),
+            Integrity::Mismatch => write!(f, "Mismatch"),
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FileHeader {
     pub file_id: [NonZeroU8; 3],
     pub version: u8,
@@ -18,8 +57,53 @@ pub struct FileHeader {
 }
 
 impl FileHeader {
+    pub const SIZE: usize = 32;
+
+    pub const FILE_ID: [u8; 3] = *b"BMX";
+    pub const VERSION: u8 = 1;
+    pub const MIN_VERSION: u8 = 1;
+
+    pub const PATTERN: [u8; 4] = [
+        Self::FILE_ID[0],
+        Self::FILE_ID[1],
+        Self::FILE_ID[2],
+        Self::VERSION,
+    ];
+    pub const PATTERN_MASK: [u8; 4] = [0xFF, 0xFF, 0xFF, 0x00];
+
+    pub const CRC32_TAG: [u8; 4] = *b"CRC1";
+    const CRC32_TAG_OFFSET: usize = 8;
+    const CRC32_OFFSET: usize = 12;
+
+    // BMX has no notion of DPI; converted images keep their source resolution here so it survives
+    // a round trip. Absent means 96 DPI.
+    pub const RESOLUTION_TAG: [u8; 4] = *b"DPI1";
+    const RESOLUTION_TAG_OFFSET: usize = 0;
+    const RESOLUTION_OFFSET: usize = 4;
+
+    pub fn builder() -> FileHeaderBuilder {
+        FileHeaderBuilder::default()
+    }
+
+    pub const fn vera_color_depth_register_for(bit_depth: u8) -> Option<u8> {
+        match bit_depth {
+            1 => Some(0),
+            2 => Some(1),
+            4 => Some(2),
+            8 => Some(3),
+            _ => None,
+        }
+    }
+
     pub const fn from_bytes(bytes: &[u8]) -> Result<FileHeader, FileHeaderError> {
-        if bytes.len() != 32 {
+        Self::from_bytes_with(bytes, Validation::Lenient)
+    }
+
+    pub const fn from_bytes_with(
+        bytes: &[u8],
+        validation: Validation,
+    ) -> Result<FileHeader, FileHeaderError> {
+        if bytes.len() != Self::SIZE {
             return Err(FileHeaderError::InvalidHeaderSize);
         }
 
@@ -57,21 +141,27 @@ impl FileHeader {
             ],
         };
 
-        match header.validate() {
+        match header.validate_with(validation) {
             Ok(()) => Ok(header),
             Err(err) => Err(err),
         }
     }
 
     pub const fn validate(&self) -> Result<(), FileHeaderError> {
-        if self.file_id[0].get() != b'B'
-            || self.file_id[1].get() != b'M'
-            || self.file_id[2].get() != b'X'
+        self.validate_with(Validation::Strict)
+    }
+
+    pub const fn validate_with(&self, validation: Validation) -> Result<(), FileHeaderError> {
+        if self.file_id[0].get() != Self::FILE_ID[0]
+            || self.file_id[1].get() != Self::FILE_ID[1]
+            || self.file_id[2].get() != Self::FILE_ID[2]
         {
             return Err(FileHeaderError::InvalidFileId);
         }
 
-        if self.version != 1 {
+        if self.version < Self::MIN_VERSION
+            || (matches!(validation, Validation::Strict) && self.version > Self::VERSION)
+        {
             return Err(FileHeaderError::InvalidVersion);
         }
 
@@ -84,26 +174,33 @@ impl FileHeader {
         }
 
         if !matches!(
-            (self.bit_depth, self.vera_color_depth_register),
-            (1, 0) | (2, 1) | (4, 2) | (8, 3)
+            Self::vera_color_depth_register_for(self.bit_depth),
+            Some(register) if register == self.vera_color_depth_register
         ) {
             return Err(FileHeaderError::BitDepthMismatch);
         }
 
-        if (self.data_start as usize)
-            < std::mem::size_of::<FileHeader>()
-                + std::mem::size_of::<PaletteEntry>() * self.palette_entry_count()
+        if (self.data_start as usize) < Self::SIZE + PaletteEntry::SIZE * self.palette_entry_count()
         {
             return Err(FileHeaderError::InvalidDataStart);
         }
 
+        // The border color is a VERA palette index, so it's checked against the entries the
+        // palette is loaded into.
+        if matches!(validation, Validation::Strict)
+            && (self.vera_border_color < self.pal_start
+                || (self.vera_border_color - self.pal_start) as usize >= self.color_count())
+        {
+            return Err(FileHeaderError::InvalidVeraBorderColor);
+        }
+
         Ok(())
     }
 
-    pub const fn to_bytes(&self) -> [u8; 32] {
+    pub const fn to_bytes(&self) -> [u8; Self::SIZE] {
         let width = self.width.to_le_bytes();
         let height = self.height.to_le_bytes();
-        let data_start = self.data_start.to_be_bytes();
+        let data_start = self.data_start.to_le_bytes();
 
         [
             self.file_id[0].get(),
@@ -148,17 +245,138 @@ impl FileHeader {
             self.pal_used as _
         }
     }
+
+    pub const fn crc32(&self) -> Option<u32> {
+        let reserved = &self.reserved;
+        let tag = Self::CRC32_TAG_OFFSET;
+        let crc = Self::CRC32_OFFSET;
+
+        if reserved[tag] == Self::CRC32_TAG[0]
+            && reserved[tag + 1] == Self::CRC32_TAG[1]
+            && reserved[tag + 2] == Self::CRC32_TAG[2]
+            && reserved[tag + 3] == Self::CRC32_TAG[3]
+        {
+            Some(u32::from_le_bytes([
+                reserved[crc],
+                reserved[crc + 1],
+                reserved[crc + 2],
+                reserved[crc + 3],
+            ]))
+        } else {
+            None
+        }
+    }
+
+    pub fn set_crc32(&mut self, crc32: Option<u32>) {
+        let (tag, crc) = match crc32 {
+            Some(crc32) => (Self::CRC32_TAG, crc32.to_le_bytes()),
+            None => ([0; 4], [0; 4]),
+        };
+
+        self.reserved[Self::CRC32_TAG_OFFSET..][..4].copy_from_slice(&tag);
+        self.reserved[Self::CRC32_OFFSET..][..4].copy_from_slice(&crc);
+    }
+
+    pub const fn resolution(&self) -> Option<(u16, u16)> {
+        let reserved = &self.reserved;
+        let tag = Self::RESOLUTION_TAG_OFFSET;
+        let resolution = Self::RESOLUTION_OFFSET;
+
+        if reserved[tag] == Self::RESOLUTION_TAG[0]
+            && reserved[tag + 1] == Self::RESOLUTION_TAG[1]
+            && reserved[tag + 2] == Self::RESOLUTION_TAG[2]
+            && reserved[tag + 3] == Self::RESOLUTION_TAG[3]
+        {
+            Some((
+                u16::from_le_bytes([reserved[resolution], reserved[resolution + 1]]),
+                u16::from_le_bytes([reserved[resolution + 2], reserved[resolution + 3]]),
+            ))
+        } else {
+            None
+        }
+    }
+
+    pub fn set_resolution(&mut self, resolution: Option<(u16, u16)>) {
+        let (tag, x, y) = match resolution {
+            Some((x, y)) => (Self::RESOLUTION_TAG, x.to_le_bytes(), y.to_le_bytes()),
+            None => ([0; 4], [0; 2], [0; 2]),
+        };
+
+        self.reserved[Self::RESOLUTION_TAG_OFFSET..][..4].copy_from_slice(&tag);
+        self.reserved[Self::RESOLUTION_OFFSET..][..2].copy_from_slice(&x);
+        self.reserved[Self::RESOLUTION_OFFSET + 2..][..2].copy_from_slice(&y);
+    }
+
+    pub fn check_integrity(&self, stored_data: &[u8]) -> Integrity {
+        let Some(expected) = self.crc32() else {
+            return Integrity::Absent;
+        };
+
+        let data_len = self.pixel_data_len();
+        let actual = if self.compressed != 0 {
+            match lzsa::decompress(stored_data, data_len) {
+                Ok(data) if data.len() == data_len => crc32(&data),
+                _ => return Integrity::Mismatch,
+            }
+        } else {
+            match stored_data.get(..data_len) {
+                Some(data) => crc32(data),
+                None => return Integrity::Mismatch,
+            }
+        };
+
+        if actual == expected {
+            Integrity::Ok
+        } else {
+            Integrity::Mismatch
+        }
+    }
+
+    pub const fn is_newer_version(&self) -> bool {
+        self.version > Self::VERSION
+    }
+
+    pub const fn bytes_per_line(&self) -> usize {
+        (self.width as usize * self.bit_depth as usize).div_ceil(8)
+    }
+
+    pub const fn pixel_data_len(&self) -> usize {
+        self.bytes_per_line() * self.height as usize
+    }
+
+    pub const fn border_fill_byte(&self) -> u8 {
+        let index = self.vera_border_color.wrapping_sub(self.pal_start)
+            & ((1u16 << self.bit_depth) - 1) as u8;
+
+        match self.bit_depth {
+            1 => 0u8.wrapping_sub(index),
+            2 => index * 0b0101_0101,
+            4 => index * 0b0001_0001,
+            _ => index,
+        }
+    }
+
+    pub const fn color_count(&self) -> usize {
+        let addressable = 1usize << self.bit_depth;
+        let palette_entry_count = self.palette_entry_count();
+
+        if addressable < palette_entry_count {
+            addressable
+        } else {
+            palette_entry_count
+        }
+    }
 }
 
 impl Default for FileHeader {
     fn default() -> Self {
         Self {
             file_id: [
-                unsafe { NonZeroU8::new_unchecked(b'B') },
-                unsafe { NonZeroU8::new_unchecked(b'M') },
-                unsafe { NonZeroU8::new_unchecked(b'X') },
+                unsafe { NonZeroU8::new_unchecked(FileHeader::FILE_ID[0]) },
+                unsafe { NonZeroU8::new_unchecked(FileHeader::FILE_ID[1]) },
+                unsafe { NonZeroU8::new_unchecked(FileHeader::FILE_ID[2]) },
             ],
-            version: 1,
+            version: FileHeader::VERSION,
             bit_depth: 0,
             vera_color_depth_register: 0,
             width: 0,
@@ -176,6 +394,131 @@ impl Default for FileHeader {
 const _: () =
     assert!(std::mem::size_of::<FileHeader>() == std::mem::size_of::<Option<FileHeader>>());
 
+#[derive(Clone, Debug, Default)]
+pub struct FileHeaderBuilder {
+    version: Option<u8>,
+    width: u16,
+    height: u16,
+    bit_depth: u8,
+    palette_len: usize,
+    pal_start: u8,
+    compressed: bool,
+    vera_border_color: Option<u8>,
+}
+
+impl FileHeaderBuilder {
+    pub fn version(mut self, version: u8) -> Self {
+        self.version = Some(version);
+        self
+    }
+
+    pub fn width(mut self, width: u16) -> Self {
+        self.width = width;
+        self
+    }
+
+    pub fn height(mut self, height: u16) -> Self {
+        self.height = height;
+        self
+    }
+
+    pub fn bit_depth(mut self, bit_depth: u8) -> Self {
+        self.bit_depth = bit_depth;
+        self
+    }
+
+    pub fn palette_len(mut self, palette_len: usize) -> Self {
+        self.palette_len = palette_len;
+        self
+    }
+
+    pub fn pal_start(mut self, pal_start: u8) -> Self {
+        self.pal_start = pal_start;
+        self
+    }
+
+    pub fn compressed(mut self, compressed: bool) -> Self {
+        self.compressed = compressed;
+        self
+    }
+
+    pub fn vera_border_color(mut self, vera_border_color: u8) -> Self {
+        self.vera_border_color = Some(vera_border_color);
+        self
+    }
+
+    pub fn build(self) -> Result<FileHeader, FileHeaderError> {
+        if !matches!(self.palette_len, 1..=256) {
+            return Err(FileHeaderError::InvalidPaletteLength);
+        }
+
+        let header = FileHeader {
+            version: self.version.unwrap_or(FileHeader::VERSION),
+            bit_depth: self.bit_depth,
+            vera_color_depth_register: FileHeader::vera_color_depth_register_for(self.bit_depth)
+                .ok_or(FileHeaderError::InvalidBitDepth)?,
+            width: self.width,
+            height: self.height,
+            pal_used: if self.palette_len == 256 {
+                0
+            } else {
+                self.palette_len as u8
+            },
+            pal_start: self.pal_start,
+            data_start: (FileHeader::SIZE + self.palette_len * PaletteEntry::SIZE) as u16,
+            compressed: self.compressed as i8,
+            // The first entry of the palette unless given.
+            vera_border_color: self.vera_border_color.unwrap_or(self.pal_start),
+            ..Default::default()
+        };
+
+        header.validate()?;
+        Ok(header)
+    }
+}
+
+const _: () = assert!(std::mem::size_of::<PaletteEntry>() == PaletteEntry::SIZE);
+
+const _: () = {
+    use std::mem::offset_of;
+
+    assert!(std::mem::size_of::<FileHeader>() == FileHeader::SIZE);
+    assert!(offset_of!(FileHeader, file_id) == 0);
+    assert!(offset_of!(FileHeader, version) == 3);
+    assert!(offset_of!(FileHeader, bit_depth) == 4);
+    assert!(offset_of!(FileHeader, vera_color_depth_register) == 5);
+    assert!(offset_of!(FileHeader, width) == 6);
+    assert!(offset_of!(FileHeader, height) == 8);
+    assert!(offset_of!(FileHeader, pal_used) == 10);
+    assert!(offset_of!(FileHeader, pal_start) == 11);
+    assert!(offset_of!(FileHeader, data_start) == 12);
+    assert!(offset_of!(FileHeader, compressed) == 14);
+    assert!(offset_of!(FileHeader, vera_border_color) == 15);
+    assert!(offset_of!(FileHeader, reserved) == 16);
+};
+
+// Whether `bytes`, the start of a file or stream, look like a BMX. Matches exactly what the decoder
+// registers as its WIC pattern, so hosts sniffing streams without an extension and the shell
+// extensions agree on what is a BMX; anything beyond that is up to the header validation.
+pub const fn sniff(bytes: &[u8]) -> bool {
+    if bytes.len() < FileHeader::PATTERN.len() {
+        return false;
+    }
+
+    let mut i = 0;
+    while i < FileHeader::PATTERN.len() {
+        if bytes[i] & FileHeader::PATTERN_MASK[i]
+            != FileHeader::PATTERN[i] & FileHeader::PATTERN_MASK[i]
+        {
+            return false;
+        }
+
+        i += 1;
+    }
+
+    true
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum FileHeaderError {
     InvalidHeaderSize,
@@ -186,6 +529,7 @@ pub enum FileHeaderError {
     BitDepthMismatch,
     InvalidDataStart,
     InvalidVeraBorderColor,
+    InvalidPaletteLength,
 }
 
 impl Display for FileHeaderError {
@@ -206,18 +550,37 @@ impl Display for FileHeaderError {
             }
             FileHeaderError::InvalidDataStart => write!(f, "Invalid data start"),
             FileHeaderError::InvalidVeraBorderColor => write!(f, "Invalid Vera border color"),
+            FileHeaderError::InvalidPaletteLength => {
+                write!(f, "Palette must have 1 to 256 entries")
+            }
         }
     }
 }
 
+impl std::error::Error for FileHeaderError {}
+
 #[repr(C)]
-#[derive(Clone, Copy, Debug, Default)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct PaletteEntry {
     pub gb: u8,
     pub r: u8,
 }
 
 impl PaletteEntry {
+    pub const SIZE: usize = 2;
+
+    pub const fn from_bytes(bytes: [u8; Self::SIZE]) -> Self {
+        Self {
+            gb: bytes[0],
+            r: bytes[1],
+        }
+    }
+
+    pub const fn to_bytes(&self) -> [u8; Self::SIZE] {
+        [self.gb, self.r]
+    }
+
     pub const fn from_rgb(r: u8, g: u8, b: u8) -> Self {
         Self {
             gb: (g >> 4) << 4 | (b >> 4),
@@ -246,3 +609,784 @@ impl PaletteEntry {
         0xFF000000 | (r as u32) << 16 | (g as u32) << 8 | (b as u32)
     }
 }
+
+#[derive(Clone, Copy, Debug)]
+pub enum BmxImageError {
+    Header(FileHeaderError),
+    Compression(LzsaError),
+    InvalidPalette,
+    InvalidPixelDataLength,
+    Truncated,
+}
+
+impl Display for BmxImageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            BmxImageError::Header(err) => write!(f, "{}", err),
+            BmxImageError::Compression(err) => write!(f, "{}", err),
+            BmxImageError::InvalidPalette => write!(f, "Palette must have 1 to 256 entries"),
+            BmxImageError::InvalidPixelDataLength => {
+                write!(f, "Pixel data length does not match image size")
+            }
+            BmxImageError::Truncated => write!(f, "File is truncated"),
+        }
+    }
+}
+
+impl std::error::Error for BmxImageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BmxImageError::Header(err) => Some(err),
+            BmxImageError::Compression(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<FileHeaderError> for BmxImageError {
+    fn from(err: FileHeaderError) -> Self {
+        Self::Header(err)
+    }
+}
+
+impl From<LzsaError> for BmxImageError {
+    fn from(err: LzsaError) -> Self {
+        Self::Compression(err)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Truncation {
+    #[default]
+    Reject,
+    FillWithBorderColor,
+}
+
+#[derive(Clone, Debug)]
+pub struct BmxImage {
+    pub header: FileHeader,
+    pub palette: Vec<PaletteEntry>,
+    pub data: Vec<u8>,
+}
+
+impl BmxImage {
+    pub fn new(
+        width: u16,
+        height: u16,
+        bit_depth: u8,
+        palette: Vec<PaletteEntry>,
+        data: Vec<u8>,
+    ) -> Result<Self, BmxImageError> {
+        if palette.is_empty() || palette.len() > 256 {
+            return Err(BmxImageError::InvalidPalette);
+        }
+
+        let header = FileHeader::builder()
+            .width(width)
+            .height(height)
+            .bit_depth(bit_depth)
+            .palette_len(palette.len())
+            .build()?;
+
+        if data.len() != header.pixel_data_len() {
+            return Err(BmxImageError::InvalidPixelDataLength);
+        }
+
+        Ok(Self {
+            header,
+            palette,
+            data,
+        })
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, BmxImageError> {
+        Self::from_bytes_with(bytes, Truncation::Reject)
+    }
+
+    pub fn from_bytes_with(bytes: &[u8], truncation: Truncation) -> Result<Self, BmxImageError> {
+        let header = FileHeader::from_bytes(bytes.get(..FileHeader::SIZE).unwrap_or(bytes))?;
+
+        let palette_end = FileHeader::SIZE + header.palette_entry_count() * PaletteEntry::SIZE;
+        let palette = bytes
+            .get(FileHeader::SIZE..palette_end)
+            .ok_or(BmxImageError::Truncated)?
+            .chunks_exact(PaletteEntry::SIZE)
+            .map(|entry| PaletteEntry::from_bytes([entry[0], entry[1]]))
+            .collect();
+
+        let data = bytes
+            .get(header.data_start as usize..)
+            .ok_or(BmxImageError::Truncated)?;
+
+        let data_len = header.pixel_data_len();
+        let mut data = if header.compressed != 0 {
+            match truncation {
+                Truncation::Reject => lzsa::decompress(data, data_len)?,
+                Truncation::FillWithBorderColor => lzsa::decompress_truncated(data, data_len)?,
+            }
+        } else {
+            data[..data.len().min(data_len)].to_vec()
+        };
+
+        if data.len() != data_len {
+            match truncation {
+                Truncation::Reject => return Err(BmxImageError::Truncated),
+                Truncation::FillWithBorderColor => data.resize(data_len, header.border_fill_byte()),
+            }
+        }
+
+        Ok(Self {
+            header,
+            palette,
+            data,
+        })
+    }
+
+    pub fn integrity(&self) -> Integrity {
+        match self.header.crc32() {
+            Some(expected) if expected == crc32(&self.data) => Integrity::Ok,
+            Some(_) => Integrity::Mismatch,
+            None => Integrity::Absent,
+        }
+    }
+
+    pub fn update_crc32(&mut self) {
+        self.header.set_crc32(Some(crc32(&self.data)));
+    }
+
+    pub fn rows_packed(&self) -> std::slice::ChunksExact<'_, u8> {
+        self.data.chunks_exact(self.header.bytes_per_line().max(1))
+    }
+
+    pub fn rows(&self) -> impl Iterator<Item = Vec<u8>> + '_ {
+        let width = self.header.width as usize;
+        let bit_depth = self.header.bit_depth;
+
+        self.rows_packed().map(move |row| {
+            (0..width)
+                .map(|x| unpack_index(row, x, bit_depth))
+                .collect()
+        })
+    }
+
+    // Pixels with the palette applied, in the byte order of GUID_WICPixelFormat32bppBGRA. Indices
+    // past the end of the palette come out black.
+    pub fn to_bgra(&self) -> Vec<u8> {
+        let mut bgra =
+            Vec::with_capacity(self.header.width as usize * self.header.height as usize * 4);
+
+        for row in self.rows() {
+            for index in row {
+                let color = self
+                    .palette
+                    .get(index as usize)
+                    .map_or(0xFF000000, PaletteEntry::to_wic);
+
+                bgra.extend_from_slice(&color.to_le_bytes());
+            }
+        }
+
+        bgra
+    }
+
+    // Centers the image on the smallest canvas with the aspect ratio `aspect_x:aspect_y` and fills
+    // the rest with the border color, the way the hardware shows whatever lies around a picture.
+    pub fn letterbox(&self, (aspect_x, aspect_y): (u16, u16)) -> BmxImage {
+        let (width, height) = (self.header.width as u64, self.header.height as u64);
+        let (aspect_x, aspect_y) = (aspect_x.max(1) as u64, aspect_y.max(1) as u64);
+
+        let (canvas_width, canvas_height) = if width * aspect_y >= height * aspect_x {
+            (width, (width * aspect_y).div_ceil(aspect_x))
+        } else {
+            ((height * aspect_x).div_ceil(aspect_y), height)
+        };
+
+        let mut header = FileHeader {
+            width: canvas_width.min(u16::MAX as u64) as u16,
+            height: canvas_height.min(u16::MAX as u64) as u16,
+            ..self.header.clone()
+        };
+        header.set_crc32(None);
+
+        let line_len = header.bytes_per_line();
+        let left = (header.width as usize - width as usize) / 2;
+        let top = (header.height as usize - height as usize) / 2;
+
+        let mut data = vec![header.border_fill_byte(); header.pixel_data_len()];
+
+        for (y, row) in self.rows().enumerate() {
+            let line = &mut data[(top + y) * line_len..][..line_len];
+
+            for (x, index) in row.into_iter().enumerate() {
+                pack_index(line, left + x, header.bit_depth, index);
+            }
+        }
+
+        BmxImage {
+            header,
+            palette: self.palette.clone(),
+            data,
+        }
+    }
+
+    pub fn pixel(&self, x: u16, y: u16) -> Option<u8> {
+        if x >= self.header.width {
+            return None;
+        }
+
+        let row = self.rows_packed().nth(y as usize)?;
+        Some(unpack_index(row, x as usize, self.header.bit_depth))
+    }
+
+    pub fn to_bytes(&self, compress: bool) -> Result<Vec<u8>, BmxImageError> {
+        let header = FileHeader {
+            compressed: compress as i8,
+            ..self.header.clone()
+        };
+
+        let mut bytes = header.to_bytes().to_vec();
+
+        for entry in &self.palette {
+            bytes.extend_from_slice(&entry.to_bytes());
+        }
+
+        bytes.resize(header.data_start as usize, 0);
+
+        if compress {
+            bytes.extend_from_slice(&lzsa::compress(&self.data)?);
+        } else {
+            bytes.extend_from_slice(&self.data);
+        }
+
+        Ok(bytes)
+    }
+}
+
+fn unpack_index(row: &[u8], x: usize, bit_depth: u8) -> u8 {
+    let bit_depth = bit_depth as usize;
+    let bit = x * bit_depth;
+    let shift = 8 - bit_depth - bit % 8;
+    let mask = ((1u16 << bit_depth) - 1) as u8;
+
+    (row[bit / 8] >> shift) & mask
+}
+
+fn pack_index(row: &mut [u8], x: usize, bit_depth: u8, index: u8) {
+    let bit_depth = bit_depth as usize;
+    let bit = x * bit_depth;
+    let shift = 8 - bit_depth - bit % 8;
+    let mask = ((1u16 << bit_depth) - 1) as u8;
+
+    row[bit / 8] = (row[bit / 8] & !(mask << shift)) | ((index & mask) << shift);
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BmxMetadata {
+    pub version: u8,
+    pub newer_version: bool,
+    pub width: u16,
+    pub height: u16,
+    pub bit_depth: u8,
+    pub pal_start: u8,
+    pub compressed: bool,
+    pub vera_border_color: u8,
+    pub integrity: Integrity,
+    pub palette: Vec<PaletteEntry>,
+}
+
+impl From<&BmxImage> for BmxMetadata {
+    fn from(image: &BmxImage) -> Self {
+        Self {
+            version: image.header.version,
+            newer_version: image.header.is_newer_version(),
+            width: image.header.width,
+            height: image.header.height,
+            bit_depth: image.header.bit_depth,
+            pal_start: image.header.pal_start,
+            compressed: image.header.compressed != 0,
+            vera_border_color: image.header.vera_border_color,
+            integrity: image.integrity(),
+            palette: image.palette.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn header() -> FileHeader {
+        FileHeader {
+            bit_depth: 4,
+            vera_color_depth_register: 2,
+            width: 0x1234,
+            height: 0x0102,
+            pal_used: 16,
+            pal_start: 32,
+            data_start: 0x0140,
+            compressed: 1,
+            vera_border_color: 7,
+            reserved: *b"0123456789abcdef",
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn sniffs_pattern() {
+        let bytes = header().to_bytes();
+        assert!(sniff(&bytes));
+        assert!(sniff(b"BMX\x02"));
+        assert!(!sniff(b"BMX"));
+        assert!(!sniff(b"BMP\x01"));
+        assert!(!sniff(b"bmx\x01"));
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let header = header();
+        let bytes = header.to_bytes();
+        let parsed = FileHeader::from_bytes(&bytes).unwrap();
+
+        assert_eq!(parsed.to_bytes(), bytes);
+        assert_eq!(parsed.width, header.width);
+        assert_eq!(parsed.height, header.height);
+        assert_eq!(parsed.data_start, header.data_start);
+    }
+
+    #[test]
+    fn header_is_little_endian() {
+        let bytes = header().to_bytes();
+
+        assert_eq!(&bytes[..6], b"BMX\x01\x04\x02");
+        assert_eq!(&bytes[6..8], &[0x34, 0x12]);
+        assert_eq!(&bytes[8..10], &[0x02, 0x01]);
+        assert_eq!(&bytes[12..14], &[0x40, 0x01]);
+    }
+
+    #[test]
+    fn header_matches_memory_layout() {
+        let header = header();
+        let memory = unsafe {
+            std::slice::from_raw_parts(
+                (&raw const header).cast::<u8>(),
+                std::mem::size_of::<FileHeader>(),
+            )
+        };
+
+        assert_eq!(memory, header.to_bytes());
+    }
+
+    #[test]
+    fn border_color_validation() {
+        let mut header = header();
+        header.vera_border_color = 32 + 15;
+        assert!(header.validate().is_ok());
+
+        header.vera_border_color = 15;
+        assert!(matches!(
+            header.validate(),
+            Err(FileHeaderError::InvalidVeraBorderColor)
+        ));
+
+        header.vera_border_color = 32 + 16;
+        assert!(matches!(
+            header.validate(),
+            Err(FileHeaderError::InvalidVeraBorderColor)
+        ));
+        assert!(FileHeader::from_bytes(&header.to_bytes()).is_ok());
+        assert!(matches!(
+            FileHeader::from_bytes_with(&header.to_bytes(), Validation::Strict),
+            Err(FileHeaderError::InvalidVeraBorderColor)
+        ));
+    }
+
+    #[test]
+    fn version_policy() {
+        let mut header = header();
+        header.version = FileHeader::VERSION + 1;
+
+        let parsed = FileHeader::from_bytes(&header.to_bytes()).unwrap();
+        assert!(parsed.is_newer_version());
+        assert!(matches!(
+            parsed.validate(),
+            Err(FileHeaderError::InvalidVersion)
+        ));
+
+        header.version = 0;
+        assert!(matches!(
+            FileHeader::from_bytes(&header.to_bytes()),
+            Err(FileHeaderError::InvalidVersion)
+        ));
+
+        assert!(matches!(
+            FileHeader::builder()
+                .version(FileHeader::VERSION + 1)
+                .bit_depth(8)
+                .palette_len(256)
+                .build(),
+            Err(FileHeaderError::InvalidVersion)
+        ));
+    }
+
+    #[test]
+    fn truncated_image() {
+        let mut header = header();
+        header.vera_border_color = 32 + 5;
+
+        assert_eq!(header.border_fill_byte(), 0x55);
+
+        let palette = vec![PaletteEntry::default(); 16];
+        let mut image = BmxImage::new(4, 4, 4, palette, vec![0x12; 8]).unwrap();
+        image.header.vera_border_color = 5;
+
+        let bytes = image.to_bytes(false).unwrap();
+        let bytes = &bytes[..bytes.len() - 3];
+
+        assert!(matches!(
+            BmxImage::from_bytes(bytes),
+            Err(BmxImageError::Truncated)
+        ));
+
+        let parsed = BmxImage::from_bytes_with(bytes, Truncation::FillWithBorderColor).unwrap();
+        assert_eq!(
+            parsed.data,
+            [0x12, 0x12, 0x12, 0x12, 0x12, 0x55, 0x55, 0x55]
+        );
+    }
+
+    #[test]
+    fn truncated_compressed_image() {
+        let palette = vec![PaletteEntry::default(); 256];
+        let data = (0..64 * 32).map(|i| (i / 64) as u8).collect::<Vec<_>>();
+        let mut image = BmxImage::new(64, 32, 8, palette, data.clone()).unwrap();
+        image.header.vera_border_color = 200;
+
+        let bytes = image.to_bytes(true).unwrap();
+        let data_start = image.header.data_start as usize;
+        let bytes = &bytes[..data_start + (bytes.len() - data_start) / 2];
+
+        assert!(matches!(
+            BmxImage::from_bytes(bytes),
+            Err(BmxImageError::Compression(LzsaError::UnexpectedEndOfInput))
+        ));
+
+        // The rows that were decoded before the data ran out are kept, the rest are the border.
+        let parsed = BmxImage::from_bytes_with(bytes, Truncation::FillWithBorderColor).unwrap();
+        let decoded = parsed.data.iter().position(|&index| index == 200).unwrap();
+
+        assert!(decoded > 0 && decoded < data.len());
+        assert_eq!(parsed.data[..decoded], data[..decoded]);
+        assert!(parsed.data[decoded..].iter().all(|&index| index == 200));
+    }
+
+    #[test]
+    fn crc32_integrity() {
+        let palette = vec![PaletteEntry::default(); 2];
+        let mut image = BmxImage::new(8, 2, 1, palette, vec![0xA5, 0x5A]).unwrap();
+
+        assert_eq!(image.integrity(), Integrity::Absent);
+
+        image.update_crc32();
+        assert_eq!(image.integrity(), Integrity::Ok);
+
+        for compress in [false, true] {
+            let bytes = image.to_bytes(compress).unwrap();
+            let parsed = BmxImage::from_bytes(&bytes).unwrap();
+
+            assert_eq!(parsed.integrity(), Integrity::Ok);
+            assert_eq!(
+                parsed
+                    .header
+                    .check_integrity(&bytes[parsed.header.data_start as usize..]),
+                Integrity::Ok
+            );
+        }
+
+        image.data[1] = 0;
+        assert_eq!(image.integrity(), Integrity::Mismatch);
+    }
+
+    #[test]
+    fn resolution_round_trip() {
+        let palette = vec![PaletteEntry::default(); 2];
+        let mut image = BmxImage::new(8, 2, 1, palette, vec![0xA5, 0x5A]).unwrap();
+
+        assert_eq!(image.header.resolution(), None);
+
+        image.header.set_resolution(Some((300, 72)));
+        image.update_crc32();
+
+        let parsed = BmxImage::from_bytes(&image.to_bytes(false).unwrap()).unwrap();
+        assert_eq!(parsed.header.resolution(), Some((300, 72)));
+        assert_eq!(parsed.integrity(), Integrity::Ok);
+
+        image.header.set_resolution(None);
+        assert_eq!(image.header.resolution(), None);
+        assert_eq!(image.header.reserved[..8], [0; 8]);
+    }
+
+    #[test]
+    fn rows_and_pixels() {
+        let palette = vec![PaletteEntry::default(); 4];
+        let image = BmxImage::new(
+            5,
+            2,
+            2,
+            palette,
+            vec![0b00_01_10_11, 0b01_000000, 0xFF, 0xC0],
+        )
+        .unwrap();
+
+        assert_eq!(
+            image.rows().collect::<Vec<_>>(),
+            [vec![0, 1, 2, 3, 1], vec![3, 3, 3, 3, 3]]
+        );
+        assert_eq!(image.rows_packed().len(), 2);
+        assert_eq!(image.pixel(3, 0), Some(3));
+        assert_eq!(image.pixel(4, 0), Some(1));
+        assert_eq!(image.pixel(5, 0), None);
+        assert_eq!(image.pixel(0, 2), None);
+    }
+
+    #[test]
+    fn letterbox_with_border_color() {
+        let palette = vec![PaletteEntry::default(); 4];
+        let mut image = BmxImage::new(3, 1, 2, palette, vec![0b01_10_01_00]).unwrap();
+        image.header.vera_border_color = 3;
+        image.update_crc32();
+
+        let square = image.letterbox((1, 1));
+        assert_eq!((square.header.width, square.header.height), (3, 3));
+        assert_eq!(square.header.crc32(), None);
+        assert_eq!(
+            square.rows().collect::<Vec<_>>(),
+            [vec![3, 3, 3], vec![1, 2, 1], vec![3, 3, 3]]
+        );
+
+        // Taller than 4:3, so only columns are added.
+        let wide = BmxImage::new(4, 4, 2, vec![PaletteEntry::default(); 4], vec![0; 4])
+            .unwrap()
+            .letterbox((4, 3));
+        assert_eq!((wide.header.width, wide.header.height), (6, 4));
+        assert_eq!(wide.rows().next().unwrap(), [0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn bgra_applies_palette() {
+        let palette = vec![
+            PaletteEntry::from_rgb(0xF0, 0x00, 0x00),
+            PaletteEntry::from_rgb(0x00, 0x80, 0x10),
+        ];
+        let image = BmxImage::new(3, 1, 2, palette, vec![0b00_01_10_00]).unwrap();
+
+        assert_eq!(
+            image.to_bgra(),
+            [0x00, 0x00, 0xF0, 0xFF, 0x10, 0x80, 0x00, 0xFF, 0x00, 0x00, 0x00, 0xFF,]
+        );
+    }
+
+    #[test]
+    fn image_round_trip() {
+        let palette = (0..16)
+            .map(|i| PaletteEntry::from_rgb(i * 16, 255 - i * 16, i))
+            .collect::<Vec<_>>();
+        let data = (0..=255).cycle().take(5 * 3).collect::<Vec<u8>>();
+
+        let image = BmxImage::new(9, 3, 4, palette, data).unwrap();
+
+        for compress in [false, true] {
+            let parsed = BmxImage::from_bytes(&image.to_bytes(compress).unwrap()).unwrap();
+
+            assert_eq!(parsed.header.data_start, image.header.data_start);
+            assert_eq!(parsed.palette.len(), image.palette.len());
+            assert_eq!(parsed.data, image.data);
+        }
+    }
+
+    struct GoldenFile {
+        name: &'static str,
+        bytes: &'static [u8],
+        width: u16,
+        height: u16,
+        bit_depth: u8,
+        palette_len: usize,
+        pal_start: u8,
+        compressed: bool,
+        palette_crc32: u32,
+        pixel_crc32: u32,
+    }
+
+    macro_rules! golden {
+        ($name:literal, $width:literal, $height:literal, $bit_depth:literal, $palette_len:literal, $pal_start:literal, $compressed:literal, $palette_crc32:literal, $pixel_crc32:literal) => {
+            GoldenFile {
+                name: $name,
+                bytes: include_bytes!(concat!("../tests/fixtures/", $name)),
+                width: $width,
+                height: $height,
+                bit_depth: $bit_depth,
+                palette_len: $palette_len,
+                pal_start: $pal_start,
+                compressed: $compressed,
+                palette_crc32: $palette_crc32,
+                pixel_crc32: $pixel_crc32,
+            }
+        };
+    }
+
+    // See tests/fixtures/generate.py for how these were produced.
+    // name, width, height, bit depth, palette length, pal_start, compressed, palette CRC, pixel CRC
+    #[rustfmt::skip]
+    const GOLDEN_FILES: &[GoldenFile] = &[
+        golden!("1bpp_8x4.bmx", 8, 4, 1, 2, 0, false, 0x22df3fff, 0xf8b71bdd),
+        golden!("1bpp_11x3_odd_width.bmx", 11, 3, 1, 2, 0, false, 0x90d67744, 0x77158966),
+        golden!("2bpp_5x3_pal_start_4.bmx", 5, 3, 2, 4, 4, false, 0x4262c0c3, 0x5de0b849),
+        golden!("4bpp_9x3_pal_start_16.bmx", 9, 3, 4, 16, 16, false, 0xcfb02d65, 0x3a55dfb1),
+        golden!("4bpp_12x6_data_start_512.bmx", 12, 6, 4, 16, 0, false, 0xcfb02d65, 0x3e22f564),
+        golden!("8bpp_16x8_full_palette.bmx", 16, 8, 8, 256, 0, false, 0x407e0f72, 0x9508e17f),
+        golden!("8bpp_10x4_short_palette.bmx", 10, 4, 8, 32, 32, false, 0x28f66087, 0x48b974fd),
+        golden!("4bpp_32x16_compressed.bmx", 32, 16, 4, 16, 0, true, 0xcfb02d65, 0x399c8bf7),
+        golden!("8bpp_64x32_compressed_crc.bmx", 64, 32, 8, 256, 0, true, 0x407e0f72, 0x952c4ed4),
+        golden!("2bpp_40x10_compressed_pal_start_8.bmx", 40, 10, 2, 4, 8, true, 0x4262c0c3, 0xcd24a0a6),
+        golden!("8bpp_128x96_compressed_offsets.bmx", 128, 96, 8, 256, 0, true, 0x407e0f72, 0x6d701acb),
+    ];
+
+    #[test]
+    fn golden_files() {
+        for golden in GOLDEN_FILES {
+            let image = BmxImage::from_bytes(golden.bytes)
+                .unwrap_or_else(|err| panic!("{}: {}", golden.name, err));
+
+            let header = &image.header;
+            assert!(header.validate().is_ok(), "{}", golden.name);
+            assert_eq!(
+                (header.width, header.height, header.bit_depth),
+                (golden.width, golden.height, golden.bit_depth),
+                "{}",
+                golden.name
+            );
+            assert_eq!(header.pal_start, golden.pal_start, "{}", golden.name);
+            assert_eq!(header.compressed != 0, golden.compressed, "{}", golden.name);
+            assert_ne!(image.integrity(), Integrity::Mismatch, "{}", golden.name);
+
+            let palette = image
+                .palette
+                .iter()
+                .flat_map(PaletteEntry::to_bytes)
+                .collect::<Vec<_>>();
+
+            assert_eq!(image.palette.len(), golden.palette_len, "{}", golden.name);
+            assert_eq!(crc32(&palette), golden.palette_crc32, "{}", golden.name);
+            assert_eq!(crc32(&image.data), golden.pixel_crc32, "{}", golden.name);
+        }
+    }
+
+    fn pack(indices: &[u8], width: usize, bit_depth: u8) -> Vec<u8> {
+        let bytes_per_line = (width * bit_depth as usize).div_ceil(8);
+        let mut data = vec![0; indices.len() / width.max(1) * bytes_per_line];
+
+        for (y, row) in indices.chunks(width.max(1)).enumerate() {
+            for (x, &index) in row.iter().enumerate() {
+                let bit = x * bit_depth as usize;
+                data[y * bytes_per_line + bit / 8] |= index << (8 - bit_depth as usize - bit % 8);
+            }
+        }
+
+        data
+    }
+
+    fn packed_image() -> impl Strategy<Value = (u16, u16, u8, Vec<u8>)> {
+        (
+            prop::sample::select(&[1u8, 2, 4, 8][..]),
+            1u16..=67,
+            1u16..=9,
+        )
+            .prop_flat_map(|(bit_depth, width, height)| {
+                let max_index = ((1u16 << bit_depth) - 1) as u8;
+                prop::collection::vec(0..=max_index, width as usize * height as usize)
+                    .prop_map(move |indices| (width, height, bit_depth, indices))
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn palette_rgb_is_stable(r: u8, g: u8, b: u8) {
+            let entry = PaletteEntry::from_rgb(r, g, b);
+
+            let (r2, g2, b2) = entry.to_rgb();
+
+            prop_assert_eq!((r2, g2, b2), (r & 0xF0, g & 0xF0, b & 0xF0));
+            prop_assert_eq!(PaletteEntry::from_rgb(r2, g2, b2), entry);
+        }
+
+        #[test]
+        fn palette_wic_round_trip(gb: u8, r in 0u8..16, color: u32) {
+            let entry = PaletteEntry { gb, r };
+
+            prop_assert_eq!(PaletteEntry::from_wic(entry.to_wic()), entry);
+            prop_assert_eq!(entry.to_wic() >> 24, 0xFF);
+
+            let quantized = PaletteEntry::from_wic(color);
+            prop_assert_eq!(PaletteEntry::from_wic(quantized.to_wic()), quantized);
+        }
+
+        #[test]
+        fn pack_unpack_round_trip((width, height, bit_depth, indices) in packed_image()) {
+            let data = pack(&indices, width as usize, bit_depth);
+            let palette = vec![PaletteEntry::default(); 1 << bit_depth];
+            let image = BmxImage::new(width, height, bit_depth, palette, data.clone()).unwrap();
+
+            let rows = image.rows().collect::<Vec<_>>();
+            prop_assert_eq!(rows.concat(), indices.clone());
+
+            for (y, row) in rows.iter().enumerate() {
+                let packed = image.rows_packed().nth(y).unwrap();
+                prop_assert_eq!(pack(row, width as usize, bit_depth), packed);
+            }
+
+            prop_assert_eq!(image.pixel(width - 1, height - 1), indices.last().copied());
+            prop_assert_eq!(image.pixel(width, 0), None);
+
+            let parsed = BmxImage::from_bytes(&image.to_bytes(false).unwrap()).unwrap();
+            prop_assert_eq!(parsed.data, data);
+        }
+
+        #[test]
+        fn header_bytes_round_trip(bytes: [u8; FileHeader::SIZE]) {
+            if let Ok(header) = FileHeader::from_bytes(&bytes) {
+                prop_assert_eq!(header.to_bytes(), bytes);
+            }
+        }
+
+        #[test]
+        fn built_header_round_trip(
+            width: u16,
+            height: u16,
+            bit_depth in prop::sample::select(&[1u8, 2, 4, 8][..]),
+            palette_len in 1usize..=256,
+            pal_start: u8,
+            compressed: bool,
+            border_color: u8,
+        ) {
+            let color_count = (1usize << bit_depth).min(palette_len).min(256 - pal_start as usize);
+            let vera_border_color = pal_start + (border_color as usize % color_count) as u8;
+
+            let header = FileHeader::builder()
+                .width(width)
+                .height(height)
+                .bit_depth(bit_depth)
+                .palette_len(palette_len)
+                .pal_start(pal_start)
+                .compressed(compressed)
+                .vera_border_color(vera_border_color)
+                .build()
+                .unwrap();
+
+            let parsed =
+                FileHeader::from_bytes_with(&header.to_bytes(), Validation::Strict).unwrap();
+            prop_assert_eq!(parsed.to_bytes(), header.to_bytes());
+            prop_assert_eq!(parsed.palette_entry_count(), palette_len);
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use std::fmt::Display;
+use std::io::ErrorKind;
+
+use super::limits::LimitError;
+use super::palette::io::PaletteIoError;
+use super::raw::RawError;
+use super::reader::BmxReadError;
+use super::{BmxImageError, FileHeaderError};
+use crate::lzsa::LzsaError;
+
+// Everything that can go wrong with a BMX file, whichever part of the crate noticed. The errors of
+// the individual modules convert into it, and the COM code turns it into an HRESULT in one place.
+#[derive(Debug)]
+pub enum BmxError {
+    Header(FileHeaderError),
+    Palette(PaletteIoError),
+    Compression(LzsaError),
+    Limit(LimitError),
+    Raw(RawError),
+    InvalidPixelDataLength,
+    Truncated,
+    Io(std::io::Error),
+}
+
+impl Display for BmxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BmxError::Header(err) => write!(f, "{}", err),
+            BmxError::Palette(err) => write!(f, "{}", err),
+            BmxError::Compression(err) => write!(f, "{}", err),
+            BmxError::Limit(err) => write!(f, "{}", err),
+            BmxError::Raw(err) => write!(f, "{}", err),
+            BmxError::InvalidPixelDataLength => {
+                write!(f, "Pixel data length does not match image size")
+            }
+            BmxError::Truncated => write!(f, "File is truncated"),
+            BmxError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for BmxError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BmxError::Header(err) => Some(err),
+            BmxError::Palette(err) => Some(err),
+            BmxError::Compression(err) => Some(err),
+            BmxError::Limit(err) => Some(err),
+            BmxError::Raw(err) => Some(err),
+            BmxError::InvalidPixelDataLength | BmxError::Truncated => None,
+            BmxError::Io(err) => Some(err),
+        }
+    }
+}
+
+impl From<FileHeaderError> for BmxError {
+    fn from(err: FileHeaderError) -> Self {
+        Self::Header(err)
+    }
+}
+
+impl From<PaletteIoError> for BmxError {
+    fn from(err: PaletteIoError) -> Self {
+        match err {
+            PaletteIoError::Io(err) => err.into(),
+            err => Self::Palette(err),
+        }
+    }
+}
+
+impl From<LzsaError> for BmxError {
+    fn from(err: LzsaError) -> Self {
+        Self::Compression(err)
+    }
+}
+
+impl From<LimitError> for BmxError {
+    fn from(err: LimitError) -> Self {
+        Self::Limit(err)
+    }
+}
+
+impl From<RawError> for BmxError {
+    fn from(err: RawError) -> Self {
+        match err {
+            RawError::Image(err) => err.into(),
+            err => Self::Raw(err),
+        }
+    }
+}
+
+impl From<BmxImageError> for BmxError {
+    fn from(err: BmxImageError) -> Self {
+        match err {
+            BmxImageError::Header(err) => err.into(),
+            BmxImageError::Compression(err) => err.into(),
+            BmxImageError::InvalidPalette => Self::Palette(PaletteIoError::InvalidEntryCount),
+            BmxImageError::InvalidPixelDataLength => Self::InvalidPixelDataLength,
+            BmxImageError::Truncated => Self::Truncated,
+        }
+    }
+}
+
+impl From<BmxReadError> for BmxError {
+    fn from(err: BmxReadError) -> Self {
+        match err {
+            BmxReadError::Header(err) => err.into(),
+            BmxReadError::Truncated => Self::Truncated,
+            BmxReadError::Io(err) => err.into(),
+        }
+    }
+}
+
+impl From<std::io::Error> for BmxError {
+    fn from(err: std::io::Error) -> Self {
+        match err.kind() {
+            ErrorKind::UnexpectedEof => Self::Truncated,
+            _ => Self::Io(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::*;
+
+    #[test]
+    fn flattens_module_errors() {
+        assert!(matches!(
+            BmxError::from(BmxImageError::Header(FileHeaderError::InvalidBitDepth)),
+            BmxError::Header(FileHeaderError::InvalidBitDepth)
+        ));
+        assert!(matches!(
+            BmxError::from(RawError::Image(BmxImageError::Truncated)),
+            BmxError::Truncated
+        ));
+        assert!(matches!(
+            BmxError::from(RawError::Empty),
+            BmxError::Raw(RawError::Empty)
+        ));
+        assert!(matches!(
+            BmxError::from(PaletteIoError::Io(ErrorKind::UnexpectedEof.into())),
+            BmxError::Truncated
+        ));
+        assert!(matches!(
+            BmxError::from(BmxReadError::Io(ErrorKind::PermissionDenied.into())),
+            BmxError::Io(_)
+        ));
+
+        let err = BmxError::from(LzsaError::InvalidOffset);
+        assert_eq!(err.to_string(), LzsaError::InvalidOffset.to_string());
+        assert!(err.source().is_some());
+        assert!(BmxError::Truncated.source().is_none());
+    }
+}
@@ -0,0 +1,142 @@
+use std::fmt::Display;
+
+use super::FileHeader;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LimitError {
+    Dimensions,
+    PixelCount,
+    DataStart,
+}
+
+impl Display for LimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            LimitError::Dimensions => write!(f, "Image is wider or taller than allowed"),
+            LimitError::PixelCount => write!(f, "Image has more pixels than allowed"),
+            LimitError::DataStart => write!(f, "Pixel data starts past the end of the file"),
+        }
+    }
+}
+
+impl std::error::Error for LimitError {}
+
+// How much a header may ask for before the decoder and the shell extensions refuse it. Explorer,
+// the indexer and preview hosts run them on files nobody chose to open, so a crafted header must
+// not make them allocate gigabytes or read far past the file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    pub max_width: u16,
+    pub max_height: u16,
+    pub max_pixels: u64,
+    // Compressed pixel data read in one piece, e.g. for the integrity check. Leaves room for
+    // LZSA's worst case on the largest image allowed.
+    pub max_stored_data_len: u64,
+}
+
+impl Limits {
+    // Far beyond anything made for the X16, whose VRAM holds 128 KiB, while keeping a decoded
+    // 32 bpp image at 256 MiB.
+    pub const DEFAULT: Self = Self {
+        max_width: 8192,
+        max_height: 8192,
+        max_pixels: 8192 * 8192,
+        max_stored_data_len: 8192 * 8192 + 8192 * 8192 / 8,
+    };
+
+    // `stream_len` counts from the start of the header, if the length is known.
+    pub const fn check(
+        &self,
+        header: &FileHeader,
+        stream_len: Option<u64>,
+    ) -> Result<(), LimitError> {
+        if header.width > self.max_width || header.height > self.max_height {
+            return Err(LimitError::Dimensions);
+        }
+
+        if header.width as u64 * header.height as u64 > self.max_pixels {
+            return Err(LimitError::PixelCount);
+        }
+
+        if let Some(stream_len) = stream_len {
+            if header.data_start as u64 > stream_len {
+                return Err(LimitError::DataStart);
+            }
+        }
+
+        Ok(())
+    }
+
+    // What reading the stored pixel data of `header` may take at most. Uncompressed data is never
+    // needed beyond what the header describes.
+    pub const fn stored_data_len(&self, header: &FileHeader) -> u64 {
+        if header.compressed == 0 {
+            header.pixel_data_len() as u64
+        } else {
+            self.max_stored_data_len
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(width: u16, height: u16, data_start: u16) -> FileHeader {
+        FileHeader {
+            width,
+            height,
+            bit_depth: 8,
+            data_start,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_headers() {
+        let limits = Limits::DEFAULT;
+
+        assert_eq!(limits.check(&header(320, 240, 544), Some(77344)), Ok(()));
+        assert_eq!(limits.check(&header(320, 240, 544), None), Ok(()));
+
+        assert_eq!(
+            limits.check(&header(u16::MAX, 1, 544), None),
+            Err(LimitError::Dimensions)
+        );
+        assert_eq!(
+            limits.check(&header(1, u16::MAX, 544), None),
+            Err(LimitError::Dimensions)
+        );
+
+        let limits = Limits {
+            max_pixels: 1000,
+            ..Limits::DEFAULT
+        };
+        assert_eq!(
+            limits.check(&header(100, 11, 544), None),
+            Err(LimitError::PixelCount)
+        );
+
+        assert_eq!(
+            Limits::DEFAULT.check(&header(16, 16, u16::MAX), Some(1024)),
+            Err(LimitError::DataStart)
+        );
+    }
+
+    #[test]
+    fn bounds_stored_data() {
+        let limits = Limits::DEFAULT;
+        let mut header = header(320, 240, 544);
+
+        assert_eq!(limits.stored_data_len(&header), 320 * 240);
+
+        header.compressed = 1;
+        assert_eq!(limits.stored_data_len(&header), limits.max_stored_data_len);
+    }
+}
@@ -0,0 +1,101 @@
+use super::PaletteEntry;
+
+pub mod io;
+
+const VERA_DEFAULT_RGB: [u16; 256] = [
+    0x000, 0xfff, 0x800, 0xafe, 0xc4c, 0x0c5, 0x00a, 0xee7, 0xd85, 0x640, 0xf77, 0x333, 0x777,
+    0xaf6, 0x08f, 0xbbb, 0x000, 0x111, 0x222, 0x333, 0x444, 0x555, 0x666, 0x777, 0x888, 0x999,
+    0xaaa, 0xbbb, 0xccc, 0xddd, 0xeee, 0xfff, 0x211, 0x433, 0x644, 0x866, 0xa88, 0xc99, 0xfbb,
+    0x211, 0x422, 0x633, 0x844, 0xa55, 0xc66, 0xf77, 0x200, 0x411, 0x611, 0x822, 0xa22, 0xc33,
+    0xf33, 0x200, 0x400, 0x600, 0x800, 0xa00, 0xc00, 0xf00, 0x221, 0x443, 0x664, 0x886, 0xaa8,
+    0xcc9, 0xfeb, 0x211, 0x432, 0x653, 0x874, 0xa95, 0xcb6, 0xfd7, 0x210, 0x431, 0x651, 0x862,
+    0xa82, 0xca3, 0xfc3, 0x210, 0x430, 0x640, 0x860, 0xa80, 0xc90, 0xfb0, 0x121, 0x343, 0x564,
+    0x786, 0x9a8, 0xbc9, 0xdfb, 0x121, 0x342, 0x463, 0x684, 0x8a5, 0x9c6, 0xbf7, 0x120, 0x241,
+    0x461, 0x582, 0x6a2, 0x8c3, 0x9f3, 0x120, 0x240, 0x360, 0x480, 0x5a0, 0x6c0, 0x7f0, 0x121,
+    0x343, 0x465, 0x686, 0x8a8, 0x9ca, 0xbfc, 0x121, 0x242, 0x364, 0x485, 0x5a6, 0x6c8, 0x7f9,
+    0x020, 0x141, 0x162, 0x283, 0x2a4, 0x3c5, 0x3f6, 0x020, 0x041, 0x061, 0x082, 0x0a2, 0x0c3,
+    0x0f3, 0x122, 0x344, 0x466, 0x688, 0x8aa, 0x9cc, 0xbff, 0x122, 0x244, 0x366, 0x488, 0x5aa,
+    0x6cc, 0x7ff, 0x022, 0x144, 0x166, 0x288, 0x2aa, 0x3cc, 0x3ff, 0x022, 0x044, 0x066, 0x088,
+    0x0aa, 0x0cc, 0x0ff, 0x112, 0x334, 0x456, 0x668, 0x88a, 0x9ac, 0xbcf, 0x112, 0x224, 0x346,
+    0x458, 0x56a, 0x68c, 0x79f, 0x002, 0x114, 0x126, 0x238, 0x24a, 0x35c, 0x36f, 0x002, 0x014,
+    0x016, 0x028, 0x02a, 0x03c, 0x03f, 0x112, 0x334, 0x546, 0x768, 0x98a, 0xb9c, 0xdbf, 0x112,
+    0x324, 0x436, 0x648, 0x85a, 0x96c, 0xb7f, 0x102, 0x214, 0x416, 0x528, 0x62a, 0x83c, 0x93f,
+    0x102, 0x204, 0x306, 0x408, 0x50a, 0x60c, 0x70f, 0x212, 0x434, 0x646, 0x868, 0xa8a, 0xc9c,
+    0xfbe, 0x211, 0x423, 0x635, 0x847, 0xa59, 0xc6b, 0xf7d, 0x201, 0x413, 0x615, 0x826, 0xa28,
+    0xc3a, 0xf3c, 0x201, 0x403, 0x604, 0x806, 0xa08, 0xc09, 0xf0b,
+];
+
+pub const VERA_DEFAULT: [PaletteEntry; 256] = {
+    let mut palette = [PaletteEntry { gb: 0, r: 0 }; 256];
+
+    let mut i = 0;
+    while i < palette.len() {
+        let rgb = VERA_DEFAULT_RGB[i];
+        palette[i] = PaletteEntry {
+            gb: rgb as u8,
+            r: (rgb >> 8) as u8,
+        };
+        i += 1;
+    }
+
+    palette
+};
+
+pub fn nearest_index(palette: &[PaletteEntry], (r, g, b): (u8, u8, u8)) -> Option<usize> {
+    palette
+        .iter()
+        .map(PaletteEntry::to_rgb)
+        .enumerate()
+        .min_by_key(|(_, (pr, pg, pb))| {
+            let dr = *pr as i32 - r as i32;
+            let dg = *pg as i32 - g as i32;
+            let db = *pb as i32 - b as i32;
+
+            dr * dr + dg * dg + db * db
+        })
+        .map(|(index, _)| index)
+}
+
+pub fn palette_diff(old: &[PaletteEntry], new: &[PaletteEntry]) -> Vec<usize> {
+    (0..old.len().max(new.len()))
+        .filter(|&index| old.get(index) != new.get(index))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearest_index_matches() {
+        assert_eq!(nearest_index(&VERA_DEFAULT, (0x80, 0x00, 0x00)), Some(2));
+        assert_eq!(nearest_index(&VERA_DEFAULT, (0xF0, 0xF0, 0xF0)), Some(1));
+
+        // Black is both 0 and 16; the first index wins, also between equally distant colors.
+        assert_eq!(nearest_index(&VERA_DEFAULT, (0, 0, 0)), Some(0));
+
+        let palette = [
+            PaletteEntry::from_rgb(0x00, 0x00, 0x00),
+            PaletteEntry::from_rgb(0x20, 0x00, 0x00),
+        ];
+        assert_eq!(nearest_index(&palette, (0x10, 0x00, 0x00)), Some(0));
+        assert_eq!(nearest_index(&palette, (0x11, 0x00, 0x00)), Some(1));
+
+        assert_eq!(nearest_index(&[], (0, 0, 0)), None);
+    }
+
+    #[test]
+    fn palette_diff_lists_changed_indices() {
+        let mut palette = VERA_DEFAULT[..16].to_vec();
+
+        assert_eq!(palette_diff(&VERA_DEFAULT[..16], &palette), []);
+
+        palette[3] = PaletteEntry::from_rgb(0x12, 0x34, 0x56);
+        assert_eq!(palette_diff(&VERA_DEFAULT[..16], &palette), [3]);
+
+        // Entries only one of the palettes has count as changed.
+        assert_eq!(palette_diff(&VERA_DEFAULT[..18], &palette), [3, 16, 17]);
+        assert_eq!(palette_diff(&[], &palette[..2]), [0, 1]);
+        assert_eq!(palette_diff(&[], &[]), []);
+    }
+}
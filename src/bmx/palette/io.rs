@@ -0,0 +1,231 @@
+use std::{fmt::Display, path::Path};
+
+use super::super::PaletteEntry;
+
+const JASC_HEADER: &str = "JASC-PAL";
+const JASC_VERSION: &str = "0100";
+const GIMP_HEADER: &str = "GIMP Palette";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PaletteFormat {
+    Vera,
+    Jasc,
+    Gimp,
+}
+
+impl PaletteFormat {
+    pub fn detect(bytes: &[u8]) -> Self {
+        if bytes.starts_with(JASC_HEADER.as_bytes()) {
+            PaletteFormat::Jasc
+        } else if bytes.starts_with(GIMP_HEADER.as_bytes()) {
+            PaletteFormat::Gimp
+        } else {
+            PaletteFormat::Vera
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "vera" | "raw" => Some(PaletteFormat::Vera),
+            "jasc" => Some(PaletteFormat::Jasc),
+            "gimp" | "gpl" => Some(PaletteFormat::Gimp),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PaletteIoError {
+    Io(std::io::Error),
+    InvalidVeraLength,
+    InvalidHeader,
+    InvalidEntry(usize),
+    EntryCountMismatch,
+    InvalidEntryCount,
+}
+
+impl Display for PaletteIoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PaletteIoError::Io(err) => write!(f, "{}", err),
+            PaletteIoError::InvalidVeraLength => {
+                write!(f, "VERA palette length must be a multiple of 2 bytes")
+            }
+            PaletteIoError::InvalidHeader => write!(f, "Invalid palette header"),
+            PaletteIoError::InvalidEntry(line) => {
+                write!(f, "Invalid palette entry on line {}", line)
+            }
+            PaletteIoError::EntryCountMismatch => {
+                write!(f, "Palette entry count does not match the header")
+            }
+            PaletteIoError::InvalidEntryCount => write!(f, "Palette must have 1 to 256 entries"),
+        }
+    }
+}
+
+impl std::error::Error for PaletteIoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PaletteIoError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for PaletteIoError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+fn check_entry_count(palette: Vec<PaletteEntry>) -> Result<Vec<PaletteEntry>, PaletteIoError> {
+    if matches!(palette.len(), 1..=256) {
+        Ok(palette)
+    } else {
+        Err(PaletteIoError::InvalidEntryCount)
+    }
+}
+
+fn parse_rgb(line_number: usize, line: &str) -> Result<PaletteEntry, PaletteIoError> {
+    let mut components = line
+        .split_whitespace()
+        .take(3)
+        .map(|component| component.parse::<u8>());
+
+    match (components.next(), components.next(), components.next()) {
+        (Some(Ok(r)), Some(Ok(g)), Some(Ok(b))) => Ok(PaletteEntry::from_rgb(r, g, b)),
+        _ => Err(PaletteIoError::InvalidEntry(line_number)),
+    }
+}
+
+pub fn parse_vera(bytes: &[u8]) -> Result<Vec<PaletteEntry>, PaletteIoError> {
+    if !bytes.len().is_multiple_of(PaletteEntry::SIZE) {
+        return Err(PaletteIoError::InvalidVeraLength);
+    }
+
+    check_entry_count(
+        bytes
+            .chunks_exact(PaletteEntry::SIZE)
+            .map(|entry| PaletteEntry::from_bytes([entry[0], entry[1]]))
+            .collect(),
+    )
+}
+
+pub fn parse_jasc(text: &str) -> Result<Vec<PaletteEntry>, PaletteIoError> {
+    let mut lines = text.lines().map(str::trim).enumerate();
+
+    if !matches!(lines.next(), Some((_, JASC_HEADER)))
+        || !matches!(lines.next(), Some((_, JASC_VERSION)))
+    {
+        return Err(PaletteIoError::InvalidHeader);
+    }
+
+    let count = lines
+        .next()
+        .and_then(|(_, count)| count.parse::<usize>().ok())
+        .ok_or(PaletteIoError::InvalidHeader)?;
+
+    let palette = lines
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(index, line)| parse_rgb(index + 1, line))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if palette.len() != count {
+        return Err(PaletteIoError::EntryCountMismatch);
+    }
+
+    check_entry_count(palette)
+}
+
+pub fn parse_gimp(text: &str) -> Result<Vec<PaletteEntry>, PaletteIoError> {
+    let mut lines = text.lines().map(str::trim).enumerate();
+
+    if !matches!(lines.next(), Some((_, GIMP_HEADER))) {
+        return Err(PaletteIoError::InvalidHeader);
+    }
+
+    let palette = lines
+        .filter(|(_, line)| {
+            !line.is_empty()
+                && !line.starts_with('#')
+                && !line.starts_with("Name:")
+                && !line.starts_with("Columns:")
+        })
+        .map(|(index, line)| parse_rgb(index + 1, line))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    check_entry_count(palette)
+}
+
+pub fn parse(bytes: &[u8]) -> Result<Vec<PaletteEntry>, PaletteIoError> {
+    parse_as(bytes, PaletteFormat::detect(bytes))
+}
+
+pub fn parse_as(bytes: &[u8], format: PaletteFormat) -> Result<Vec<PaletteEntry>, PaletteIoError> {
+    match format {
+        PaletteFormat::Vera => parse_vera(bytes),
+        PaletteFormat::Jasc => parse_jasc(&String::from_utf8_lossy(bytes)),
+        PaletteFormat::Gimp => parse_gimp(&String::from_utf8_lossy(bytes)),
+    }
+}
+
+pub fn serialize(palette: &[PaletteEntry], format: PaletteFormat) -> Vec<u8> {
+    match format {
+        PaletteFormat::Vera => palette.iter().flat_map(PaletteEntry::to_bytes).collect(),
+        PaletteFormat::Jasc => {
+            let mut text = format!(
+                "{}\r\n{}\r\n{}\r\n",
+                JASC_HEADER,
+                JASC_VERSION,
+                palette.len()
+            );
+
+            for (r, g, b) in palette.iter().map(PaletteEntry::to_rgb) {
+                text += &format!("{} {} {}\r\n", r, g, b);
+            }
+
+            text.into_bytes()
+        }
+        PaletteFormat::Gimp => {
+            let mut text = format!("{}\nName: BMX\nColumns: 16\n#\n", GIMP_HEADER);
+
+            for (index, (r, g, b)) in palette.iter().map(PaletteEntry::to_rgb).enumerate() {
+                text += &format!("{:3} {:3} {:3}\tIndex {}\n", r, g, b, index);
+            }
+
+            text.into_bytes()
+        }
+    }
+}
+
+pub fn read(path: impl AsRef<Path>) -> Result<Vec<PaletteEntry>, PaletteIoError> {
+    parse(&std::fs::read(path)?)
+}
+
+pub fn write(
+    path: impl AsRef<Path>,
+    palette: &[PaletteEntry],
+    format: PaletteFormat,
+) -> Result<(), PaletteIoError> {
+    Ok(std::fs::write(path, serialize(palette, format))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bmx::palette::VERA_DEFAULT;
+
+    #[test]
+    fn round_trip() {
+        for format in [
+            PaletteFormat::Vera,
+            PaletteFormat::Jasc,
+            PaletteFormat::Gimp,
+        ] {
+            let bytes = serialize(&VERA_DEFAULT[..16], format);
+
+            assert_eq!(PaletteFormat::detect(&bytes), format);
+            assert_eq!(parse(&bytes).unwrap(), &VERA_DEFAULT[..16]);
+        }
+    }
+}
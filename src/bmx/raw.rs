@@ -0,0 +1,200 @@
+use std::fmt::Display;
+
+use super::palette::VERA_DEFAULT;
+use super::{BmxImage, BmxImageError};
+
+// How raw VERA data, e.g. a VRAM dump or an asset meant to be loaded straight into VRAM, is laid
+// out. The data itself doesn't say, so this has to come from the user.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RawLayout {
+    // Rows of `width` pixels, as a bitmap layer reads them.
+    Bitmap {
+        width: u16,
+    },
+    // Tiles stored one after another, as a tile layer reads them, arranged `columns` to a row.
+    Tiles {
+        tile_width: u8,
+        tile_height: u8,
+        columns: u16,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RawGeometry {
+    pub bit_depth: u8,
+    pub layout: RawLayout,
+    // Bytes before the data, e.g. the two-byte load address of files written with SAVE.
+    pub skip: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum RawError {
+    InvalidGeometry,
+    // Not even a single row or tile.
+    Empty,
+    Image(BmxImageError),
+}
+
+impl Display for RawError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            RawError::InvalidGeometry => write!(f, "Invalid geometry for raw VERA data"),
+            RawError::Empty => write!(f, "Raw VERA data is too short for the geometry"),
+            RawError::Image(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for RawError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RawError::Image(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<BmxImageError> for RawError {
+    fn from(err: BmxImageError) -> Self {
+        Self::Image(err)
+    }
+}
+
+// Turns raw VERA data into an image with the default VERA palette. Trailing bytes that don't make
+// up a whole row or tile are ignored, and tiles missing from the last row are left at index 0.
+pub fn decode_raw(data: &[u8], geometry: &RawGeometry) -> Result<BmxImage, RawError> {
+    let bit_depth = geometry.bit_depth;
+
+    if !matches!(bit_depth, 1 | 2 | 4 | 8) {
+        return Err(RawError::InvalidGeometry);
+    }
+
+    let data = data.get(geometry.skip..).unwrap_or_default();
+
+    let (width, height, pixels) = match geometry.layout {
+        RawLayout::Bitmap { width } => {
+            if width == 0 {
+                return Err(RawError::InvalidGeometry);
+            }
+
+            let line_len = (width as usize * bit_depth as usize).div_ceil(8);
+            let height = (data.len() / line_len).min(u16::MAX as usize);
+
+            (width as usize, height, data[..height * line_len].to_vec())
+        }
+        RawLayout::Tiles {
+            tile_width,
+            tile_height,
+            columns,
+        } => {
+            if !matches!(tile_width, 8 | 16) || !matches!(tile_height, 8 | 16) || columns == 0 {
+                return Err(RawError::InvalidGeometry);
+            }
+
+            let (tile_width, tile_height) = (tile_width as usize, tile_height as usize);
+            let tile_line_len = tile_width * bit_depth as usize / 8;
+            let tile_len = tile_line_len * tile_height;
+
+            let tile_count = data.len() / tile_len;
+            let columns = (columns as usize).min(tile_count.max(1));
+            let rows = tile_count.div_ceil(columns);
+
+            let line_len = columns * tile_line_len;
+            let mut pixels = vec![0; line_len * rows * tile_height];
+
+            for (i, tile) in data.chunks_exact(tile_len).enumerate() {
+                let (column, row) = (i % columns, i / columns);
+
+                for (y, tile_line) in tile.chunks_exact(tile_line_len).enumerate() {
+                    let offset = (row * tile_height + y) * line_len + column * tile_line_len;
+                    pixels[offset..][..tile_line_len].copy_from_slice(tile_line);
+                }
+            }
+
+            (columns * tile_width, rows * tile_height, pixels)
+        }
+    };
+
+    if height == 0 {
+        return Err(RawError::Empty);
+    }
+
+    let (Ok(width), Ok(height)) = (u16::try_from(width), u16::try_from(height)) else {
+        return Err(RawError::InvalidGeometry);
+    };
+
+    let palette = VERA_DEFAULT[..1 << bit_depth].to_vec();
+    Ok(BmxImage::new(width, height, bit_depth, palette, pixels)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tiles(columns: u16) -> RawGeometry {
+        RawGeometry {
+            bit_depth: 4,
+            layout: RawLayout::Tiles {
+                tile_width: 8,
+                tile_height: 8,
+                columns,
+            },
+            skip: 0,
+        }
+    }
+
+    #[test]
+    fn bitmap_rows() {
+        let geometry = RawGeometry {
+            bit_depth: 2,
+            layout: RawLayout::Bitmap { width: 6 },
+            skip: 2,
+        };
+
+        // Load address, two rows of two bytes each, and a stray byte.
+        let image = decode_raw(&[0x00, 0xA0, 0x1B, 0x40, 0xE4, 0x80, 0xFF], &geometry).unwrap();
+
+        assert_eq!((image.header.width, image.header.height), (6, 2));
+        assert_eq!(image.data, [0x1B, 0x40, 0xE4, 0x80]);
+        assert_eq!(image.palette, VERA_DEFAULT[..4]);
+    }
+
+    #[test]
+    fn tiles_are_laid_out_in_rows() {
+        // Three 8x8 4bpp tiles, each filled with its own index.
+        let data = (1..=3u8).flat_map(|i| [i * 0x11; 32]).collect::<Vec<_>>();
+
+        let image = decode_raw(&data, &tiles(2)).unwrap();
+        assert_eq!((image.header.width, image.header.height), (16, 16));
+
+        let rows = image.rows().collect::<Vec<_>>();
+        assert_eq!(rows[0], [[1; 8], [2; 8]].concat());
+        assert_eq!(rows[8], [[3; 8], [0; 8]].concat());
+
+        // Fewer tiles than columns give a single row.
+        let image = decode_raw(&data, &tiles(16)).unwrap();
+        assert_eq!((image.header.width, image.header.height), (24, 8));
+    }
+
+    #[test]
+    fn invalid_geometry() {
+        let data = [0u8; 64];
+
+        let mut geometry = tiles(1);
+        geometry.bit_depth = 3;
+        assert!(matches!(
+            decode_raw(&data, &geometry),
+            Err(RawError::InvalidGeometry)
+        ));
+
+        assert!(matches!(
+            decode_raw(&data, &tiles(0)),
+            Err(RawError::InvalidGeometry)
+        ));
+
+        assert!(matches!(
+            decode_raw(&data[..31], &tiles(1)),
+            Err(RawError::Empty)
+        ));
+    }
+}
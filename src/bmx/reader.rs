@@ -0,0 +1,170 @@
+use std::fmt::Display;
+use std::io::{ErrorKind, Read};
+
+use super::{FileHeader, FileHeaderError, PaletteEntry};
+
+#[derive(Debug)]
+pub enum BmxReadError {
+    Header(FileHeaderError),
+    Truncated,
+    Io(std::io::Error),
+}
+
+impl Display for BmxReadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BmxReadError::Header(err) => write!(f, "{}", err),
+            BmxReadError::Truncated => write!(f, "File is truncated"),
+            BmxReadError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for BmxReadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BmxReadError::Header(err) => Some(err),
+            BmxReadError::Truncated => None,
+            BmxReadError::Io(err) => Some(err),
+        }
+    }
+}
+
+impl From<FileHeaderError> for BmxReadError {
+    fn from(err: FileHeaderError) -> Self {
+        Self::Header(err)
+    }
+}
+
+impl From<std::io::Error> for BmxReadError {
+    fn from(err: std::io::Error) -> Self {
+        match err.kind() {
+            ErrorKind::UnexpectedEof => Self::Truncated,
+            _ => Self::Io(err),
+        }
+    }
+}
+
+// Reads a BMX file front to back: the header on construction, then the palette and the stored
+// pixel data on demand. Only the parts that are asked for are read, so callers can check the
+// header before committing to the rest.
+pub struct BmxReader<R> {
+    inner: R,
+    header: FileHeader,
+    palette: Option<Vec<PaletteEntry>>,
+}
+
+impl<R: Read> BmxReader<R> {
+    pub fn new(mut inner: R) -> Result<Self, BmxReadError> {
+        let mut header = [0u8; FileHeader::SIZE];
+        inner.read_exact(&mut header)?;
+
+        Ok(Self {
+            header: FileHeader::from_bytes(&header)?,
+            inner,
+            palette: None,
+        })
+    }
+
+    pub fn header(&self) -> &FileHeader {
+        &self.header
+    }
+
+    pub fn palette(&mut self) -> Result<&[PaletteEntry], BmxReadError> {
+        if self.palette.is_none() {
+            let mut bytes = vec![0u8; self.header.palette_entry_count() * PaletteEntry::SIZE];
+            self.inner.read_exact(&mut bytes)?;
+
+            self.palette = Some(
+                bytes
+                    .chunks_exact(PaletteEntry::SIZE)
+                    .map(|entry| PaletteEntry::from_bytes([entry[0], entry[1]]))
+                    .collect(),
+            );
+        }
+
+        Ok(self.palette.as_deref().unwrap())
+    }
+
+    // The pixel data as stored, i.e. still compressed if the file is, up to the end of the input.
+    pub fn read_stored_data(&mut self) -> Result<Vec<u8>, BmxReadError> {
+        self.read_stored_data_up_to(u64::MAX)
+    }
+
+    // Like read_stored_data, but stops after `max_len` bytes; see Limits::stored_data_len.
+    pub fn read_stored_data_up_to(&mut self, max_len: u64) -> Result<Vec<u8>, BmxReadError> {
+        let palette_len = self.palette()?.len();
+
+        // Validated by FileHeader::from_bytes.
+        let gap = self.header.data_start as u64
+            - (FileHeader::SIZE + palette_len * PaletteEntry::SIZE) as u64;
+
+        if std::io::copy(&mut (&mut self.inner).take(gap), &mut std::io::sink())? != gap {
+            return Err(BmxReadError::Truncated);
+        }
+
+        let mut data = Vec::new();
+        (&mut self.inner).take(max_len).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::bmx::BmxImage;
+
+    fn test_image() -> BmxImage {
+        let palette = (0..16)
+            .map(|i| PaletteEntry::from_rgb(i * 0x11, 0, 0xFF - i * 0x11))
+            .collect();
+
+        BmxImage::new(5, 3, 4, palette, (0..9).collect()).unwrap()
+    }
+
+    #[test]
+    fn reads_in_stages() {
+        let image = test_image();
+        let mut bytes = image.to_bytes(false).unwrap();
+
+        // Leave a gap between the palette and the pixel data.
+        bytes.splice(
+            image.header.data_start as usize..image.header.data_start as usize,
+            [0xAA; 6],
+        );
+        bytes[12..14].copy_from_slice(&(image.header.data_start + 6).to_le_bytes());
+
+        let mut reader = BmxReader::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(reader.header().width, 5);
+        assert_eq!(reader.palette().unwrap(), &image.palette[..]);
+        assert_eq!(reader.read_stored_data().unwrap(), image.data);
+    }
+
+    #[test]
+    fn stored_data_up_to_limit() {
+        let image = test_image();
+        let bytes = [image.to_bytes(false).unwrap(), vec![0xAA; 100]].concat();
+
+        let mut reader = BmxReader::new(&bytes[..]).unwrap();
+        assert_eq!(reader.read_stored_data_up_to(4).unwrap(), image.data[..4]);
+    }
+
+    #[test]
+    fn truncated_input() {
+        let bytes = test_image().to_bytes(false).unwrap();
+
+        assert!(matches!(
+            BmxReader::new(&bytes[..FileHeader::SIZE - 1]),
+            Err(BmxReadError::Truncated)
+        ));
+
+        let mut reader = BmxReader::new(&bytes[..FileHeader::SIZE + 3]).unwrap();
+        assert!(matches!(reader.palette(), Err(BmxReadError::Truncated)));
+    }
+}
@@ -0,0 +1,135 @@
+use windows::Win32::Foundation::{
+    ERROR_ARITHMETIC_OVERFLOW, WINCODEC_ERR_BADHEADER, WINCODEC_ERR_BADIMAGE,
+    WINCODEC_ERR_CODECTOOMANYSCANLINES, WINCODEC_ERR_IMAGESIZEOUTOFRANGE,
+    WINCODEC_ERR_INSUFFICIENTBUFFER, WINCODEC_ERR_PALETTEUNAVAILABLE,
+    WINCODEC_ERR_SOURCERECTDOESNOTMATCHDIMENSIONS, WINCODEC_ERR_STREAMREAD,
+    WINCODEC_ERR_UNEXPECTEDSIZE, WINCODEC_ERR_UNSUPPORTEDOPERATION,
+    WINCODEC_ERR_UNSUPPORTEDVERSION, WINCODEC_ERR_VALUEOUTOFRANGE,
+};
+use windows::Win32::Graphics::Imaging::WINCODEC_ERR_INVALIDPARAMETER;
+use windows_core::HRESULT;
+
+use crate::bmx::limits::LimitError;
+use crate::bmx::{BmxError, FileHeaderError};
+
+// Not among the windows crate's constants; wincodec.h defines it as INTSAFE_E_ARITHMETIC_OVERFLOW.
+pub const WINCODEC_ERR_VALUEOVERFLOW: HRESULT = HRESULT::from_win32(ERROR_ARITHMETIC_OVERFLOW.0);
+
+// The ways the decoder and encoder fail, each with the HRESULT WIC's own codecs return for the same
+// situation, so callers that handle those treat BMX files alike.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Condition {
+    BadHeader,
+    UnsupportedVersion,
+    // Pixel data or a palette that can't be decoded.
+    BadImage,
+    // The stream ends before what the header describes.
+    Truncated,
+    ImageTooLarge,
+    TooManyScanlines,
+    // Committed without a size or before all scanlines were written.
+    MissingScanlines,
+    SourceRectMismatch,
+    InsufficientBuffer,
+    PaletteUnavailable,
+    UnsupportedOperation,
+    ValueOutOfRange,
+    Overflow,
+    // An encoder option the codec doesn't accept.
+    InvalidParameter,
+}
+
+impl Condition {
+    pub const fn hresult(self) -> HRESULT {
+        match self {
+            Condition::BadHeader => WINCODEC_ERR_BADHEADER,
+            Condition::UnsupportedVersion => WINCODEC_ERR_UNSUPPORTEDVERSION,
+            Condition::BadImage => WINCODEC_ERR_BADIMAGE,
+            Condition::Truncated => WINCODEC_ERR_STREAMREAD,
+            Condition::ImageTooLarge => WINCODEC_ERR_IMAGESIZEOUTOFRANGE,
+            Condition::TooManyScanlines => WINCODEC_ERR_CODECTOOMANYSCANLINES,
+            Condition::MissingScanlines => WINCODEC_ERR_UNEXPECTEDSIZE,
+            Condition::SourceRectMismatch => WINCODEC_ERR_SOURCERECTDOESNOTMATCHDIMENSIONS,
+            Condition::InsufficientBuffer => WINCODEC_ERR_INSUFFICIENTBUFFER,
+            Condition::PaletteUnavailable => WINCODEC_ERR_PALETTEUNAVAILABLE,
+            Condition::UnsupportedOperation => WINCODEC_ERR_UNSUPPORTEDOPERATION,
+            Condition::ValueOutOfRange => WINCODEC_ERR_VALUEOUTOFRANGE,
+            Condition::Overflow => WINCODEC_ERR_VALUEOVERFLOW,
+            Condition::InvalidParameter => HRESULT(WINCODEC_ERR_INVALIDPARAMETER),
+        }
+    }
+
+    pub fn error<T: AsRef<str>>(self, message: T) -> windows::core::Error {
+        windows::core::Error::new(self.hresult(), message)
+    }
+
+    pub const fn of_header_error(err: FileHeaderError) -> Self {
+        match err {
+            FileHeaderError::InvalidVersion => Condition::UnsupportedVersion,
+            _ => Condition::BadHeader,
+        }
+    }
+
+    pub const fn of_limit_error(err: LimitError) -> Self {
+        match err {
+            LimitError::DataStart => Condition::BadHeader,
+            LimitError::Dimensions | LimitError::PixelCount => Condition::ImageTooLarge,
+        }
+    }
+
+    // I/O errors carry their own code, if any.
+    pub const fn of_bmx_error(err: &BmxError) -> Option<Self> {
+        match err {
+            BmxError::Header(err) => Some(Self::of_header_error(*err)),
+            BmxError::Limit(err) => Some(Self::of_limit_error(*err)),
+            BmxError::Truncated => Some(Condition::Truncated),
+            BmxError::Palette(_)
+            | BmxError::Compression(_)
+            | BmxError::Raw(_)
+            | BmxError::InvalidPixelDataLength => Some(Condition::BadImage),
+            BmxError::Io(_) => None,
+        }
+    }
+}
+
+impl From<Condition> for windows::core::Error {
+    fn from(condition: Condition) -> Self {
+        condition.hresult().into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lzsa::LzsaError;
+
+    #[test]
+    fn maps_conditions() {
+        assert_eq!(WINCODEC_ERR_VALUEOVERFLOW, HRESULT(0x80070216_u32 as _));
+
+        assert_eq!(
+            Condition::of_header_error(FileHeaderError::InvalidVersion),
+            Condition::UnsupportedVersion
+        );
+        assert_eq!(
+            Condition::of_header_error(FileHeaderError::InvalidDataStart),
+            Condition::BadHeader
+        );
+        assert_eq!(
+            Condition::of_limit_error(LimitError::PixelCount).hresult(),
+            WINCODEC_ERR_IMAGESIZEOUTOFRANGE
+        );
+        assert_eq!(
+            Condition::of_bmx_error(&BmxError::Truncated),
+            Some(Condition::Truncated)
+        );
+        assert_eq!(
+            Condition::of_bmx_error(&LzsaError::InvalidOffset.into()),
+            Some(Condition::BadImage)
+        );
+        assert_eq!(
+            Condition::of_bmx_error(&BmxError::Io(std::io::ErrorKind::PermissionDenied.into())),
+            None
+        );
+    }
+}
@@ -0,0 +1,139 @@
+use windows::Win32::Graphics::Imaging::{
+    CATID_WICBitmapDecoders, CATID_WICBitmapEncoders, CATID_WICMetadataReader,
+    CATID_WICMetadataWriter,
+};
+use windows_core::{ComObject, ComObjectInner, ComObjectInterface, IUnknown, Interface, GUID};
+
+use super::shell::command::{
+    paste_as_bmx::PasteAsBmx, send_to_emulator::SendToEmulator, transcode::Transcode,
+    vera_preview::VeraPreview,
+};
+use super::shell::{
+    data_handler::DataHandler, filter::Filter, property_store::PropertyStore,
+    thumbnail_provider::ThumbnailProvider,
+};
+use super::wic::class_factory::ClassFactory;
+use super::wic::decoder::{self, BitmapDecoder};
+use super::wic::encoder::BitmapEncoder;
+use super::wic::raw::RawDecoder;
+use super::wic::reserved::{ReservedMetadata, ReservedMetadataReader, ReservedMetadataWriter};
+use super::CoClass;
+use crate::registry::transaction::Key;
+use crate::registry::{
+    manifest_com_class, register_decoder, register_encoder, register_extension,
+    register_metadata_reader, register_metadata_writer, register_raw_decoder, unregister_component,
+    unregister_extension, NullTerminatedSlice,
+};
+
+// A class the module serves: how DllGetClassObject creates it, what registering and unregistering
+// it writes and removes, and its entry in the activation manifest.
+pub(crate) struct ClassEntry {
+    pub clsid: GUID,
+    pub class_factory: fn() -> ClassFactory,
+    pub register: for<'a> fn(&Key<'a>, NullTerminatedSlice) -> windows::core::Result<()>,
+    pub unregister: for<'a> fn(&Key<'a>) -> windows::core::Result<()>,
+    pub manifest: fn() -> String,
+}
+
+impl ClassEntry {
+    const fn of<T: CoClass>(
+        class_factory: fn() -> ClassFactory,
+        register: for<'a> fn(&Key<'a>, NullTerminatedSlice) -> windows::core::Result<()>,
+        unregister: for<'a> fn(&Key<'a>) -> windows::core::Result<()>,
+    ) -> Self {
+        Self {
+            clsid: T::CLSID,
+            class_factory,
+            register,
+            unregister,
+            manifest: manifest_com_class::<T>,
+        }
+    }
+
+    // A shell extension without registration of its own beyond the class.
+    const fn extension<T: CoClass + Default + ComObjectInner>() -> Self
+    where
+        T::Outer: ComObjectInterface<IUnknown>,
+    {
+        Self::of::<T>(
+            instance_factory::<T>,
+            register_extension::<T>,
+            unregister_extension::<T>,
+        )
+    }
+}
+
+fn instance_factory<T: Default + ComObjectInner>() -> ClassFactory
+where
+    T::Outer: ComObjectInterface<IUnknown>,
+{
+    ClassFactory::new(|iid, ppv| unsafe {
+        ComObject::new(T::default())
+            .as_interface::<IUnknown>()
+            .query(iid, ppv)
+    })
+}
+
+// Everything DllGetClassObject serves, in registration order. The extra registration of shell
+// extensions, such as file associations and verbs, is done by register_server.
+pub(crate) static CLASSES: &[ClassEntry] = &[
+    ClassEntry::of::<BitmapDecoder>(
+        || {
+            instance_factory::<BitmapDecoder>()
+                .with_aggregation(decoder::aggregated::create_instance)
+        },
+        register_decoder,
+        |classes_root| unregister_component::<BitmapDecoder>(classes_root, CATID_WICBitmapDecoders),
+    ),
+    ClassEntry::of::<BitmapEncoder>(
+        instance_factory::<BitmapEncoder>,
+        register_encoder,
+        |classes_root| unregister_component::<BitmapEncoder>(classes_root, CATID_WICBitmapEncoders),
+    ),
+    ClassEntry::of::<RawDecoder>(
+        instance_factory::<RawDecoder>,
+        register_raw_decoder,
+        |classes_root| unregister_component::<RawDecoder>(classes_root, CATID_WICBitmapDecoders),
+    ),
+    ClassEntry::of::<ReservedMetadataReader>(
+        instance_factory::<ReservedMetadata>,
+        register_metadata_reader,
+        |classes_root| {
+            unregister_component::<ReservedMetadataReader>(classes_root, CATID_WICMetadataReader)
+        },
+    ),
+    ClassEntry::of::<ReservedMetadataWriter>(
+        instance_factory::<ReservedMetadata>,
+        register_metadata_writer,
+        |classes_root| {
+            unregister_component::<ReservedMetadataWriter>(classes_root, CATID_WICMetadataWriter)
+        },
+    ),
+    ClassEntry::extension::<PropertyStore>(),
+    ClassEntry::extension::<Filter>(),
+    ClassEntry::extension::<ThumbnailProvider>(),
+    ClassEntry::extension::<DataHandler>(),
+    ClassEntry::extension::<Transcode>(),
+    ClassEntry::extension::<VeraPreview>(),
+    ClassEntry::extension::<SendToEmulator>(),
+    ClassEntry::extension::<PasteAsBmx>(),
+];
+
+pub(crate) fn find_class(clsid: &GUID) -> Option<&'static ClassEntry> {
+    CLASSES.iter().find(|class| class.clsid == *clsid)
+}
+
+#[cfg(all(test, windows))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clsids_are_unique() {
+        for (i, class) in CLASSES.iter().enumerate() {
+            assert!(CLASSES[..i].iter().all(|other| other.clsid != class.clsid));
+            assert!(std::ptr::eq(find_class(&class.clsid).unwrap(), class));
+        }
+
+        assert!(find_class(&GUID::zeroed()).is_none());
+    }
+}
@@ -1,84 +1,359 @@
+use std::ffi::c_void;
+use std::fmt::Display;
+use std::io::ErrorKind;
+
+use windows::Win32::Graphics::Imaging::{IWICBitmapDecoder, IWICBitmapEncoder};
+use windows::Win32::UI::Shell::PropertiesSystem::IPropertyStore;
 use windows::Win32::{
-    Foundation::{E_UNEXPECTED, S_FALSE, S_OK, WINCODEC_ERR_BADHEADER},
-    System::Com::{IStream, STREAM_SEEK_CUR},
+    Foundation::{E_FAIL, S_FALSE, S_OK, WINCODEC_ERR_STREAMREAD, WINCODEC_ERR_STREAMWRITE},
+    System::Com::{IStream, STATFLAG_NONAME, STATSTG, STREAM_SEEK_CUR},
 };
-use windows_core::{GUID, PCWSTR};
+use windows_core::{ComObject, GUID, HRESULT, PCWSTR};
 
-use crate::bmx::{FileHeader, FileHeaderError};
+use crate::bmx::limits::{LimitError, Limits};
+use crate::bmx::reader::{BmxReadError, BmxReader};
+use crate::bmx::{self, BmxError, FileHeader, FileHeaderError};
 
+pub mod hresult;
+pub(crate) mod inventory;
 pub mod shell;
+pub mod transcode;
 mod util;
 pub mod wic;
 
+use hresult::Condition;
+use shell::property_store::PropertyStore;
+use wic::decoder::BitmapDecoder;
+use wic::encoder::BitmapEncoder;
+use wic::util::StreamPositionPreserver;
+pub use wic::util::StreamReadWriteWrapper;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThreadingModel {
+    Apartment,
+    Free,
+    Both,
+    Neutral,
+}
+
+impl ThreadingModel {
+    // As written to ThreadingModel under InprocServer32 and to the activation manifest.
+    pub const fn name(self) -> &'static str {
+        match self {
+            ThreadingModel::Apartment => "Apartment",
+            ThreadingModel::Free => "Free",
+            ThreadingModel::Both => "Both",
+            ThreadingModel::Neutral => "Neutral",
+        }
+    }
+}
+
 pub trait CoClass {
     const CLSID: GUID;
     const PROG_ID: PCWSTR;
     const VERSION_INDEPENDENT_PROG_ID: PCWSTR;
+    // What WIC, category listings and the activation manifest show for the class.
+    const FRIENDLY_NAME: PCWSTR;
+    // The default value of the CLSID key; codecs use it for the file type they handle.
+    const DESCRIPTION: PCWSTR = Self::FRIENDLY_NAME;
+    // All our objects are free-threaded or aggregate the free-threaded marshaler.
+    const THREADING: ThreadingModel = ThreadingModel::Both;
+}
+
+// The objects DllGetClassObject hands out, for applications that link the crate and use them
+// in-process without registering anything. COM must be initialized on the calling thread.
+pub fn create_decoder() -> IWICBitmapDecoder {
+    ComObject::new(BitmapDecoder::new()).into_interface()
+}
+
+pub fn create_encoder() -> IWICBitmapEncoder {
+    ComObject::new(BitmapEncoder::new()).into_interface()
+}
+
+pub fn create_property_store() -> IPropertyStore {
+    ComObject::new(PropertyStore::new()).into_interface()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamOperation {
+    Read,
+    Write,
+    Seek,
+    Stat,
 }
 
-pub fn stream_read_exact(stream: &IStream, buf: &mut [u8]) -> windows::core::Result<usize> {
-    let mut read = 0;
-    unsafe {
-        match stream.Read(
-            buf.as_mut_ptr().cast(),
-            buf.len().try_into().unwrap(),
-            Some(&raw mut read),
-        ) {
-            S_OK => Ok(read as _),
-            S_FALSE => Err(E_UNEXPECTED.into()),
-            err => Err(err.into()),
+impl Display for StreamOperation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StreamOperation::Read => write!(f, "read"),
+            StreamOperation::Write => write!(f, "write"),
+            StreamOperation::Seek => write!(f, "seek"),
+            StreamOperation::Stat => write!(f, "stat"),
         }
     }
 }
 
-pub fn stream_read_exact_items<T>(stream: &IStream, buf: &mut [T]) -> windows::core::Result<usize> {
-    let mut read = 0;
-    unsafe {
-        match stream.Read(
-            buf.as_mut_ptr().cast(),
-            std::mem::size_of_val(buf).try_into().unwrap(),
-            Some(&raw mut read),
-        ) {
-            S_OK => Ok(read as _),
-            S_FALSE => Err(E_UNEXPECTED.into()),
-            err => Err(err.into()),
+// A stream operation that failed or stopped short, with enough context to tell what was asked for
+// and where. Converts to a windows::core::Error carrying the same description.
+#[derive(Debug)]
+pub struct StreamError {
+    pub operation: StreamOperation,
+    // Bytes asked for and actually transferred; both zero for seeks and stats.
+    pub requested: u64,
+    pub actual: u64,
+    // Where the operation started, if the stream could still tell afterwards.
+    pub position: Option<u64>,
+    // What the stream returned; None if it only ended early.
+    pub error: Option<windows::core::Error>,
+}
+
+impl StreamError {
+    fn new(
+        stream: &IStream,
+        operation: StreamOperation,
+        requested: u64,
+        actual: u64,
+        error: Option<windows::core::Error>,
+    ) -> Self {
+        let mut position = 0;
+        let position = unsafe { stream.Seek(0, STREAM_SEEK_CUR, Some(&raw mut position)) }
+            .ok()
+            .map(|_| position.saturating_sub(actual));
+
+        Self {
+            operation,
+            requested,
+            actual,
+            position,
+            error,
+        }
+    }
+
+    pub fn code(&self) -> HRESULT {
+        match (&self.error, self.operation) {
+            (Some(err), _) => err.code(),
+            (None, StreamOperation::Write) => WINCODEC_ERR_STREAMWRITE,
+            (None, _) => WINCODEC_ERR_STREAMREAD,
         }
     }
 }
 
-pub fn stream_write_exact_items<T>(stream: &IStream, buf: &[T]) -> windows::core::Result<usize> {
-    let mut written = 0;
-    unsafe {
-        match stream.Write(
-            buf.as_ptr().cast(),
-            std::mem::size_of_val(buf).try_into().unwrap(),
-            Some(&raw mut written),
-        ) {
-            S_OK => Ok(written as _),
-            S_FALSE => Err(E_UNEXPECTED.into()),
-            err => Err(err.into()),
+impl Display for StreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Stream {}", self.operation)?;
+
+        if self.requested > 0 {
+            write!(f, " of {} bytes", self.requested)?;
+        }
+
+        match self.position {
+            Some(position) => write!(f, " at offset {}", position)?,
+            None => write!(f, " at an unknown offset")?,
+        }
+
+        match self.error {
+            Some(ref err) => write!(f, " failed: {}", err.message()),
+            None => write!(f, " ended after {} bytes", self.actual),
         }
     }
 }
 
-pub fn stream_tell(stream: &IStream) -> windows::core::Result<u64> {
-    let mut position = 0;
-    unsafe {
-        stream.Seek(0, STREAM_SEEK_CUR, Some(&raw mut position))?;
+impl std::error::Error for StreamError {}
+
+impl From<StreamError> for windows::core::Error {
+    fn from(err: StreamError) -> Self {
+        windows::core::Error::new(err.code(), err.to_string())
     }
+}
+
+// Network and custom streams may return fewer bytes than asked for with S_OK, so both helpers below
+// keep going until everything is transferred. S_FALSE, or a call that makes no progress, means the
+// end of the stream.
+fn stream_read_raw(stream: &IStream, buf: *mut c_void, len: usize) -> Result<usize, StreamError> {
+    let mut total = 0;
+
+    while total < len {
+        let requested = u32::try_from(len - total).unwrap_or(u32::MAX);
+        let mut read = 0;
+
+        let result = unsafe {
+            stream.Read(
+                buf.cast::<u8>().add(total).cast(),
+                requested,
+                Some(&raw mut read),
+            )
+        };
+        total += read as usize;
+
+        let error = match result {
+            S_OK if read > 0 => continue,
+            S_OK | S_FALSE => None,
+            err => Some(err.into()),
+        };
+
+        return Err(StreamError::new(
+            stream,
+            StreamOperation::Read,
+            len as _,
+            total as _,
+            error,
+        ));
+    }
+
+    Ok(total)
+}
+
+pub fn stream_read_exact(stream: &IStream, buf: &mut [u8]) -> Result<usize, StreamError> {
+    stream_read_raw(stream, buf.as_mut_ptr().cast(), buf.len())
+}
+
+pub fn stream_read_exact_items<T>(stream: &IStream, buf: &mut [T]) -> Result<usize, StreamError> {
+    stream_read_raw(stream, buf.as_mut_ptr().cast(), std::mem::size_of_val(buf))
+}
+
+pub fn stream_write_exact_items<T>(stream: &IStream, buf: &[T]) -> Result<usize, StreamError> {
+    let len = std::mem::size_of_val(buf);
+    let bytes = buf.as_ptr().cast::<u8>();
+    let mut total = 0;
+
+    while total < len {
+        let requested = u32::try_from(len - total).unwrap_or(u32::MAX);
+        let mut written = 0;
+
+        let result =
+            unsafe { stream.Write(bytes.add(total).cast(), requested, Some(&raw mut written)) };
+        total += written as usize;
+
+        let error = match result {
+            S_OK if written > 0 => continue,
+            S_OK | S_FALSE => None,
+            err => Some(err.into()),
+        };
+
+        return Err(StreamError::new(
+            stream,
+            StreamOperation::Write,
+            len as _,
+            total as _,
+            error,
+        ));
+    }
+
+    Ok(total)
+}
+
+pub fn stream_tell(stream: &IStream) -> Result<u64, StreamError> {
+    let mut position = 0;
+    unsafe { stream.Seek(0, STREAM_SEEK_CUR, Some(&raw mut position)) }.map_err(|err| {
+        StreamError {
+            operation: StreamOperation::Seek,
+            requested: 0,
+            actual: 0,
+            position: None,
+            error: Some(err),
+        }
+    })?;
 
     Ok(position)
 }
 
-pub trait FileHeaderExt: Sized {
-    fn from_stream(stream: &IStream) -> windows::core::Result<Self>;
+pub fn stream_read_to_end(stream: &IStream) -> Result<Vec<u8>, StreamError> {
+    let mut data = Vec::new();
+    let mut buffer = [0u8; 4096];
+
+    loop {
+        let mut read = 0;
+        unsafe {
+            stream.Read(
+                buffer.as_mut_ptr().cast(),
+                buffer.len() as _,
+                Some(&raw mut read),
+            )
+        }
+        .ok()
+        .map_err(|err| {
+            StreamError::new(
+                stream,
+                StreamOperation::Read,
+                0,
+                data.len() as u64 + read as u64,
+                Some(err),
+            )
+        })?;
+
+        if read == 0 {
+            return Ok(data);
+        }
+
+        data.extend_from_slice(&buffer[..read as usize]);
+    }
 }
 
-impl FileHeaderExt for FileHeader {
-    fn from_stream(stream: &IStream) -> windows::core::Result<Self> {
-        let mut header = [0u8; std::mem::size_of::<FileHeader>()];
-        stream_read_exact(stream, &mut header)?;
-        FileHeader::from_bytes(&header).map_err(FileHeaderErrorExt::to_win_error)
+// Whether the stream holds a BMX at its current position, which is left as it is. Streams too short
+// for the pattern simply aren't one.
+pub fn stream_sniff(stream: &IStream) -> windows::core::Result<bool> {
+    let _position_preserver = StreamPositionPreserver::new(stream.clone())?;
+    let mut bytes = [0u8; FileHeader::PATTERN.len()];
+
+    match stream_read_exact(stream, &mut bytes) {
+        Ok(_) => Ok(bmx::sniff(&bytes)),
+        Err(StreamError { error: None, .. }) => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+// The header at the stream's current position, which is left as it is, checked against `limits`
+// before anything it describes is read.
+pub fn stream_checked_header(
+    stream: &IStream,
+    limits: &Limits,
+) -> windows::core::Result<FileHeader> {
+    let _position_preserver = StreamPositionPreserver::new(stream.clone())?;
+    let begin_position = stream_tell(stream)?;
+
+    let header = BmxReader::from_stream(stream)?.header().clone();
+    let stream_len = stream_size(stream)?.saturating_sub(begin_position);
+
+    limits
+        .check(&header, Some(stream_len))
+        .map_err(LimitErrorExt::to_win_error)?;
+
+    Ok(header)
+}
+
+pub fn stream_size(stream: &IStream) -> Result<u64, StreamError> {
+    let mut stat = STATSTG::default();
+    unsafe { stream.Stat(&raw mut stat, STATFLAG_NONAME) }
+        .map_err(|err| StreamError::new(stream, StreamOperation::Stat, 0, 0, Some(err)))?;
+
+    Ok(stat.cbSize)
+}
+
+pub trait BmxReaderExt<'a>: Sized {
+    fn from_stream(stream: &'a IStream) -> windows::core::Result<Self>;
+}
+
+impl<'a> BmxReaderExt<'a> for BmxReader<StreamReadWriteWrapper<'a>> {
+    fn from_stream(stream: &'a IStream) -> windows::core::Result<Self> {
+        BmxReader::new(StreamReadWriteWrapper::new(stream)).map_err(BmxReadErrorExt::to_win_error)
+    }
+}
+
+pub trait IoErrorExt: Sized {
+    fn to_win_error(self) -> windows::core::Error;
+}
+
+impl IoErrorExt for std::io::Error {
+    fn to_win_error(self) -> windows::core::Error {
+        let code = match self.kind() {
+            // Same as a short read through stream_read_exact.
+            ErrorKind::UnexpectedEof => WINCODEC_ERR_STREAMREAD,
+            _ => self
+                .raw_os_error()
+                .map_or(E_FAIL, |code| HRESULT::from_win32(code as u32)),
+        };
+
+        windows::core::Error::new(code, self.to_string())
     }
 }
 
@@ -88,6 +363,95 @@ pub trait FileHeaderErrorExt: Sized {
 
 impl FileHeaderErrorExt for FileHeaderError {
     fn to_win_error(self) -> windows::core::Error {
-        windows::core::Error::new(WINCODEC_ERR_BADHEADER, self.to_string())
+        Condition::of_header_error(self).error(self.to_string())
+    }
+}
+
+pub trait LimitErrorExt: Sized {
+    fn to_win_error(self) -> windows::core::Error;
+}
+
+impl LimitErrorExt for LimitError {
+    fn to_win_error(self) -> windows::core::Error {
+        Condition::of_limit_error(self).error(self.to_string())
+    }
+}
+
+pub trait BmxReadErrorExt: Sized {
+    fn to_win_error(self) -> windows::core::Error;
+}
+
+impl BmxReadErrorExt for BmxReadError {
+    fn to_win_error(self) -> windows::core::Error {
+        BmxError::from(self).to_win_error()
+    }
+}
+
+pub trait BmxErrorExt: Sized {
+    fn to_win_error(self) -> windows::core::Error;
+}
+
+impl BmxErrorExt for BmxError {
+    fn to_win_error(self) -> windows::core::Error {
+        match (Condition::of_bmx_error(&self), self) {
+            (Some(condition), err) => condition.error(err.to_string()),
+            (None, BmxError::Io(err)) => err.to_win_error(),
+            (None, err) => windows::core::Error::new(E_FAIL, err.to_string()),
+        }
+    }
+}
+
+// Lets COM code use `?` on anything that converts into a BmxError.
+impl From<BmxError> for windows::core::Error {
+    fn from(err: BmxError) -> Self {
+        err.to_win_error()
+    }
+}
+
+#[cfg(all(test, windows))]
+mod tests {
+    use windows::Win32::Graphics::Imaging::WICDecodeMetadataCacheOnDemand;
+    use windows::Win32::System::Com::{CoInitializeEx, COINIT_MULTITHREADED};
+    use windows::Win32::UI::Shell::PropertiesSystem::IInitializeWithStream;
+    use windows::Win32::UI::Shell::SHCreateMemStream;
+    use windows_core::Interface;
+
+    use super::*;
+    use crate::bmx::{BmxImage, PaletteEntry};
+    use crate::com::wic::com::CONTAINER_FORMAT;
+
+    #[test]
+    fn creates_objects_in_process() {
+        let _ = unsafe { CoInitializeEx(None, COINIT_MULTITHREADED) };
+
+        let image =
+            BmxImage::new(8, 2, 8, vec![PaletteEntry::default(); 256], vec![0; 16]).unwrap();
+        let bytes = image.to_bytes(false).unwrap();
+
+        let decoder = create_decoder();
+        unsafe {
+            let stream = SHCreateMemStream(Some(&bytes)).unwrap();
+            decoder
+                .Initialize(&stream, WICDecodeMetadataCacheOnDemand)
+                .unwrap();
+            assert_eq!(decoder.GetFrameCount().unwrap(), 1);
+        }
+
+        let encoder = create_encoder();
+        assert_eq!(
+            unsafe { encoder.GetContainerFormat() }.unwrap(),
+            CONTAINER_FORMAT
+        );
+
+        let property_store = create_property_store();
+        unsafe {
+            let stream = SHCreateMemStream(Some(&bytes)).unwrap();
+            property_store
+                .cast::<IInitializeWithStream>()
+                .unwrap()
+                .Initialize(&stream, 0)
+                .unwrap();
+            assert!(property_store.GetCount().unwrap() > 0);
+        }
     }
 }
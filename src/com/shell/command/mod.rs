@@ -1 +1,15 @@
+use windows_core::PCWSTR;
+
+use crate::com::CoClass;
+
+pub mod options;
+pub mod owner;
+pub mod paste_as_bmx;
+pub mod send_to_emulator;
 pub mod transcode;
+pub mod vera_preview;
+
+pub trait ExplorerCommandClass: CoClass {
+    const FILE_TYPE: PCWSTR;
+    const VERB: PCWSTR;
+}
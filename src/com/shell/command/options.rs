@@ -0,0 +1,508 @@
+use std::ffi::c_void;
+use std::path::Path;
+use std::sync::RwLock;
+
+use windows::core::{implement, w, IUnknown, Interface, GUID, HSTRING, PCWSTR, PWSTR};
+use windows::Win32::Foundation::{
+    BOOL, ERROR_CANCELLED, E_FAIL, E_NOTIMPL, E_POINTER, HINSTANCE, HWND, LPARAM, WPARAM,
+};
+use windows::Win32::System::Com::{CoCreateInstance, IBindCtx, CLSCTX_INPROC_SERVER};
+use windows::Win32::System::Ole::{IObjectWithSite, IObjectWithSite_Impl};
+use windows::Win32::UI::Controls::{
+    CheckDlgButton, IsDlgButtonChecked, BST_CHECKED, BST_UNCHECKED,
+};
+use windows::Win32::UI::Shell::Common::COMDLG_FILTERSPEC;
+use windows::Win32::UI::Shell::{
+    FileOpenDialog, IEnumExplorerCommand, IExplorerCommand, IExplorerCommand_Impl, IFileOpenDialog,
+    IShellItemArray, SHStrDupW, ECF_SEPARATORBEFORE, ECS_ENABLED, SIGDN_FILESYSPATH,
+};
+use windows::Win32::UI::WindowsAndMessaging::{
+    DialogBoxIndirectParamW, EndDialog, GetDlgItem, GetWindowTextLengthW, GetWindowTextW,
+    MessageBoxW, SendDlgItemMessageW, SetDlgItemTextW, BS_AUTOCHECKBOX, BS_DEFPUSHBUTTON,
+    BS_PUSHBUTTON, CBS_DROPDOWNLIST, CB_ADDSTRING, CB_GETCURSEL, CB_SETCURSEL, DLGTEMPLATE,
+    DS_CENTER, DS_FIXEDSYS, DS_MODALFRAME, DS_SETFONT, ES_AUTOHSCROLL, IDCANCEL, IDOK,
+    MB_ICONERROR, WM_COMMAND, WM_INITDIALOG, WS_BORDER, WS_CAPTION, WS_CHILD, WS_POPUP, WS_SYSMENU,
+    WS_TABSTOP, WS_VISIBLE, WS_VSCROLL,
+};
+
+use crate::com::shell::command::owner::ModalOwner;
+use crate::com::shell::CoTaskMemPWSTR;
+use crate::settings::{self, Dithering, ThumbnailBackground};
+use crate::util::{get_this_module_handle, guid, PerMonitorDpiAwareness};
+
+const TITLE: &str = "BMX Options";
+
+const TOLERATE_TRUNCATION_ID: i32 = 100;
+const EXPAND_TO_8BPP_ID: i32 = 101;
+const DITHERING_ID: i32 = 102;
+const THUMBNAIL_BACKGROUND_ID: i32 = 103;
+const EMULATOR_PATH_ID: i32 = 104;
+const BROWSE_ID: i32 = 105;
+const HIDE_SOURCE_FORMAT_ID: i32 = 106;
+
+// Predefined window class atoms for dialog items.
+const BUTTON: u16 = 0x0080;
+const EDIT: u16 = 0x0081;
+const STATIC: u16 = 0x0082;
+const COMBO_BOX: u16 = 0x0085;
+
+// An in-memory DLGTEMPLATE, so the dialog needs no resource script. Coordinates are in dialog
+// units.
+struct DialogTemplate {
+    data: Vec<u16>,
+}
+
+impl DialogTemplate {
+    fn new(title: &str, width: u16, height: u16) -> Self {
+        let style = (WS_POPUP | WS_CAPTION | WS_SYSMENU).0
+            | (DS_MODALFRAME | DS_SETFONT | DS_FIXEDSYS | DS_CENTER) as u32;
+
+        let mut template = Self { data: Vec::new() };
+        template.push_u32(style);
+        template.push_u32(0);
+        // Item count, updated by `item`.
+        template.data.push(0);
+        template.data.extend_from_slice(&[0, 0, width, height]);
+        // No menu, default class.
+        template.data.extend_from_slice(&[0, 0]);
+        template.push_str(title);
+        template.data.push(8);
+        template.push_str("MS Shell Dlg");
+        template
+    }
+
+    fn push_u32(&mut self, value: u32) {
+        self.data
+            .extend_from_slice(&[value as u16, (value >> 16) as u16]);
+    }
+
+    fn push_str(&mut self, value: &str) {
+        self.data.extend(value.encode_utf16());
+        self.data.push(0);
+    }
+
+    fn item(&mut self, class: u16, style: u32, rect: [u16; 4], id: i32, title: &str) {
+        // Items start on a DWORD boundary.
+        if !self.data.len().is_multiple_of(2) {
+            self.data.push(0);
+        }
+
+        self.push_u32(style | (WS_CHILD | WS_VISIBLE).0);
+        self.push_u32(0);
+        self.data.extend_from_slice(&rect);
+        self.data.extend_from_slice(&[id as u16, 0xFFFF, class]);
+        self.push_str(title);
+        // No creation data.
+        self.data.push(0);
+
+        self.data[4] += 1;
+    }
+
+    // As DWORDs, since the template has to be DWORD aligned.
+    fn build(mut self) -> Vec<u32> {
+        if !self.data.len().is_multiple_of(2) {
+            self.data.push(0);
+        }
+
+        self.data
+            .chunks_exact(2)
+            .map(|pair| pair[0] as u32 | (pair[1] as u32) << 16)
+            .collect()
+    }
+}
+
+fn template() -> Vec<u32> {
+    let checkbox = BS_AUTOCHECKBOX as u32 | WS_TABSTOP.0;
+    let combo_box = CBS_DROPDOWNLIST as u32 | (WS_TABSTOP | WS_VSCROLL).0;
+
+    let mut template = DialogTemplate::new(TITLE, 240, 131);
+
+    template.item(
+        BUTTON,
+        checkbox,
+        [7, 7, 226, 10],
+        TOLERATE_TRUNCATION_ID,
+        "Show truncated files instead of failing to decode them",
+    );
+    template.item(
+        BUTTON,
+        checkbox,
+        [7, 21, 226, 10],
+        EXPAND_TO_8BPP_ID,
+        "Decode 1, 2 and 4bpp images as 8bpp for older applications",
+    );
+    template.item(
+        BUTTON,
+        checkbox,
+        [7, 35, 226, 10],
+        HIDE_SOURCE_FORMAT_ID,
+        "Leave a file's own format out of the Transcode menu",
+    );
+
+    template.item(STATIC, 0, [7, 54, 80, 8], -1, "Dithering:");
+    template.item(COMBO_BOX, combo_box, [90, 52, 143, 60], DITHERING_ID, "");
+
+    template.item(STATIC, 0, [7, 72, 80, 8], -1, "Thumbnail background:");
+    template.item(
+        COMBO_BOX,
+        combo_box,
+        [90, 70, 143, 60],
+        THUMBNAIL_BACKGROUND_ID,
+        "",
+    );
+
+    template.item(STATIC, 0, [7, 90, 80, 8], -1, "X16 emulator:");
+    template.item(
+        EDIT,
+        ES_AUTOHSCROLL as u32 | (WS_BORDER | WS_TABSTOP).0,
+        [90, 88, 97, 12],
+        EMULATOR_PATH_ID,
+        "",
+    );
+    template.item(
+        BUTTON,
+        BS_PUSHBUTTON as u32 | WS_TABSTOP.0,
+        [190, 87, 43, 14],
+        BROWSE_ID,
+        "Browse...",
+    );
+
+    template.item(
+        BUTTON,
+        BS_DEFPUSHBUTTON as u32 | WS_TABSTOP.0,
+        [129, 110, 50, 14],
+        IDOK.0,
+        "OK",
+    );
+    template.item(
+        BUTTON,
+        BS_PUSHBUTTON as u32 | WS_TABSTOP.0,
+        [183, 110, 50, 14],
+        IDCANCEL.0,
+        "Cancel",
+    );
+
+    template.build()
+}
+
+fn check(dialog: HWND, id: i32, checked: bool) {
+    let state = if checked { BST_CHECKED } else { BST_UNCHECKED };
+    unsafe {
+        _ = CheckDlgButton(dialog, id, state);
+    }
+}
+
+fn is_checked(dialog: HWND, id: i32) -> bool {
+    unsafe { IsDlgButtonChecked(dialog, id) == BST_CHECKED.0 }
+}
+
+fn fill_combo_box<T: Copy + PartialEq>(
+    dialog: HWND,
+    id: i32,
+    values: &[T],
+    name: fn(T) -> &'static str,
+    selected: T,
+) {
+    for &value in values {
+        let name = HSTRING::from(name(value));
+
+        unsafe {
+            SendDlgItemMessageW(
+                dialog,
+                id,
+                CB_ADDSTRING,
+                WPARAM(0),
+                LPARAM(name.as_ptr() as _),
+            );
+        }
+    }
+
+    let index = values.iter().position(|&v| v == selected).unwrap_or(0);
+
+    unsafe {
+        SendDlgItemMessageW(dialog, id, CB_SETCURSEL, WPARAM(index), LPARAM(0));
+    }
+}
+
+fn selected<T: Copy + Default>(dialog: HWND, id: i32, values: &[T]) -> T {
+    let index = unsafe { SendDlgItemMessageW(dialog, id, CB_GETCURSEL, WPARAM(0), LPARAM(0)) };
+
+    usize::try_from(index.0)
+        .ok()
+        .and_then(|index| values.get(index).copied())
+        .unwrap_or_default()
+}
+
+fn initialize(dialog: HWND) {
+    check(
+        dialog,
+        TOLERATE_TRUNCATION_ID,
+        settings::tolerate_truncation(),
+    );
+    check(dialog, EXPAND_TO_8BPP_ID, settings::expand_to_8bpp());
+    check(
+        dialog,
+        HIDE_SOURCE_FORMAT_ID,
+        settings::hide_source_format(),
+    );
+
+    fill_combo_box(
+        dialog,
+        DITHERING_ID,
+        &Dithering::ALL,
+        Dithering::name,
+        settings::dithering(),
+    );
+
+    fill_combo_box(
+        dialog,
+        THUMBNAIL_BACKGROUND_ID,
+        &ThumbnailBackground::ALL,
+        ThumbnailBackground::name,
+        settings::thumbnail_background(),
+    );
+
+    if let Some(path) = settings::emulator_path() {
+        unsafe {
+            _ = SetDlgItemTextW(dialog, EMULATOR_PATH_ID, &HSTRING::from(path.as_os_str()));
+        }
+    }
+}
+
+fn emulator_path(dialog: HWND) -> String {
+    unsafe {
+        let Ok(edit) = GetDlgItem(dialog, EMULATOR_PATH_ID) else {
+            return String::new();
+        };
+
+        let mut buffer = vec![0u16; GetWindowTextLengthW(edit) as usize + 1];
+        let len = GetWindowTextW(edit, &mut buffer) as usize;
+
+        String::from_utf16_lossy(&buffer[..len]).trim().to_owned()
+    }
+}
+
+fn save(dialog: HWND) -> windows::core::Result<()> {
+    settings::set_tolerate_truncation(is_checked(dialog, TOLERATE_TRUNCATION_ID))?;
+    settings::set_expand_to_8bpp(is_checked(dialog, EXPAND_TO_8BPP_ID))?;
+    settings::set_hide_source_format(is_checked(dialog, HIDE_SOURCE_FORMAT_ID))?;
+    settings::set_dithering(selected(dialog, DITHERING_ID, &Dithering::ALL))?;
+    settings::set_thumbnail_background(selected(
+        dialog,
+        THUMBNAIL_BACKGROUND_ID,
+        &ThumbnailBackground::ALL,
+    ))?;
+
+    let path = emulator_path(dialog);
+    settings::set_emulator_path((!path.is_empty()).then(|| Path::new(&path)))
+}
+
+fn browse(dialog: HWND) -> windows::core::Result<()> {
+    let file_dialog: IFileOpenDialog =
+        unsafe { CoCreateInstance(&FileOpenDialog, None, CLSCTX_INPROC_SERVER)? };
+
+    unsafe {
+        file_dialog.SetTitle(w!("Select X16 Emulator"))?;
+        file_dialog.SetFileTypes(&[COMDLG_FILTERSPEC {
+            pszName: w!("Programs"),
+            pszSpec: w!("*.exe"),
+        }])?;
+
+        match file_dialog.Show(dialog) {
+            Err(err) if err.code() == ERROR_CANCELLED.to_hresult() => return Ok(()),
+            result => result?,
+        }
+
+        let path = CoTaskMemPWSTR::new(file_dialog.GetResult()?.GetDisplayName(SIGDN_FILESYSPATH)?);
+        SetDlgItemTextW(dialog, EMULATOR_PATH_ID, PCWSTR::from_raw(path.as_ptr()))
+    }
+}
+
+fn show_error(dialog: HWND, err: &windows::core::Error) {
+    unsafe {
+        MessageBoxW(
+            dialog,
+            &HSTRING::from(err.message()),
+            &HSTRING::from(TITLE),
+            MB_ICONERROR,
+        );
+    }
+}
+
+unsafe extern "system" fn dialog_proc(
+    dialog: HWND,
+    message: u32,
+    wparam: WPARAM,
+    _lparam: LPARAM,
+) -> isize {
+    match message {
+        WM_INITDIALOG => {
+            initialize(dialog);
+            1
+        }
+        WM_COMMAND => {
+            match (wparam.0 & 0xFFFF) as i32 {
+                id if id == IDOK.0 => match save(dialog) {
+                    Ok(()) => unsafe { _ = EndDialog(dialog, IDOK.0 as _) },
+                    Err(err) => show_error(dialog, &err),
+                },
+                id if id == IDCANCEL.0 => unsafe { _ = EndDialog(dialog, IDCANCEL.0 as _) },
+                BROWSE_ID => {
+                    if let Err(err) = browse(dialog) {
+                        show_error(dialog, &err);
+                    }
+                }
+                _ => return 0,
+            }
+
+            1
+        }
+        _ => 0,
+    }
+}
+
+pub fn show_options_dialog(owner_window: HWND) -> windows::core::Result<()> {
+    let template = template();
+    let module = unsafe { get_this_module_handle()? };
+
+    // Dialog units are scaled by the system for the monitor the dialog is on.
+    let _dpi_awareness = PerMonitorDpiAwareness::new();
+
+    let result = unsafe {
+        DialogBoxIndirectParamW(
+            HINSTANCE(module.0),
+            template.as_ptr().cast::<DLGTEMPLATE>(),
+            owner_window,
+            Some(dialog_proc),
+            LPARAM(0),
+        )
+    };
+
+    if result == -1 {
+        Err(windows::core::Error::from_win32())
+    } else {
+        Ok(())
+    }
+}
+
+// The "Options..." entry at the end of the Transcode submenu.
+#[derive(Default)]
+#[implement(IExplorerCommand, IObjectWithSite)]
+pub struct Options {
+    site: RwLock<Option<IUnknown>>,
+}
+
+impl Options {
+    const CANONICAL_NAME: GUID = guid::from_str("5b8e0f63-1d47-4a92-bc3e-7f26d9a4e015");
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl IExplorerCommand_Impl for Options_Impl {
+    fn GetTitle(&self, _items: Option<&IShellItemArray>) -> windows::core::Result<PWSTR> {
+        unsafe { SHStrDupW(w!("Options\u{2026}")) }
+    }
+
+    fn GetIcon(&self, _items: Option<&IShellItemArray>) -> windows::core::Result<PWSTR> {
+        Err(E_NOTIMPL.into())
+    }
+
+    fn GetToolTip(&self, _items: Option<&IShellItemArray>) -> windows::core::Result<PWSTR> {
+        unsafe { SHStrDupW(w!("Change how BMX images are decoded, converted and shown")) }
+    }
+
+    fn GetCanonicalName(&self) -> windows::core::Result<GUID> {
+        Ok(Options::CANONICAL_NAME)
+    }
+
+    fn GetState(
+        &self,
+        _items: Option<&IShellItemArray>,
+        _ok_to_be_slow: BOOL,
+    ) -> windows::core::Result<u32> {
+        Ok(ECS_ENABLED.0 as _)
+    }
+
+    fn Invoke(
+        &self,
+        _items: Option<&IShellItemArray>,
+        _pbc: Option<&IBindCtx>,
+    ) -> windows::core::Result<()> {
+        let owner = ModalOwner::new(self.site.read().unwrap().as_ref());
+        let owner_window = owner.window();
+
+        show_options_dialog(owner_window)
+    }
+
+    fn GetFlags(&self) -> windows::core::Result<u32> {
+        Ok(ECF_SEPARATORBEFORE.0 as _)
+    }
+
+    fn EnumSubCommands(&self) -> windows::core::Result<IEnumExplorerCommand> {
+        Err(E_NOTIMPL.into())
+    }
+}
+
+impl IObjectWithSite_Impl for Options_Impl {
+    fn SetSite(&self, site: Option<&IUnknown>) -> windows::core::Result<()> {
+        *self.site.write().unwrap() = site.cloned();
+        Ok(())
+    }
+
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn GetSite(&self, riid: *const GUID, ppv: *mut *mut c_void) -> windows::core::Result<()> {
+        if ppv.is_null() {
+            return Err(E_POINTER.into());
+        }
+
+        if riid.is_null() {
+            unsafe {
+                ppv.write(std::ptr::null_mut());
+            }
+
+            return Err(E_POINTER.into());
+        }
+
+        match *self.site.read().unwrap() {
+            Some(ref site) => unsafe { site.query(riid, ppv).ok() },
+            None => {
+                unsafe {
+                    ppv.write(std::ptr::null_mut());
+                }
+                Err(E_FAIL.into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn template_layout() {
+        let template = template();
+        let words = template
+            .iter()
+            .flat_map(|&dword| [dword as u16, (dword >> 16) as u16])
+            .collect::<Vec<_>>();
+
+        // Item count, then the size.
+        assert_eq!(words[4], 12);
+        assert_eq!(&words[7..9], [240, 131]);
+
+        // The first item follows the font name on a DWORD boundary.
+        let font_end = words
+            .windows(12)
+            .position(|window| window == "MS Shell Dlg".encode_utf16().collect::<Vec<_>>())
+            .unwrap()
+            + 13;
+        let first_item = font_end.next_multiple_of(2);
+
+        assert_eq!(&words[first_item + 4..first_item + 8], [7, 7, 226, 10]);
+        assert_eq!(words[first_item + 8], TOLERATE_TRUNCATION_ID as u16);
+        assert_eq!(&words[first_item + 9..first_item + 11], [0xFFFF, BUTTON]);
+    }
+}
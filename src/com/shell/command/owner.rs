@@ -0,0 +1,74 @@
+use windows::core::IUnknown;
+use windows::Win32::Foundation::HWND;
+use windows::Win32::System::Threading::GetCurrentProcessId;
+use windows::Win32::UI::Shell::{
+    IShellBrowser, IUnknown_GetWindow, IUnknown_QueryService, SID_STopLevelBrowser,
+};
+use windows::Win32::UI::WindowsAndMessaging::{
+    GetAncestor, GetClassNameW, GetForegroundWindow, GetWindowThreadProcessId, GA_ROOT,
+};
+
+// Top-level windows of File Explorer and the desktop.
+const EXPLORER_WINDOW_CLASSES: [&str; 4] = ["CabinetWClass", "ExploreWClass", "Progman", "WorkerW"];
+
+fn is_explorer_window(window: HWND) -> bool {
+    let mut process_id = 0;
+    unsafe { GetWindowThreadProcessId(window, Some(&raw mut process_id)) };
+
+    if process_id == unsafe { GetCurrentProcessId() } {
+        return true;
+    }
+
+    let mut class_name = [0u16; 64];
+    let len = unsafe { GetClassNameW(window, &mut class_name) } as usize;
+    let class_name = String::from_utf16_lossy(&class_name[..len]);
+
+    EXPLORER_WINDOW_CLASSES.contains(&class_name.as_str())
+}
+
+// The window dialogs and message boxes are owned by while a command runs. Dialogs without an owner
+// can end up behind the Explorer window they were opened from, so when the site has no window, the
+// foreground window is used instead if it belongs to Explorer.
+//
+// Also disables the hosting browser's modeless UI for as long as it lives, so Explorer doesn't
+// process input, e.g. invoke the same command again, while one of our modal loops is running.
+pub struct ModalOwner {
+    window: HWND,
+    browser: Option<IShellBrowser>,
+}
+
+impl ModalOwner {
+    pub fn new(site: Option<&IUnknown>) -> Self {
+        let window = site
+            .and_then(|site| unsafe { IUnknown_GetWindow(site) }.ok())
+            .filter(|window| !window.is_invalid())
+            .or_else(|| {
+                Some(unsafe { GetForegroundWindow() })
+                    .filter(|window| !window.is_invalid() && is_explorer_window(*window))
+            })
+            .map(|window| unsafe { GetAncestor(window, GA_ROOT) })
+            .unwrap_or_default();
+
+        let browser = site.and_then(|site| {
+            unsafe { IUnknown_QueryService::<_, IShellBrowser>(site, &SID_STopLevelBrowser) }.ok()
+        });
+
+        if let Some(ref browser) = browser {
+            let _ = unsafe { browser.EnableModelessSB(false) };
+        }
+
+        Self { window, browser }
+    }
+
+    pub fn window(&self) -> HWND {
+        self.window
+    }
+}
+
+impl Drop for ModalOwner {
+    fn drop(&mut self) {
+        if let Some(ref browser) = self.browser {
+            let _ = unsafe { browser.EnableModelessSB(true) };
+        }
+    }
+}
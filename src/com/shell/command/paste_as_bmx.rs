@@ -0,0 +1,338 @@
+use std::ffi::c_void;
+use std::sync::RwLock;
+
+use windows::core::{implement, w, IUnknown, Interface, GUID, HSTRING, PCWSTR, PWSTR};
+use windows::Win32::Foundation::{
+    BOOL, E_FAIL, E_NOTIMPL, E_OUTOFMEMORY, E_POINTER, HGLOBAL, HWND, WINCODEC_ERR_BADIMAGE,
+};
+use windows::Win32::Graphics::Imaging::IWICImagingFactory;
+use windows::Win32::System::Com::{
+    CoCreateInstance, IBindCtx, CLSCTX_INPROC_SERVER, STREAM_SEEK_SET,
+};
+use windows::Win32::System::DataExchange::{
+    CloseClipboard, GetClipboardData, IsClipboardFormatAvailable, OpenClipboard,
+    RegisterClipboardFormatW,
+};
+use windows::Win32::System::Memory::{GlobalLock, GlobalSize, GlobalUnlock};
+use windows::Win32::System::Ole::{IObjectWithSite, IObjectWithSite_Impl, CF_DIB};
+use windows::Win32::UI::Shell::{
+    FileOperation, IEnumExplorerCommand, IExplorerCommand, IExplorerCommand_Impl, IFileOperation,
+    IShellItem, IShellItemArray, SHCreateItemFromParsingName, SHCreateMemStream, SHStrDupW,
+    ECF_DEFAULT, ECS_DISABLED, ECS_ENABLED, FOF_ALLOWUNDO, FOF_RENAMEONCOLLISION,
+};
+use windows::Win32::UI::WindowsAndMessaging::{MessageBoxW, MB_ICONERROR};
+
+use crate::com::shell::command::owner::ModalOwner;
+use crate::com::shell::command::ExplorerCommandClass;
+use crate::com::transcode::{TranscodeOptions, TranscodeRequest};
+use crate::com::wic::com::CONTAINER_FORMAT;
+use crate::com::wic::create_imaging_factory;
+use crate::com::{stream_read_to_end, CoClass};
+use crate::util::guid;
+
+const FILE_NAME: PCWSTR = w!("clipboard.bmx");
+
+const BI_BITFIELDS: u32 = 3;
+const BI_ALPHABITFIELDS: u32 = 6;
+
+fn png_format() -> u32 {
+    unsafe { RegisterClipboardFormatW(w!("PNG")) }
+}
+
+fn clipboard_has_image() -> bool {
+    unsafe {
+        IsClipboardFormatAvailable(png_format()).is_ok()
+            || IsClipboardFormatAvailable(CF_DIB.0 as _).is_ok()
+    }
+}
+
+// Opened for as long as this lives.
+struct Clipboard;
+
+impl Clipboard {
+    fn open(owner_window: HWND) -> windows::core::Result<Self> {
+        unsafe { OpenClipboard(owner_window)? };
+        Ok(Self)
+    }
+
+    fn data(&self, format: u32) -> Option<Vec<u8>> {
+        let global = HGLOBAL(unsafe { GetClipboardData(format) }.ok()?.0);
+
+        unsafe {
+            let data = GlobalLock(global);
+            if data.is_null() {
+                return None;
+            }
+
+            let bytes = std::slice::from_raw_parts(data.cast::<u8>(), GlobalSize(global)).to_vec();
+            _ = GlobalUnlock(global);
+
+            Some(bytes)
+        }
+    }
+
+    // PNG keeps transparency and is what browsers and most image editors put on the clipboard;
+    // CF_DIB is there for everything else, including screenshots. It's returned as a BMP file so
+    // WIC can decode either.
+    fn image(&self) -> Option<Vec<u8>> {
+        self.data(png_format())
+            .or_else(|| self.data(CF_DIB.0 as _).and_then(|dib| dib_to_bmp(&dib)))
+    }
+}
+
+impl Drop for Clipboard {
+    fn drop(&mut self) {
+        unsafe {
+            _ = CloseClipboard();
+        }
+    }
+}
+
+// A BMP file is a packed DIB behind a file header that says where the pixels start, which depends
+// on the size of the info header, the color masks and the color table.
+fn dib_to_bmp(dib: &[u8]) -> Option<Vec<u8>> {
+    let u32_at = |offset: usize| {
+        Some(u32::from_le_bytes(
+            dib.get(offset..offset + 4)?.try_into().ok()?,
+        ))
+    };
+
+    let header_size = u32_at(0)? as usize;
+    if header_size < 40 {
+        return None;
+    }
+
+    let bit_count = u16::from_le_bytes(dib.get(14..16)?.try_into().ok()?);
+    let compression = u32_at(16)?;
+    let colors_used = u32_at(32)? as usize;
+
+    let masks_size = match (header_size, compression) {
+        (40, BI_BITFIELDS) => 12,
+        (40, BI_ALPHABITFIELDS) => 16,
+        _ => 0,
+    };
+
+    let colors = match (colors_used, bit_count) {
+        (0, 1..=8) => 1 << bit_count,
+        (colors_used, _) => colors_used,
+    };
+
+    let offset = 14 + header_size + masks_size + colors * 4;
+
+    let mut bmp = Vec::with_capacity(14 + dib.len());
+    bmp.extend_from_slice(b"BM");
+    bmp.extend_from_slice(&u32::try_from(14 + dib.len()).ok()?.to_le_bytes());
+    bmp.extend_from_slice(&0u32.to_le_bytes());
+    bmp.extend_from_slice(&u32::try_from(offset).ok()?.to_le_bytes());
+    bmp.extend_from_slice(dib);
+
+    Some(bmp)
+}
+
+// Decodes the image, reduces it to 256 colors and encodes it as a BMX.
+fn encode(imaging_factory: &IWICImagingFactory, image: &[u8]) -> windows::core::Result<Vec<u8>> {
+    let source = unsafe { SHCreateMemStream(Some(image)) }.ok_or(E_OUTOFMEMORY)?;
+    let target = unsafe { SHCreateMemStream(None) }.ok_or(E_OUTOFMEMORY)?;
+
+    TranscodeRequest {
+        options: TranscodeOptions {
+            bit_depth: Some(8),
+            ..Default::default()
+        },
+        ..TranscodeRequest::new(source, target.clone(), CONTAINER_FORMAT)
+    }
+    .run(imaging_factory)?;
+
+    unsafe { target.Seek(0, STREAM_SEEK_SET, None)? };
+    Ok(stream_read_to_end(&target)?)
+}
+
+// The file is written to the temp folder first and then moved into place, so the file operation
+// takes care of name collisions, undo and the folder view picking up the new item.
+fn paste(folder: &IShellItem, owner_window: HWND) -> windows::core::Result<()> {
+    let image = Clipboard::open(owner_window)?.image().ok_or_else(|| {
+        windows::core::Error::new(WINCODEC_ERR_BADIMAGE, "No image on the clipboard")
+    })?;
+
+    let bmx = encode(&create_imaging_factory()?, &image)?;
+
+    let temp_path = std::env::temp_dir().join(format!("bmx-shell-{}.bmx", std::process::id()));
+    std::fs::write(&temp_path, bmx)
+        .map_err(|err| windows::core::Error::new(E_FAIL, err.to_string()))?;
+
+    let result = (|| unsafe {
+        let source: IShellItem =
+            SHCreateItemFromParsingName(&HSTRING::from(temp_path.as_os_str()), None)?;
+
+        let operation: IFileOperation =
+            CoCreateInstance(&FileOperation, None, CLSCTX_INPROC_SERVER)?;
+
+        operation.SetOwnerWindow(owner_window)?;
+        operation.SetOperationFlags(FOF_ALLOWUNDO | FOF_RENAMEONCOLLISION)?;
+        operation.MoveItem(&source, folder, FILE_NAME, None)?;
+        operation.PerformOperations()
+    })();
+
+    // Only still there if the move failed or was cancelled.
+    _ = std::fs::remove_file(&temp_path);
+
+    result
+}
+
+// Writes the image on the clipboard into the folder as clipboard.bmx. Shown on the folder
+// background, and disabled unless the clipboard holds a PNG or a DIB.
+#[derive(Default)]
+#[implement(IExplorerCommand, IObjectWithSite)]
+pub struct PasteAsBmx {
+    site: RwLock<Option<IUnknown>>,
+}
+
+impl PasteAsBmx {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl CoClass for PasteAsBmx {
+    const CLSID: GUID = guid::from_str("e83a5f17-2c9d-4b60-91e4-5d7f0a3b6c28");
+    const PROG_ID: PCWSTR = w!("X16BMX.PasteAsBmx.1");
+    const VERSION_INDEPENDENT_PROG_ID: PCWSTR = w!("X16BMX.PasteAsBmx");
+    const FRIENDLY_NAME: PCWSTR = w!("Paste as BMX");
+}
+
+impl ExplorerCommandClass for PasteAsBmx {
+    const FILE_TYPE: PCWSTR = w!("Directory\\Background");
+    const VERB: PCWSTR = w!("PasteAsBmx");
+}
+
+impl IExplorerCommand_Impl for PasteAsBmx_Impl {
+    fn GetTitle(&self, _items: Option<&IShellItemArray>) -> windows::core::Result<PWSTR> {
+        unsafe { SHStrDupW(w!("Paste image as BMX")) }
+    }
+
+    fn GetIcon(&self, _items: Option<&IShellItemArray>) -> windows::core::Result<PWSTR> {
+        Err(E_NOTIMPL.into())
+    }
+
+    fn GetToolTip(&self, _items: Option<&IShellItemArray>) -> windows::core::Result<PWSTR> {
+        unsafe { SHStrDupW(w!("Save the image on the clipboard as clipboard.bmx")) }
+    }
+
+    fn GetCanonicalName(&self) -> windows::core::Result<GUID> {
+        Ok(PasteAsBmx::CLSID)
+    }
+
+    fn GetState(
+        &self,
+        _items: Option<&IShellItemArray>,
+        _ok_to_be_slow: BOOL,
+    ) -> windows::core::Result<u32> {
+        if clipboard_has_image() {
+            Ok(ECS_ENABLED.0 as _)
+        } else {
+            Ok(ECS_DISABLED.0 as _)
+        }
+    }
+
+    fn Invoke(
+        &self,
+        items: Option<&IShellItemArray>,
+        _pbc: Option<&IBindCtx>,
+    ) -> windows::core::Result<()> {
+        // For the folder background, the folder itself is the only item.
+        let folder = unsafe { items.ok_or(E_POINTER)?.GetItemAt(0)? };
+
+        let owner = ModalOwner::new(self.site.read().unwrap().as_ref());
+        let owner_window = owner.window();
+
+        if let Err(err) = paste(&folder, owner_window) {
+            unsafe {
+                MessageBoxW(
+                    owner_window,
+                    &HSTRING::from(err.message()),
+                    w!("Paste image as BMX"),
+                    MB_ICONERROR,
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    fn GetFlags(&self) -> windows::core::Result<u32> {
+        Ok(ECF_DEFAULT.0 as _)
+    }
+
+    fn EnumSubCommands(&self) -> windows::core::Result<IEnumExplorerCommand> {
+        Err(E_NOTIMPL.into())
+    }
+}
+
+impl IObjectWithSite_Impl for PasteAsBmx_Impl {
+    fn SetSite(&self, site: Option<&IUnknown>) -> windows::core::Result<()> {
+        *self.site.write().unwrap() = site.cloned();
+        Ok(())
+    }
+
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn GetSite(&self, riid: *const GUID, ppv: *mut *mut c_void) -> windows::core::Result<()> {
+        if ppv.is_null() {
+            return Err(E_POINTER.into());
+        }
+
+        if riid.is_null() {
+            unsafe {
+                ppv.write(std::ptr::null_mut());
+            }
+
+            return Err(E_POINTER.into());
+        }
+
+        match *self.site.read().unwrap() {
+            Some(ref site) => unsafe { site.query(riid, ppv).ok() },
+            None => {
+                unsafe {
+                    ppv.write(std::ptr::null_mut());
+                }
+                Err(E_FAIL.into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info_header(bit_count: u16, compression: u32, colors_used: u32) -> Vec<u8> {
+        let mut header = vec![0; 40];
+        header[0..4].copy_from_slice(&40u32.to_le_bytes());
+        header[14..16].copy_from_slice(&bit_count.to_le_bytes());
+        header[16..20].copy_from_slice(&compression.to_le_bytes());
+        header[32..36].copy_from_slice(&colors_used.to_le_bytes());
+        header
+    }
+
+    fn pixel_offset(bmp: &[u8]) -> u32 {
+        u32::from_le_bytes(bmp[10..14].try_into().unwrap())
+    }
+
+    #[test]
+    fn dib_to_bmp_pixel_offset() {
+        let bmp = dib_to_bmp(&info_header(8, 0, 0)).unwrap();
+        assert_eq!(&bmp[..2], b"BM");
+        assert_eq!(bmp.len(), 54);
+        assert_eq!(pixel_offset(&bmp), 54 + 256 * 4);
+
+        let bmp = dib_to_bmp(&info_header(4, 0, 3)).unwrap();
+        assert_eq!(pixel_offset(&bmp), 54 + 3 * 4);
+
+        let bmp = dib_to_bmp(&info_header(32, BI_BITFIELDS, 0)).unwrap();
+        assert_eq!(pixel_offset(&bmp), 54 + 12);
+
+        let bmp = dib_to_bmp(&info_header(24, 0, 0)).unwrap();
+        assert_eq!(pixel_offset(&bmp), 54);
+
+        assert!(dib_to_bmp(&[0; 12]).is_none());
+    }
+}
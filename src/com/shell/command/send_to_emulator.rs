@@ -0,0 +1,259 @@
+use std::ffi::c_void;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::RwLock;
+
+use windows::core::{implement, w, IUnknown, Interface, GUID, HSTRING, PCWSTR, PWSTR};
+use windows::Win32::Foundation::{BOOL, E_FAIL, E_NOTIMPL, E_POINTER};
+use windows::Win32::System::Com::IBindCtx;
+use windows::Win32::System::Ole::{IObjectWithSite, IObjectWithSite_Impl};
+use windows::Win32::UI::Shell::{
+    IEnumExplorerCommand, IExplorerCommand, IExplorerCommand_Impl, IShellItemArray, SHStrDupW,
+    ECF_DEFAULT, ECS_ENABLED, ECS_HIDDEN, SIGDN_FILESYSPATH,
+};
+use windows::Win32::UI::WindowsAndMessaging::{MessageBoxW, MB_ICONERROR};
+
+use crate::com::shell::command::owner::ModalOwner;
+use crate::com::shell::command::ExplorerCommandClass;
+use crate::com::shell::CoTaskMemPWSTR;
+use crate::com::CoClass;
+use crate::registry::get_class_string_setting;
+use crate::settings;
+use crate::util::guid;
+
+// Folder the files are copied to, e.g. one that is synced into an SD card image. Without it, the
+// emulator gets the folder of the selected files as its host file system.
+pub const SD_CARD_FOLDER: PCWSTR = w!("SdCardFolder");
+
+fn setting(name: PCWSTR) -> Option<PathBuf> {
+    get_class_string_setting::<SendToEmulator>(name).map(PathBuf::from)
+}
+
+fn item_paths(items: &IShellItemArray) -> windows::core::Result<Vec<PathBuf>> {
+    (0..unsafe { items.GetCount()? })
+        .map(|i| {
+            let path = CoTaskMemPWSTR::new(unsafe {
+                items.GetItemAt(i)?.GetDisplayName(SIGDN_FILESYSPATH)?
+            });
+
+            Ok(PathBuf::from(
+                unsafe { path.to_string() }.map_err(|_| E_FAIL)?,
+            ))
+        })
+        .collect()
+}
+
+// The folder all files are in. The emulator only gets one host file system, so files from several
+// folders can't be sent together unless they're copied into one first.
+fn common_folder(paths: &[PathBuf]) -> Result<PathBuf, String> {
+    let mut folders = paths
+        .iter()
+        .map(|path| path.parent().unwrap_or(Path::new("")));
+    let first = folders.next().unwrap_or(Path::new(""));
+
+    if folders.all(|folder| folder == first) {
+        Ok(first.to_path_buf())
+    } else {
+        Err("The selected files have to be in the same folder.".to_owned())
+    }
+}
+
+// Typed in by the emulator once it has started, loading the image into banked RAM from $A000 on,
+// where programs under test can pick it up.
+fn autoload(file_name: &str) -> String {
+    format!("BLOAD \"{}\",8,1,$A000\n", file_name.replace('"', ""))
+}
+
+fn send_to_emulator(paths: &[PathBuf]) -> Result<(), String> {
+    let emulator = settings::emulator_path();
+
+    let fs_root = match setting(SD_CARD_FOLDER) {
+        Some(folder) => {
+            for path in paths {
+                let target = folder.join(path.file_name().unwrap_or_default());
+
+                std::fs::copy(path, &target).map_err(|err| {
+                    format!(
+                        "Couldn't copy {} to {}: {}",
+                        path.display(),
+                        folder.display(),
+                        err
+                    )
+                })?;
+            }
+
+            folder
+        }
+        None => common_folder(paths)?,
+    };
+
+    if let Some(emulator) = emulator {
+        let mut command = Command::new(&emulator);
+        command.arg("-fsroot").arg(&fs_root);
+
+        // Only one image can be loaded; the rest of a selection is just on the file system.
+        if let [path] = paths {
+            let script = std::env::temp_dir().join("x16bmx-autoload.bas");
+            let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+
+            std::fs::write(&script, autoload(&file_name))
+                .map_err(|err| format!("Couldn't write {}: {}", script.display(), err))?;
+            command.arg("-bas").arg(&script);
+        }
+
+        // The emulator looks for its ROM next to itself.
+        if let Some(folder) = emulator.parent() {
+            command.current_dir(folder);
+        }
+
+        command
+            .spawn()
+            .map_err(|err| format!("Couldn't start {}: {}", emulator.display(), err))?;
+    }
+
+    Ok(())
+}
+
+// Launches the Commander X16 emulator with the selected files on its host file system, or copies
+// them to a configured folder first. A single selected file is also loaded once the emulator has
+// started. Hidden until at least one of the two is configured.
+#[derive(Default)]
+#[implement(IExplorerCommand, IObjectWithSite)]
+pub struct SendToEmulator {
+    site: RwLock<Option<IUnknown>>,
+}
+
+impl SendToEmulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl CoClass for SendToEmulator {
+    const CLSID: GUID = guid::from_str("4c1b7e92-3d58-4a6f-8e20-b7f5d913c6a8");
+    const PROG_ID: PCWSTR = w!("X16BMX.SendToEmulator.1");
+    const VERSION_INDEPENDENT_PROG_ID: PCWSTR = w!("X16BMX.SendToEmulator");
+    const FRIENDLY_NAME: PCWSTR = w!("Send to X16 Emulator");
+}
+
+impl ExplorerCommandClass for SendToEmulator {
+    const FILE_TYPE: PCWSTR = w!("SystemFileAssociations\\.bmx");
+    const VERB: PCWSTR = w!("SendToX16Emulator");
+}
+
+impl IExplorerCommand_Impl for SendToEmulator_Impl {
+    fn GetTitle(&self, _items: Option<&IShellItemArray>) -> windows::core::Result<PWSTR> {
+        unsafe { SHStrDupW(w!("Send to X16 emulator")) }
+    }
+
+    fn GetIcon(&self, _items: Option<&IShellItemArray>) -> windows::core::Result<PWSTR> {
+        Err(E_NOTIMPL.into())
+    }
+
+    fn GetToolTip(&self, _items: Option<&IShellItemArray>) -> windows::core::Result<PWSTR> {
+        unsafe { SHStrDupW(w!("Open the images in the Commander X16 emulator")) }
+    }
+
+    fn GetCanonicalName(&self) -> windows::core::Result<GUID> {
+        Ok(SendToEmulator::CLSID)
+    }
+
+    fn GetState(
+        &self,
+        _items: Option<&IShellItemArray>,
+        _ok_to_be_slow: BOOL,
+    ) -> windows::core::Result<u32> {
+        if settings::emulator_path().is_some() || setting(SD_CARD_FOLDER).is_some() {
+            Ok(ECS_ENABLED.0 as _)
+        } else {
+            Ok(ECS_HIDDEN.0 as _)
+        }
+    }
+
+    fn Invoke(
+        &self,
+        items: Option<&IShellItemArray>,
+        _pbc: Option<&IBindCtx>,
+    ) -> windows::core::Result<()> {
+        let items = items.ok_or(E_POINTER)?;
+
+        if let Err(message) = send_to_emulator(&item_paths(items)?) {
+            let owner = ModalOwner::new(self.site.read().unwrap().as_ref());
+            let owner_window = owner.window();
+
+            unsafe {
+                MessageBoxW(
+                    owner_window,
+                    &HSTRING::from(message),
+                    w!("Send to X16 emulator"),
+                    MB_ICONERROR,
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    fn GetFlags(&self) -> windows::core::Result<u32> {
+        Ok(ECF_DEFAULT.0 as _)
+    }
+
+    fn EnumSubCommands(&self) -> windows::core::Result<IEnumExplorerCommand> {
+        Err(E_NOTIMPL.into())
+    }
+}
+
+impl IObjectWithSite_Impl for SendToEmulator_Impl {
+    fn SetSite(&self, site: Option<&IUnknown>) -> windows::core::Result<()> {
+        *self.site.write().unwrap() = site.cloned();
+        Ok(())
+    }
+
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn GetSite(&self, riid: *const GUID, ppv: *mut *mut c_void) -> windows::core::Result<()> {
+        if ppv.is_null() {
+            return Err(E_POINTER.into());
+        }
+
+        if riid.is_null() {
+            unsafe {
+                ppv.write(std::ptr::null_mut());
+            }
+
+            return Err(E_POINTER.into());
+        }
+
+        match *self.site.read().unwrap() {
+            Some(ref site) => unsafe { site.query(riid, ppv).ok() },
+            None => {
+                unsafe {
+                    ppv.write(std::ptr::null_mut());
+                }
+                Err(E_FAIL.into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_have_to_share_a_folder() {
+        let paths = [PathBuf::from("C:/Art/a.bmx"), PathBuf::from("C:/Art/b.bmx")];
+        assert_eq!(common_folder(&paths), Ok(PathBuf::from("C:/Art")));
+
+        let paths = [
+            PathBuf::from("C:/Art/a.bmx"),
+            PathBuf::from("C:/Other/b.bmx"),
+        ];
+        assert!(common_folder(&paths).is_err());
+    }
+
+    #[test]
+    fn autoload_loads_into_banked_ram() {
+        assert_eq!(autoload("TILES.BMX"), "BLOAD \"TILES.BMX\",8,1,$A000\n");
+        assert_eq!(autoload("a\"b.bmx"), "BLOAD \"ab.bmx\",8,1,$A000\n");
+    }
+}
@@ -1,68 +1,67 @@
 use std::ffi::c_void;
-use std::fmt::Display;
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::mem::MaybeUninit;
-use std::sync::{Mutex, RwLock};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 #[allow(unused)]
 use windows::core::{implement, ComObject, IUnknownImpl, Interface, GUID, HRESULT};
-use windows::core::{w, Array, IUnknown, HSTRING, PCWSTR, PROPVARIANT, PWSTR};
+use windows::core::{w, IUnknown, HSTRING, PCWSTR, PROPVARIANT, PWSTR};
 use windows::Win32::Foundation::{
-    BOOL, ERROR_ALREADY_INITIALIZED, ERROR_NO_MORE_ITEMS, E_FAIL, E_INVALIDARG, E_NOTIMPL,
-    E_POINTER, E_UNEXPECTED, HWND, S_FALSE, S_OK, WINCODEC_ERR_UNSUPPORTEDOPERATION,
+    BOOL, ERROR_FILE_EXISTS, E_ABORT, E_FAIL, E_INVALIDARG, E_NOTIMPL, E_POINTER, E_UNEXPECTED,
+    HWND, MAX_PATH, S_FALSE, S_OK,
 };
 use windows::Win32::Graphics::Imaging::{
-    IWICBitmapCodecInfo, IWICImagingFactory, WICBitmapEncoderNoCache, WICComponentEnumerateDefault,
-    WICConvertBitmapSource, WICDecodeMetadataCacheOnDemand, WICDecoder, WICEncoder,
+    IWICImagingFactory, IWICPixelFormatInfo, WICDecodeMetadataCacheOnDemand,
 };
 use windows::Win32::Storage::EnhancedStorage::{PKEY_Kind, PKEY_MIMEType};
 use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_NORMAL;
 use windows::Win32::System::Com::StructuredStorage::IPropertyBag;
 use windows::Win32::System::Com::Urlmon::E_PENDING;
 use windows::Win32::System::Com::{
-    CoCreateInstance, CreateBindCtx, IBindCtx, IEnumUnknown, IStream, BIND_OPTS,
-    CLSCTX_INPROC_SERVER, STGM_WRITE,
+    CoCreateInstance, CreateBindCtx, IBindCtx, IStream, BIND_OPTS, CLSCTX_INPROC_SERVER, STGM_WRITE,
 };
 use windows::Win32::System::Diagnostics::Debug::OutputDebugStringW;
-use windows::Win32::System::Ole::{IObjectWithSite, IObjectWithSite_Impl};
+use windows::Win32::System::Ole::{IObjectWithSite, IObjectWithSite_Impl, IOleWindow};
 use windows::Win32::System::Variant::{VT_LPWSTR, VT_VECTOR};
 use windows::Win32::UI::Shell::Common::COMDLG_FILTERSPEC;
-use windows::Win32::UI::Shell::PropertiesSystem::IPropertyStore;
+use windows::Win32::UI::Shell::PropertiesSystem::{IPropertyStore, PDOPS_CANCELLED};
 use windows::Win32::UI::Shell::{
-    BHID_PropertyStore, BHID_Stream, FileOpenDialog, FileOperation, FileSaveDialog,
-    IEnumExplorerCommand, IEnumExplorerCommand_Impl, IExplorerCommand, IExplorerCommand_Impl,
-    IFileDialog, IFileDialogControlEvents, IFileDialogControlEvents_Impl, IFileDialogCustomize,
-    IFileDialogEvents, IFileDialogEvents_Impl, IFileOperation, IFileOperationProgressSink,
-    IFileOperationProgressSink_Impl, IInitializeCommand, IInitializeCommand_Impl, IShellItem,
-    IShellItemArray, IUnknown_GetWindow, SHGetFileInfoW, SHStrDupW, ECF_DEFAULT,
-    ECF_HASSUBCOMMANDS, ECF_ISDROPDOWN, ECS_ENABLED, ECS_HIDDEN, FDE_OVERWRITE_RESPONSE,
-    FDE_SHAREVIOLATION_RESPONSE, FOS_PICKFOLDERS, FOS_STRICTFILETYPES, SHFILEINFOW, SHGFI_TYPENAME,
-    SHGFI_USEFILEATTRIBUTES, SIGDN_PARENTRELATIVEPARSING,
+    BHID_PropertyStore, BHID_Stream, CLSID_ProgressDialog, FileOpenDialog, FileOperation,
+    FileSaveDialog, IEnumExplorerCommand, IEnumExplorerCommand_Impl, IExplorerCommand,
+    IExplorerCommand_Impl, IFileDialog, IFileDialogControlEvents, IFileDialogControlEvents_Impl,
+    IFileDialogCustomize, IFileDialogEvents, IFileDialogEvents_Impl, IFileOperation,
+    IFileOperationProgressSink, IFileOperationProgressSink_Impl, IInitializeCommand,
+    IInitializeCommand_Impl, IOperationsProgressDialog, IShellItem, IShellItemArray,
+    SHChangeNotify, SHCreateItemFromRelativeName, SHGetFileInfoW, SHStrDupW, StrFormatByteSizeEx,
+    COPYENGINE_E_USER_CANCELLED, ECF_DEFAULT, ECF_HASSUBCOMMANDS, ECF_ISDROPDOWN, ECS_ENABLED,
+    ECS_HIDDEN, FDE_OVERWRITE_RESPONSE, FDE_SHAREVIOLATION_RESPONSE, FOF_ALLOWUNDO,
+    FOF_NOCONFIRMATION, FOF_NOCONFIRMMKDIR, FOS_PICKFOLDERS, FOS_STRICTFILETYPES,
+    SFBS_FLAGS_ROUND_TO_NEAREST_DISPLAYED_DIGIT, SHCNE_CREATE, SHCNF_PATHW, SHFILEINFOW,
+    SHGFI_TYPENAME, SHGFI_USEFILEATTRIBUTES, SIGDN_DESKTOPABSOLUTEPARSING, SIGDN_FILESYSPATH,
+    SIGDN_NORMALDISPLAY, SIGDN_PARENTRELATIVEPARSING,
+};
+use windows::Win32::UI::WindowsAndMessaging::{
+    MessageBoxW, IDYES, MB_ICONERROR, MB_ICONINFORMATION, MB_ICONWARNING, MB_YESNO,
 };
-use windows::Win32::UI::WindowsAndMessaging::{MessageBoxW, MB_ICONERROR};
 
+use crate::bmx::{FileHeader, PaletteEntry};
+use crate::com::shell::command::options::Options;
+use crate::com::shell::command::owner::ModalOwner;
+use crate::com::shell::command::ExplorerCommandClass;
 use crate::com::shell::CoTaskMemPWSTR;
+use crate::com::transcode::{TranscodeError, TranscodeOptions, TranscodeRequest, TranscodeStream};
+use crate::com::util::ComState;
+use crate::com::wic::com::CONTAINER_FORMAT;
 use crate::com::wic::{
-    codec_mime_types, create_imaging_factory, get_component_iterator, pixel_format_friendly_name,
-    pixel_format_is_known,
+    bit_depth_to_pixel_format, create_imaging_factory, enumerate_decoders, enumerate_encoders,
+    pixel_format_friendly_name, CodecInfo,
 };
 use crate::com::CoClass;
-use crate::get_with_buffer;
-
-fn pcwstr_is_equal_to_slice_no_case(first: PCWSTR, second: &[u16]) -> bool {
-    unsafe extern "C" {
-        fn _wcsnicmp(a: *const u16, b: *const u16, count: usize) -> i32;
-    }
-
-    unsafe { _wcsnicmp(first.as_ptr(), second.as_ptr(), second.len()) == 0 }
-}
-
-fn pcwstr_is_equal_to_pcwstr_no_case(first: PCWSTR, second: PCWSTR) -> bool {
-    unsafe extern "C" {
-        fn _wcsicmp(a: *const u16, b: *const u16) -> i32;
-    }
-
-    unsafe { _wcsicmp(first.as_ptr(), second.as_ptr()) == 0 }
-}
+use crate::settings;
+use crate::util::{wstr, PerMonitorDpiAwareness};
 
 fn propvariant_to_lpwstr(variant: &PROPVARIANT) -> Option<PWSTR> {
     unsafe {
@@ -99,72 +98,229 @@ fn debug_output<S: AsRef<str>>(s: S) {
     }
 }
 
-fn item_array_has_matching_decoders(
+// Selections beyond this are judged by their first items, so huge selections don't stall the menu.
+const MAX_SCANNED_ITEMS: u32 = 64;
+
+// MIME types of every decoder that produces pixel formats we can convert from.
+fn decoder_mime_types(imaging_factory: &IWICImagingFactory) -> windows::core::Result<Vec<String>> {
+    Ok(enumerate_decoders(imaging_factory)?
+        .into_iter()
+        .filter(|decoder| {
+            if decoder.known_pixel_formats().next().is_none() {
+                debug_output("no known pixel formats for decoder");
+                return false;
+            }
+
+            true
+        })
+        .flat_map(|decoder| decoder.mime_types)
+        .collect())
+}
+
+// The item's MIME type, in lowercase, if one of the decoders can read it.
+fn decodable_mime_type(
+    item: &IShellItem,
+    decoder_mime_types: &[String],
+) -> windows::core::Result<Option<String>> {
+    let properties: IPropertyStore = unsafe { item.BindToHandler(None, &BHID_PropertyStore)? };
+
+    let variant = unsafe { properties.GetValue(&PKEY_Kind)? };
+
+    let Some(kind) = propvariant_to_lpwstr_slice(&variant) else {
+        return Ok(None);
+    };
+
+    if !kind.iter().any(|kind| {
+        wstr::eq_ignore_case(unsafe { kind.as_wide() }, unsafe {
+            w!("picture").as_wide()
+        })
+    }) {
+        debug_output("no picture");
+        return Ok(None);
+    }
+
+    let variant = unsafe { properties.GetValue(&PKEY_MIMEType)? };
+
+    let Some(item_mime_type) = propvariant_to_lpwstr(&variant) else {
+        debug_output("no mime type");
+        return Ok(None);
+    };
+
+    let item_mime_type =
+        String::from_utf16_lossy(unsafe { item_mime_type.as_wide() }).to_lowercase();
+
+    if !decoder_mime_types
+        .iter()
+        .any(|wic_mime_type| wic_mime_type.eq_ignore_ascii_case(&item_mime_type))
+    {
+        return Ok(None);
+    }
+
+    Ok(Some(item_mime_type))
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct SelectionState {
+    // Whether any of the items can be transcoded; the others are skipped.
+    decodable: bool,
+    // Set for a single item, so its own format can be left out of the submenu.
+    source_mime_type: Option<String>,
+}
+
+fn evaluate_selection(
     items: &IShellItemArray,
     imaging_factory: &IWICImagingFactory,
-) -> windows::core::Result<bool> {
+) -> windows::core::Result<SelectionState> {
     let count = unsafe { items.GetCount()? };
+    let decoder_mime_types = decoder_mime_types(imaging_factory)?;
 
-    for i in 0..count {
+    for i in 0..count.min(MAX_SCANNED_ITEMS) {
         let item = unsafe { items.GetItemAt(i)? };
 
-        let properties: IPropertyStore = unsafe { item.BindToHandler(None, &BHID_PropertyStore)? };
+        // Items without a property store, e.g. in some virtual folders, just don't count.
+        if let Ok(Some(mime_type)) = decodable_mime_type(&item, &decoder_mime_types) {
+            debug_output("found decoder");
 
-        let variant = unsafe { properties.GetValue(&PKEY_Kind)? };
+            return Ok(SelectionState {
+                decodable: true,
+                source_mime_type: (count == 1).then_some(mime_type),
+            });
+        }
+    }
 
-        let Some(kind) = propvariant_to_lpwstr_slice(&variant) else {
-            return Ok(false);
-        };
+    Ok(SelectionState {
+        decodable: false,
+        source_mime_type: None,
+    })
+}
 
-        if !kind.iter().any(|kind| {
-            pcwstr_is_equal_to_pcwstr_no_case(PCWSTR::from_raw(kind.as_ptr()), w!("picture"))
-        }) {
-            debug_output("no picture");
-            return Ok(false);
-        }
+// Explorer asks for the state of the command and of every subcommand, each time the menu opens,
+// and every evaluation binds a property store per item. The result is reused for the same
+// selection for a short while instead.
+struct StateCache {
+    selection: u64,
+    evaluated: Instant,
+    state: SelectionState,
+}
 
-        let variant = unsafe { properties.GetValue(&PKEY_MIMEType)? };
+impl StateCache {
+    const TTL: Duration = Duration::from_secs(5);
 
-        let Some(item_mime_type) = propvariant_to_lpwstr(&variant) else {
-            debug_output("no mime type");
-            return Ok(false);
-        };
+    fn get(cache: &Option<Self>, selection: u64, now: Instant) -> Option<SelectionState> {
+        cache
+            .as_ref()
+            .filter(|cache| {
+                cache.selection == selection && now.duration_since(cache.evaluated) < Self::TTL
+            })
+            .map(|cache| cache.state.clone())
+    }
+}
 
-        let item_mime_type = PCWSTR::from_raw(item_mime_type.as_ptr());
+static STATE_CACHE: Mutex<Option<StateCache>> = Mutex::new(None);
 
-        if get_component_iterator::<IWICBitmapCodecInfo>(
-            imaging_factory,
-            WICDecoder,
-            WICComponentEnumerateDefault,
-        )?
-        .filter_map(|result| result.ok())
-        .any(|decoder| {
-            let Ok(pixel_formats) = get_with_buffer!(&decoder, GetPixelFormats) else {
-                debug_output("no pixel formats for decoder");
-                return false;
-            };
+// Hashes the parsing names, as the same selection comes in a new item array every time. Only the
+// items evaluate_selection looks at count, besides how many there are.
+fn selection_key(items: &IShellItemArray) -> windows::core::Result<u64> {
+    let mut hasher = DefaultHasher::new();
 
-            if !pixel_formats.iter().any(pixel_format_is_known) {
-                debug_output("no known pixel formats for decoder");
-                return false;
-            }
+    let count = unsafe { items.GetCount()? };
+    count.hash(&mut hasher);
 
-            let Ok(mime_types) = codec_mime_types(&decoder) else {
-                debug_output("no mime types for decoder");
-                return false;
-            };
-            mime_types
-                .split(|c| *c == b',' as u16)
-                .any(|wic_mime_type| {
-                    pcwstr_is_equal_to_slice_no_case(item_mime_type, wic_mime_type)
-                })
-        }) {
-            debug_output("found decoder");
-            return Ok(true);
+    for i in 0..count.min(MAX_SCANNED_ITEMS) {
+        let name = CoTaskMemPWSTR::new(unsafe {
+            items
+                .GetItemAt(i)?
+                .GetDisplayName(SIGDN_DESKTOPABSOLUTEPARSING)?
+        });
+
+        unsafe { name.as_wide() }.hash(&mut hasher);
+    }
+
+    Ok(hasher.finish())
+}
+
+fn evaluate_selection_cached(
+    items: &IShellItemArray,
+    imaging_factory: &IWICImagingFactory,
+) -> windows::core::Result<SelectionState> {
+    let selection = selection_key(items)?;
+
+    if let Some(state) = StateCache::get(&STATE_CACHE.lock().unwrap(), selection, Instant::now()) {
+        return Ok(state);
+    }
+
+    let state = evaluate_selection(items, imaging_factory)?;
+
+    *STATE_CACHE.lock().unwrap() = Some(StateCache {
+        selection,
+        evaluated: Instant::now(),
+        state: state.clone(),
+    });
+
+    Ok(state)
+}
+
+// Splits the selection into the items that can be transcoded and the names of those that can't.
+// Unlike the menu state, this looks at every item.
+fn partition_decodable(
+    items: &IShellItemArray,
+    imaging_factory: &IWICImagingFactory,
+) -> windows::core::Result<(Vec<IShellItem>, Vec<String>)> {
+    let decoder_mime_types = decoder_mime_types(imaging_factory)?;
+
+    let mut decodable = Vec::new();
+    let mut skipped = Vec::new();
+
+    for i in 0..unsafe { items.GetCount()? } {
+        let item = unsafe { items.GetItemAt(i)? };
+
+        if let Ok(Some(_)) = decodable_mime_type(&item, &decoder_mime_types) {
+            decodable.push(item);
+        } else {
+            let name = CoTaskMemPWSTR::new(unsafe { item.GetDisplayName(SIGDN_NORMALDISPLAY)? });
+            skipped.push(unsafe { name.to_string() }.unwrap_or_default());
         }
     }
 
-    Ok(false)
+    Ok((decodable, skipped))
+}
+
+fn skipped_summary(skipped: &[String]) -> String {
+    let mut summary = if skipped.len() == 1 {
+        "1 item was skipped because it is not a supported image:\n".to_owned()
+    } else {
+        format!(
+            "{} items were skipped because they are not supported images:\n",
+            skipped.len()
+        )
+    };
+
+    list_items(&mut summary, skipped);
+    summary
+}
+
+fn failed_summary(failed: &[String]) -> String {
+    let mut summary = if failed.len() == 1 {
+        "1 item could not be transcoded:\n".to_owned()
+    } else {
+        format!("{} items could not be transcoded:\n", failed.len())
+    };
+
+    list_items(&mut summary, failed);
+    summary
+}
+
+fn list_items(summary: &mut String, items: &[String]) {
+    const MAX_LISTED: usize = 10;
+
+    for item in items.iter().take(MAX_LISTED) {
+        summary.push('\n');
+        summary.push_str(item);
+    }
+
+    if items.len() > MAX_LISTED {
+        summary.push_str(&format!("\n… and {} more", items.len() - MAX_LISTED));
+    }
 }
 
 struct TranscodeData {
@@ -173,20 +329,18 @@ struct TranscodeData {
     #[allow(unused)]
     properties: IPropertyBag,
     imaging_factory: IWICImagingFactory,
-    site: Option<IUnknown>,
+    site: RwLock<Option<IUnknown>>,
 }
 
 #[derive(Default)]
 #[implement(IExplorerCommand, IInitializeCommand, IObjectWithSite)]
 pub struct Transcode {
-    inner: RwLock<Option<TranscodeData>>,
+    inner: ComState<TranscodeData>,
 }
 
 impl Transcode {
     pub fn new() -> Self {
-        Self {
-            inner: RwLock::new(None),
-        }
+        Self::default()
     }
 }
 
@@ -194,6 +348,12 @@ impl CoClass for Transcode {
     const CLSID: GUID = GUID::from_u128(0xbe8b5162_693a_4d66_9efb_01ea923c1f4du128);
     const PROG_ID: PCWSTR = w!("X16BMX.Transcode.1");
     const VERSION_INDEPENDENT_PROG_ID: PCWSTR = w!("X16BMX.Transcode");
+    const FRIENDLY_NAME: PCWSTR = w!("Transcode");
+}
+
+impl ExplorerCommandClass for Transcode {
+    const FILE_TYPE: PCWSTR = w!("*");
+    const VERB: PCWSTR = w!("Transcode");
 }
 
 impl IExplorerCommand_Impl for Transcode_Impl {
@@ -224,10 +384,9 @@ impl IExplorerCommand_Impl for Transcode_Impl {
             return Err(E_PENDING.into());
         }
 
-        let inner = self.inner.read().unwrap();
-        let inner = inner.as_ref().ok_or(E_UNEXPECTED)?;
+        let inner = self.inner.get()?;
 
-        if item_array_has_matching_decoders(items, &inner.imaging_factory)? {
+        if evaluate_selection_cached(items, &inner.imaging_factory)?.decodable {
             Ok(ECS_ENABLED.0 as _)
         } else {
             Ok(ECS_HIDDEN.0 as _)
@@ -247,8 +406,7 @@ impl IExplorerCommand_Impl for Transcode_Impl {
     }
 
     fn EnumSubCommands(&self) -> windows::core::Result<IEnumExplorerCommand> {
-        let inner = self.inner.read().unwrap();
-        let inner = inner.as_ref().ok_or(E_UNEXPECTED)?;
+        let inner = self.inner.get()?;
 
         Ok(ComObject::new(TranscodeEnumSubcommands::new(&inner.imaging_factory)?).to_interface())
     }
@@ -260,18 +418,14 @@ impl IInitializeCommand_Impl for Transcode_Impl {
         command_name: &windows::core::PCWSTR,
         property_bag: Option<&IPropertyBag>,
     ) -> windows::core::Result<()> {
-        let mut inner = self.inner.write().unwrap();
+        self.inner.ensure_uninitialized()?;
 
-        if inner.is_some() {
-            return Err(HRESULT::from_win32(ERROR_ALREADY_INITIALIZED.0).into());
-        }
-
-        inner.replace(TranscodeData {
+        self.inner.initialize(TranscodeData {
             command_name: unsafe { command_name.to_string().map_err(|_| E_INVALIDARG)? },
             properties: property_bag.ok_or(E_POINTER)?.clone(),
             imaging_factory: create_imaging_factory()?,
-            site: None,
-        });
+            site: RwLock::new(None),
+        })?;
 
         Ok(())
     }
@@ -279,9 +433,7 @@ impl IInitializeCommand_Impl for Transcode_Impl {
 
 impl IObjectWithSite_Impl for Transcode_Impl {
     fn SetSite(&self, site: Option<&IUnknown>) -> windows::core::Result<()> {
-        let mut inner = self.inner.write().unwrap();
-        let inner = inner.as_mut().ok_or(E_UNEXPECTED)?;
-        inner.site = site.cloned();
+        *self.inner.get()?.site.write().unwrap() = site.cloned();
         Ok(())
     }
 
@@ -299,10 +451,7 @@ impl IObjectWithSite_Impl for Transcode_Impl {
             return Err(E_POINTER.into());
         }
 
-        let inner = self.inner.read().unwrap();
-        let inner = inner.as_ref().ok_or(E_UNEXPECTED)?;
-
-        match inner.site {
+        match *self.inner.get()?.site.read().unwrap() {
             Some(ref site) => unsafe { site.query(riid, ppv).ok() },
             None => {
                 unsafe {
@@ -314,9 +463,54 @@ impl IObjectWithSite_Impl for Transcode_Impl {
     }
 }
 
+// Encoders that can write at least one pixel format we know, one per container format, sorted by
+// friendly name.
+fn submenu_encoders(imaging_factory: &IWICImagingFactory) -> windows::core::Result<Vec<CodecInfo>> {
+    let mut encoders = enumerate_encoders(imaging_factory)?
+        .into_iter()
+        .filter(|encoder| encoder.known_pixel_formats().next().is_some())
+        .collect::<Vec<_>>();
+
+    encoders.sort_by_cached_key(|encoder| encoder.friendly_name.to_lowercase());
+
+    let mut container_formats = Vec::new();
+    encoders.retain(|encoder| {
+        if container_formats.contains(&encoder.container) {
+            false
+        } else {
+            container_formats.push(encoder.container);
+            true
+        }
+    });
+
+    Ok(encoders)
+}
+
+#[derive(Clone)]
 struct TranscodeEnumSubcommandsData {
     imaging_factory: IWICImagingFactory,
-    enumerator: IEnumUnknown,
+    encoders: Vec<CodecInfo>,
+    // Index of the next command; Options... comes after the last encoder.
+    position: usize,
+}
+
+impl TranscodeEnumSubcommandsData {
+    fn command_count(&self) -> usize {
+        self.encoders.len() + 1
+    }
+
+    fn command(&self, index: usize) -> Option<IExplorerCommand> {
+        match self.encoders.get(index) {
+            Some(codec_info) => Some(
+                ComObject::new(TranscodeSubcommand::new(&self.imaging_factory, codec_info))
+                    .to_interface(),
+            ),
+            None if index == self.encoders.len() => {
+                Some(ComObject::new(Options::new()).to_interface())
+            }
+            None => None,
+        }
+    }
 }
 
 #[implement(IEnumExplorerCommand)]
@@ -326,15 +520,11 @@ struct TranscodeEnumSubcommands {
 
 impl TranscodeEnumSubcommands {
     pub fn new(imaging_factory: &IWICImagingFactory) -> windows::core::Result<Self> {
-        let enumerator = unsafe {
-            imaging_factory
-                .CreateComponentEnumerator(WICEncoder.0 as _, WICComponentEnumerateDefault.0 as _)?
-        };
-
         Ok(Self {
             inner: Mutex::new(TranscodeEnumSubcommandsData {
                 imaging_factory: imaging_factory.clone(),
-                enumerator,
+                encoders: submenu_encoders(imaging_factory)?,
+                position: 0,
             }),
         })
     }
@@ -344,17 +534,14 @@ impl IEnumExplorerCommand_Impl for TranscodeEnumSubcommands_Impl {
     fn Clone(&self) -> windows::core::Result<IEnumExplorerCommand> {
         let inner = self.inner.lock().unwrap();
         Ok(ComObject::new(TranscodeEnumSubcommands {
-            inner: Mutex::new(TranscodeEnumSubcommandsData {
-                imaging_factory: inner.imaging_factory.clone(),
-                enumerator: unsafe { inner.enumerator.Clone()? },
-            }),
+            inner: Mutex::new(inner.clone()),
         })
         .to_interface())
     }
 
     fn Next(
         &self,
-        mut count: u32,
+        count: u32,
         mut commands: *mut Option<IExplorerCommand>,
         fetched: *mut u32,
     ) -> windows::core::HRESULT {
@@ -372,57 +559,23 @@ impl IEnumExplorerCommand_Impl for TranscodeEnumSubcommands_Impl {
             return E_POINTER;
         }
 
-        let inner = self.inner.lock().unwrap();
+        let mut inner = self.inner.lock().unwrap();
 
         let mut total_count = 0;
 
-        let result = loop {
-            let mut buffer = [const { None }; 20];
-            let mut inner_fetched = 0;
-
-            let result = unsafe {
-                inner.enumerator.Next(
-                    &mut buffer[..count.min(20) as _],
-                    Some(&raw mut inner_fetched),
-                )
+        while total_count < count {
+            let Some(command) = inner.command(inner.position) else {
+                break;
             };
 
-            if result.is_err() {
-                break result;
-            }
-
-            let element_count = inner_fetched as usize;
-
-            for command in &buffer[..element_count as _] {
-                let Some(command) = command else {
-                    inner_fetched -= 1;
-                    continue;
-                };
-
-                let Ok(codec_info) = command.cast::<IWICBitmapCodecInfo>() else {
-                    inner_fetched -= 1;
-                    continue;
-                };
-
-                let command = ComObject::new(TranscodeSubcommand::new(
-                    &inner.imaging_factory,
-                    &codec_info,
-                ))
-                .to_interface();
-
-                unsafe {
-                    commands.write(Some(command));
-                    commands = commands.add(1);
-                }
+            unsafe {
+                commands.write(Some(command));
+                commands = commands.add(1);
             }
 
-            total_count += inner_fetched;
-            count -= inner_fetched;
-
-            if result == S_FALSE || count == 0 {
-                break result;
-            }
-        };
+            inner.position += 1;
+            total_count += 1;
+        }
 
         if !fetched.is_null() {
             unsafe {
@@ -430,17 +583,29 @@ impl IEnumExplorerCommand_Impl for TranscodeEnumSubcommands_Impl {
             }
         }
 
-        result
+        if total_count == count {
+            S_OK
+        } else {
+            S_FALSE
+        }
     }
 
     fn Reset(&self) -> windows::core::Result<()> {
-        let inner = self.inner.lock().unwrap();
-        unsafe { inner.enumerator.Reset() }
+        self.inner.lock().unwrap().position = 0;
+        Ok(())
     }
 
     fn Skip(&self, count: u32) -> windows::core::Result<()> {
-        let inner = self.inner.lock().unwrap();
-        unsafe { inner.enumerator.Skip(count) }
+        let mut inner = self.inner.lock().unwrap();
+        let position = inner.position.saturating_add(count as usize);
+
+        inner.position = position.min(inner.command_count());
+
+        if position > inner.command_count() {
+            Err(S_FALSE.into())
+        } else {
+            Ok(())
+        }
     }
 }
 
@@ -448,80 +613,149 @@ impl IEnumExplorerCommand_Impl for TranscodeEnumSubcommands_Impl {
 struct TranscodeSubcommandData {
     properties: Option<IPropertyBag>,
     imaging_factory: IWICImagingFactory,
-    codec_info: IWICBitmapCodecInfo,
-    site: Option<IUnknown>,
+    codec_info: CodecInfo,
+    site: RwLock<Option<IUnknown>>,
 }
 
 #[derive(Default)]
 #[implement(IExplorerCommand, IInitializeCommand, IObjectWithSite)]
 struct TranscodeSubcommand {
-    inner: RwLock<Option<TranscodeSubcommandData>>,
+    inner: ComState<TranscodeSubcommandData>,
 }
 
 impl TranscodeSubcommand {
-    pub fn new(imaging_factory: &IWICImagingFactory, codec_info: &IWICBitmapCodecInfo) -> Self {
+    pub fn new(imaging_factory: &IWICImagingFactory, codec_info: &CodecInfo) -> Self {
         Self {
-            inner: RwLock::new(Some(TranscodeSubcommandData {
+            inner: ComState::from(TranscodeSubcommandData {
                 properties: None,
                 imaging_factory: imaging_factory.clone(),
                 codec_info: codec_info.clone(),
-                site: None,
-            })),
+                site: RwLock::new(None),
+            }),
         }
     }
 
-    fn item_name_without_extension(item: &IShellItem) -> windows::core::Result<CoTaskMemPWSTR> {
+    fn item_name_without_extension(item: &IShellItem) -> windows::core::Result<Vec<u16>> {
+        let file_name =
+            CoTaskMemPWSTR::new(unsafe { item.GetDisplayName(SIGDN_PARENTRELATIVEPARSING)? });
+
+        Ok(wstr::file_stem(unsafe { file_name.as_wide() }).to_vec())
+    }
+
+    fn default_extension(codec_info: &CodecInfo) -> windows::core::Result<Vec<u16>> {
+        codec_info
+            .default_extension()
+            .map(|extension| extension.encode_utf16().collect())
+            .ok_or(E_UNEXPECTED.into())
+    }
+
+    // Null-terminated name of the file a batch transcode creates for `item`.
+    fn batch_file_name(item: &IShellItem, extension: &[u16]) -> windows::core::Result<Vec<u16>> {
+        let file_name =
+            CoTaskMemPWSTR::new(unsafe { item.GetDisplayName(SIGDN_PARENTRELATIVEPARSING)? });
+
+        Ok(wstr::with_nul(&wstr::replace_extension(
+            unsafe { file_name.as_wide() },
+            extension,
+        )))
+    }
+
+    // The file operation drives the progress dialog; we only poll it for cancellation. None if
+    // there is no copy engine to create it, in which case files are written directly.
+    fn create_file_operation(
+        owner_window: HWND,
+        overwrite: bool,
+    ) -> windows::core::Result<Option<(IFileOperation, CancellationToken)>> {
+        let Ok(operation) = (unsafe {
+            CoCreateInstance::<_, IFileOperation>(&FileOperation, None, CLSCTX_INPROC_SERVER)
+        }) else {
+            return Ok(None);
+        };
+
         unsafe {
-            let file_name = CoTaskMemPWSTR::new(item.GetDisplayName(SIGDN_PARENTRELATIVEPARSING)?);
+            operation.SetOwnerWindow(owner_window)?;
 
-            for i in (0..file_name.len()).rev() {
-                if *file_name.as_ptr().add(i) == b'.' as u16 {
-                    *file_name.as_ptr().add(i) = 0;
-                    break;
-                }
+            // The user already confirmed replacing existing files in the save dialog.
+            if overwrite {
+                operation
+                    .SetOperationFlags(FOF_ALLOWUNDO | FOF_NOCONFIRMMKDIR | FOF_NOCONFIRMATION)?;
             }
-            Ok(file_name)
+        }
+
+        let progress_dialog: Option<IOperationsProgressDialog> =
+            unsafe { CoCreateInstance(&CLSID_ProgressDialog, None, CLSCTX_INPROC_SERVER) }.ok();
+
+        if let Some(ref progress_dialog) = progress_dialog {
+            unsafe { operation.SetProgressDialog(progress_dialog)? };
+        }
+
+        Ok(Some((operation, CancellationToken::new(progress_dialog))))
+    }
+
+    fn show_error(owner_window: HWND, message: String) {
+        unsafe {
+            MessageBoxW(
+                owner_window,
+                PCWSTR::from_raw(HSTRING::from(message).as_ptr()),
+                w!("Transcoding Error"),
+                MB_ICONERROR,
+            );
         }
     }
 
     fn transcode_items(
         imaging_factory: &IWICImagingFactory,
-        items: &IShellItemArray,
+        items: &[IShellItem],
         result: SaveDialogResult,
         container_format: &GUID,
-        codec_info: &IWICBitmapCodecInfo,
+        codec_info: &CodecInfo,
         owner_window: HWND,
     ) -> windows::core::Result<()> {
-        let operation: IFileOperation =
-            unsafe { CoCreateInstance(&FileOperation, None, CLSCTX_INPROC_SERVER)? };
+        let extension = TranscodeSubcommand::default_extension(codec_info)?;
 
-        unsafe {
-            operation.SetOwnerWindow(owner_window)?;
-        }
+        let Some((operation, cancellation)) =
+            TranscodeSubcommand::create_file_operation(owner_window, result.overwrite)?
+        else {
+            // One bad item doesn't stop the rest, as with the file operation.
+            let mut failed = Vec::new();
+
+            for item in items {
+                let new_filename = TranscodeSubcommand::batch_file_name(item, &extension)?;
 
-        for i in 0..unsafe { items.GetCount()? } {
-            let item = unsafe { items.GetItemAt(i)? };
+                if let Err(err) = transcode_direct(
+                    imaging_factory,
+                    item,
+                    &result.item,
+                    &new_filename,
+                    container_format,
+                    &result,
+                ) {
+                    failed.push(format!(
+                        "{}: {}",
+                        String::from_utf16_lossy(wstr::until_nul(&new_filename)),
+                        err
+                    ));
+                }
+            }
+
+            if !failed.is_empty() {
+                TranscodeSubcommand::show_error(owner_window, failed_summary(&failed));
+            }
+
+            return Ok(());
+        };
 
+        for item in items {
             let operation_sink = ComObject::new(TranscodeOperation::new(
                 imaging_factory,
-                &item,
+                item,
                 container_format,
                 &result.pixel_format,
+                result.bit_depth,
+                &cancellation,
             ));
 
-            let extensions = get_with_buffer!(codec_info, GetFileExtensions)?;
-
-            let extension = extensions
-                .split(|c| *c == b',' as u16)
-                .next()
-                .ok_or(E_UNEXPECTED)?;
-
-            let new_filename = [
-                unsafe { TranscodeSubcommand::item_name_without_extension(&item)?.as_wide() },
-                extension,
-                std::slice::from_ref(&0u16),
-            ]
-            .concat();
+            let new_filename = TranscodeSubcommand::batch_file_name(item, &extension)?;
 
             unsafe {
                 operation.NewItem(
@@ -533,8 +767,10 @@ impl TranscodeSubcommand {
                 )?;
             }
         }
-        unsafe { operation.PerformOperations()? };
-        Ok(())
+        match unsafe { operation.PerformOperations() } {
+            Err(_) if cancellation.is_cancelled() => Ok(()),
+            result => result,
+        }
     }
 
     fn transcode_item(
@@ -544,65 +780,64 @@ impl TranscodeSubcommand {
         container_format: &GUID,
         owner_window: HWND,
     ) -> windows::core::Result<()> {
-        let operation: IFileOperation =
-            unsafe { CoCreateInstance(&FileOperation, None, CLSCTX_INPROC_SERVER)? };
+        let filename = CoTaskMemPWSTR::new(unsafe {
+            result.item.GetDisplayName(SIGDN_PARENTRELATIVEPARSING)?
+        });
 
-        unsafe {
-            operation.SetOwnerWindow(owner_window)?;
-        }
+        let filename = wstr::concat_with_nul(&[
+            unsafe { filename.as_wide() },
+            result.extension.as_deref().unwrap_or_default(),
+        ]);
+
+        let folder = unsafe { result.item.GetParent()? };
+
+        let Some((operation, cancellation)) =
+            TranscodeSubcommand::create_file_operation(owner_window, result.overwrite)?
+        else {
+            return transcode_direct(
+                imaging_factory,
+                item,
+                &folder,
+                &filename,
+                container_format,
+                &result,
+            )
+            .inspect_err(|err| TranscodeSubcommand::show_error(owner_window, err.to_string()))
+            .map_err(Into::into);
+        };
 
         let operation_sink = ComObject::new(TranscodeOperation::new(
             imaging_factory,
             item,
             container_format,
             &result.pixel_format,
+            result.bit_depth,
+            &cancellation,
         ));
 
-        enum Filename {
-            WithoutExtension(CoTaskMemPWSTR),
-            WithExtension(Vec<u16>),
-        }
-
-        let filename = CoTaskMemPWSTR::new(unsafe {
-            result.item.GetDisplayName(SIGDN_PARENTRELATIVEPARSING)?
-        });
-
-        let filename = match result.extension {
-            Some(extension) => Filename::WithExtension(
-                [
-                    unsafe { filename.as_wide() },
-                    &extension,
-                    std::slice::from_ref(&0u16),
-                ]
-                .concat(),
-            ),
-            None => Filename::WithoutExtension(filename),
-        };
-
         unsafe {
             operation.NewItem(
-                &result.item.GetParent()?,
+                &folder,
                 FILE_ATTRIBUTE_NORMAL.0,
-                PCWSTR::from_raw(match filename {
-                    Filename::WithoutExtension(filename) => filename.as_ptr(),
-                    Filename::WithExtension(filename) => filename.as_ptr(),
-                }),
+                PCWSTR::from_raw(filename.as_ptr()),
                 None,
                 Some(&operation_sink.to_interface()),
             )?;
         }
 
-        unsafe { operation.PerformOperations() }.inspect_err(|err| unsafe {
-            let message = operation_sink
-                .error_message()
-                .unwrap_or_else(|| err.message());
+        let result = unsafe { operation.PerformOperations() };
 
-            MessageBoxW(
+        if result.is_err() && cancellation.is_cancelled() {
+            return Ok(());
+        }
+
+        result.inspect_err(|err| {
+            TranscodeSubcommand::show_error(
                 owner_window,
-                PCWSTR::from_raw(HSTRING::from(message).as_ptr()),
-                w!("Transcoding Error"),
-                MB_ICONERROR,
-            );
+                operation_sink
+                    .error_message()
+                    .unwrap_or_else(|| err.message()),
+            )
         })?;
 
         Ok(())
@@ -613,25 +848,16 @@ impl CoClass for TranscodeSubcommand {
     const CLSID: GUID = GUID::from_u128(0xa30460cf_027e_4157_ba2e_e49840b5e851u128);
     const PROG_ID: PCWSTR = w!("X16BMX.TranscodeSubcommand.1");
     const VERSION_INDEPENDENT_PROG_ID: PCWSTR = w!("X16BMX.TranscodeSubcommand");
+    const FRIENDLY_NAME: PCWSTR = w!("Transcode Subcommand");
 }
 
 impl IExplorerCommand_Impl for TranscodeSubcommand_Impl {
     fn GetTitle(&self, _items: Option<&IShellItemArray>) -> windows::core::Result<PWSTR> {
-        let inner = self.inner.read().unwrap();
-        let inner = inner.as_ref().ok_or(E_UNEXPECTED)?;
-
-        unsafe {
-            let mut actual = 0;
-            _ = inner.codec_info.GetFriendlyName(&mut [], &raw mut actual);
-
-            let mut buffer: Array<u16> = Array::with_len(actual as _);
+        let inner = self.inner.get()?;
 
-            inner
-                .codec_info
-                .GetFriendlyName(&mut buffer, &raw mut actual)?;
+        let name = wstr::encode_with_nul(&inner.codec_info.friendly_name);
 
-            Ok(PWSTR::from_raw(buffer.into_abi().0))
-        }
+        unsafe { SHStrDupW(PCWSTR::from_raw(name.as_ptr())) }
     }
 
     fn GetIcon(&self, _items: Option<&IShellItemArray>) -> windows::core::Result<PWSTR> {
@@ -657,10 +883,16 @@ impl IExplorerCommand_Impl for TranscodeSubcommand_Impl {
             return Err(E_PENDING.into());
         }
 
-        let inner = self.inner.read().unwrap();
-        let inner = inner.as_ref().ok_or(E_UNEXPECTED)?;
+        let inner = self.inner.get()?;
+
+        let state = evaluate_selection_cached(items, &inner.imaging_factory)?;
 
-        if item_array_has_matching_decoders(items, &inner.imaging_factory)? {
+        let own_format = settings::hide_source_format()
+            && state
+                .source_mime_type
+                .is_some_and(|mime_type| inner.codec_info.has_mime_type(&mime_type));
+
+        if state.decodable && !own_format {
             Ok(ECS_ENABLED.0 as _)
         } else {
             Ok(ECS_HIDDEN.0 as _)
@@ -674,10 +906,15 @@ impl IExplorerCommand_Impl for TranscodeSubcommand_Impl {
     ) -> windows::core::Result<()> {
         let items = items.ok_or(E_POINTER)?;
 
-        let inner = self.inner.read().unwrap();
-        let inner = inner.as_ref().ok_or(E_UNEXPECTED)?;
+        let inner = self.inner.get()?;
+
+        let (items, skipped) = partition_decodable(items, &inner.imaging_factory)?;
 
-        let one_item = unsafe { items.GetCount()? } == 1;
+        if items.is_empty() {
+            return Ok(());
+        }
+
+        let one_item = items.len() == 1;
 
         let mode = if one_item {
             SaveDialogMode::File
@@ -685,42 +922,57 @@ impl IExplorerCommand_Impl for TranscodeSubcommand_Impl {
             SaveDialogMode::Folder
         };
 
-        let file_name = if one_item {
-            TranscodeSubcommand::item_name_without_extension(&unsafe { items.GetItemAt(0)? })?
+        let file_name = wstr::with_nul(&if one_item {
+            TranscodeSubcommand::item_name_without_extension(&items[0])?
         } else {
-            CoTaskMemPWSTR::null()
-        };
-
-        let default_folder = unsafe { items.GetItemAt(0)?.GetParent()? };
+            Vec::new()
+        });
 
-        let file_extensions = get_with_buffer!(&inner.codec_info, GetFileExtensions)?;
+        let default_folder = unsafe { items[0].GetParent()? };
 
-        let known_pixel_formats = get_with_buffer!(&inner.codec_info, GetPixelFormats)?
-            .into_iter()
-            .filter(pixel_format_is_known)
+        let known_pixel_formats = inner
+            .codec_info
+            .known_pixel_formats()
+            .copied()
             .collect::<Vec<_>>();
 
+        let container_format = inner.codec_info.container;
+
+        let size_estimator =
+            OutputSizeEstimator::new(&inner.imaging_factory, &items, &container_format)?;
+
+        let batch_file_names = match mode {
+            SaveDialogMode::Folder => {
+                let extension = TranscodeSubcommand::default_extension(&inner.codec_info)?;
+
+                items
+                    .iter()
+                    .map(|item| TranscodeSubcommand::batch_file_name(item, &extension))
+                    .collect::<windows::core::Result<Vec<_>>>()?
+            }
+            SaveDialogMode::File => Vec::new(),
+        };
+
+        let owner = ModalOwner::new(inner.site.read().unwrap().as_ref());
+        let owner_window = owner.window();
+
         let dialog = ComObject::new(SaveDialog::new());
 
         let result = dialog.show(
+            owner_window,
             PCWSTR::from_raw(file_name.as_ptr()),
             mode,
             Some(default_folder),
-            file_extensions,
+            &inner.codec_info.extensions,
             known_pixel_formats,
+            size_estimator,
+            batch_file_names,
         )?;
 
-        let container_format = unsafe { inner.codec_info.GetContainerFormat()? };
-
-        let owner_window = match inner.site {
-            Some(ref site) => unsafe { IUnknown_GetWindow(site).unwrap_or(HWND::default()) },
-            None => HWND::default(),
-        };
-
         match mode {
             SaveDialogMode::Folder => TranscodeSubcommand::transcode_items(
                 &inner.imaging_factory,
-                items,
+                &items,
                 result,
                 &container_format,
                 &inner.codec_info,
@@ -728,13 +980,24 @@ impl IExplorerCommand_Impl for TranscodeSubcommand_Impl {
             )?,
             SaveDialogMode::File => TranscodeSubcommand::transcode_item(
                 &inner.imaging_factory,
-                unsafe { &items.GetItemAt(0)? },
+                &items[0],
                 result,
                 &container_format,
                 owner_window,
             )?,
         }
 
+        if !skipped.is_empty() {
+            unsafe {
+                MessageBoxW(
+                    owner_window,
+                    &HSTRING::from(skipped_summary(&skipped)),
+                    w!("Transcode"),
+                    MB_ICONINFORMATION,
+                );
+            }
+        }
+
         Ok(())
     }
 
@@ -759,9 +1022,7 @@ impl IInitializeCommand_Impl for TranscodeSubcommand_Impl {
 
 impl IObjectWithSite_Impl for TranscodeSubcommand_Impl {
     fn SetSite(&self, site: Option<&IUnknown>) -> windows::core::Result<()> {
-        let mut inner = self.inner.write().unwrap();
-        let inner = inner.as_mut().ok_or(E_UNEXPECTED)?;
-        inner.site = site.cloned();
+        *self.inner.get()?.site.write().unwrap() = site.cloned();
         Ok(())
     }
 
@@ -779,10 +1040,7 @@ impl IObjectWithSite_Impl for TranscodeSubcommand_Impl {
             return Err(E_POINTER.into());
         }
 
-        let inner = self.inner.read().unwrap();
-        let inner = inner.as_ref().ok_or(E_UNEXPECTED)?;
-
-        match inner.site {
+        match *self.inner.get()?.site.read().unwrap() {
             Some(ref site) => unsafe { site.query(riid, ppv).ok() },
             None => {
                 unsafe {
@@ -805,36 +1063,249 @@ struct SaveDialogResult {
     pub pixel_format: GUID,
     pub item: IShellItem,
     pub extension: Option<Vec<u16>>,
+    pub bit_depth: Option<u8>,
+    pub overwrite: bool,
+}
+
+// Estimates the size of the transcoded output from the dimensions of all source frames. BMX files
+// are estimated exactly (uncompressed), other containers by their raw pixel data.
+struct OutputSizeEstimator {
+    imaging_factory: IWICImagingFactory,
+    container_format: GUID,
+    frame_sizes: Vec<(u32, u32)>,
+}
+
+impl OutputSizeEstimator {
+    pub fn new(
+        imaging_factory: &IWICImagingFactory,
+        items: &[IShellItem],
+        container_format: &GUID,
+    ) -> windows::core::Result<Self> {
+        let mut frame_sizes = Vec::new();
+
+        for item in items {
+            // Sources we can't decode here fail later with a proper error, so just skip them.
+            let Ok(decoder) = (unsafe {
+                item.BindToHandler::<_, IStream>(None, &BHID_Stream)
+                    .and_then(|stream| {
+                        imaging_factory.CreateDecoderFromStream(
+                            &stream,
+                            std::ptr::null(),
+                            WICDecodeMetadataCacheOnDemand,
+                        )
+                    })
+            }) else {
+                continue;
+            };
+
+            for j in 0..unsafe { decoder.GetFrameCount()? } {
+                let frame = unsafe { decoder.GetFrame(j)? };
+
+                let mut width = 0;
+                let mut height = 0;
+                unsafe { frame.GetSize(&raw mut width, &raw mut height)? };
+                frame_sizes.push((width, height));
+            }
+        }
+
+        Ok(Self {
+            imaging_factory: imaging_factory.clone(),
+            container_format: *container_format,
+            frame_sizes,
+        })
+    }
+
+    fn bits_per_pixel(&self, pixel_format: &GUID) -> Option<u32> {
+        let info: IWICPixelFormatInfo = unsafe {
+            self.imaging_factory
+                .CreateComponentInfo(pixel_format)
+                .ok()?
+                .cast()
+                .ok()?
+        };
+
+        unsafe { info.GetBitsPerPixel() }.ok()
+    }
+
+    pub fn estimate(&self, pixel_format: &GUID) -> Option<u64> {
+        let bits_per_pixel = self.bits_per_pixel(pixel_format)? as u64;
+
+        let size = if self.container_format == CONTAINER_FORMAT {
+            // The encoder quantizes everything deeper than 8 bits to a full palette.
+            let bit_depth = bits_per_pixel.min(8);
+
+            self.frame_sizes
+                .iter()
+                .map(|&(width, height)| {
+                    FileHeader::SIZE as u64
+                        + (1 << bit_depth) * PaletteEntry::SIZE as u64
+                        + (width as u64 * bit_depth).div_ceil(8) * height as u64
+                })
+                .sum()
+        } else {
+            self.frame_sizes
+                .iter()
+                .map(|&(width, height)| (width as u64 * bits_per_pixel).div_ceil(8) * height as u64)
+                .sum()
+        };
+
+        Some(size)
+    }
+
+    pub fn label(&self, pixel_format: &GUID) -> HSTRING {
+        let Some(size) = self.estimate(pixel_format) else {
+            return HSTRING::from("Estimated size: unknown");
+        };
+
+        let mut buffer = [0u16; 32];
+
+        match unsafe {
+            StrFormatByteSizeEx(
+                size,
+                SFBS_FLAGS_ROUND_TO_NEAREST_DISPLAYED_DIGIT,
+                &mut buffer,
+            )
+        } {
+            Ok(()) => {
+                let len = buffer.iter().position(|c| *c == 0).unwrap_or(buffer.len());
+                HSTRING::from(format!(
+                    "Estimated size: {}",
+                    String::from_utf16_lossy(&buffer[..len])
+                ))
+            }
+            Err(_) => HSTRING::from(format!("Estimated size: {} bytes", size)),
+        }
+    }
 }
 
-#[expect(unused)]
 struct SaveDialogData {
     mode: SaveDialogMode,
     extensions: Option<Vec<Vec<u16>>>,
     pixel_formats: Vec<GUID>,
     selected_item: u32,
+    // Only present when targeting BMX.
+    bit_depth: Option<u8>,
+    size_estimator: OutputSizeEstimator,
+    // Null-terminated names of the files created in folder mode.
+    batch_file_names: Vec<Vec<u16>>,
+    overwrite: bool,
+}
+
+impl SaveDialogData {
+    fn selected_extension(&self, dialog: &IFileDialog) -> windows::core::Result<Option<Vec<u16>>> {
+        Ok(match self.extensions {
+            Some(ref extensions) => extensions
+                .get(unsafe { dialog.GetFileTypeIndex()? } as usize)
+                .cloned(),
+            None => None,
+        })
+    }
+
+    fn effective_pixel_format(&self) -> GUID {
+        self.bit_depth
+            .and_then(bit_depth_to_pixel_format)
+            .unwrap_or(self.pixel_formats[self.selected_item as usize])
+    }
 }
 
 #[implement(IFileDialogEvents, IFileDialogControlEvents)]
 struct SaveDialog {
-    inner: Mutex<Option<SaveDialogData>>,
+    inner: ComState<Mutex<SaveDialogData>>,
 }
 
 impl SaveDialog {
     const COMBO_BOX_GROUP_CONTROL_ID: u32 = u32::from_le_bytes(*b"BMX\0");
     const COMBO_BOX_CONTROL_ID: u32 = SaveDialog::COMBO_BOX_GROUP_CONTROL_ID + 1;
+    const ESTIMATED_SIZE_CONTROL_ID: u32 = SaveDialog::COMBO_BOX_CONTROL_ID + 1;
+    const BIT_DEPTH_GROUP_CONTROL_ID: u32 = SaveDialog::ESTIMATED_SIZE_CONTROL_ID + 1;
+    const BIT_DEPTH_CONTROL_ID: u32 = SaveDialog::BIT_DEPTH_GROUP_CONTROL_ID + 1;
+
+    const BIT_DEPTHS: [(u8, PCWSTR); 4] = [
+        (8, w!("256 colors (8bpp)")),
+        (4, w!("16 colors (4bpp)")),
+        (2, w!("4 colors (2bpp)")),
+        (1, w!("2 colors (1bpp)")),
+    ];
 
     pub fn new() -> Self {
         Self {
-            inner: Mutex::new(None),
+            inner: ComState::default(),
         }
     }
 
-    fn do_show(&self, dialog: &IFileDialog) -> windows::core::Result<SaveDialogResult> {
-        unsafe { dialog.Show(None)? };
+    fn validate_file_name(name: &[u16]) -> Result<(), String> {
+        const RESERVED_NAMES: [&str; 22] = [
+            "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7",
+            "COM8", "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+        ];
 
-        let inner = self.inner.lock().unwrap();
-        let inner = inner.as_ref().ok_or(E_UNEXPECTED)?;
+        let display_name = String::from_utf16_lossy(name);
+
+        if let Some(c) = name
+            .iter()
+            .find(|&&c| c < 0x20 || "<>:\"/\\|?*".encode_utf16().any(|illegal| illegal == c))
+        {
+            return Err(format!(
+                "\"{}\" contains the invalid character '{}'.",
+                display_name,
+                char::from_u32(*c as u32).unwrap_or(char::REPLACEMENT_CHARACTER)
+            ));
+        }
+
+        if matches!(name.last(), Some(&c) if c == b' ' as u16 || c == b'.' as u16) {
+            return Err(format!(
+                "\"{}\" must not end with a space or a period.",
+                display_name
+            ));
+        }
+
+        let stem = display_name.split('.').next().unwrap_or_default();
+
+        if RESERVED_NAMES
+            .iter()
+            .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+        {
+            return Err(format!("\"{}\" is a reserved file name.", display_name));
+        }
+
+        Ok(())
+    }
+
+    // Returns a message describing why `name` can't be created in `folder`.
+    fn validate_target(folder: &IShellItem, name: &[u16]) -> windows::core::Result<Option<String>> {
+        if let Err(message) = SaveDialog::validate_file_name(name) {
+            return Ok(Some(message));
+        }
+
+        // Folders outside the file system don't have a path length limit we could check.
+        let Ok(folder_path) =
+            unsafe { folder.GetDisplayName(SIGDN_FILESYSPATH) }.map(CoTaskMemPWSTR::new)
+        else {
+            return Ok(None);
+        };
+
+        let path_len = unsafe { folder_path.as_wide() }.len() + 1 + name.len();
+
+        if path_len >= MAX_PATH as usize {
+            return Ok(Some(format!(
+                "The path of \"{}\" would be {} characters long, but at most {} are supported.",
+                String::from_utf16_lossy(name),
+                path_len,
+                MAX_PATH - 1
+            )));
+        }
+
+        Ok(None)
+    }
+
+    fn do_show(
+        &self,
+        dialog: &IFileDialog,
+        owner_window: HWND,
+    ) -> windows::core::Result<SaveDialogResult> {
+        unsafe { dialog.Show(owner_window)? };
+
+        let inner = self.inner.get()?.lock().unwrap();
 
         /*let pixel_format = if inner.selected_item == 0 {
             GUID::zeroed()
@@ -844,34 +1315,35 @@ impl SaveDialog {
 
         let pixel_format = inner.pixel_formats[inner.selected_item as usize];
 
-        let extension = match inner.extensions {
-            Some(ref extensions) => extensions
-                .get(unsafe { dialog.GetFileTypeIndex()? } as usize)
-                .cloned(),
-            None => None,
-        };
+        let extension = inner.selected_extension(dialog)?;
 
         Ok(SaveDialogResult {
             pixel_format,
             item: unsafe { dialog.GetResult()? },
             extension,
+            bit_depth: inner.bit_depth,
+            overwrite: inner.overwrite,
         })
     }
 }
 
 impl SaveDialog_Impl {
+    #[allow(clippy::too_many_arguments)]
     pub fn show(
         &self,
+        owner_window: HWND,
         filename: PCWSTR,
         mode: SaveDialogMode,
         default_folder: Option<IShellItem>,
-        file_extensions: Vec<u16>,
+        file_extensions: &[String],
         pixel_formats: Vec<GUID>,
+        size_estimator: OutputSizeEstimator,
+        batch_file_names: Vec<Vec<u16>>,
     ) -> windows::core::Result<SaveDialogResult> {
-        let mut inner = self.inner.lock().unwrap();
-        if inner.is_some() {
-            return Err(HRESULT::from_win32(ERROR_ALREADY_INITIALIZED.0).into());
-        }
+        self.inner.ensure_uninitialized()?;
+
+        // The dialog and the combo boxes added to it are laid out for the monitor they're on.
+        let _dpi_awareness = PerMonitorDpiAwareness::new();
 
         let clsid = match mode {
             SaveDialogMode::Folder => &FileOpenDialog,
@@ -889,16 +1361,14 @@ impl SaveDialog_Impl {
                 }
 
                 let extensions = file_extensions
-                    .split(|c| *c == b',' as u16)
-                    .map(|ext| ext.to_vec())
+                    .iter()
+                    .map(|extension| extension.encode_utf16().collect::<Vec<_>>())
                     .collect::<Vec<_>>();
 
                 let extension_type_names = extensions
                     .iter()
                     .filter_map(|ext| unsafe {
-                        let mut ext_buffer = vec![0u16; ext.len() + 2];
-                        ext_buffer[0] = b'*' as u16;
-                        ext_buffer[1..ext.len() + 1].copy_from_slice(ext);
+                        let ext_buffer = wstr::concat_with_nul(&[&[b'*' as u16], ext]);
 
                         let mut file_info = MaybeUninit::uninit();
                         if SHGetFileInfoW(
@@ -924,18 +1394,15 @@ impl SaveDialog_Impl {
                     })
                     .collect::<Vec<_>>();
 
-                let mut all_formats_buf = vec![];
+                let patterns = extensions
+                    .iter()
+                    .map(|extension| [&[b'*' as u16], &extension[..]].concat())
+                    .collect::<Vec<_>>();
 
-                for extension in extensions.iter() {
-                    all_formats_buf.push(b'*' as _);
-                    all_formats_buf.extend_from_slice(extension);
-                    all_formats_buf.push(b',' as _);
-                }
+                let all_formats_buf =
+                    wstr::with_nul(&wstr::join(patterns.iter().map(Vec::as_slice), ';'));
 
-                {
-                    let len = all_formats_buf.len();
-                    all_formats_buf[len - 1] = 0;
-                }
+                let default_extension = wstr::with_nul(&extensions[0][1..]);
 
                 filter_spec.extend_from_slice(&[
                     COMDLG_FILTERSPEC {
@@ -950,7 +1417,7 @@ impl SaveDialog_Impl {
 
                 unsafe {
                     dialog.SetFileTypes(&filter_spec)?;
-                    dialog.SetDefaultExtension(PCWSTR::from_raw(extensions[0].as_ptr().add(1)))?;
+                    dialog.SetDefaultExtension(PCWSTR::from_raw(default_extension.as_ptr()))?;
                 }
 
                 Some(extensions)
@@ -994,18 +1461,52 @@ impl SaveDialog_Impl {
 
         unsafe { customize.SetSelectedControlItem(SaveDialog::COMBO_BOX_CONTROL_ID, 0)? };
 
+        let bit_depth = if size_estimator.container_format == CONTAINER_FORMAT {
+            unsafe {
+                customize
+                    .StartVisualGroup(SaveDialog::BIT_DEPTH_GROUP_CONTROL_ID, w!("Colors:"))?;
+                customize.AddComboBox(SaveDialog::BIT_DEPTH_CONTROL_ID)?;
+                customize.EndVisualGroup()?;
+
+                for (i, (_, name)) in SaveDialog::BIT_DEPTHS.iter().enumerate() {
+                    customize.AddControlItem(SaveDialog::BIT_DEPTH_CONTROL_ID, i as _, *name)?;
+                }
+
+                customize.SetSelectedControlItem(SaveDialog::BIT_DEPTH_CONTROL_ID, 0)?;
+            }
+
+            Some(SaveDialog::BIT_DEPTHS[0].0)
+        } else {
+            None
+        };
+
+        let estimate_pixel_format = bit_depth
+            .and_then(bit_depth_to_pixel_format)
+            .or_else(|| pixel_formats.first().copied());
+
+        if let Some(pixel_format) = estimate_pixel_format {
+            unsafe {
+                customize.AddText(
+                    SaveDialog::ESTIMATED_SIZE_CONTROL_ID,
+                    &size_estimator.label(&pixel_format),
+                )?
+            };
+        }
+
         let cookie = unsafe { dialog.Advise(&self.to_interface::<IFileDialogEvents>())? };
 
-        inner.replace(SaveDialogData {
+        self.inner.initialize(Mutex::new(SaveDialogData {
             mode,
             extensions,
             pixel_formats,
             selected_item: 0,
-        });
+            bit_depth,
+            size_estimator,
+            batch_file_names,
+            overwrite: false,
+        }))?;
 
-        std::mem::drop(inner);
-
-        let result = self.do_show(&dialog);
+        let result = self.do_show(&dialog, owner_window);
 
         unsafe {
             dialog.Unadvise(cookie)?;
@@ -1018,7 +1519,115 @@ impl SaveDialog_Impl {
 #[expect(unused_variables)]
 impl IFileDialogEvents_Impl for SaveDialog_Impl {
     fn OnFileOk(&self, dialog: Option<&IFileDialog>) -> windows::core::Result<()> {
-        Err(E_NOTIMPL.into())
+        // S_FALSE keeps the dialog open so the user can pick something else.
+        const REJECT: HRESULT = S_FALSE;
+
+        let dialog = dialog.ok_or(E_POINTER)?;
+        let window = unsafe { dialog.cast::<IOleWindow>()?.GetWindow()? };
+
+        let mut inner = self.inner.get()?.lock().unwrap();
+        let inner = &mut *inner;
+
+        let result = unsafe { dialog.GetResult()? };
+
+        match inner.mode {
+            SaveDialogMode::File => {
+                let name = CoTaskMemPWSTR::new(unsafe {
+                    result.GetDisplayName(SIGDN_PARENTRELATIVEPARSING)?
+                });
+
+                let name = [
+                    unsafe { name.as_wide() },
+                    inner
+                        .selected_extension(dialog)?
+                        .as_deref()
+                        .unwrap_or_default(),
+                ]
+                .concat();
+
+                if let Some(message) =
+                    SaveDialog::validate_target(&unsafe { result.GetParent()? }, &name)?
+                {
+                    unsafe {
+                        MessageBoxW(
+                            window,
+                            PCWSTR::from_raw(HSTRING::from(message).as_ptr()),
+                            w!("Invalid File Name"),
+                            MB_ICONERROR,
+                        )
+                    };
+                    return Err(REJECT.into());
+                }
+            }
+            SaveDialogMode::Folder => {
+                let mut existing = Vec::new();
+
+                for name in inner.batch_file_names.iter() {
+                    let name_without_nul = wstr::until_nul(name);
+
+                    if let Some(message) = SaveDialog::validate_target(&result, name_without_nul)? {
+                        unsafe {
+                            MessageBoxW(
+                                window,
+                                PCWSTR::from_raw(HSTRING::from(message).as_ptr()),
+                                w!("Invalid File Name"),
+                                MB_ICONERROR,
+                            )
+                        };
+                        return Err(REJECT.into());
+                    }
+
+                    if unsafe {
+                        SHCreateItemFromRelativeName::<_, _, _, IShellItem>(
+                            &result,
+                            PCWSTR::from_raw(name.as_ptr()),
+                            None,
+                        )
+                    }
+                    .is_ok()
+                    {
+                        existing.push(String::from_utf16_lossy(name_without_nul));
+                    }
+                }
+
+                if !existing.is_empty() {
+                    const MAX_LISTED: usize = 10;
+
+                    let mut message = format!(
+                        "{} of the files already exist in the selected folder:\n\n",
+                        existing.len()
+                    );
+
+                    for name in existing.iter().take(MAX_LISTED) {
+                        message.push_str(name);
+                        message.push('\n');
+                    }
+
+                    if existing.len() > MAX_LISTED {
+                        message.push_str("...\n");
+                    }
+
+                    message.push_str("\nDo you want to replace them?");
+
+                    let response = unsafe {
+                        MessageBoxW(
+                            window,
+                            PCWSTR::from_raw(HSTRING::from(message).as_ptr()),
+                            w!("Confirm File Replace"),
+                            MB_ICONWARNING | MB_YESNO,
+                        )
+                    };
+
+                    if response != IDYES {
+                        return Err(REJECT.into());
+                    }
+
+                    inner.overwrite = true;
+                }
+            }
+        }
+
+        Ok(())
     }
 
     fn OnFolderChange(&self, pfd: Option<&IFileDialog>) -> windows::core::Result<()> {
@@ -1058,7 +1667,6 @@ impl IFileDialogEvents_Impl for SaveDialog_Impl {
     }
 }
 
-#[expect(unused_variables)]
 impl IFileDialogControlEvents_Impl for SaveDialog_Impl {
     fn OnButtonClicked(
         &self,
@@ -1091,22 +1699,150 @@ impl IFileDialogControlEvents_Impl for SaveDialog_Impl {
         control_id: u32,
         item_id: u32,
     ) -> windows::core::Result<()> {
-        if control_id == SaveDialog::COMBO_BOX_CONTROL_ID {
-            let mut inner = self.inner.lock().unwrap();
-            let inner = inner.as_mut().ok_or(E_UNEXPECTED)?;
-
-            if
-            /*item_id == 0 || (item_id - 1)*/
-            item_id < inner.pixel_formats.len() as u32 {
-                inner.selected_item = item_id;
-                Ok(())
-            } else {
-                Err(E_INVALIDARG.into())
+        let mut inner = self.inner.get()?.lock().unwrap();
+        let inner = &mut *inner;
+
+        match control_id {
+            SaveDialog::COMBO_BOX_CONTROL_ID => {
+                if
+                /*item_id == 0 || (item_id - 1)*/
+                item_id < inner.pixel_formats.len() as u32 {
+                    inner.selected_item = item_id;
+                } else {
+                    return Err(E_INVALIDARG.into());
+                }
             }
-        } else {
-            Err(E_NOTIMPL.into())
+            SaveDialog::BIT_DEPTH_CONTROL_ID if inner.bit_depth.is_some() => {
+                let (bit_depth, _) = SaveDialog::BIT_DEPTHS
+                    .get(item_id as usize)
+                    .ok_or(E_INVALIDARG)?;
+
+                inner.bit_depth = Some(*bit_depth);
+            }
+            _ => return Err(E_NOTIMPL.into()),
+        }
+
+        if let Some(customize) = pfdc {
+            let label = inner.size_estimator.label(&inner.effective_pixel_format());
+
+            unsafe { customize.SetControlLabel(SaveDialog::ESTIMATED_SIZE_CONTROL_ID, &label)? };
+        }
+
+        Ok(())
+    }
+}
+
+// Shared by every item of a file operation. Cancelled either explicitly, when the file operation
+// reports an abort, or implicitly through the Cancel button of the operation's progress dialog.
+#[derive(Clone)]
+struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    progress_dialog: Option<IOperationsProgressDialog>,
+}
+
+impl CancellationToken {
+    pub fn new(progress_dialog: Option<IOperationsProgressDialog>) -> Self {
+        Self {
+            cancelled: Arc::new(AtomicBool::new(false)),
+            progress_dialog,
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        if self.cancelled.load(Ordering::Acquire) {
+            return true;
+        }
+
+        let dialog_cancelled = self.progress_dialog.as_ref().is_some_and(|dialog| {
+            unsafe { dialog.GetOperationStatus() }.is_ok_and(|status| status == PDOPS_CANCELLED)
+        });
+
+        if dialog_cancelled {
+            self.cancel();
         }
+
+        dialog_cancelled
+    }
+}
+
+// A zeroed pixel format leaves the choice to the encoder.
+fn transcode_request(
+    source: IStream,
+    target: impl Into<TranscodeStream>,
+    container_format: &GUID,
+    pixel_format: &GUID,
+    bit_depth: Option<u8>,
+) -> TranscodeRequest {
+    TranscodeRequest {
+        pixel_format: Some(*pixel_format).filter(|format| *format != GUID::zeroed()),
+        options: TranscodeOptions {
+            bit_depth,
+            ..Default::default()
+        },
+        ..TranscodeRequest::new(source, target, *container_format)
+    }
+}
+
+// Used when no file operation can be created, e.g. in locked-down shells without the copy engine.
+// The file is written under a temporary name next to the target and renamed into place once
+// complete, so a failed transcode never leaves a partial file behind.
+fn transcode_direct(
+    imaging_factory: &IWICImagingFactory,
+    source: &IShellItem,
+    folder: &IShellItem,
+    filename: &[u16],
+    container_format: &GUID,
+    result: &SaveDialogResult,
+) -> Result<(), TranscodeError> {
+    let folder = CoTaskMemPWSTR::new(unsafe { folder.GetDisplayName(SIGDN_FILESYSPATH)? });
+    let folder = PathBuf::from(unsafe { folder.to_string() }.map_err(|_| E_INVALIDARG)?);
+
+    let filename = String::from_utf16(wstr::until_nul(filename)).map_err(|_| E_INVALIDARG)?;
+
+    let target = folder.join(&filename);
+    let temporary = folder.join(format!("~{}.{}.tmp", filename, std::process::id()));
+
+    if !result.overwrite && target.exists() {
+        return Err(HRESULT::from_win32(ERROR_FILE_EXISTS.0).into());
+    }
+
+    let source: IStream = unsafe { source.BindToHandler(None, &BHID_Stream)? };
+
+    transcode_request(
+        source,
+        temporary.clone(),
+        container_format,
+        &result.pixel_format,
+        result.bit_depth,
+    )
+    .run(imaging_factory)
+    .and_then(|_| {
+        std::fs::rename(&temporary, &target).map_err(|err| {
+            err.raw_os_error()
+                .map_or(E_FAIL, |code| HRESULT::from_win32(code as u32))
+                .into()
+        })
+    })
+    .inspect_err(|_| {
+        let _ = std::fs::remove_file(&temporary);
+    })?;
+
+    let target = HSTRING::from(target.as_os_str());
+
+    unsafe {
+        SHChangeNotify(
+            SHCNE_CREATE,
+            SHCNF_PATHW,
+            Some(target.as_ptr().cast()),
+            None,
+        );
     }
+
+    Ok(())
 }
 
 struct TranscodeOperationData {
@@ -1114,6 +1850,8 @@ struct TranscodeOperationData {
     source: IShellItem,
     container_format: GUID,
     pixel_format: GUID,
+    bit_depth: Option<u8>,
+    cancellation: CancellationToken,
     error_message: Option<String>,
 }
 
@@ -1128,6 +1866,8 @@ impl TranscodeOperation {
         source: &IShellItem,
         container_format: &GUID,
         pixel_format: &GUID,
+        bit_depth: Option<u8>,
+        cancellation: &CancellationToken,
     ) -> Self {
         Self {
             inner: Mutex::new(TranscodeOperationData {
@@ -1135,6 +1875,8 @@ impl TranscodeOperation {
                 source: source.clone(),
                 container_format: *container_format,
                 pixel_format: *pixel_format,
+                bit_depth,
+                cancellation: cancellation.clone(),
                 error_message: None,
             }),
         }
@@ -1146,7 +1888,11 @@ impl TranscodeOperation {
 }
 
 impl IFileOperationProgressSink_Impl for TranscodeOperation_Impl {
-    fn FinishOperations(&self, _hrresult: windows::core::HRESULT) -> windows::core::Result<()> {
+    fn FinishOperations(&self, hrresult: windows::core::HRESULT) -> windows::core::Result<()> {
+        if hrresult == COPYENGINE_E_USER_CANCELLED || hrresult == E_ABORT {
+            self.inner.lock().unwrap().cancellation.cancel();
+        }
+
         Ok(())
     }
 
@@ -1222,7 +1968,11 @@ impl IFileOperationProgressSink_Impl for TranscodeOperation_Impl {
         _psidestinationfolder: Option<&IShellItem>,
         _psznewname: &windows::core::PCWSTR,
     ) -> windows::core::Result<()> {
-        Ok(())
+        if self.inner.lock().unwrap().cancellation.is_cancelled() {
+            Err(COPYENGINE_E_USER_CANCELLED.into())
+        } else {
+            Ok(())
+        }
     }
 
     fn PostNewItem(
@@ -1235,25 +1985,49 @@ impl IFileOperationProgressSink_Impl for TranscodeOperation_Impl {
         hrnew: windows::core::HRESULT,
         new_item: Option<&IShellItem>,
     ) -> windows::core::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if hrnew == COPYENGINE_E_USER_CANCELLED || hrnew == E_ABORT {
+            inner.cancellation.cancel();
+        }
+
         hrnew.ok()?;
         let new_item = new_item.ok_or(E_POINTER)?;
 
-        let mut inner = self.inner.lock().unwrap();
+        let source: IStream = unsafe { inner.source.BindToHandler(None, &BHID_Stream)? };
+        let bind_ctx = unsafe { CreateBindCtx(0)? };
+
+        let mut bind_options = BIND_OPTS {
+            cbStruct: std::mem::size_of::<BIND_OPTS>() as _,
+            ..Default::default()
+        };
+
+        unsafe { bind_ctx.GetBindOptions(&raw mut bind_options)? };
 
-        transcode(
-            &inner.imaging_factory,
-            &inner.source,
-            new_item,
+        bind_options.grfMode = STGM_WRITE.0;
+        unsafe { bind_ctx.SetBindOptions(&raw const bind_options)? };
+
+        let target: IStream = unsafe { new_item.BindToHandler(Some(&bind_ctx), &BHID_Stream)? };
+
+        let request = transcode_request(
+            source,
+            target,
             &inner.container_format,
             &inner.pixel_format,
-        )
-        .inspect_err(|err| match err {
-            TranscodeError::Win(_) => {}
-            err => {
-                inner.error_message = Some(err.to_string());
-            }
-        })
-        .map_err(|err| err.into())
+            inner.bit_depth,
+        );
+
+        let cancellation = inner.cancellation.clone();
+
+        request
+            .run_cancellable(&inner.imaging_factory, &|| cancellation.is_cancelled())
+            .inspect_err(|err| match err {
+                TranscodeError::Win(_) | TranscodeError::Cancelled => {}
+                err => {
+                    inner.error_message = Some(err.to_string());
+                }
+            })
+            .map_err(|err| err.into())
     }
 
     fn PreRenameItem(
@@ -1293,138 +2067,59 @@ impl IFileOperationProgressSink_Impl for TranscodeOperation_Impl {
     }
 }
 
-enum TranscodeError {
-    Win(windows::core::Error),
-    NoFrames,
-    DoesNotSupportMultiframe,
-}
-
-impl Display for TranscodeError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Win(err) => write!(f, "{}", err),
-            Self::NoFrames => write!(f, "No frames in source"),
-            Self::DoesNotSupportMultiframe => {
-                write!(
-                    f,
-                    "Source has multiple frames, which the encoder does not support."
-                )
-            }
-        }
-    }
-}
-
-impl From<windows::core::Error> for TranscodeError {
-    fn from(err: windows::core::Error) -> Self {
-        Self::Win(err)
-    }
-}
-
-impl From<HRESULT> for TranscodeError {
-    fn from(hr: HRESULT) -> Self {
-        Self::Win(hr.into())
-    }
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-impl From<TranscodeError> for windows::core::Error {
-    fn from(err: TranscodeError) -> Self {
-        match err {
-            TranscodeError::Win(err) => err,
-            err => windows::core::Error::new(
-                match err {
-                    TranscodeError::NoFrames => HRESULT::from_win32(ERROR_NO_MORE_ITEMS.0),
-                    TranscodeError::DoesNotSupportMultiframe => WINCODEC_ERR_UNSUPPORTEDOPERATION,
-                    _ => unreachable!(),
-                },
-                err.to_string(),
-            ),
-        }
-    }
-}
-
-fn transcode(
-    imaging_factory: &IWICImagingFactory,
-    source: &IShellItem,
-    target: &IShellItem,
-    container_format: &GUID,
-    pixel_format: &GUID,
-) -> Result<(), TranscodeError> {
-    let source_stream: IStream = unsafe { source.BindToHandler(None, &BHID_Stream)? };
-    let bind_ctx = unsafe { CreateBindCtx(0)? };
-
-    let mut bind_options = BIND_OPTS {
-        cbStruct: std::mem::size_of::<BIND_OPTS>() as _,
-        ..Default::default()
-    };
-
-    unsafe { bind_ctx.GetBindOptions(&raw mut bind_options)? };
-
-    bind_options.grfMode = STGM_WRITE.0;
-    unsafe { bind_ctx.SetBindOptions(&raw const bind_options)? };
-
-    let target_stream: IStream = unsafe { target.BindToHandler(Some(&bind_ctx), &BHID_Stream)? };
+    #[test]
+    fn state_cache_expires() {
+        let now = Instant::now();
+        let state = SelectionState {
+            decodable: true,
+            source_mime_type: Some("image/png".to_owned()),
+        };
 
-    let decoder = unsafe {
-        imaging_factory.CreateDecoderFromStream(
-            &source_stream,
-            std::ptr::null(),
-            WICDecodeMetadataCacheOnDemand,
-        )?
-    };
+        let cache = Some(StateCache {
+            selection: 1,
+            evaluated: now,
+            state: state.clone(),
+        });
 
-    let frame_count = unsafe { decoder.GetFrameCount()? };
-    if frame_count < 1 {
-        return Err(TranscodeError::NoFrames);
+        assert_eq!(StateCache::get(&cache, 1, now), Some(state));
+        assert_eq!(StateCache::get(&cache, 2, now), None);
+        assert_eq!(StateCache::get(&cache, 1, now + StateCache::TTL), None);
+        assert_eq!(StateCache::get(&None, 1, now), None);
     }
 
-    let encoder = unsafe { imaging_factory.CreateEncoder(container_format, std::ptr::null())? };
+    #[test]
+    fn skipped_summary_lists_names() {
+        assert_eq!(
+            skipped_summary(&["a.txt".to_owned()]),
+            "1 item was skipped because it is not a supported image:\n\na.txt"
+        );
 
-    if frame_count > 1 {
-        let encoder_info = unsafe { encoder.GetEncoderInfo()? };
+        let names = (0..12).map(|i| format!("{}.txt", i)).collect::<Vec<_>>();
+        let summary = skipped_summary(&names);
 
-        if unsafe { !encoder_info.DoesSupportMultiframe()?.as_bool() } {
-            return Err(TranscodeError::DoesNotSupportMultiframe);
-        }
+        assert!(summary.starts_with("12 items were skipped"));
+        assert!(summary.contains("\n9.txt"));
+        assert!(!summary.contains("10.txt"));
+        assert!(summary.ends_with("… and 2 more"));
     }
 
-    unsafe {
-        encoder.Initialize(&target_stream, WICBitmapEncoderNoCache)?;
-    }
-
-    for i in 0..frame_count {
-        let frame = {
-            let frame = unsafe { decoder.GetFrame(i)? }.cast()?;
-            if *pixel_format != GUID::zeroed() {
-                unsafe { WICConvertBitmapSource(pixel_format, &frame)? }
-            } else {
-                frame
-            }
-        };
+    #[test]
+    fn failed_summary_lists_errors() {
+        assert_eq!(
+            failed_summary(&["a.png: Access is denied.".to_owned()]),
+            "1 item could not be transcoded:\n\na.png: Access is denied."
+        );
 
-        let mut property_bag = None;
-
-        let frame_encode = unsafe {
-            let mut frame_encode = None;
-            encoder.CreateNewFrame(&raw mut frame_encode, &raw mut property_bag)?;
-            frame_encode.ok_or(E_FAIL)?
-        };
-
-        unsafe {
-            (Interface::vtable(&frame_encode).Initialize)(
-                Interface::as_raw(&frame_encode),
-                property_bag
-                    .as_ref()
-                    .map_or(std::ptr::null_mut(), Interface::as_raw),
-            )
-            .ok()?;
-            frame_encode.WriteSource(&frame, std::ptr::null())?;
-            frame_encode.Commit()?;
-        }
-    }
+        let failed = (0..11)
+            .map(|i| format!("{}.png: error", i))
+            .collect::<Vec<_>>();
+        let summary = failed_summary(&failed);
 
-    unsafe {
-        encoder.Commit()?;
+        assert!(summary.starts_with("11 items could not be transcoded:\n\n0.png: error"));
+        assert!(summary.ends_with("… and 1 more"));
     }
-
-    Ok(())
 }
@@ -0,0 +1,458 @@
+use std::cell::RefCell;
+use std::ffi::c_void;
+use std::sync::RwLock;
+
+use windows::core::{implement, w, IUnknown, Interface, GUID, HSTRING, PCWSTR, PWSTR};
+use windows::Win32::Foundation::{
+    BOOL, COLORREF, E_FAIL, E_NOTIMPL, E_POINTER, HWND, LPARAM, LRESULT, RECT, WPARAM,
+};
+use windows::Win32::Graphics::Gdi::{
+    BeginPaint, CreateSolidBrush, DeleteObject, EndPaint, FillRect, SetStretchBltMode,
+    StretchDIBits, BITMAPINFO, BITMAPINFOHEADER, BI_RGB, COLORONCOLOR, DIB_RGB_COLORS, PAINTSTRUCT,
+    SRCCOPY,
+};
+use windows::Win32::System::Com::{IBindCtx, IStream};
+use windows::Win32::System::Ole::{IObjectWithSite, IObjectWithSite_Impl};
+use windows::Win32::UI::HiDpi::{AdjustWindowRectExForDpi, GetDpiForWindow};
+use windows::Win32::UI::Shell::{
+    BHID_Stream, IEnumExplorerCommand, IExplorerCommand, IExplorerCommand_Impl, IShellItemArray,
+    SHStrDupW, ECF_DEFAULT, ECS_ENABLED, ECS_HIDDEN, SIGDN_NORMALDISPLAY,
+};
+use windows::Win32::UI::WindowsAndMessaging::{
+    CreateWindowExW, DefWindowProcW, DestroyWindow, DispatchMessageW, GetClientRect, GetMessageW,
+    LoadCursorW, MessageBoxW, PostQuitMessage, RegisterClassExW, SetWindowPos, SetWindowTextW,
+    TranslateMessage, UnregisterClassW, CW_USEDEFAULT, IDC_ARROW, MB_ICONERROR, MSG,
+    SWP_NOACTIVATE, SWP_NOMOVE, SWP_NOZORDER, USER_DEFAULT_SCREEN_DPI, WINDOW_EX_STYLE, WM_CHAR,
+    WM_DESTROY, WM_DPICHANGED, WM_ERASEBKGND, WM_PAINT, WNDCLASSEXW, WS_OVERLAPPEDWINDOW,
+    WS_VISIBLE,
+};
+
+use crate::bmx::{BmxImage, PaletteEntry, Truncation};
+use crate::com::shell::command::owner::ModalOwner;
+use crate::com::shell::command::ExplorerCommandClass;
+use crate::com::shell::CoTaskMemPWSTR;
+use crate::com::{stream_read_to_end, CoClass};
+use crate::util::{get_this_module_handle, guid, ModulePin, PerMonitorDpiAwareness};
+
+const WINDOW_CLASS: PCWSTR = w!("X16BMX.VeraPreview");
+
+// Border color shown around the image at 1x, in VERA pixels.
+const BORDER: i32 = 16;
+
+struct PreviewWindow {
+    title: String,
+    width: i32,
+    height: i32,
+    bgra: Vec<u8>,
+    border_color: COLORREF,
+    scale: i32,
+    // Of the monitor the window is on. The scale is in logical pixels, so it stays the same
+    // physical size everywhere.
+    dpi: u32,
+}
+
+thread_local! {
+    // Every preview gets its own thread, which only ever has this one window.
+    static PREVIEW: RefCell<Option<PreviewWindow>> = const { RefCell::new(None) };
+}
+
+impl PreviewWindow {
+    fn new(title: String, image: &BmxImage) -> Self {
+        let border = image
+            .palette
+            .get(
+                image
+                    .header
+                    .vera_border_color
+                    .wrapping_sub(image.header.pal_start) as usize,
+            )
+            .map_or(0, PaletteEntry::to_wic);
+
+        Self {
+            title,
+            width: image.header.width as i32,
+            height: image.header.height as i32,
+            bgra: image.to_bgra(),
+            border_color: COLORREF(
+                ((border & 0xFF) << 16) | (border & 0xFF00) | ((border >> 16) & 0xFF),
+            ),
+            scale: 2,
+            dpi: USER_DEFAULT_SCREEN_DPI,
+        }
+    }
+
+    // Device pixels per VERA pixel. Rounded, so every VERA pixel is the same number of pixels wide.
+    fn pixel_scale(&self) -> i32 {
+        ((self.scale * self.dpi as i32 + USER_DEFAULT_SCREEN_DPI as i32 / 2)
+            / USER_DEFAULT_SCREEN_DPI as i32)
+            .max(1)
+    }
+
+    fn window_title(&self) -> HSTRING {
+        HSTRING::from(format!(
+            "{} - VERA Preview ({}x, 1-3 to scale)",
+            self.title, self.scale
+        ))
+    }
+
+    fn window_size(&self) -> (i32, i32) {
+        let mut rect = RECT {
+            left: 0,
+            top: 0,
+            right: (self.width + 2 * BORDER) * self.pixel_scale(),
+            bottom: (self.height + 2 * BORDER) * self.pixel_scale(),
+        };
+
+        unsafe {
+            _ = AdjustWindowRectExForDpi(
+                &raw mut rect,
+                WS_OVERLAPPEDWINDOW,
+                false,
+                WINDOW_EX_STYLE::default(),
+                self.dpi,
+            );
+        }
+
+        (rect.right - rect.left, rect.bottom - rect.top)
+    }
+
+    fn paint(&self, window: HWND) {
+        let mut paint = PAINTSTRUCT::default();
+        let mut client = RECT::default();
+
+        let info = BITMAPINFO {
+            bmiHeader: BITMAPINFOHEADER {
+                biSize: std::mem::size_of::<BITMAPINFOHEADER>() as u32,
+                biWidth: self.width,
+                biHeight: -self.height,
+                biPlanes: 1,
+                biBitCount: 32,
+                biCompression: BI_RGB.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        unsafe {
+            let dc = BeginPaint(window, &raw mut paint);
+            _ = GetClientRect(window, &raw mut client);
+
+            let brush = CreateSolidBrush(self.border_color);
+            FillRect(dc, &raw const client, brush);
+            _ = DeleteObject(brush);
+
+            // Nearest neighbor, so every VERA pixel stays a sharp square.
+            SetStretchBltMode(dc, COLORONCOLOR);
+
+            let (width, height) = (
+                self.width * self.pixel_scale(),
+                self.height * self.pixel_scale(),
+            );
+            StretchDIBits(
+                dc,
+                (client.right - width) / 2,
+                (client.bottom - height) / 2,
+                width,
+                height,
+                0,
+                0,
+                self.width,
+                self.height,
+                Some(self.bgra.as_ptr().cast()),
+                &raw const info,
+                DIB_RGB_COLORS,
+                SRCCOPY,
+            );
+
+            _ = EndPaint(window, &raw const paint);
+        }
+    }
+}
+
+// Applies `update` and resizes the window to fit, at `position` if given.
+fn update_preview(
+    window: HWND,
+    position: Option<(i32, i32)>,
+    update: impl FnOnce(&mut PreviewWindow),
+) {
+    let Some((title, (width, height))) = PREVIEW.with_borrow_mut(|preview| {
+        preview.as_mut().map(|preview| {
+            update(preview);
+            (preview.window_title(), preview.window_size())
+        })
+    }) else {
+        return;
+    };
+
+    let (x, y, flags) = match position {
+        Some((x, y)) => (x, y, SWP_NOZORDER | SWP_NOACTIVATE),
+        None => (0, 0, SWP_NOMOVE | SWP_NOZORDER),
+    };
+
+    unsafe {
+        _ = SetWindowTextW(window, &title);
+        _ = SetWindowPos(window, None, x, y, width, height, flags);
+    }
+}
+
+extern "system" fn window_proc(
+    window: HWND,
+    message: u32,
+    wparam: WPARAM,
+    lparam: LPARAM,
+) -> LRESULT {
+    match message {
+        WM_PAINT => {
+            PREVIEW.with_borrow(|preview| {
+                if let Some(preview) = preview {
+                    preview.paint(window);
+                }
+            });
+
+            LRESULT(0)
+        }
+        // WM_PAINT covers the whole client area.
+        WM_ERASEBKGND => LRESULT(1),
+        WM_CHAR => {
+            match char::from_u32(wparam.0 as u32) {
+                Some(digit @ '1'..='3') => update_preview(window, None, |preview| {
+                    preview.scale = digit as i32 - '0' as i32
+                }),
+                Some('\x1b') => unsafe { _ = DestroyWindow(window) },
+                _ => {}
+            }
+
+            LRESULT(0)
+        }
+        WM_DPICHANGED => {
+            // Keeps the suggested position, but not the size, which would scale the border and
+            // the image by the DPI ratio rather than by whole pixels.
+            let suggested = unsafe { &*(lparam.0 as *const RECT) };
+
+            update_preview(window, Some((suggested.left, suggested.top)), |preview| {
+                preview.dpi = (wparam.0 & 0xFFFF) as u32
+            });
+
+            LRESULT(0)
+        }
+        WM_DESTROY => {
+            unsafe { PostQuitMessage(0) };
+            LRESULT(0)
+        }
+        _ => unsafe { DefWindowProcW(window, message, wparam, lparam) },
+    }
+}
+
+fn run_preview(preview: PreviewWindow) -> windows::core::Result<()> {
+    let _dpi_awareness = PerMonitorDpiAwareness::new();
+    let instance = unsafe { get_this_module_handle()? };
+
+    let class = WNDCLASSEXW {
+        cbSize: std::mem::size_of::<WNDCLASSEXW>() as u32,
+        lpfnWndProc: Some(window_proc),
+        hInstance: instance.into(),
+        hCursor: unsafe { LoadCursorW(None, IDC_ARROW)? },
+        lpszClassName: WINDOW_CLASS,
+        ..Default::default()
+    };
+
+    // Fails if another preview already registered the class, which is just as good.
+    unsafe { RegisterClassExW(&raw const class) };
+
+    let title = preview.window_title();
+    let (width, height) = preview.window_size();
+    PREVIEW.set(Some(preview));
+
+    let result = unsafe {
+        CreateWindowExW(
+            WINDOW_EX_STYLE::default(),
+            WINDOW_CLASS,
+            &title,
+            WS_OVERLAPPEDWINDOW | WS_VISIBLE,
+            CW_USEDEFAULT,
+            CW_USEDEFAULT,
+            width,
+            height,
+            None,
+            None,
+            instance,
+            None,
+        )
+    };
+
+    if let Ok(window) = result {
+        // The window is only on a monitor once it exists.
+        update_preview(window, None, |preview| {
+            preview.dpi = match unsafe { GetDpiForWindow(window) } {
+                0 => USER_DEFAULT_SCREEN_DPI,
+                dpi => dpi,
+            }
+        });
+
+        let mut message = MSG::default();
+
+        unsafe {
+            while GetMessageW(&raw mut message, None, 0, 0).0 > 0 {
+                _ = TranslateMessage(&raw const message);
+                DispatchMessageW(&raw const message);
+            }
+        }
+    }
+
+    PREVIEW.take();
+
+    // Classes registered by a DLL outlive it. This only succeeds once the last preview is closed.
+    unsafe {
+        _ = UnregisterClassW(WINDOW_CLASS, instance);
+    }
+
+    result.map(|_| ())
+}
+
+// Shows a BMX file the way the X16 would: with its own palette, at an integer scale and surrounded
+// by the border color.
+#[derive(Default)]
+#[implement(IExplorerCommand, IObjectWithSite)]
+pub struct VeraPreview {
+    site: RwLock<Option<IUnknown>>,
+}
+
+impl VeraPreview {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl CoClass for VeraPreview {
+    const CLSID: GUID = guid::from_str("9d27c1e4-6a8b-4f35-b0d9-3e5c72a1f864");
+    const PROG_ID: PCWSTR = w!("X16BMX.VeraPreview.1");
+    const VERSION_INDEPENDENT_PROG_ID: PCWSTR = w!("X16BMX.VeraPreview");
+    const FRIENDLY_NAME: PCWSTR = w!("VERA Preview");
+}
+
+impl ExplorerCommandClass for VeraPreview {
+    const FILE_TYPE: PCWSTR = w!("SystemFileAssociations\\.bmx");
+    const VERB: PCWSTR = w!("VeraPreview");
+}
+
+impl IExplorerCommand_Impl for VeraPreview_Impl {
+    fn GetTitle(&self, _items: Option<&IShellItemArray>) -> windows::core::Result<PWSTR> {
+        unsafe { SHStrDupW(w!("Open in VERA preview")) }
+    }
+
+    fn GetIcon(&self, _items: Option<&IShellItemArray>) -> windows::core::Result<PWSTR> {
+        Err(E_NOTIMPL.into())
+    }
+
+    fn GetToolTip(&self, _items: Option<&IShellItemArray>) -> windows::core::Result<PWSTR> {
+        unsafe { SHStrDupW(w!("Show the image as it looks on the Commander X16")) }
+    }
+
+    fn GetCanonicalName(&self) -> windows::core::Result<GUID> {
+        Ok(VeraPreview::CLSID)
+    }
+
+    fn GetState(
+        &self,
+        items: Option<&IShellItemArray>,
+        _ok_to_be_slow: BOOL,
+    ) -> windows::core::Result<u32> {
+        let items = items.ok_or(E_POINTER)?;
+
+        if unsafe { items.GetCount()? } == 1 {
+            Ok(ECS_ENABLED.0 as _)
+        } else {
+            Ok(ECS_HIDDEN.0 as _)
+        }
+    }
+
+    fn Invoke(
+        &self,
+        items: Option<&IShellItemArray>,
+        _pbc: Option<&IBindCtx>,
+    ) -> windows::core::Result<()> {
+        let items = items.ok_or(E_POINTER)?;
+        let item = unsafe { items.GetItemAt(0)? };
+
+        let owner = ModalOwner::new(self.site.read().unwrap().as_ref());
+        let owner_window = owner.window();
+
+        let title = CoTaskMemPWSTR::new(unsafe { item.GetDisplayName(SIGDN_NORMALDISPLAY)? });
+        let title = unsafe { title.to_string() }.unwrap_or_default();
+
+        let stream: IStream = unsafe { item.BindToHandler(None, &BHID_Stream)? };
+        let bytes = stream_read_to_end(&stream)?;
+
+        let image = match BmxImage::from_bytes_with(&bytes, Truncation::FillWithBorderColor) {
+            Ok(image) => image,
+            Err(err) => {
+                unsafe {
+                    MessageBoxW(
+                        owner_window,
+                        &HSTRING::from(err.to_string()),
+                        w!("VERA Preview"),
+                        MB_ICONERROR,
+                    );
+                }
+
+                return Ok(());
+            }
+        };
+
+        let preview = PreviewWindow::new(title, &image);
+
+        // Explorer must not wait for the window to close, and may unload the module meanwhile.
+        let pin = ModulePin::new()?;
+
+        std::thread::spawn(move || {
+            let exit_code = match run_preview(preview) {
+                Ok(()) => 0,
+                Err(err) => err.code().0 as u32,
+            };
+
+            pin.exit_thread(exit_code)
+        });
+
+        Ok(())
+    }
+
+    fn GetFlags(&self) -> windows::core::Result<u32> {
+        Ok(ECF_DEFAULT.0 as _)
+    }
+
+    fn EnumSubCommands(&self) -> windows::core::Result<IEnumExplorerCommand> {
+        Err(E_NOTIMPL.into())
+    }
+}
+
+impl IObjectWithSite_Impl for VeraPreview_Impl {
+    fn SetSite(&self, site: Option<&IUnknown>) -> windows::core::Result<()> {
+        *self.site.write().unwrap() = site.cloned();
+        Ok(())
+    }
+
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn GetSite(&self, riid: *const GUID, ppv: *mut *mut c_void) -> windows::core::Result<()> {
+        if ppv.is_null() {
+            return Err(E_POINTER.into());
+        }
+
+        if riid.is_null() {
+            unsafe {
+                ppv.write(std::ptr::null_mut());
+            }
+
+            return Err(E_POINTER.into());
+        }
+
+        match *self.site.read().unwrap() {
+            Some(ref site) => unsafe { site.query(riid, ppv).ok() },
+            None => {
+                unsafe {
+                    ppv.write(std::ptr::null_mut());
+                }
+                Err(E_FAIL.into())
+            }
+        }
+    }
+}
@@ -0,0 +1,309 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use windows::Win32::Foundation::{
+    GlobalFree, BOOL, DV_E_FORMATETC, DV_E_TYMED, E_INVALIDARG, E_NOTIMPL, E_OUTOFMEMORY,
+    E_POINTER, HGLOBAL, OLE_E_ADVISENOTSUPPORTED, SIZE, S_FALSE, S_OK,
+};
+use windows::Win32::Graphics::Gdi::HBITMAP;
+use windows::Win32::Graphics::Imaging::{GUID_ContainerFormatBmp, GUID_WICPixelFormat24bppBGR};
+use windows::Win32::System::Com::{
+    IAdviseSink, IDataObject, IDataObject_Impl, IEnumFORMATETC, IEnumSTATDATA, IPersistFile,
+    IPersistFile_Impl, IPersist_Impl, DATADIR_GET, DVASPECT_CONTENT, FORMATETC, STGM, STGMEDIUM,
+    STGMEDIUM_0, STGM_READ, STGM_SHARE_DENY_WRITE, STREAM_SEEK_SET, TYMED_HGLOBAL,
+};
+use windows::Win32::System::Memory::{GlobalAlloc, GlobalLock, GlobalUnlock, GMEM_MOVEABLE};
+use windows::Win32::System::Ole::CF_DIB;
+use windows::Win32::UI::Shell::{
+    IExtractImage, IExtractImage_Impl, SHCreateMemStream, SHCreateStdEnumFmtEtc,
+    SHCreateStreamOnFileEx, SHStrDupW,
+};
+use windows_core::{implement, w, GUID, HRESULT, HSTRING, PCWSTR, PWSTR};
+
+use super::thumbnail_provider::thumbnail_bitmap;
+use crate::com::transcode::TranscodeRequest;
+use crate::com::util::{AccessMode, ComState};
+use crate::com::wic::create_imaging_factory;
+use crate::com::{stream_read_to_end, CoClass};
+use crate::util::guid;
+
+// What IExtractImage hands out if the caller never asks for a size.
+const DEFAULT_IMAGE_SIZE: u32 = 96;
+
+const BMP_FILE_HEADER_SIZE: usize = 14;
+
+// A packed DIB is a BMP file without its file header; the pixels follow the info header, the
+// color masks and the color table, just as in the file.
+fn bmp_to_dib(bmp: &[u8]) -> Option<&[u8]> {
+    if !bmp.starts_with(b"BM") {
+        return None;
+    }
+
+    bmp.get(BMP_FILE_HEADER_SIZE..)
+        .filter(|dib| dib.len() >= 40)
+}
+
+fn dib_format() -> FORMATETC {
+    FORMATETC {
+        cfFormat: CF_DIB.0,
+        ptd: std::ptr::null_mut(),
+        dwAspect: DVASPECT_CONTENT.0,
+        lindex: -1,
+        tymed: TYMED_HGLOBAL.0 as u32,
+    }
+}
+
+fn check_format(format: &FORMATETC) -> HRESULT {
+    if format.cfFormat != CF_DIB.0 || format.dwAspect != DVASPECT_CONTENT.0 {
+        DV_E_FORMATETC
+    } else if format.tymed & TYMED_HGLOBAL.0 as u32 == 0 {
+        DV_E_TYMED
+    } else {
+        S_OK
+    }
+}
+
+fn global_from_bytes(bytes: &[u8]) -> windows::core::Result<HGLOBAL> {
+    unsafe {
+        let global = GlobalAlloc(GMEM_MOVEABLE, bytes.len())?;
+
+        let data = GlobalLock(global);
+        if data.is_null() {
+            _ = GlobalFree(global);
+            return Err(E_OUTOFMEMORY.into());
+        }
+
+        std::ptr::copy_nonoverlapping(bytes.as_ptr(), data.cast::<u8>(), bytes.len());
+        _ = GlobalUnlock(global);
+
+        Ok(global)
+    }
+}
+
+struct DataHandlerData {
+    path: PathBuf,
+}
+
+// Extends the data object Explorer builds for BMX files, e.g. when they are dragged or copied, with
+// the decoded image as CF_DIB, so applications that accept pictures on drop or paste get the image
+// rather than just the file. Also offers the thumbnail through IExtractImage for hosts that still
+// ask for one that way, such as the drag image of older shell views.
+#[derive(Default)]
+#[implement(IPersistFile, IDataObject, IExtractImage)]
+pub struct DataHandler {
+    inner: ComState<DataHandlerData>,
+    image_size: AtomicU32,
+}
+
+impl DataHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Transcoded through the BMP encoder, which writes a file that is a DIB behind a file header.
+    fn dib(&self) -> windows::core::Result<Vec<u8>> {
+        let inner = self.inner.get()?;
+        let target = unsafe { SHCreateMemStream(None) }.ok_or(E_OUTOFMEMORY)?;
+
+        TranscodeRequest {
+            pixel_format: Some(GUID_WICPixelFormat24bppBGR),
+            ..TranscodeRequest::new(inner.path.clone(), target.clone(), GUID_ContainerFormatBmp)
+        }
+        .run(&create_imaging_factory()?)?;
+
+        unsafe { target.Seek(0, STREAM_SEEK_SET, None)? };
+        let bmp = stream_read_to_end(&target)?;
+
+        Ok(bmp_to_dib(&bmp).ok_or(DV_E_FORMATETC)?.to_vec())
+    }
+}
+
+impl CoClass for DataHandler {
+    const CLSID: GUID = guid::from_str("f5bbb6a0-80a7-4057-bcf7-6c35563b9cf3");
+    const PROG_ID: PCWSTR = w!("X16BMX.DataHandler.1");
+    const VERSION_INDEPENDENT_PROG_ID: PCWSTR = w!("X16BMX.DataHandler");
+    const FRIENDLY_NAME: PCWSTR = w!("BMX Data Handler");
+}
+
+impl IPersist_Impl for DataHandler_Impl {
+    fn GetClassID(&self) -> windows::core::Result<GUID> {
+        Ok(DataHandler::CLSID)
+    }
+}
+
+impl IPersistFile_Impl for DataHandler_Impl {
+    fn IsDirty(&self) -> HRESULT {
+        S_FALSE
+    }
+
+    fn Load(&self, file_name: &PCWSTR, mode: STGM) -> windows::core::Result<()> {
+        AccessMode::check(mode.0, false)?;
+
+        if file_name.is_null() {
+            return Err(E_INVALIDARG.into());
+        }
+
+        let path = PathBuf::from(unsafe { file_name.to_string() }.map_err(|_| E_INVALIDARG)?);
+
+        self.inner.ensure_uninitialized()?;
+        self.inner.initialize(DataHandlerData { path })?;
+        Ok(())
+    }
+
+    fn Save(&self, _file_name: &PCWSTR, _remember: BOOL) -> windows::core::Result<()> {
+        Err(E_NOTIMPL.into())
+    }
+
+    fn SaveCompleted(&self, _file_name: &PCWSTR) -> windows::core::Result<()> {
+        Err(E_NOTIMPL.into())
+    }
+
+    fn GetCurFile(&self) -> windows::core::Result<PWSTR> {
+        let inner = self.inner.get()?;
+        unsafe { SHStrDupW(&HSTRING::from(inner.path.as_os_str())) }
+    }
+}
+
+impl IDataObject_Impl for DataHandler_Impl {
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn GetData(&self, format: *const FORMATETC) -> windows::core::Result<STGMEDIUM> {
+        let format = unsafe { format.as_ref() }.ok_or(E_POINTER)?;
+        check_format(format).ok()?;
+
+        let global = global_from_bytes(&self.dib()?)?;
+
+        Ok(STGMEDIUM {
+            tymed: TYMED_HGLOBAL.0 as u32,
+            u: STGMEDIUM_0 { hGlobal: global },
+            pUnkForRelease: Default::default(),
+        })
+    }
+
+    fn GetDataHere(
+        &self,
+        _format: *const FORMATETC,
+        _medium: *mut STGMEDIUM,
+    ) -> windows::core::Result<()> {
+        Err(E_NOTIMPL.into())
+    }
+
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn QueryGetData(&self, format: *const FORMATETC) -> HRESULT {
+        match unsafe { format.as_ref() } {
+            Some(format) => check_format(format),
+            None => E_POINTER,
+        }
+    }
+
+    fn GetCanonicalFormatEtc(&self, _format: *const FORMATETC, _out: *mut FORMATETC) -> HRESULT {
+        E_NOTIMPL
+    }
+
+    fn SetData(
+        &self,
+        _format: *const FORMATETC,
+        _medium: *const STGMEDIUM,
+        _release: BOOL,
+    ) -> windows::core::Result<()> {
+        Err(E_NOTIMPL.into())
+    }
+
+    fn EnumFormatEtc(&self, direction: u32) -> windows::core::Result<IEnumFORMATETC> {
+        if direction != DATADIR_GET.0 as u32 {
+            return Err(E_NOTIMPL.into());
+        }
+
+        unsafe { SHCreateStdEnumFmtEtc(&[dib_format()]) }
+    }
+
+    fn DAdvise(
+        &self,
+        _format: *const FORMATETC,
+        _flags: u32,
+        _sink: Option<&IAdviseSink>,
+    ) -> windows::core::Result<u32> {
+        Err(OLE_E_ADVISENOTSUPPORTED.into())
+    }
+
+    fn DUnadvise(&self, _connection: u32) -> windows::core::Result<()> {
+        Err(OLE_E_ADVISENOTSUPPORTED.into())
+    }
+
+    fn EnumDAdvise(&self) -> windows::core::Result<IEnumSTATDATA> {
+        Err(OLE_E_ADVISENOTSUPPORTED.into())
+    }
+}
+
+impl IExtractImage_Impl for DataHandler_Impl {
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn GetLocation(
+        &self,
+        path_buffer: PWSTR,
+        len: u32,
+        _priority: *mut u32,
+        size: *const SIZE,
+        _color_depth: u32,
+        _flags: *mut u32,
+    ) -> windows::core::Result<()> {
+        let inner = self.inner.get()?;
+
+        // Identifies the image in the host's cache.
+        if !path_buffer.is_null() && len > 0 {
+            let path = HSTRING::from(inner.path.as_os_str());
+            let copied = path.len().min(len as usize - 1);
+
+            unsafe {
+                std::ptr::copy_nonoverlapping(path.as_ptr(), path_buffer.0, copied);
+                path_buffer.0.add(copied).write(0);
+            }
+        }
+
+        if let Some(size) = unsafe { size.as_ref() } {
+            let longest = size.cx.max(size.cy).max(1) as u32;
+            self.image_size.store(longest, Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+
+    fn Extract(&self) -> windows::core::Result<HBITMAP> {
+        let inner = self.inner.get()?;
+
+        let stream = unsafe {
+            SHCreateStreamOnFileEx(
+                &HSTRING::from(inner.path.as_os_str()),
+                (STGM_READ | STGM_SHARE_DENY_WRITE).0,
+                0,
+                false,
+                None,
+            )?
+        };
+
+        let size = match self.image_size.load(Ordering::Relaxed) {
+            0 => DEFAULT_IMAGE_SIZE,
+            size => size,
+        };
+
+        thumbnail_bitmap(&stream, size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_file_header() {
+        let mut bmp = b"BM".to_vec();
+        bmp.extend_from_slice(&[0; 12]);
+        bmp.extend_from_slice(&40u32.to_le_bytes());
+        bmp.extend_from_slice(&[0; 36]);
+
+        let dib = bmp_to_dib(&bmp).unwrap();
+        assert_eq!(dib.len(), 40);
+        assert_eq!(&dib[..4], &40u32.to_le_bytes());
+
+        assert!(bmp_to_dib(&bmp[..20]).is_none());
+        assert!(bmp_to_dib(&[0; 60]).is_none());
+    }
+}
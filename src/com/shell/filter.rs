@@ -0,0 +1,430 @@
+use std::sync::Mutex;
+
+use windows::core::PROPVARIANT;
+use windows::Win32::Foundation::{
+    BOOL, E_INVALIDARG, E_NOTIMPL, E_OUTOFMEMORY, E_POINTER, S_FALSE, S_OK,
+};
+use windows::Win32::Storage::EnhancedStorage::PKEY_Search_Contents;
+use windows::Win32::Storage::IndexServer::{
+    IFilter, IFilter_Impl, CHUNK_EOS, CHUNK_TEXT, CHUNK_VALUE, FILTERREGION,
+    FILTER_E_END_OF_CHUNKS, FILTER_E_NO_MORE_TEXT, FILTER_E_NO_MORE_VALUES, FILTER_E_NO_TEXT,
+    FILTER_E_NO_VALUES, FILTER_S_LAST_TEXT, FULLPROPSPEC, IFILTER_INIT_APPLY_INDEX_ATTRIBUTES,
+    IFILTER_INIT_APPLY_OTHER_ATTRIBUTES, STAT_CHUNK,
+};
+use windows::Win32::System::Com::StructuredStorage::{PROPSPEC, PROPSPEC_0, PRSPEC_PROPID};
+use windows::Win32::System::Com::{
+    CoTaskMemAlloc, IPersistStream, IPersistStream_Impl, IPersist_Impl, IStream, Marshal::IMarshal,
+};
+use windows::Win32::UI::Shell::PropertiesSystem::PROPERTYKEY;
+use windows_core::{implement, w, GUID, HRESULT, PCWSTR, PWSTR};
+
+use super::property_store::PropertyStore;
+use crate::com::util::{impl_free_threaded_marshaler, ComState, FreeThreadedMarshaler};
+use crate::com::CoClass;
+use crate::util::guid;
+
+enum ChunkContent {
+    Text(Vec<u16>),
+    Value(PROPVARIANT),
+}
+
+struct Chunk {
+    key: PROPERTYKEY,
+    content: ChunkContent,
+}
+
+#[derive(Default)]
+struct FilterState {
+    // Indices of the chunks Init asked for, and how far GetChunk, GetText and GetValue got.
+    selected: Vec<usize>,
+    current: Option<usize>,
+    text_offset: usize,
+    value_read: bool,
+}
+
+struct FilterData {
+    chunks: Vec<Chunk>,
+    state: Mutex<FilterState>,
+}
+
+// Hands the indexer the same properties as the property store, plus a short text description
+// with the dimensions and bit depth so full-text searches find BMX files too.
+#[derive(Default)]
+#[implement(IFilter, IPersistStream, IMarshal)]
+pub struct Filter {
+    inner: ComState<FilterData>,
+    marshaler: FreeThreadedMarshaler,
+}
+
+impl Filter {
+    // Referenced by the extension's PersistentHandler key; its PersistentAddinsRegistered key
+    // points the indexer to the filter.
+    pub const PERSISTENT_HANDLER: GUID = guid::from_str("6e1f8a3d-27c4-4b59-9d0e-81a5c3f2b7e6");
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn load(stream: &IStream) -> windows::core::Result<FilterData> {
+        let properties = PropertyStore::read_properties(stream)?;
+        let header = properties.header();
+
+        let text = format!(
+            "{} x {}, {} bpp",
+            header.width, header.height, header.bit_depth
+        );
+
+        let mut chunks = vec![Chunk {
+            key: PKEY_Search_Contents,
+            content: ChunkContent::Text(text.encode_utf16().collect()),
+        }];
+
+        for key in properties.keys() {
+            chunks.push(Chunk {
+                key: *key,
+                content: ChunkContent::Value(properties.value(key)?),
+            });
+        }
+
+        Ok(FilterData {
+            chunks,
+            state: Default::default(),
+        })
+    }
+
+    fn with_current_chunk<F>(&self, op: F) -> HRESULT
+    where
+        F: FnOnce(Option<&Chunk>, &mut FilterState) -> HRESULT,
+    {
+        let inner = match self.inner.get() {
+            Ok(inner) => inner,
+            Err(err) => return err.code(),
+        };
+
+        let mut state = inner.state.lock().unwrap();
+
+        let chunk = state
+            .current
+            .map(|current| &inner.chunks[state.selected[current]]);
+
+        op(chunk, &mut state)
+    }
+}
+
+impl CoClass for Filter {
+    const CLSID: GUID = guid::from_str("b3d6e2a9-5f41-4c8e-a7d2-0c9f64e1b835");
+    const PROG_ID: PCWSTR = w!("X16BMX.Filter.1");
+    const VERSION_INDEPENDENT_PROG_ID: PCWSTR = w!("X16BMX.Filter");
+    const FRIENDLY_NAME: PCWSTR = w!("BMX Filter");
+}
+
+impl_free_threaded_marshaler!(Filter_Impl, marshaler);
+
+impl IFilter_Impl for Filter_Impl {
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn Init(
+        &self,
+        grfflags: u32,
+        cattributes: u32,
+        aattributes: *const FULLPROPSPEC,
+        pflags: *mut u32,
+    ) -> i32 {
+        let inner = match self.inner.get() {
+            Ok(inner) => inner,
+            Err(err) => return err.code().0,
+        };
+
+        let attributes = match (cattributes, aattributes.is_null()) {
+            (0, _) => &[][..],
+            (_, true) => return E_INVALIDARG.0,
+            (count, false) => unsafe { std::slice::from_raw_parts(aattributes, count as usize) },
+        };
+
+        let all_properties = grfflags
+            & (IFILTER_INIT_APPLY_INDEX_ATTRIBUTES.0 | IFILTER_INIT_APPLY_OTHER_ATTRIBUTES.0)
+                as u32
+            != 0;
+
+        // Without an explicit list, only the contents are wanted unless the flags ask for more.
+        let wanted = |key: &PROPERTYKEY| {
+            if attributes.is_empty() {
+                all_properties || *key == PKEY_Search_Contents
+            } else {
+                attributes.iter().any(|attribute| {
+                    attribute.guidPropSet == key.fmtid
+                        && attribute.psProperty.ulKind == PRSPEC_PROPID
+                        && unsafe { attribute.psProperty.Anonymous.propid } == key.pid
+                })
+            }
+        };
+
+        *inner.state.lock().unwrap() = FilterState {
+            selected: (0..inner.chunks.len())
+                .filter(|&i| wanted(&inner.chunks[i].key))
+                .collect(),
+            ..Default::default()
+        };
+
+        if !pflags.is_null() {
+            unsafe { *pflags = 0 };
+        }
+
+        S_OK.0
+    }
+
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn GetChunk(&self, pstat: *mut STAT_CHUNK) -> i32 {
+        if pstat.is_null() {
+            return E_POINTER.0;
+        }
+
+        let inner = match self.inner.get() {
+            Ok(inner) => inner,
+            Err(err) => return err.code().0,
+        };
+
+        let mut state = inner.state.lock().unwrap();
+
+        let next = state.current.map_or(0, |current| current + 1);
+        let Some(&index) = state.selected.get(next) else {
+            return FILTER_E_END_OF_CHUNKS.0;
+        };
+
+        *state = FilterState {
+            selected: std::mem::take(&mut state.selected),
+            current: Some(next),
+            ..Default::default()
+        };
+
+        let chunk = &inner.chunks[index];
+        let id = next as u32 + 1;
+
+        unsafe {
+            *pstat = STAT_CHUNK {
+                idChunk: id,
+                breakType: CHUNK_EOS,
+                flags: match chunk.content {
+                    ChunkContent::Text(_) => CHUNK_TEXT,
+                    ChunkContent::Value(_) => CHUNK_VALUE,
+                },
+                locale: 0,
+                attribute: FULLPROPSPEC {
+                    guidPropSet: chunk.key.fmtid,
+                    psProperty: PROPSPEC {
+                        ulKind: PRSPEC_PROPID,
+                        Anonymous: PROPSPEC_0 {
+                            propid: chunk.key.pid,
+                        },
+                    },
+                },
+                idChunkSource: id,
+                cwcStartSource: 0,
+                cwcLenSource: 0,
+            };
+        }
+
+        S_OK.0
+    }
+
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn GetText(&self, pcwcbuffer: *mut u32, awcbuffer: PWSTR) -> i32 {
+        if pcwcbuffer.is_null() || awcbuffer.is_null() {
+            return E_POINTER.0;
+        }
+
+        self.with_current_chunk(|chunk, state| {
+            let Some(ChunkContent::Text(text)) = chunk.map(|chunk| &chunk.content) else {
+                return FILTER_E_NO_TEXT;
+            };
+
+            let rest = &text[state.text_offset..];
+
+            if rest.is_empty() {
+                return FILTER_E_NO_MORE_TEXT;
+            }
+
+            let count = rest.len().min(unsafe { *pcwcbuffer } as usize);
+
+            unsafe {
+                awcbuffer
+                    .as_ptr()
+                    .copy_from_nonoverlapping(rest.as_ptr(), count);
+                *pcwcbuffer = count as u32;
+            }
+
+            state.text_offset += count;
+
+            if state.text_offset == text.len() {
+                FILTER_S_LAST_TEXT
+            } else {
+                S_OK
+            }
+        })
+        .0
+    }
+
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn GetValue(&self, pppropvalue: *mut *mut PROPVARIANT) -> i32 {
+        if pppropvalue.is_null() {
+            return E_POINTER.0;
+        }
+
+        self.with_current_chunk(|chunk, state| {
+            let Some(ChunkContent::Value(value)) = chunk.map(|chunk| &chunk.content) else {
+                return FILTER_E_NO_VALUES;
+            };
+
+            if state.value_read {
+                return FILTER_E_NO_MORE_VALUES;
+            }
+
+            // The caller frees the value with PropVariantClear and CoTaskMemFree.
+            let buffer =
+                unsafe { CoTaskMemAlloc(std::mem::size_of::<PROPVARIANT>()) }.cast::<PROPVARIANT>();
+
+            if buffer.is_null() {
+                return E_OUTOFMEMORY;
+            }
+
+            unsafe {
+                buffer.write(value.clone());
+                *pppropvalue = buffer;
+            }
+
+            state.value_read = true;
+            S_OK
+        })
+        .0
+    }
+
+    fn BindRegion(
+        &self,
+        _origpos: &FILTERREGION,
+        _riid: *const GUID,
+        _ppunk: *mut *mut core::ffi::c_void,
+    ) -> i32 {
+        E_NOTIMPL.0
+    }
+}
+
+impl IPersist_Impl for Filter_Impl {
+    fn GetClassID(&self) -> windows::core::Result<GUID> {
+        Ok(Filter::CLSID)
+    }
+}
+
+impl IPersistStream_Impl for Filter_Impl {
+    fn IsDirty(&self) -> HRESULT {
+        S_FALSE
+    }
+
+    fn Load(&self, stream: Option<&IStream>) -> windows::core::Result<()> {
+        let stream = stream.ok_or(E_INVALIDARG)?;
+
+        self.inner.ensure_uninitialized()?;
+        self.inner.initialize(Filter::load(stream)?)?;
+
+        Ok(())
+    }
+
+    fn Save(&self, _stream: Option<&IStream>, _clear_dirty: BOOL) -> windows::core::Result<()> {
+        Err(E_NOTIMPL.into())
+    }
+
+    fn GetSizeMax(&self) -> windows::core::Result<u64> {
+        Err(E_NOTIMPL.into())
+    }
+}
+
+#[cfg(all(test, windows))]
+mod tests {
+    use windows::Win32::Storage::EnhancedStorage::{
+        PKEY_Image_Dimensions, PKEY_Image_HorizontalSize,
+    };
+    use windows::Win32::System::Com::CoTaskMemFree;
+    use windows::Win32::System::Variant::{VT_LPWSTR, VT_UI4};
+    use windows::Win32::UI::Shell::SHCreateMemStream;
+    use windows_core::{ComObject, Interface};
+
+    use super::*;
+    use crate::bmx::{BmxImage, PaletteEntry};
+
+    #[test]
+    fn emits_contents_and_properties() {
+        let image =
+            BmxImage::new(320, 2, 8, vec![PaletteEntry::default(); 256], vec![0; 640]).unwrap();
+        let bytes = image.to_bytes(false).unwrap();
+        let stream = unsafe { SHCreateMemStream(Some(&bytes)) }.unwrap();
+
+        let filter: IFilter = ComObject::new(Filter::new()).into_interface();
+
+        let mut text = String::new();
+        let mut values = Vec::new();
+
+        unsafe {
+            filter
+                .cast::<IPersistStream>()
+                .unwrap()
+                .Load(&stream)
+                .unwrap();
+
+            let mut flags = 0;
+            assert_eq!(
+                filter.Init(
+                    IFILTER_INIT_APPLY_INDEX_ATTRIBUTES.0 as u32,
+                    &[],
+                    &mut flags
+                ),
+                S_OK.0
+            );
+
+            loop {
+                let mut chunk = STAT_CHUNK::default();
+
+                match filter.GetChunk(&mut chunk) {
+                    code if code == FILTER_E_END_OF_CHUNKS.0 => break,
+                    code => assert_eq!(code, S_OK.0),
+                }
+
+                let key = PROPERTYKEY {
+                    fmtid: chunk.attribute.guidPropSet,
+                    pid: chunk.attribute.psProperty.Anonymous.propid,
+                };
+
+                if chunk.flags == CHUNK_TEXT {
+                    let mut buffer = [0u16; 4];
+
+                    loop {
+                        let mut len = buffer.len() as u32;
+                        let code = filter.GetText(&mut len, PWSTR(buffer.as_mut_ptr()));
+                        if code == FILTER_E_NO_MORE_TEXT.0 {
+                            break;
+                        }
+
+                        text += &String::from_utf16(&buffer[..len as usize]).unwrap();
+                    }
+                } else {
+                    let mut value = std::ptr::null_mut();
+                    assert_eq!(filter.GetValue(&mut value), S_OK.0);
+                    assert_eq!(filter.GetValue(&mut value), FILTER_E_NO_MORE_VALUES.0);
+
+                    values.push((key, value.read()));
+                    CoTaskMemFree(Some(value.cast()));
+                }
+            }
+        }
+
+        assert_eq!(text, "320 x 2, 8 bpp");
+
+        let value = |key| &values.iter().find(|(k, _)| *k == key).unwrap().1;
+        let vt = |value: &PROPVARIANT| unsafe { value.as_raw().Anonymous.Anonymous.vt };
+
+        // The indexer only picks up values whose type matches the property schema.
+        assert_eq!(vt(value(PKEY_Image_HorizontalSize)), VT_UI4.0);
+        assert_eq!(
+            u32::try_from(value(PKEY_Image_HorizontalSize)).unwrap(),
+            320
+        );
+        assert_eq!(vt(value(PKEY_Image_Dimensions)), VT_LPWSTR.0);
+    }
+}
@@ -4,7 +4,10 @@ use windows::Win32::System::Com::CoTaskMemFree;
 use windows_core::PWSTR;
 
 pub mod command;
+pub mod data_handler;
+pub mod filter;
 pub mod property_store;
+pub mod thumbnail_provider;
 
 pub struct CoTaskMemPWSTR(PWSTR);
 
@@ -1,32 +1,40 @@
-use std::sync::RwLock;
-
 use windows::core::PROPVARIANT;
-use windows::Win32::Foundation::{E_OUTOFMEMORY, S_FALSE};
-use windows::Win32::Storage::EnhancedStorage::{PKEY_Image_Compression, PKEY_MIMEType};
-use windows::Win32::System::Com::CoTaskMemAlloc;
+use windows::Win32::Foundation::{E_OUTOFMEMORY, E_POINTER, S_FALSE};
+use windows::Win32::Storage::EnhancedStorage::{
+    PKEY_Comment, PKEY_Image_Compression, PKEY_MIMEType,
+};
+use windows::Win32::System::Com::{CoTaskMemAlloc, Marshal::IMarshal};
 use windows::Win32::System::Variant::VT_LPWSTR;
 use windows::{
-    core::{implement, w, Interface, HRESULT, PCWSTR},
+    core::{implement, w, PCWSTR},
     Win32::{
-        Foundation::{ERROR_ALREADY_INITIALIZED, E_INVALIDARG, E_UNEXPECTED, STG_E_ACCESSDENIED},
+        Foundation::{E_INVALIDARG, STG_E_ACCESSDENIED},
         Storage::EnhancedStorage::{
             PKEY_Image_BitDepth, PKEY_Image_CompressionText, PKEY_Image_Dimensions,
             PKEY_Image_HorizontalSize, PKEY_Image_VerticalSize,
         },
-        System::Com::{IStream, STGM_READ, STGM_WRITE},
+        System::Com::IStream,
         UI::Shell::PropertiesSystem::{
-            IInitializeWithStream, IInitializeWithStream_Impl, IPropertyStore, IPropertyStoreCache,
+            IInitializeWithStream, IInitializeWithStream_Impl, IPropertyStore,
             IPropertyStoreCapabilities, IPropertyStoreCapabilities_Impl, IPropertyStore_Impl,
-            PSCreateMemoryPropertyStore, PROPERTYKEY, PSC_READONLY,
+            PROPERTYKEY,
         },
     },
 };
 use windows_core::{GUID, HSTRING};
 
+use crate::com::util::{impl_free_threaded_marshaler, AccessMode, ComState, FreeThreadedMarshaler};
 use crate::com::wic::com::MIME_TYPE;
 use crate::com::CoClass;
 use crate::util::guid;
-use crate::{bmx::FileHeader, com::FileHeaderExt};
+use crate::{
+    bmx::{
+        limits::Limits,
+        reader::{BmxReadError, BmxReader},
+        FileHeader, Integrity,
+    },
+    com::{stream_checked_header, BmxReadErrorExt, BmxReaderExt},
+};
 
 fn propvariant_init_lpwstr(string: PCWSTR) -> windows::core::Result<PROPVARIANT> {
     if string.is_null() {
@@ -65,94 +73,163 @@ fn propvariant_init_string<T: AsRef<str>>(string: T) -> windows::core::Result<PR
     propvariant_init_lpwstr(PCWSTR::from_raw(HSTRING::from(string.as_ref()).as_ptr()))
 }
 
-struct PropertyStoreData {
-    properties: IPropertyStoreCache,
-}
+// The BMX-specific properties, described to the property system by SCHEMA so Explorer can show
+// them as columns and in the details pane.
+pub const FMTID_BMX: GUID = guid::from_str("037a4590-4139-4832-b925-a7f409dd65e2");
 
-#[derive(Default)]
-#[implement(IPropertyStore, IPropertyStoreCapabilities, IInitializeWithStream)]
-pub struct PropertyStore {
-    inner: RwLock<Option<PropertyStoreData>>,
-}
+#[allow(non_upper_case_globals)]
+pub const PKEY_BMX_PaletteSize: PROPERTYKEY = PROPERTYKEY {
+    fmtid: FMTID_BMX,
+    pid: 2,
+};
 
-impl PropertyStore {
-    pub const PREVIEW_DETAILS: PCWSTR =
-        w!("prop:System.Image.Dimensions;System.Image.BitDepth;System.Image.Compression");
+#[allow(non_upper_case_globals)]
+pub const PKEY_BMX_PaletteStart: PROPERTYKEY = PROPERTYKEY {
+    fmtid: FMTID_BMX,
+    pid: 3,
+};
 
-    pub fn new() -> Self {
-        Self {
-            inner: RwLock::new(None),
+#[allow(non_upper_case_globals)]
+pub const PKEY_BMX_BorderColor: PROPERTYKEY = PROPERTYKEY {
+    fmtid: FMTID_BMX,
+    pid: 4,
+};
+
+// Written next to the module and registered with the property system.
+pub const SCHEMA: &str = include_str!("bmx.propdesc");
+pub const SCHEMA_FILE_NAME: &str = "bmx_shell.propdesc";
+
+// Every key the store can have, in the order GetAt reports them. Which of them a file actually has
+// depends on its header; see BmxProperties::has.
+const KEYS: [PROPERTYKEY; 11] = [
+    PKEY_MIMEType,
+    PKEY_Image_BitDepth,
+    PKEY_Image_Dimensions,
+    PKEY_Image_HorizontalSize,
+    PKEY_Image_VerticalSize,
+    PKEY_BMX_PaletteSize,
+    PKEY_BMX_PaletteStart,
+    PKEY_BMX_BorderColor,
+    PKEY_Image_Compression,
+    PKEY_Image_CompressionText,
+    PKEY_Comment,
+];
+
+// The properties of a BMX file. Only the header is kept; values are converted to PROPVARIANTs
+// when asked for, as hosts like the indexer often only want one or two of them.
+pub(crate) struct BmxProperties {
+    header: FileHeader,
+    integrity: Integrity,
+}
+
+impl BmxProperties {
+    fn has(&self, key: &PROPERTYKEY) -> bool {
+        if *key == PKEY_Image_CompressionText {
+            self.header.compressed != 0
+        } else if *key == PKEY_Comment {
+            self.integrity != Integrity::Absent
+        } else {
+            KEYS.contains(key)
         }
     }
 
-    fn initialize_from_header(
-        &self,
-        header: FileHeader,
-    ) -> windows::core::Result<IPropertyStoreCache> {
-        let properties = unsafe {
-            let mut property_store = std::ptr::null_mut();
-            PSCreateMemoryPropertyStore(&IPropertyStoreCache::IID, &raw mut property_store)?;
+    pub(crate) fn header(&self) -> &FileHeader {
+        &self.header
+    }
 
-            IPropertyStoreCache::from_raw(property_store)
-        };
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &'static PROPERTYKEY> + '_ {
+        KEYS.iter().filter(|key| self.has(key))
+    }
 
-        macro_rules! set_property {
-            ($key:ident = $value:expr) => {
-                unsafe {
-                    properties.SetValueAndState(&$key, &PROPVARIANT::from($value), PSC_READONLY)?
-                }
-            };
+    // Empty for keys the file doesn't have, as IPropertyStore::GetValue expects.
+    pub(crate) fn value(&self, key: &PROPERTYKEY) -> windows::core::Result<PROPVARIANT> {
+        let header = &self.header;
+
+        if !self.has(key) {
+            Ok(PROPVARIANT::default())
+        } else if *key == PKEY_MIMEType {
+            propvariant_init_lpwstr(MIME_TYPE)
+        } else if *key == PKEY_Image_BitDepth {
+            Ok(PROPVARIANT::from(header.bit_depth as u32))
+        } else if *key == PKEY_Image_Dimensions {
+            propvariant_init_string(format!("{}x{}", header.width, header.height))
+        } else if *key == PKEY_Image_HorizontalSize {
+            Ok(PROPVARIANT::from(header.width as u32))
+        } else if *key == PKEY_Image_VerticalSize {
+            Ok(PROPVARIANT::from(header.height as u32))
+        } else if *key == PKEY_BMX_PaletteSize {
+            Ok(PROPVARIANT::from(header.palette_entry_count() as u32))
+        } else if *key == PKEY_BMX_PaletteStart {
+            Ok(PROPVARIANT::from(header.pal_start as u32))
+        } else if *key == PKEY_BMX_BorderColor {
+            Ok(PROPVARIANT::from(header.vera_border_color as u32))
+        } else if *key == PKEY_Image_Compression {
+            Ok(PROPVARIANT::from(match header.compressed {
+                0 => 1u16,
+                1 => u16::MAX - 1,
+                _ => u16::MAX,
+            }))
+        } else if *key == PKEY_Image_CompressionText {
+            propvariant_init_lpwstr(match header.compressed {
+                1 => w!("LZSA"),
+                _ => w!("Unknown"),
+            })
+        } else {
+            propvariant_init_string(format!("Integrity: {}", self.integrity))
         }
+    }
+}
 
-        macro_rules! set_properties {
-            ($key: ident = $value:expr) => {
-                set_property!($key = $value);
-            };
+#[derive(Default)]
+#[implement(
+    IPropertyStore,
+    IPropertyStoreCapabilities,
+    IInitializeWithStream,
+    IMarshal
+)]
+pub struct PropertyStore {
+    inner: ComState<BmxProperties>,
+    marshaler: FreeThreadedMarshaler,
+}
 
-            ($key: ident = $value:expr, $($rest:tt)*) => {
-                set_property!($key = $value);
-                set_properties!($($rest)*);
-            };
-        }
+impl PropertyStore {
+    // None of the properties can be written yet, so the store only takes streams opened for reading.
+    const WRITABLE: bool = false;
 
-        set_properties!(
-            PKEY_MIMEType = propvariant_init_lpwstr(MIME_TYPE)?,
-            PKEY_Image_BitDepth = header.bit_depth as u32,
-            PKEY_Image_Dimensions =
-                propvariant_init_string(format!("{}x{}", header.width, header.height))?,
-            PKEY_Image_HorizontalSize = header.width as u32,
-            PKEY_Image_VerticalSize = header.height as u32
-        );
-
-        match header.compressed {
-            0 => {
-                set_properties!(PKEY_Image_Compression = 1u16);
-            }
-            1 => {
-                set_properties!(
-                    PKEY_Image_Compression = u16::MAX - 1,
-                    PKEY_Image_CompressionText = propvariant_init_lpwstr(w!("LZSA"))?
-                );
-            }
-            _ => {
-                set_properties!(
-                    PKEY_Image_Compression = u16::MAX,
-                    PKEY_Image_CompressionText = propvariant_init_lpwstr(w!("Unknown"))?
-                );
-            }
-        }
+    pub const PREVIEW_DETAILS: PCWSTR =
+        w!("prop:System.Image.Dimensions;System.Image.BitDepth;System.Image.Compression");
 
-        Ok(properties)
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    fn with_property_store<F, R>(&self, op: F) -> windows::core::Result<R>
-    where
-        F: FnOnce(&IPropertyStoreCache) -> windows::core::Result<R>,
-    {
-        let inner = self.inner.read().unwrap();
-        let inner = inner.as_ref().ok_or(E_UNEXPECTED)?;
+    // Reads the properties of the BMX file in `stream`. The filter emits the same values to the
+    // indexer.
+    pub(crate) fn read_properties(stream: &IStream) -> windows::core::Result<BmxProperties> {
+        let limits = Limits::DEFAULT;
+        stream_checked_header(stream, &limits)?;
+
+        let mut reader = BmxReader::from_stream(stream)?;
+
+        let integrity = if reader.header().crc32().is_some() {
+            // A file cut off before its pixel data still gets its properties, just not a valid
+            // checksum.
+            let max_len = limits.stored_data_len(reader.header());
+            let data = match reader.read_stored_data_up_to(max_len) {
+                Ok(data) => data,
+                Err(BmxReadError::Truncated) => Vec::new(),
+                Err(err) => return Err(err.to_win_error()),
+            };
 
-        op(&inner.properties)
+            reader.header().check_integrity(&data)
+        } else {
+            Integrity::Absent
+        };
+
+        Ok(BmxProperties {
+            header: reader.header().clone(),
+            integrity,
+        })
     }
 }
 
@@ -160,27 +237,32 @@ impl CoClass for PropertyStore {
     const CLSID: GUID = guid::from_str("04f579e2-ace3-481c-81ee-f153ffd42551");
     const PROG_ID: PCWSTR = w!("X16BMX.PropertyStore.1");
     const VERSION_INDEPENDENT_PROG_ID: PCWSTR = w!("X16BMX.PropertyStore");
+    const FRIENDLY_NAME: PCWSTR = w!("BMXPropertyStore");
 }
 
+impl_free_threaded_marshaler!(PropertyStore_Impl, marshaler);
+
 impl IPropertyStore_Impl for PropertyStore_Impl {
     fn GetCount(&self) -> windows::core::Result<u32> {
-        self.with_property_store(|properties| unsafe { properties.GetCount() })
+        Ok(self.inner.get()?.keys().count() as u32)
     }
 
     #[allow(clippy::not_unsafe_ptr_arg_deref)]
     fn GetAt(&self, index: u32, key: *mut PROPERTYKEY) -> windows::core::Result<()> {
-        self.with_property_store(
-            #[allow(clippy::not_unsafe_ptr_arg_deref)]
-            |properties| unsafe { properties.GetAt(index, key) },
-        )
+        if key.is_null() {
+            return Err(E_POINTER.into());
+        }
+
+        let found = self.inner.get()?.keys().nth(index as usize);
+
+        unsafe { key.write(*found.ok_or(E_INVALIDARG)?) };
+        Ok(())
     }
 
     #[allow(clippy::not_unsafe_ptr_arg_deref)]
-    fn GetValue(
-        &self,
-        key: *const PROPERTYKEY,
-    ) -> windows::core::Result<windows::core::PROPVARIANT> {
-        self.with_property_store(|properties| unsafe { properties.GetValue(key) })
+    fn GetValue(&self, key: *const PROPERTYKEY) -> windows::core::Result<PROPVARIANT> {
+        let key = unsafe { key.as_ref() }.ok_or(E_POINTER)?;
+        self.inner.get()?.value(key)
     }
 
     fn SetValue(
@@ -204,23 +286,58 @@ impl IPropertyStoreCapabilities_Impl for PropertyStore_Impl {
 
 impl IInitializeWithStream_Impl for PropertyStore_Impl {
     fn Initialize(&self, stream: Option<&IStream>, grfmode: u32) -> windows::core::Result<()> {
-        if grfmode & (STGM_READ.0 | STGM_WRITE.0) != 0 {
-            return Err(STG_E_ACCESSDENIED.into());
-        }
+        AccessMode::check(grfmode, PropertyStore::WRITABLE)?;
 
         let stream = stream.ok_or(E_INVALIDARG)?;
 
-        let mut inner = self.inner.write().unwrap();
+        self.inner.ensure_uninitialized()?;
 
-        if inner.is_some() {
-            return Err(HRESULT::from_win32(ERROR_ALREADY_INITIALIZED.0).into());
-        }
+        self.inner
+            .initialize(PropertyStore::read_properties(stream)?)?;
 
-        let header = FileHeader::from_stream(stream)?;
-        let properties = self.initialize_from_header(header)?;
+        Ok(())
+    }
+}
 
-        inner.replace(PropertyStoreData { properties });
+#[cfg(test)]
+mod tests {
+    use windows::Win32::Storage::EnhancedStorage::PKEY_Search_Contents;
 
-        Ok(())
+    use super::*;
+    use crate::util::guid::Guid;
+
+    #[test]
+    fn keys_follow_header() {
+        let mut properties = BmxProperties {
+            header: FileHeader::default(),
+            integrity: Integrity::Absent,
+        };
+
+        assert_eq!(properties.keys().count(), KEYS.len() - 2);
+        assert!(!properties.has(&PKEY_Image_CompressionText));
+
+        properties.header.compressed = 1;
+        properties.integrity = Integrity::Ok;
+
+        assert!(properties.keys().eq(KEYS.iter()));
+        assert!(!properties.has(&PKEY_Search_Contents));
+    }
+
+    #[test]
+    fn schema_matches_keys() {
+        let format_id = format!("formatID=\"{}\"", Guid(FMTID_BMX)).to_uppercase();
+
+        for (name, key) in [
+            ("X16BMX.Image.PaletteSize", PKEY_BMX_PaletteSize),
+            ("X16BMX.Image.PaletteStart", PKEY_BMX_PaletteStart),
+            ("X16BMX.Image.BorderColor", PKEY_BMX_BorderColor),
+        ] {
+            let description = format!("name=\"{}\" {} propID=\"{}\"", name, format_id, key.pid);
+            assert!(
+                SCHEMA.to_uppercase().contains(&description.to_uppercase()),
+                "{}",
+                description
+            );
+        }
     }
 }
@@ -0,0 +1,199 @@
+use windows::Win32::Foundation::{E_INVALIDARG, E_POINTER, WINCODEC_ERR_UNKNOWNIMAGEFORMAT};
+use windows::Win32::Graphics::Gdi::{
+    CreateDIBSection, DeleteObject, BITMAPINFO, BITMAPINFOHEADER, BI_RGB, DIB_RGB_COLORS, HBITMAP,
+};
+use windows::Win32::Graphics::Imaging::{
+    GUID_WICPixelFormat32bppBGRA, IWICBitmapSource, WICBitmapDitherTypeNone,
+    WICBitmapInterpolationModeFant, WICBitmapInterpolationModeNearestNeighbor,
+    WICBitmapPaletteTypeCustom, WICDecodeMetadataCacheOnDemand,
+};
+use windows::Win32::System::Com::IStream;
+use windows::Win32::UI::Shell::{
+    BHID_Stream, IInitializeWithItem, IInitializeWithItem_Impl, IShellItem, IThumbnailProvider,
+    IThumbnailProvider_Impl, WTSAT_ARGB, WTS_ALPHATYPE,
+};
+use windows_core::{implement, w, GUID, PCWSTR};
+
+use crate::bmx::limits::Limits;
+use crate::com::util::{AccessMode, ComState};
+use crate::com::wic::create_imaging_factory;
+use crate::com::wic::util::{checked_image_size, checked_u32};
+use crate::com::{create_decoder, stream_checked_header, stream_sniff, CoClass};
+use crate::util::guid;
+
+// Registry values on the ProgID that tell Explorer how to present our thumbnails. Pixel art gets
+// neither a photo border nor the application icon in its corner.
+pub const TREATMENT: PCWSTR = w!("Treatment");
+pub const TREATMENT_NONE: u32 = 0;
+pub const TYPE_OVERLAY: PCWSTR = w!("TypeOverlay");
+
+struct ThumbnailProviderData {
+    item: IShellItem,
+}
+
+// Explorer's thumbnail provider for BMX files. Unlike the generic photo thumbnail provider, it is
+// initialized with the shell item rather than a stream, so Explorer can hand it items from any
+// namespace, and it scales pixel art up without blurring it.
+#[derive(Default)]
+#[implement(IThumbnailProvider, IInitializeWithItem)]
+pub struct ThumbnailProvider {
+    inner: ComState<ThumbnailProviderData>,
+}
+
+impl ThumbnailProvider {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Fits `width` x `height` into a `size` square, keeping the aspect ratio.
+    fn fit(width: u32, height: u32, size: u32) -> (u32, u32) {
+        let longest = width.max(height).max(1) as u64;
+        let scale = |value: u32| ((value as u64 * size as u64 / longest) as u32).max(1);
+
+        (scale(width), scale(height))
+    }
+
+    fn create_bitmap(source: &IWICBitmapSource) -> windows::core::Result<HBITMAP> {
+        let (mut width, mut height) = (0, 0);
+        unsafe { source.GetSize(&raw mut width, &raw mut height)? };
+
+        let stride = checked_u32(checked_image_size(width as usize, 4)?)?;
+        let buffer_len = checked_image_size(stride as usize, height as usize)?;
+
+        let info = BITMAPINFO {
+            bmiHeader: BITMAPINFOHEADER {
+                biSize: std::mem::size_of::<BITMAPINFOHEADER>() as u32,
+                biWidth: width as i32,
+                // Top-down, like the rows WIC copies.
+                biHeight: -(height as i32),
+                biPlanes: 1,
+                biBitCount: 32,
+                biCompression: BI_RGB.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let mut bits = std::ptr::null_mut();
+        let bitmap =
+            unsafe { CreateDIBSection(None, &info, DIB_RGB_COLORS, &raw mut bits, None, 0)? };
+
+        let buffer = unsafe { std::slice::from_raw_parts_mut(bits.cast::<u8>(), buffer_len) };
+
+        if let Err(err) = unsafe { source.CopyPixels(std::ptr::null(), stride, buffer) } {
+            let _ = unsafe { DeleteObject(bitmap) };
+            return Err(err);
+        }
+
+        Ok(bitmap)
+    }
+}
+
+impl CoClass for ThumbnailProvider {
+    const CLSID: GUID = guid::from_str("2caf4ab6-187b-4ae4-be79-0edeeaa8966c");
+    const PROG_ID: PCWSTR = w!("X16BMX.ThumbnailProvider.1");
+    const VERSION_INDEPENDENT_PROG_ID: PCWSTR = w!("X16BMX.ThumbnailProvider");
+    const FRIENDLY_NAME: PCWSTR = w!("BMX Thumbnail Provider");
+}
+
+impl IInitializeWithItem_Impl for ThumbnailProvider_Impl {
+    fn Initialize(&self, item: Option<&IShellItem>, mode: u32) -> windows::core::Result<()> {
+        AccessMode::check(mode, false)?;
+
+        let item = item.ok_or(E_INVALIDARG)?;
+
+        self.inner.ensure_uninitialized()?;
+        self.inner
+            .initialize(ThumbnailProviderData { item: item.clone() })?;
+        Ok(())
+    }
+}
+
+// Decodes the thumbnail of the BMX in `stream` and scales it to fit a `size` square. Shared with the
+// data handler, which hands out the same image through IExtractImage.
+pub fn thumbnail_bitmap(stream: &IStream, size: u32) -> windows::core::Result<HBITMAP> {
+    // Explorer may hand over items whose extension says BMX but whose contents don't.
+    if !stream_sniff(stream)? {
+        return Err(WINCODEC_ERR_UNKNOWNIMAGEFORMAT.into());
+    }
+
+    // The decoder checks the limits as well; refusing the header here spares creating the imaging
+    // factory for it.
+    stream_checked_header(stream, &Limits::DEFAULT)?;
+
+    let imaging_factory = create_imaging_factory()?;
+
+    let decoder = create_decoder();
+    unsafe { decoder.Initialize(stream, WICDecodeMetadataCacheOnDemand)? };
+
+    // Already letterboxed to a square, as configured in the options.
+    let thumbnail = unsafe { decoder.GetThumbnail()? };
+
+    let (mut width, mut height) = (0, 0);
+    unsafe { thumbnail.GetSize(&raw mut width, &raw mut height)? };
+
+    let (scaled_width, scaled_height) = ThumbnailProvider::fit(width, height, size);
+
+    // Enlarging keeps the pixels sharp; shrinking averages them so detail doesn't vanish.
+    let interpolation_mode = if scaled_width >= width {
+        WICBitmapInterpolationModeNearestNeighbor
+    } else {
+        WICBitmapInterpolationModeFant
+    };
+
+    let scaler = unsafe { imaging_factory.CreateBitmapScaler()? };
+    unsafe { scaler.Initialize(&thumbnail, scaled_width, scaled_height, interpolation_mode)? };
+
+    let converter = unsafe { imaging_factory.CreateFormatConverter()? };
+    unsafe {
+        converter.Initialize(
+            &scaler,
+            &GUID_WICPixelFormat32bppBGRA,
+            WICBitmapDitherTypeNone,
+            None,
+            0.0,
+            WICBitmapPaletteTypeCustom,
+        )?
+    };
+
+    ThumbnailProvider::create_bitmap(&converter)
+}
+
+impl IThumbnailProvider_Impl for ThumbnailProvider_Impl {
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn GetThumbnail(
+        &self,
+        size: u32,
+        bitmap: *mut HBITMAP,
+        alpha_type: *mut WTS_ALPHATYPE,
+    ) -> windows::core::Result<()> {
+        if bitmap.is_null() || alpha_type.is_null() {
+            return Err(E_POINTER.into());
+        }
+
+        let inner = self.inner.get()?;
+
+        let stream: IStream = unsafe { inner.item.BindToHandler(None, &BHID_Stream)? };
+        let result = thumbnail_bitmap(&stream, size)?;
+
+        unsafe {
+            bitmap.write(result);
+            alpha_type.write(WTSAT_ARGB);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fits_into_square() {
+        assert_eq!(ThumbnailProvider::fit(320, 240, 96), (96, 72));
+        assert_eq!(ThumbnailProvider::fit(16, 64, 256), (64, 256));
+        assert_eq!(ThumbnailProvider::fit(1000, 1, 32), (32, 1));
+        assert_eq!(ThumbnailProvider::fit(0, 0, 32), (1, 1));
+    }
+}
@@ -0,0 +1,469 @@
+use std::fmt::Display;
+use std::path::PathBuf;
+
+use windows::Win32::Foundation::{
+    ERROR_NO_MORE_ITEMS, E_FAIL, E_INVALIDARG, WINCODEC_ERR_COMPONENTNOTFOUND,
+    WINCODEC_ERR_UNSUPPORTEDOPERATION,
+};
+use windows::Win32::Graphics::Imaging::{
+    IWICBitmapDecoder, IWICBitmapEncoder, IWICBitmapFrameEncode, IWICBitmapSource,
+    IWICImagingFactory, WICBitmapEncoderNoCache, WICBitmapPaletteTypeCustom,
+    WICConvertBitmapSource, WICDecodeMetadataCacheOnDemand, WICRect,
+};
+use windows::Win32::Storage::FileSystem::FILE_ATTRIBUTE_NORMAL;
+use windows::Win32::System::Com::StructuredStorage::PROPBAG2;
+use windows::Win32::System::Com::{
+    IStream, STGM_CREATE, STGM_READ, STGM_SHARE_DENY_WRITE, STGM_SHARE_EXCLUSIVE, STGM_WRITE,
+    STREAM_SEEK_SET,
+};
+use windows::Win32::UI::Shell::{SHCreateStreamOnFileEx, COPYENGINE_E_USER_CANCELLED};
+use windows_core::{Interface, GUID, HRESULT, HSTRING, PWSTR, VARIANT};
+
+use super::stream_tell;
+use super::wic::bit_depth_to_pixel_format;
+use super::wic::com::CONTAINER_FORMAT;
+use crate::settings::{self, Dithering};
+
+pub enum TranscodeError {
+    Win(windows::core::Error),
+    NoFrames,
+    DoesNotSupportMultiframe,
+    Cancelled,
+}
+
+impl Display for TranscodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Win(err) => write!(f, "{}", err),
+            Self::NoFrames => write!(f, "No frames in source"),
+            Self::DoesNotSupportMultiframe => {
+                write!(
+                    f,
+                    "Source has multiple frames, which the encoder does not support."
+                )
+            }
+            Self::Cancelled => write!(f, "Transcoding was cancelled"),
+        }
+    }
+}
+
+impl From<windows::core::Error> for TranscodeError {
+    fn from(err: windows::core::Error) -> Self {
+        Self::Win(err)
+    }
+}
+
+impl From<HRESULT> for TranscodeError {
+    fn from(hr: HRESULT) -> Self {
+        Self::Win(hr.into())
+    }
+}
+
+impl From<TranscodeError> for windows::core::Error {
+    fn from(err: TranscodeError) -> Self {
+        match err {
+            TranscodeError::Win(err) => err,
+            err => windows::core::Error::new(
+                match err {
+                    TranscodeError::NoFrames => HRESULT::from_win32(ERROR_NO_MORE_ITEMS.0),
+                    TranscodeError::DoesNotSupportMultiframe => WINCODEC_ERR_UNSUPPORTEDOPERATION,
+                    TranscodeError::Cancelled => COPYENGINE_E_USER_CANCELLED,
+                    _ => unreachable!(),
+                },
+                err.to_string(),
+            ),
+        }
+    }
+}
+
+// Where a transcode reads from or writes to. Paths are opened when the transcode starts, and target
+// files are created or replaced.
+pub enum TranscodeStream {
+    Path(PathBuf),
+    Stream(IStream),
+}
+
+impl TranscodeStream {
+    fn open_read(&self) -> windows::core::Result<IStream> {
+        match self {
+            Self::Path(path) => unsafe {
+                SHCreateStreamOnFileEx(
+                    &HSTRING::from(path.as_os_str()),
+                    (STGM_READ | STGM_SHARE_DENY_WRITE).0,
+                    0,
+                    false,
+                    None,
+                )
+            },
+            Self::Stream(stream) => Ok(stream.clone()),
+        }
+    }
+
+    fn open_write(&self) -> windows::core::Result<IStream> {
+        match self {
+            Self::Path(path) => unsafe {
+                SHCreateStreamOnFileEx(
+                    &HSTRING::from(path.as_os_str()),
+                    (STGM_CREATE | STGM_WRITE | STGM_SHARE_EXCLUSIVE).0,
+                    FILE_ATTRIBUTE_NORMAL.0,
+                    true,
+                    None,
+                )
+            },
+            Self::Stream(stream) => Ok(stream.clone()),
+        }
+    }
+}
+
+impl From<PathBuf> for TranscodeStream {
+    fn from(path: PathBuf) -> Self {
+        Self::Path(path)
+    }
+}
+
+impl From<IStream> for TranscodeStream {
+    fn from(stream: IStream) -> Self {
+        Self::Stream(stream)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct TranscodeOptions {
+    // Reduces every frame to an optimized palette of `1 << bit_depth` colors first.
+    pub bit_depth: Option<u8>,
+    // Used for bit_depth; the configured dithering if None.
+    pub dithering: Option<Dithering>,
+    // Written to the encoder's property bag for every frame, e.g. ImageQuality for JPEG.
+    pub encoder_options: Vec<(HSTRING, VARIANT)>,
+}
+
+pub struct TranscodeRequest {
+    pub source: TranscodeStream,
+    pub target: TranscodeStream,
+    pub container: GUID,
+    // The encoder picks the closest format it supports; None leaves the choice entirely to it.
+    pub pixel_format: Option<GUID>,
+    pub options: TranscodeOptions,
+}
+
+impl TranscodeRequest {
+    pub fn new(
+        source: impl Into<TranscodeStream>,
+        target: impl Into<TranscodeStream>,
+        container: GUID,
+    ) -> Self {
+        Self {
+            source: source.into(),
+            target: target.into(),
+            container,
+            pixel_format: None,
+            options: TranscodeOptions::default(),
+        }
+    }
+
+    pub fn run(&self, imaging_factory: &IWICImagingFactory) -> Result<(), TranscodeError> {
+        self.run_cancellable(imaging_factory, &|| false)
+    }
+
+    // `is_cancelled` is polled between frames and batches of scanlines.
+    pub fn run_cancellable(
+        &self,
+        imaging_factory: &IWICImagingFactory,
+        is_cancelled: &dyn Fn() -> bool,
+    ) -> Result<(), TranscodeError> {
+        let check_cancelled = || {
+            if is_cancelled() {
+                Err(TranscodeError::Cancelled)
+            } else {
+                Ok(())
+            }
+        };
+
+        let decoder = create_decoder(imaging_factory, &self.source.open_read()?)?;
+
+        let frame_count = unsafe { decoder.GetFrameCount()? };
+        if frame_count < 1 {
+            return Err(TranscodeError::NoFrames);
+        }
+
+        let encoder = create_encoder(imaging_factory, &self.container)?;
+
+        if frame_count > 1 {
+            let encoder_info = unsafe { encoder.GetEncoderInfo()? };
+
+            if unsafe { !encoder_info.DoesSupportMultiframe()?.as_bool() } {
+                return Err(TranscodeError::DoesNotSupportMultiframe);
+            }
+        }
+
+        unsafe {
+            encoder.Initialize(&self.target.open_write()?, WICBitmapEncoderNoCache)?;
+        }
+
+        for i in 0..frame_count {
+            check_cancelled()?;
+
+            // Quantizing converts to an indexed format of its own, so the frame doesn't go through
+            // the default conversion as well and have its colors reduced twice.
+            let frame: IWICBitmapSource = unsafe { decoder.GetFrame(i)? }.cast()?;
+            let frame = match (self.options.bit_depth, &self.pixel_format) {
+                (Some(bit_depth), _) => quantize(
+                    imaging_factory,
+                    &frame,
+                    bit_depth,
+                    self.options.dithering.unwrap_or_else(settings::dithering),
+                )?,
+                (None, Some(pixel_format)) => unsafe {
+                    WICConvertBitmapSource(pixel_format, &frame)?
+                },
+                (None, None) => frame,
+            };
+
+            let mut property_bag = None;
+
+            let frame_encode = unsafe {
+                let mut frame_encode = None;
+                encoder.CreateNewFrame(&raw mut frame_encode, &raw mut property_bag)?;
+                frame_encode.ok_or(E_FAIL)?
+            };
+
+            if let Some(ref property_bag) = property_bag {
+                for (name, value) in &self.options.encoder_options {
+                    let option = PROPBAG2 {
+                        pstrName: PWSTR::from_raw(name.as_ptr().cast_mut()),
+                        ..Default::default()
+                    };
+
+                    unsafe { property_bag.Write(1, &raw const option, value)? };
+                }
+            }
+
+            unsafe {
+                (Interface::vtable(&frame_encode).Initialize)(
+                    Interface::as_raw(&frame_encode),
+                    property_bag
+                        .as_ref()
+                        .map_or(std::ptr::null_mut(), Interface::as_raw),
+                )
+                .ok()?;
+            }
+
+            write_source(&frame_encode, &frame, &check_cancelled)?;
+
+            unsafe {
+                frame_encode.Commit()?;
+            }
+        }
+
+        unsafe {
+            encoder.Commit()?;
+        }
+
+        Ok(())
+    }
+}
+
+// Falls back to the BMX decoder when no registered one fits, so this works without registration.
+fn create_decoder(
+    imaging_factory: &IWICImagingFactory,
+    stream: &IStream,
+) -> windows::core::Result<IWICBitmapDecoder> {
+    let position = stream_tell(stream)?;
+
+    match unsafe {
+        imaging_factory.CreateDecoderFromStream(
+            stream,
+            std::ptr::null(),
+            WICDecodeMetadataCacheOnDemand,
+        )
+    } {
+        Err(err) if err.code() == WINCODEC_ERR_COMPONENTNOTFOUND => {
+            let decoder = super::create_decoder();
+
+            unsafe {
+                stream.Seek(position as i64, STREAM_SEEK_SET, None)?;
+                decoder.Initialize(stream, WICDecodeMetadataCacheOnDemand)?;
+            }
+
+            Ok(decoder)
+        }
+        result => result,
+    }
+}
+
+fn create_encoder(
+    imaging_factory: &IWICImagingFactory,
+    container: &GUID,
+) -> windows::core::Result<IWICBitmapEncoder> {
+    match unsafe { imaging_factory.CreateEncoder(container, std::ptr::null()) } {
+        Err(err)
+            if err.code() == WINCODEC_ERR_COMPONENTNOTFOUND && *container == CONTAINER_FORMAT =>
+        {
+            Ok(super::create_encoder())
+        }
+        result => result,
+    }
+}
+
+// Reduces the source to an optimized palette of `1 << bit_depth` colors, so the BMX encoder gets
+// the requested bit depth instead of whatever the default conversion would produce.
+pub fn quantize(
+    imaging_factory: &IWICImagingFactory,
+    source: &IWICBitmapSource,
+    bit_depth: u8,
+    dithering: Dithering,
+) -> windows::core::Result<IWICBitmapSource> {
+    let pixel_format = bit_depth_to_pixel_format(bit_depth).ok_or(E_INVALIDARG)?;
+
+    unsafe {
+        let palette = imaging_factory.CreatePalette()?;
+        palette.InitializeFromBitmap(source, 1 << bit_depth, false)?;
+
+        let converter = imaging_factory.CreateFormatConverter()?;
+        converter.Initialize(
+            source,
+            &pixel_format,
+            dithering.into(),
+            &palette,
+            0.0,
+            WICBitmapPaletteTypeCustom,
+        )?;
+
+        converter.cast()
+    }
+}
+
+// Equivalent to a single WriteSource call, but split into batches of full-width scanlines so
+// cancellation is noticed while large frames are being encoded.
+fn write_source(
+    frame_encode: &IWICBitmapFrameEncode,
+    source: &IWICBitmapSource,
+    check_cancelled: &dyn Fn() -> Result<(), TranscodeError>,
+) -> Result<(), TranscodeError> {
+    const ROWS_PER_BATCH: u32 = 64;
+
+    let (width, height) = unsafe {
+        let mut width = 0;
+        let mut height = 0;
+        source.GetSize(&raw mut width, &raw mut height)?;
+        (width, height)
+    };
+
+    // Otherwise the first band would fix the frame's size, as WriteSource does before SetSize.
+    let mut pixel_format = unsafe { source.GetPixelFormat()? };
+    unsafe {
+        frame_encode.SetSize(width, height)?;
+        frame_encode.SetPixelFormat(&raw mut pixel_format)?;
+    }
+
+    for y in (0..height).step_by(ROWS_PER_BATCH as _) {
+        check_cancelled()?;
+
+        let rect = WICRect {
+            X: 0,
+            Y: y as _,
+            Width: width as _,
+            Height: ROWS_PER_BATCH.min(height - y) as _,
+        };
+
+        unsafe { frame_encode.WriteSource(source, &raw const rect)? };
+    }
+
+    Ok(())
+}
+
+#[cfg(all(test, windows))]
+mod tests {
+    use windows::Win32::Graphics::Imaging::GUID_ContainerFormatPng;
+    use windows::Win32::System::Com::{CoInitializeEx, COINIT_MULTITHREADED};
+    use windows::Win32::UI::Shell::SHCreateMemStream;
+
+    use super::*;
+    use crate::bmx::{BmxImage, PaletteEntry};
+    use crate::com::stream_read_to_end;
+    use crate::com::wic::create_imaging_factory;
+
+    fn transcode(source: &[u8], container: GUID, options: TranscodeOptions) -> Vec<u8> {
+        let imaging_factory = create_imaging_factory().unwrap();
+        let source = unsafe { SHCreateMemStream(Some(source)) }.unwrap();
+        let target = unsafe { SHCreateMemStream(None) }.unwrap();
+
+        let request = TranscodeRequest {
+            options,
+            ..TranscodeRequest::new(source, target.clone(), container)
+        };
+
+        assert!(request.run(&imaging_factory).is_ok());
+
+        unsafe { target.Seek(0, STREAM_SEEK_SET, None) }.unwrap();
+        stream_read_to_end(&target).unwrap()
+    }
+
+    #[test]
+    fn round_trips_through_png() {
+        let _ = unsafe { CoInitializeEx(None, COINIT_MULTITHREADED) };
+
+        let mut palette = vec![PaletteEntry::default(); 256];
+        palette[1] = PaletteEntry::from_wic(0xFFFFFFFF);
+
+        let data = (0..32u8).map(|i| i % 2).collect::<Vec<_>>();
+        let image = BmxImage::new(8, 4, 8, palette, data).unwrap();
+
+        let png = transcode(
+            &image.to_bytes(false).unwrap(),
+            GUID_ContainerFormatPng,
+            TranscodeOptions::default(),
+        );
+
+        let bmx = transcode(
+            &png,
+            CONTAINER_FORMAT,
+            TranscodeOptions {
+                bit_depth: Some(1),
+                dithering: Some(Dithering::None),
+                ..Default::default()
+            },
+        );
+
+        let result = BmxImage::from_bytes(&bmx).unwrap();
+        assert_eq!(result.header.bit_depth, 1);
+        assert_eq!((result.header.width, result.header.height), (8, 4));
+        assert_eq!(
+            result.to_bgra().chunks_exact(4).collect::<Vec<_>>(),
+            image.to_bgra().chunks_exact(4).collect::<Vec<_>>()
+        );
+    }
+
+    // Taller than one batch of scanlines, so the frame must be sized before the first one.
+    #[test]
+    fn transcodes_images_taller_than_a_batch() {
+        let _ = unsafe { CoInitializeEx(None, COINIT_MULTITHREADED) };
+
+        let palette = (0..4)
+            .map(|i| PaletteEntry::from_rgb(i * 0x50, 0xF0 - i * 0x50, i * 0x30))
+            .chain(std::iter::repeat_n(PaletteEntry::default(), 252))
+            .collect();
+        let data = (0..16 * 200).map(|i| (i % 7 % 4) as u8).collect::<Vec<_>>();
+        let image = BmxImage::new(16, 200, 8, palette, data).unwrap();
+
+        let png = transcode(
+            &image.to_bytes(false).unwrap(),
+            GUID_ContainerFormatPng,
+            TranscodeOptions::default(),
+        );
+
+        let bmx = transcode(
+            &png,
+            CONTAINER_FORMAT,
+            TranscodeOptions {
+                bit_depth: Some(2),
+                dithering: Some(Dithering::None),
+                ..Default::default()
+            },
+        );
+
+        let result = BmxImage::from_bytes(&bmx).unwrap();
+        assert_eq!((result.header.width, result.header.height), (16, 200));
+        assert_eq!(result.to_bgra(), image.to_bgra());
+    }
+}
@@ -1 +1,242 @@
+use std::sync::OnceLock;
 
+use windows::Win32::Foundation::{
+    ERROR_ALREADY_INITIALIZED, E_UNEXPECTED, STG_E_ACCESSDENIED, STG_E_INVALIDFLAG,
+};
+use windows::Win32::System::Com::{
+    CoCreateFreeThreadedMarshaler, Marshal::IMarshal, STGM_READ, STGM_READWRITE, STGM_WRITE,
+};
+use windows_core::{Interface, HRESULT};
+
+// State set up once by an `Initialize`-style method. Reads don't take a lock and a panicking
+// method can't poison it; anything that changes after initialization needs its own lock inside `T`.
+pub struct ComState<T>(OnceLock<T>);
+
+impl<T> Default for ComState<T> {
+    fn default() -> Self {
+        Self(OnceLock::new())
+    }
+}
+
+impl<T> From<T> for ComState<T> {
+    fn from(value: T) -> Self {
+        Self(OnceLock::from(value))
+    }
+}
+
+impl<T> ComState<T> {
+    pub fn get(&self) -> windows::core::Result<&T> {
+        self.0
+            .get()
+            .ok_or_else(|| windows::core::Error::new(E_UNEXPECTED, "Object is not initialized"))
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        self.0.get().is_some()
+    }
+
+    // Lets `Initialize` fail early, before doing any work; `initialize` still catches a racing call.
+    pub fn ensure_uninitialized(&self) -> windows::core::Result<()> {
+        if self.is_initialized() {
+            Err(HRESULT::from_win32(ERROR_ALREADY_INITIALIZED.0).into())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn initialize(&self, value: T) -> windows::core::Result<&T> {
+        self.0
+            .set(value)
+            .map_err(|_| HRESULT::from_win32(ERROR_ALREADY_INITIALIZED.0))?;
+
+        self.get()
+    }
+}
+
+// The access an `Initialize` or `Load` call asks for in its STGM mode. Sharing and creation flags
+// are ignored, as they only matter to whoever opened the stream or file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessMode {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl AccessMode {
+    const MASK: u32 = 0b11;
+
+    pub fn from_stgm(mode: u32) -> windows::core::Result<Self> {
+        match mode & Self::MASK {
+            access if access == STGM_READ.0 => Ok(Self::Read),
+            access if access == STGM_WRITE.0 => Ok(Self::Write),
+            access if access == STGM_READWRITE.0 => Ok(Self::ReadWrite),
+            _ => Err(STG_E_INVALIDFLAG.into()),
+        }
+    }
+
+    // Anything but read access is denied unless the handler can write.
+    pub fn check(mode: u32, writable: bool) -> windows::core::Result<Self> {
+        match Self::from_stgm(mode)? {
+            Self::Read => Ok(Self::Read),
+            _ if !writable => Err(STG_E_ACCESSDENIED.into()),
+            access => Ok(access),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct FreeThreadedMarshaler(OnceLock<IMarshal>);
+
+impl FreeThreadedMarshaler {
+    pub fn get(&self) -> windows::core::Result<&IMarshal> {
+        if let Some(marshaler) = self.0.get() {
+            return Ok(marshaler);
+        }
+
+        let marshaler: IMarshal = unsafe { CoCreateFreeThreadedMarshaler(None)? }.cast()?;
+        Ok(self.0.get_or_init(|| marshaler))
+    }
+}
+
+// Forwards IMarshal to the free-threaded marshaler stored in `$field`. Only use this for classes
+// whose state is fully guarded by their own locks.
+macro_rules! impl_free_threaded_marshaler {
+    ($impl:ty, $field:ident) => {
+        #[allow(clippy::not_unsafe_ptr_arg_deref)]
+        impl windows::Win32::System::Com::Marshal::IMarshal_Impl for $impl {
+            fn GetUnmarshalClass(
+                &self,
+                riid: *const windows_core::GUID,
+                pv: *const core::ffi::c_void,
+                dwdestcontext: u32,
+                pvdestcontext: *const core::ffi::c_void,
+                mshlflags: u32,
+            ) -> windows::core::Result<windows_core::GUID> {
+                unsafe {
+                    self.$field.get()?.GetUnmarshalClass(
+                        riid,
+                        Some(pv),
+                        dwdestcontext,
+                        Some(pvdestcontext),
+                        mshlflags,
+                    )
+                }
+            }
+
+            fn GetMarshalSizeMax(
+                &self,
+                riid: *const windows_core::GUID,
+                pv: *const core::ffi::c_void,
+                dwdestcontext: u32,
+                pvdestcontext: *const core::ffi::c_void,
+                mshlflags: u32,
+            ) -> windows::core::Result<u32> {
+                unsafe {
+                    self.$field.get()?.GetMarshalSizeMax(
+                        riid,
+                        Some(pv),
+                        dwdestcontext,
+                        Some(pvdestcontext),
+                        mshlflags,
+                    )
+                }
+            }
+
+            fn MarshalInterface(
+                &self,
+                pstm: Option<&windows::Win32::System::Com::IStream>,
+                riid: *const windows_core::GUID,
+                pv: *const core::ffi::c_void,
+                dwdestcontext: u32,
+                pvdestcontext: *const core::ffi::c_void,
+                mshlflags: u32,
+            ) -> windows::core::Result<()> {
+                unsafe {
+                    self.$field.get()?.MarshalInterface(
+                        pstm,
+                        riid,
+                        Some(pv),
+                        dwdestcontext,
+                        Some(pvdestcontext),
+                        mshlflags,
+                    )
+                }
+            }
+
+            fn UnmarshalInterface(
+                &self,
+                pstm: Option<&windows::Win32::System::Com::IStream>,
+                riid: *const windows_core::GUID,
+                ppv: *mut *mut core::ffi::c_void,
+            ) -> windows::core::Result<()> {
+                unsafe { self.$field.get()?.UnmarshalInterface(pstm, riid, ppv) }
+            }
+
+            fn ReleaseMarshalData(
+                &self,
+                pstm: Option<&windows::Win32::System::Com::IStream>,
+            ) -> windows::core::Result<()> {
+                unsafe { self.$field.get()?.ReleaseMarshalData(pstm) }
+            }
+
+            fn DisconnectObject(&self, dwreserved: u32) -> windows::core::Result<()> {
+                unsafe { self.$field.get()?.DisconnectObject(dwreserved) }
+            }
+        }
+    };
+}
+
+pub(crate) use impl_free_threaded_marshaler;
+
+#[cfg(all(test, windows))]
+mod tests {
+    use windows::Win32::System::Com::{STGM, STGM_SHARE_DENY_WRITE, STGM_SHARE_EXCLUSIVE};
+
+    use super::*;
+
+    #[test]
+    fn com_state_initializes_once() {
+        let state = ComState::default();
+        assert_eq!(state.get().unwrap_err().code(), E_UNEXPECTED);
+        assert!(state.ensure_uninitialized().is_ok());
+
+        assert_eq!(*state.initialize(1).unwrap(), 1);
+        assert_eq!(
+            state.initialize(2).unwrap_err().code(),
+            HRESULT::from_win32(ERROR_ALREADY_INITIALIZED.0)
+        );
+        assert!(state.ensure_uninitialized().is_err());
+        assert_eq!(*state.get().unwrap(), 1);
+    }
+
+    #[test]
+    fn access_modes() {
+        let read_only = |mode: STGM| AccessMode::check(mode.0, false);
+
+        assert_eq!(read_only(STGM_READ).unwrap(), AccessMode::Read);
+        assert_eq!(
+            read_only(STGM_READ | STGM_SHARE_DENY_WRITE).unwrap(),
+            AccessMode::Read
+        );
+        assert_eq!(
+            read_only(STGM_WRITE).unwrap_err().code(),
+            STG_E_ACCESSDENIED
+        );
+        assert_eq!(
+            read_only(STGM_READWRITE | STGM_SHARE_EXCLUSIVE)
+                .unwrap_err()
+                .code(),
+            STG_E_ACCESSDENIED
+        );
+        assert_eq!(read_only(STGM(3)).unwrap_err().code(), STG_E_INVALIDFLAG);
+
+        assert_eq!(
+            AccessMode::check(STGM_READWRITE.0, true).unwrap(),
+            AccessMode::ReadWrite
+        );
+        assert_eq!(
+            AccessMode::check(STGM_WRITE.0, true).unwrap(),
+            AccessMode::Write
+        );
+    }
+}
@@ -9,18 +9,30 @@ use windows::{
         System::Com::{IClassFactory, IClassFactory_Impl},
     },
 };
-use windows_core::HRESULT;
+use windows_core::{IUnknown, Interface, HRESULT};
 
 static LOCK_COUNT: AtomicUsize = AtomicUsize::new(0);
 
 #[implement(IClassFactory)]
 pub struct ClassFactory {
     constructor: fn(*const GUID, *mut *mut c_void) -> HRESULT,
+    aggregating_constructor: Option<fn(&IUnknown, *const GUID, *mut *mut c_void) -> HRESULT>,
 }
 
 impl ClassFactory {
     pub fn new(constructor: fn(*const GUID, *mut *mut c_void) -> HRESULT) -> Self {
-        Self { constructor }
+        Self {
+            constructor,
+            aggregating_constructor: None,
+        }
+    }
+
+    pub fn with_aggregation(
+        mut self,
+        constructor: fn(&IUnknown, *const GUID, *mut *mut c_void) -> HRESULT,
+    ) -> Self {
+        self.aggregating_constructor = Some(constructor);
+        self
     }
 }
 
@@ -32,10 +44,6 @@ impl IClassFactory_Impl for ClassFactory_Impl {
         iid: *const GUID,
         ppv: *mut *mut core::ffi::c_void,
     ) -> windows::core::Result<()> {
-        if outer.is_some() {
-            return Err(CLASS_E_NOAGGREGATION.into());
-        }
-
         if iid.is_null() {
             return Err(E_POINTER.into());
         }
@@ -44,7 +52,16 @@ impl IClassFactory_Impl for ClassFactory_Impl {
             return Err(E_POINTER.into());
         }
 
-        (self.constructor)(iid, ppv).ok()
+        match outer {
+            Some(outer) => match self.aggregating_constructor {
+                // An aggregated object can only hand its non-delegating IUnknown to the outer one.
+                Some(constructor) if unsafe { *iid } == IUnknown::IID => {
+                    constructor(outer, iid, ppv).ok()
+                }
+                _ => Err(CLASS_E_NOAGGREGATION.into()),
+            },
+            None => (self.constructor)(iid, ppv).ok(),
+        }
     }
 
     fn LockServer(&self, flock: BOOL) -> windows::core::Result<()> {
@@ -0,0 +1,119 @@
+use windows::Win32::Graphics::Imaging::{
+    IWICBitmapCodecInfo, IWICImagingFactory, WICComponentEnumerateDefault, WICComponentType,
+    WICDecoder, WICEncoder,
+};
+use windows_core::GUID;
+
+use super::{get_component_iterator, pixel_format_is_known, read_counted_buffer};
+use crate::util::wstr;
+
+// What WIC reports about an installed decoder or encoder, read once into Rust types.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CodecInfo {
+    pub clsid: GUID,
+    pub friendly_name: String,
+    // With the leading dot, in the order the codec lists them; the first one is its default.
+    pub extensions: Vec<String>,
+    pub mime_types: Vec<String>,
+    pub pixel_formats: Vec<GUID>,
+    pub container: GUID,
+}
+
+// Splits one of WIC's comma-separated, nul-terminated lists.
+fn split_list(list: &[u16]) -> Vec<String> {
+    wstr::split(list, ',')
+        .map(|part| String::from_utf16_lossy(part).trim().to_owned())
+        .filter(|part| !part.is_empty())
+        .collect()
+}
+
+impl CodecInfo {
+    pub fn from_codec_info(codec: &IWICBitmapCodecInfo) -> windows::core::Result<Self> {
+        let friendly_name =
+            read_counted_buffer(|buffer, actual| unsafe { codec.GetFriendlyName(buffer, actual) })?;
+        let extensions = read_counted_buffer(|buffer, actual| unsafe {
+            codec.GetFileExtensions(buffer, actual)
+        })?;
+        let mime_types =
+            read_counted_buffer(|buffer, actual| unsafe { codec.GetMimeTypes(buffer, actual) })?;
+        let pixel_formats =
+            read_counted_buffer(|buffer, actual| unsafe { codec.GetPixelFormats(buffer, actual) })?;
+
+        Ok(Self {
+            clsid: unsafe { codec.GetCLSID()? },
+            friendly_name: String::from_utf16_lossy(wstr::until_nul(&friendly_name)),
+            extensions: split_list(&extensions),
+            mime_types: split_list(&mime_types),
+            pixel_formats,
+            container: unsafe { codec.GetContainerFormat()? },
+        })
+    }
+
+    pub fn default_extension(&self) -> Option<&str> {
+        self.extensions.first().map(String::as_str)
+    }
+
+    pub fn has_mime_type(&self, mime_type: &str) -> bool {
+        self.mime_types
+            .iter()
+            .any(|codec_mime_type| codec_mime_type.eq_ignore_ascii_case(mime_type))
+    }
+
+    // Pixel formats we can convert from or to; codecs without any are of no use to us.
+    pub fn known_pixel_formats(&self) -> impl Iterator<Item = &GUID> {
+        self.pixel_formats
+            .iter()
+            .filter(|f| pixel_format_is_known(f))
+    }
+}
+
+// Codecs whose information can't be read are skipped.
+fn enumerate_codecs(
+    imaging_factory: &IWICImagingFactory,
+    component_type: WICComponentType,
+) -> windows::core::Result<Vec<CodecInfo>> {
+    Ok(get_component_iterator::<IWICBitmapCodecInfo>(
+        imaging_factory,
+        component_type,
+        WICComponentEnumerateDefault,
+    )?
+    .filter_map(|codec| CodecInfo::from_codec_info(&codec.ok()?).ok())
+    .collect())
+}
+
+pub fn enumerate_decoders(
+    imaging_factory: &IWICImagingFactory,
+) -> windows::core::Result<Vec<CodecInfo>> {
+    enumerate_codecs(imaging_factory, WICDecoder)
+}
+
+pub fn enumerate_encoders(
+    imaging_factory: &IWICImagingFactory,
+) -> windows::core::Result<Vec<CodecInfo>> {
+    enumerate_codecs(imaging_factory, WICEncoder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_lists() {
+        let list = ".jpg,.jpeg, .jfif,\0".encode_utf16().collect::<Vec<_>>();
+        assert_eq!(split_list(&list), [".jpg", ".jpeg", ".jfif"]);
+        assert!(split_list(&[0]).is_empty());
+
+        let codec = CodecInfo {
+            clsid: GUID::zeroed(),
+            friendly_name: String::new(),
+            extensions: split_list(&list),
+            mime_types: vec!["image/jpeg".to_owned()],
+            pixel_formats: Vec::new(),
+            container: GUID::zeroed(),
+        };
+
+        assert_eq!(codec.default_extension(), Some(".jpg"));
+        assert!(codec.has_mime_type("Image/JPEG"));
+        assert!(!codec.has_mime_type("image/png"));
+    }
+}
@@ -1,5 +1,11 @@
+use windows::Win32::Graphics::Imaging::{
+    GUID_WICPixelFormat1bppIndexed, GUID_WICPixelFormat2bppIndexed, GUID_WICPixelFormat4bppIndexed,
+    GUID_WICPixelFormat8bppIndexed,
+};
 use windows_core::{w, GUID, PCWSTR};
 
+use crate::util::wstr;
+
 pub const VENDOR: GUID = GUID::from_values(
     0x9cac5e90,
     0xf9e5,
@@ -21,9 +27,118 @@ pub const FORMAT: GUID = GUID::from_values(
     [0xa9, 0xaf, 0x01, 0x26, 0x16, 0x2d, 0x38, 0x39],
 );
 
+// Raw VERA data read by the raw decoder.
+pub const RAW_CONTAINER_FORMAT: GUID = GUID::from_values(
+    0x3b8e6f12,
+    0x5d4a,
+    0x4c7e,
+    [0x9a, 0x61, 0x2f, 0x0b, 0xd8, 0x47, 0xc3, 0x95],
+);
+
+// Metadata block holding the reserved header bytes.
+pub const RESERVED_METADATA_FORMAT: GUID = GUID::from_values(
+    0xec6deb0e,
+    0x75cd,
+    0x4c13,
+    [0x9c, 0x33, 0x10, 0x8d, 0x31, 0x4d, 0x11, 0xff],
+);
+
+pub const AUTHOR: PCWSTR = w!("Fulgen");
+
+const fn version_part(value: &str) -> u16 {
+    let bytes = value.as_bytes();
+    let mut part = 0u16;
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(bytes[i].is_ascii_digit());
+        part = part * 10 + (bytes[i] - b'0') as u16;
+        i += 1;
+    }
+
+    part
+}
+
+// The crate version as major, minor, build and revision, the way DllGetVersion and WIC's
+// component info report it.
+pub const VERSION_PARTS: [u16; 4] = [
+    version_part(env!("CARGO_PKG_VERSION_MAJOR")),
+    version_part(env!("CARGO_PKG_VERSION_MINOR")),
+    version_part(env!("CARGO_PKG_VERSION_PATCH")),
+    0,
+];
+
+const VERSION_STRING: &str = concat!(
+    env!("CARGO_PKG_VERSION_MAJOR"),
+    ".",
+    env!("CARGO_PKG_VERSION_MINOR"),
+    ".",
+    env!("CARGO_PKG_VERSION_PATCH"),
+    ".0"
+);
+const VERSION_WIDE: &[u16; VERSION_STRING.len() + 1] = &wstr::encode_ascii_with_nul(VERSION_STRING);
+
+pub const VERSION: PCWSTR = PCWSTR::from_raw(VERSION_WIDE.as_ptr());
+// Tracks the codec's own releases, so support tooling can tell builds apart by either value.
+pub const SPEC_VERSION: PCWSTR = VERSION;
+pub const COLOR_MANAGEMENT_VERSION: PCWSTR = w!("1.0.0.0");
+
+pub const SUPPORTS_ANIMATION: bool = false;
+pub const SUPPORTS_CHROMAKEY: bool = false;
+pub const SUPPORTS_LOSSLESS: bool = true;
+pub const SUPPORTS_MULTIFRAME: bool = false;
+
+pub const PIXEL_FORMATS: [GUID; 4] = [
+    GUID_WICPixelFormat1bppIndexed,
+    GUID_WICPixelFormat2bppIndexed,
+    GUID_WICPixelFormat4bppIndexed,
+    GUID_WICPixelFormat8bppIndexed,
+];
+// The deepest of PIXEL_FORMATS.
+pub const BITS_PER_PIXEL: u32 = 8;
+
 pub const MIME_TYPE: PCWSTR = w!("image/vnd.X16BMX.bmx");
 
+pub const RAW_EXTENSION: PCWSTR = w!(".bin");
+
 pub const PROG_ID: PCWSTR = w!("bmxfile");
 pub const EXTENSION: PCWSTR = w!(".bmx");
 pub const PREVIEW_DETAILS: PCWSTR =
     w!("prop:System.Image.Dimensions;System.Image.BitDepth;System.Image.Compression");
+// The details pane and the property sheet's Details tab.
+pub const FULL_DETAILS: PCWSTR = w!("prop:System.PropGroup.Image;System.Image.Dimensions;System.Image.HorizontalSize;System.Image.VerticalSize;System.Image.BitDepth;System.Image.Compression;X16BMX.Image.PaletteSize;X16BMX.Image.PaletteStart;X16BMX.Image.BorderColor;System.PropGroup.FileSystem;System.ItemNameDisplay;System.ItemTypeText;System.ItemFolderPathDisplay;System.Size;System.DateCreated;System.DateModified;System.FileAttributes;System.OfflineAvailability;System.OfflineStatus;System.SharedWith;System.FileOwner;System.ComputerName");
+pub const INFO_TIP: PCWSTR =
+    w!("prop:System.ItemTypeText;System.Image.Dimensions;System.Image.BitDepth;System.Size;System.DateModified");
+
+// Breaks ties between decoders claiming the same extension or pattern. Higher wins; the built-in
+// codecs leave it unset.
+pub const ARBITRATION_PRIORITY: u32 = 10;
+
+// What the Photos app and Windows Photo Viewer register images under, so BMX files show up in
+// their Open with entries.
+pub const PHOTOS_PROG_ID: PCWSTR = w!("AppX43hnxtbyyps62jhe9sqpdzxn1790zetc");
+pub const PHOTO_VIEWER_PROG_ID: PCWSTR = w!("PhotoViewer.FileAssoc.Tiff");
+pub const PHOTO_VIEWER_FILE_ASSOCIATIONS: PCWSTR =
+    w!("Software\\Microsoft\\Windows Photo Viewer\\Capabilities\\FileAssociations");
+
+pub const APPLICATION_NAME: PCWSTR = w!("X16 BMX");
+pub const APPLICATION_DESCRIPTION: PCWSTR =
+    w!("Windows Imaging Component codec and Explorer integration for Commander X16 BMX images");
+pub const CAPABILITIES: PCWSTR = w!("Software\\X16BMX\\BMX\\Capabilities");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_crate_version() {
+        assert_eq!(
+            VERSION_PARTS.map(|part| part.to_string()).join("."),
+            VERSION_STRING
+        );
+        assert_eq!(
+            String::from_utf16(wstr::until_nul(VERSION_WIDE)).unwrap(),
+            VERSION_STRING
+        );
+        assert_eq!(VERSION_WIDE.last(), Some(&0));
+    }
+}
@@ -0,0 +1,485 @@
+// A subset of the WIC codec conformance guidelines, run in-process against our own decoder and
+// encoder: QueryCapability contract, CopyPixels rectangle semantics and pixel format negotiation.
+
+use std::fmt::Display;
+
+use windows::Win32::Foundation::{E_INVALIDARG, WINCODEC_ERR_INSUFFICIENTBUFFER};
+use windows::Win32::Graphics::Imaging::{
+    GUID_WICPixelFormat32bppBGRA, IWICBitmapDecoder, IWICBitmapEncoder, IWICBitmapFrameDecode,
+    IWICImagingFactory, WICBitmapDecoderCapabilityCanDecodeAllImages, WICBitmapEncoderNoCache,
+    WICDecodeMetadataCacheOnDemand, WICRect,
+};
+use windows::Win32::System::Com::{IStream, STREAM_SEEK_SET};
+use windows::Win32::UI::Shell::SHCreateMemStream;
+use windows_core::{ComObject, HRESULT};
+
+use super::com::{CONTAINER_FORMAT, PIXEL_FORMATS};
+use super::decoder::{frame_height, sprite_height_setting, BitmapDecoder};
+use super::encoder::BitmapEncoder;
+use super::util::{bit_depth_to_pixel_format, bytes_per_line};
+use crate::bmx::{BmxImage, PaletteEntry};
+use crate::com::{stream_read_to_end, stream_tell};
+
+const WIDTH: u16 = 13;
+const HEIGHT: u16 = 5;
+const BIT_DEPTHS: [u8; 4] = [1, 2, 4, 8];
+
+pub struct CheckResult {
+    pub name: String,
+    pub outcome: Result<(), String>,
+}
+
+impl Display for CheckResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.outcome {
+            Ok(()) => write!(f, "[PASS] {}", self.name),
+            Err(ref reason) => write!(f, "[FAIL] {}: {}", self.name, reason),
+        }
+    }
+}
+
+type Check = Result<(), String>;
+
+fn ensure(condition: bool, reason: impl FnOnce() -> String) -> Check {
+    if condition {
+        Ok(())
+    } else {
+        Err(reason())
+    }
+}
+
+fn expect_error<T>(result: windows::core::Result<T>, code: HRESULT) -> Check {
+    match result {
+        Ok(_) => Err(format!("succeeded, expected {}", code)),
+        Err(err) if err.code() == code => Ok(()),
+        Err(err) => Err(format!("returned {}, expected {}", err.code(), code)),
+    }
+}
+
+fn win(err: impl std::fmt::Display) -> String {
+    err.to_string()
+}
+
+fn test_image(bit_depth: u8) -> BmxImage {
+    let colors = 1usize << bit_depth;
+    let palette = (0..colors)
+        .map(|i| PaletteEntry::from_rgb((i * 16) as u8, (i * 48) as u8, (i * 112) as u8))
+        .collect();
+
+    let line_len = bytes_per_line(WIDTH, bit_depth) as usize;
+    let mut data = vec![0u8; line_len * HEIGHT as usize];
+
+    for y in 0..HEIGHT as usize {
+        for x in 0..WIDTH as usize {
+            let index = ((x * 3 + y * 5) % colors) as u8;
+            let bit = x * bit_depth as usize;
+            data[y * line_len + bit / 8] |= index << (8 - bit_depth as usize - bit % 8);
+        }
+    }
+
+    BmxImage::new(WIDTH, HEIGHT, bit_depth, palette, data).unwrap()
+}
+
+fn index_at(data: &[u8], stride: usize, x: usize, y: usize, bit_depth: u8) -> u8 {
+    let bit = x * bit_depth as usize;
+    let mask = ((1u16 << bit_depth) - 1) as u8;
+    (data[y * stride + bit / 8] >> (8 - bit_depth as usize - bit % 8)) & mask
+}
+
+fn memory_stream(bytes: &[u8]) -> windows::core::Result<IStream> {
+    unsafe { SHCreateMemStream(Some(bytes)) }.ok_or_else(windows::core::Error::from_win32)
+}
+
+fn decoder(bytes: &[u8]) -> windows::core::Result<IWICBitmapDecoder> {
+    let decoder: IWICBitmapDecoder = ComObject::new(BitmapDecoder::new()).into_interface();
+    unsafe { decoder.Initialize(&memory_stream(bytes)?, WICDecodeMetadataCacheOnDemand)? };
+    Ok(decoder)
+}
+
+fn frame(bytes: &[u8]) -> windows::core::Result<IWICBitmapFrameDecode> {
+    unsafe { decoder(bytes)?.GetFrame(0) }
+}
+
+fn query_capability(image: &BmxImage) -> Check {
+    let stream =
+        memory_stream(&image.to_bytes(false).map_err(|err| err.to_string())?).map_err(win)?;
+    let decoder: IWICBitmapDecoder = ComObject::new(BitmapDecoder::new()).into_interface();
+
+    let capabilities = unsafe { decoder.QueryCapability(&stream) }.map_err(win)?;
+    ensure(
+        capabilities & WICBitmapDecoderCapabilityCanDecodeAllImages.0 as u32 != 0,
+        || format!("capabilities {:#x} lack CanDecodeAllImages", capabilities),
+    )?;
+
+    let position = stream_tell(&stream).map_err(win)?;
+    ensure(position == 0, || {
+        format!("stream position moved to {}", position)
+    })
+}
+
+fn query_capability_foreign_data() -> Check {
+    let stream = memory_stream(
+        b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR\0\0\0\x01\0\0\0\x01\x08\x06\0\0\0\x1f\x15\xc4\x89",
+    )
+    .map_err(win)?;
+    let decoder: IWICBitmapDecoder = ComObject::new(BitmapDecoder::new()).into_interface();
+
+    match unsafe { decoder.QueryCapability(&stream) } {
+        Ok(0) | Err(_) => Ok(()),
+        Ok(capabilities) => Err(format!("claimed capabilities {:#x}", capabilities)),
+    }
+}
+
+fn initialize_twice(bytes: &[u8]) -> Check {
+    let decoder = decoder(bytes).map_err(win)?;
+    let stream = memory_stream(bytes).map_err(win)?;
+
+    ensure(
+        unsafe { decoder.Initialize(&stream, WICDecodeMetadataCacheOnDemand) }.is_err(),
+        || "second Initialize succeeded".to_owned(),
+    )
+}
+
+fn frames(image: &BmxImage, bytes: &[u8]) -> Check {
+    let decoder = decoder(bytes).map_err(win)?;
+
+    // More than one if the decoder is set up to split sprite sheets.
+    let height = image.header.height;
+    let expected = (height / frame_height(height, sprite_height_setting())) as u32;
+
+    let count = unsafe { decoder.GetFrameCount() }.map_err(win)?;
+    ensure(count == expected, || {
+        format!("GetFrameCount returned {}, expected {}", count, expected)
+    })?;
+    ensure(unsafe { decoder.GetFrame(count) }.is_err(), || {
+        format!("GetFrame({}) succeeded", count)
+    })?;
+
+    let format = unsafe { decoder.GetContainerFormat() }.map_err(win)?;
+    ensure(format == CONTAINER_FORMAT, || {
+        format!("container format {:?}", format)
+    })
+}
+
+fn pixel_format(image: &BmxImage, bytes: &[u8]) -> Check {
+    let pixel_format = unsafe { frame(bytes).map_err(win)?.GetPixelFormat() }.map_err(win)?;
+
+    ensure(
+        Some(pixel_format) == bit_depth_to_pixel_format(image.header.bit_depth),
+        || format!("pixel format {:?}", pixel_format),
+    )
+}
+
+fn copy_palette(imaging_factory: &IWICImagingFactory, image: &BmxImage, bytes: &[u8]) -> Check {
+    let palette = unsafe { imaging_factory.CreatePalette() }.map_err(win)?;
+    unsafe { frame(bytes).map_err(win)?.CopyPalette(&palette) }.map_err(win)?;
+
+    let mut colors = [0u32; 256];
+    let mut count = 0;
+    unsafe { palette.GetColors(&mut colors, &mut count) }.map_err(win)?;
+
+    let expected = image
+        .palette
+        .iter()
+        .map(PaletteEntry::to_wic)
+        .collect::<Vec<_>>();
+
+    ensure(colors[..count as usize] == expected[..], || {
+        format!("{} colors differ from the file palette", count)
+    })
+}
+
+// Copies `rect` with a padded stride and compares every pixel, and that the padding is untouched.
+fn copy_rect(image: &BmxImage, bytes: &[u8], rect: Option<WICRect>) -> Check {
+    let bit_depth = image.header.bit_depth;
+    let full = WICRect {
+        X: 0,
+        Y: 0,
+        Width: WIDTH as i32,
+        Height: HEIGHT as i32,
+    };
+    let (rect_ptr, rect) = match rect {
+        Some(ref rect) => (rect as *const WICRect, *rect),
+        None => (std::ptr::null(), full),
+    };
+
+    let line_len = bytes_per_line(rect.Width as u16, bit_depth) as usize;
+    let stride = line_len + 3;
+    let mut buffer = vec![0xCDu8; stride * rect.Height as usize];
+
+    let frame = frame(bytes).map_err(win)?;
+    unsafe { frame.CopyPixels(rect_ptr, stride as u32, &mut buffer) }.map_err(win)?;
+
+    let source_stride = bytes_per_line(WIDTH, bit_depth) as usize;
+
+    for y in 0..rect.Height as usize {
+        for x in 0..rect.Width as usize {
+            let expected = index_at(
+                &image.data,
+                source_stride,
+                rect.X as usize + x,
+                rect.Y as usize + y,
+                bit_depth,
+            );
+            let actual = index_at(&buffer, stride, x, y, bit_depth);
+
+            ensure(actual == expected, || {
+                format!("pixel ({}, {}) is {}, expected {}", x, y, actual, expected)
+            })?;
+        }
+
+        ensure(
+            buffer[y * stride + line_len..(y + 1) * stride]
+                .iter()
+                .all(|&byte| byte == 0xCD),
+            || format!("row {} wrote past its line", y),
+        )?;
+    }
+
+    Ok(())
+}
+
+fn copy_rects(image: &BmxImage, bytes: &[u8]) -> Check {
+    let (width, height) = (WIDTH as i32, HEIGHT as i32);
+    let rects = [
+        (0, 0, width, height),
+        (1, 1, width - 2, height - 2),
+        (3, 2, 5, 1),
+        (7, 0, 6, height),
+        (width - 1, height - 1, 1, 1),
+        (4, 3, 0, 0),
+    ];
+
+    for (x, y, w, h) in rects {
+        let rect = WICRect {
+            X: x,
+            Y: y,
+            Width: w,
+            Height: h,
+        };
+
+        copy_rect(image, bytes, Some(rect))
+            .map_err(|reason| format!("rect ({}, {}, {}, {}): {}", x, y, w, h, reason))?;
+    }
+
+    Ok(())
+}
+
+fn copy_rect_out_of_bounds(bytes: &[u8]) -> Check {
+    let frame = frame(bytes).map_err(win)?;
+    let mut buffer = vec![0u8; 4096];
+    let (width, height) = (WIDTH as i32, HEIGHT as i32);
+
+    for (x, y, w, h) in [
+        (-1, 0, 1, 1),
+        (0, -1, 1, 1),
+        (0, 0, width + 1, 1),
+        (0, 0, 1, height + 1),
+        (width, 0, 1, 1),
+        (0, 0, -1, 1),
+        (i32::MAX, 0, i32::MAX, 1),
+    ] {
+        let rect = WICRect {
+            X: x,
+            Y: y,
+            Width: w,
+            Height: h,
+        };
+
+        expect_error(
+            unsafe { frame.CopyPixels(&rect, 256, &mut buffer) },
+            E_INVALIDARG,
+        )
+        .map_err(|reason| format!("rect ({}, {}, {}, {}) {}", x, y, w, h, reason))?;
+    }
+
+    Ok(())
+}
+
+fn copy_small_buffers(image: &BmxImage, bytes: &[u8]) -> Check {
+    let frame = frame(bytes).map_err(win)?;
+    let line_len = bytes_per_line(WIDTH, image.header.bit_depth) as usize;
+    let mut buffer = vec![0u8; line_len * HEIGHT as usize];
+
+    expect_error(
+        unsafe { frame.CopyPixels(std::ptr::null(), line_len as u32 - 1, &mut buffer) },
+        WINCODEC_ERR_INSUFFICIENTBUFFER,
+    )
+    .map_err(|reason| format!("short stride {}", reason))?;
+
+    let size = buffer.len() - 1;
+    expect_error(
+        unsafe { frame.CopyPixels(std::ptr::null(), line_len as u32, &mut buffer[..size]) },
+        WINCODEC_ERR_INSUFFICIENTBUFFER,
+    )
+    .map_err(|reason| format!("short buffer {}", reason))
+}
+
+fn encode(
+    imaging_factory: &IWICImagingFactory,
+    image: &BmxImage,
+    requested_format: windows_core::GUID,
+) -> Result<(windows_core::GUID, Vec<u8>), String> {
+    let stream =
+        unsafe { SHCreateMemStream(None) }.ok_or_else(|| "SHCreateMemStream failed".to_owned())?;
+    let encoder: IWICBitmapEncoder = ComObject::new(BitmapEncoder::new()).into_interface();
+
+    unsafe {
+        encoder
+            .Initialize(&stream, WICBitmapEncoderNoCache)
+            .map_err(win)?;
+
+        let format = encoder.GetContainerFormat().map_err(win)?;
+        ensure(format == CONTAINER_FORMAT, || {
+            format!("container format {:?}", format)
+        })?;
+
+        let mut frame = None;
+        encoder
+            .CreateNewFrame(&mut frame, std::ptr::null_mut())
+            .map_err(win)?;
+        let frame = frame.ok_or_else(|| "CreateNewFrame returned no frame".to_owned())?;
+
+        let mut second = None;
+        ensure(
+            encoder
+                .CreateNewFrame(&mut second, std::ptr::null_mut())
+                .is_err(),
+            || "a second frame was created".to_owned(),
+        )?;
+
+        frame.Initialize(None).map_err(win)?;
+        frame.SetSize(WIDTH as u32, HEIGHT as u32).map_err(win)?;
+
+        let mut pixel_format = requested_format;
+        frame.SetPixelFormat(&mut pixel_format).map_err(win)?;
+
+        let bit_depth = match bit_depth_to_pixel_format(image.header.bit_depth) {
+            Some(format) if format == pixel_format => image.header.bit_depth,
+            _ => return Ok((pixel_format, Vec::new())),
+        };
+
+        let colors = image
+            .palette
+            .iter()
+            .map(PaletteEntry::to_wic)
+            .collect::<Vec<_>>();
+        let palette = imaging_factory.CreatePalette().map_err(win)?;
+        palette.InitializeCustom(&colors).map_err(win)?;
+        frame.SetPalette(&palette).map_err(win)?;
+
+        let line_len = bytes_per_line(WIDTH, bit_depth) as u32;
+        frame
+            .WritePixels(HEIGHT as u32, line_len, &image.data)
+            .map_err(win)?;
+
+        frame.Commit().map_err(win)?;
+        encoder.Commit().map_err(win)?;
+
+        stream.Seek(0, STREAM_SEEK_SET, None).map_err(win)?;
+    }
+
+    Ok((requested_format, stream_read_to_end(&stream).map_err(win)?))
+}
+
+fn encoder_pixel_formats(imaging_factory: &IWICImagingFactory, image: &BmxImage) -> Check {
+    let requested = bit_depth_to_pixel_format(image.header.bit_depth).unwrap();
+    let (negotiated, bytes) = encode(imaging_factory, image, requested)?;
+
+    ensure(negotiated == requested, || {
+        format!("SetPixelFormat changed {:?} to {:?}", requested, negotiated)
+    })?;
+
+    let decoded = BmxImage::from_bytes(&bytes).map_err(|err| err.to_string())?;
+    ensure(decoded.data == image.data, || {
+        "encoded pixels differ".to_owned()
+    })?;
+    ensure(decoded.palette == image.palette, || {
+        "encoded palette differs".to_owned()
+    })
+}
+
+fn encoder_negotiates_unsupported_format(imaging_factory: &IWICImagingFactory) -> Check {
+    let (negotiated, _) = encode(
+        imaging_factory,
+        &test_image(8),
+        GUID_WICPixelFormat32bppBGRA,
+    )?;
+
+    ensure(PIXEL_FORMATS.contains(&negotiated), || {
+        format!("32bppBGRA was negotiated to {:?}", negotiated)
+    })
+}
+
+pub fn run(imaging_factory: &IWICImagingFactory) -> Vec<CheckResult> {
+    let mut results = Vec::new();
+    let mut check = |name: String, outcome: Check| results.push(CheckResult { name, outcome });
+
+    check(
+        "QueryCapability rejects foreign data".to_owned(),
+        query_capability_foreign_data(),
+    );
+    check(
+        "Encoder negotiates unsupported pixel formats".to_owned(),
+        encoder_negotiates_unsupported_format(imaging_factory),
+    );
+
+    for bit_depth in BIT_DEPTHS {
+        let image = test_image(bit_depth);
+        let bytes = match image.to_bytes(false) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                check(
+                    format!("{}bpp: build test image", bit_depth),
+                    Err(err.to_string()),
+                );
+                continue;
+            }
+        };
+
+        let name = |description: &str| format!("{}bpp: {}", bit_depth, description);
+
+        check(
+            name("QueryCapability accepts the image and preserves the stream position"),
+            query_capability(&image),
+        );
+        check(
+            name("Initialize fails when called twice"),
+            initialize_twice(&bytes),
+        );
+        check(
+            name("Frame count and container format"),
+            frames(&image, &bytes),
+        );
+        check(
+            name("Frame pixel format matches the bit depth"),
+            pixel_format(&image, &bytes),
+        );
+        check(
+            name("CopyPalette returns the file palette"),
+            copy_palette(imaging_factory, &image, &bytes),
+        );
+        check(
+            name("CopyPixels without a rectangle copies the whole frame"),
+            copy_rect(&image, &bytes, None),
+        );
+        check(
+            name("CopyPixels honors sub-rectangles and the stride"),
+            copy_rects(&image, &bytes),
+        );
+        check(
+            name("CopyPixels rejects rectangles outside the frame"),
+            copy_rect_out_of_bounds(&bytes),
+        );
+        check(
+            name("CopyPixels rejects short strides and buffers"),
+            copy_small_buffers(&image, &bytes),
+        );
+        check(
+            name("Encoder keeps the pixel format and round trips the image"),
+            encoder_pixel_formats(imaging_factory, &image),
+        );
+    }
+
+    results
+}
@@ -0,0 +1,184 @@
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use windows::Win32::{
+    Foundation::{E_NOINTERFACE, E_POINTER, S_OK},
+    Graphics::Imaging::{IWICBitmapDecoder, IWICBitmapDecoder_Vtbl, WICDecodeOptions},
+};
+use windows_core::{ComObject, IUnknown, IUnknown_Vtbl, Interface, GUID, HRESULT};
+
+use super::BitmapDecoder;
+
+// `#[implement]` always owns its IUnknown, so aggregation needs a hand-written object: the
+// IWICBitmapDecoder vtable delegates IUnknown to the outer object and forwards everything else to
+// a regular decoder, while the second vtable is the non-delegating IUnknown handed to the outer
+// object, which owns this one.
+#[repr(C)]
+struct AggregatedDecoder {
+    decoder_vtable: &'static IWICBitmapDecoder_Vtbl,
+    unknown_vtable: &'static IUnknown_Vtbl,
+    // Not reference counted; the outer object outlives us.
+    outer: *mut c_void,
+    count: AtomicU32,
+    inner: IWICBitmapDecoder,
+}
+
+impl AggregatedDecoder {
+    unsafe fn from_decoder<'a>(this: *mut c_void) -> &'a Self {
+        unsafe { &*(this as *const Self) }
+    }
+
+    unsafe fn from_unknown<'a>(this: *mut c_void) -> &'a Self {
+        unsafe { &*((this as *const *const c_void).sub(1) as *const Self) }
+    }
+
+    fn outer(&self) -> &IUnknown {
+        unsafe { IUnknown::from_raw_borrowed(&self.outer) }.unwrap()
+    }
+}
+
+unsafe extern "system" fn delegating_query_interface(
+    this: *mut c_void,
+    iid: *const GUID,
+    ppv: *mut *mut c_void,
+) -> HRESULT {
+    unsafe {
+        AggregatedDecoder::from_decoder(this)
+            .outer()
+            .query(iid, ppv)
+    }
+}
+
+unsafe extern "system" fn delegating_add_ref(this: *mut c_void) -> u32 {
+    let outer = unsafe { AggregatedDecoder::from_decoder(this) }.outer();
+    unsafe { (outer.vtable().AddRef)(outer.as_raw()) }
+}
+
+unsafe extern "system" fn delegating_release(this: *mut c_void) -> u32 {
+    let outer = unsafe { AggregatedDecoder::from_decoder(this) }.outer();
+    unsafe { (outer.vtable().Release)(outer.as_raw()) }
+}
+
+unsafe extern "system" fn query_interface(
+    this: *mut c_void,
+    iid: *const GUID,
+    ppv: *mut *mut c_void,
+) -> HRESULT {
+    if iid.is_null() || ppv.is_null() {
+        return E_POINTER;
+    }
+
+    let object = unsafe { AggregatedDecoder::from_unknown(this) };
+
+    match unsafe { *iid } {
+        IUnknown::IID => unsafe {
+            add_ref(this);
+            *ppv = this;
+        },
+        IWICBitmapDecoder::IID => unsafe {
+            let decoder = &raw const object.decoder_vtable as *mut c_void;
+            delegating_add_ref(decoder);
+            *ppv = decoder;
+        },
+        _ => {
+            unsafe { *ppv = std::ptr::null_mut() };
+            return E_NOINTERFACE;
+        }
+    }
+
+    S_OK
+}
+
+unsafe extern "system" fn add_ref(this: *mut c_void) -> u32 {
+    unsafe { AggregatedDecoder::from_unknown(this) }
+        .count
+        .fetch_add(1, Ordering::AcqRel)
+        + 1
+}
+
+unsafe extern "system" fn release(this: *mut c_void) -> u32 {
+    let object = unsafe { AggregatedDecoder::from_unknown(this) };
+    let count = object.count.fetch_sub(1, Ordering::AcqRel) - 1;
+
+    if count == 0 {
+        drop(unsafe {
+            Box::from_raw(object as *const AggregatedDecoder as *mut AggregatedDecoder)
+        });
+    }
+
+    count
+}
+
+macro_rules! forward {
+    ($($method:ident($($arg:ident: $type:ty),*);)*) => {
+        $(
+            pub(super) unsafe extern "system" fn $method(this: *mut c_void, $($arg: $type),*) -> HRESULT {
+                let inner = &unsafe { AggregatedDecoder::from_decoder(this) }.inner;
+                unsafe { (inner.vtable().$method)(inner.as_raw(), $($arg),*) }
+            }
+        )*
+    };
+}
+
+#[allow(non_snake_case)]
+mod methods {
+    use super::*;
+
+    forward! {
+        QueryCapability(stream: *mut c_void, capability: *mut u32);
+        Initialize(stream: *mut c_void, options: WICDecodeOptions);
+        GetContainerFormat(format: *mut GUID);
+        GetDecoderInfo(info: *mut *mut c_void);
+        CopyPalette(palette: *mut c_void);
+        GetMetadataQueryReader(reader: *mut *mut c_void);
+        GetPreview(preview: *mut *mut c_void);
+        GetColorContexts(count: u32, contexts: *mut *mut c_void, actual_count: *mut u32);
+        GetThumbnail(thumbnail: *mut *mut c_void);
+        GetFrameCount(count: *mut u32);
+        GetFrame(index: u32, frame: *mut *mut c_void);
+    }
+}
+
+static DECODER_VTABLE: IWICBitmapDecoder_Vtbl = IWICBitmapDecoder_Vtbl {
+    base__: IUnknown_Vtbl {
+        QueryInterface: delegating_query_interface,
+        AddRef: delegating_add_ref,
+        Release: delegating_release,
+    },
+    QueryCapability: methods::QueryCapability,
+    Initialize: methods::Initialize,
+    GetContainerFormat: methods::GetContainerFormat,
+    GetDecoderInfo: methods::GetDecoderInfo,
+    CopyPalette: methods::CopyPalette,
+    GetMetadataQueryReader: methods::GetMetadataQueryReader,
+    GetPreview: methods::GetPreview,
+    GetColorContexts: methods::GetColorContexts,
+    GetThumbnail: methods::GetThumbnail,
+    GetFrameCount: methods::GetFrameCount,
+    GetFrame: methods::GetFrame,
+};
+
+static UNKNOWN_VTABLE: IUnknown_Vtbl = IUnknown_Vtbl {
+    QueryInterface: query_interface,
+    AddRef: add_ref,
+    Release: release,
+};
+
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn create_instance(outer: &IUnknown, iid: *const GUID, ppv: *mut *mut c_void) -> HRESULT {
+    let object = Box::into_raw(Box::new(AggregatedDecoder {
+        decoder_vtable: &DECODER_VTABLE,
+        unknown_vtable: &UNKNOWN_VTABLE,
+        outer: outer.as_raw(),
+        count: AtomicU32::new(1),
+        inner: ComObject::new(BitmapDecoder::new()).into_interface(),
+    }));
+
+    let unknown = unsafe { &raw mut (*object).unknown_vtable } as *mut c_void;
+
+    unsafe {
+        let result = query_interface(unknown, iid, ppv);
+        release(unknown);
+        result
+    }
+}
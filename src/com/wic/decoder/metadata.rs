@@ -0,0 +1,116 @@
+use windows::{
+    core::{implement, PCWSTR, PROPVARIANT, PWSTR},
+    Win32::{
+        Foundation::{E_INVALIDARG, E_NOTIMPL, WINCODEC_ERR_PROPERTYNOTFOUND},
+        Graphics::Imaging::{IWICMetadataQueryReader, IWICMetadataQueryReader_Impl},
+        System::Com::IEnumString,
+    },
+};
+use windows_core::{w, GUID};
+
+use crate::bmx::Integrity;
+
+use super::super::com::CONTAINER_FORMAT;
+
+const LOCATION: PCWSTR = w!("/");
+const INTEGRITY: &str = "/integrity";
+const CRC32: &str = "/crc32";
+pub const PAL_USED: &str = "/palUsed";
+pub const PAL_START: &str = "/palStart";
+// Set when the file is from a newer version of the format and was decoded best-effort.
+pub const NEWER_VERSION: &str = "/newerVersion";
+
+#[implement(IWICMetadataQueryReader)]
+pub struct MetadataQueryReader {
+    integrity: Integrity,
+    crc32: Option<u32>,
+    pal_used: u8,
+    pal_start: u8,
+    newer_version: bool,
+}
+
+impl MetadataQueryReader {
+    pub fn new(
+        integrity: Integrity,
+        crc32: Option<u32>,
+        pal_used: u8,
+        pal_start: u8,
+        newer_version: bool,
+    ) -> Self {
+        Self {
+            integrity,
+            crc32,
+            pal_used,
+            pal_start,
+            newer_version,
+        }
+    }
+}
+
+impl IWICMetadataQueryReader_Impl for MetadataQueryReader_Impl {
+    fn GetContainerFormat(&self) -> windows::core::Result<GUID> {
+        Ok(CONTAINER_FORMAT)
+    }
+
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn GetLocation(
+        &self,
+        max_length: u32,
+        namespace: &PWSTR,
+        actual_length: *mut u32,
+    ) -> windows::core::Result<()> {
+        if actual_length.is_null() {
+            return Err(E_INVALIDARG.into());
+        }
+
+        let location = unsafe { LOCATION.as_wide() };
+        unsafe { actual_length.write(location.len() as u32 + 1) };
+
+        if !namespace.is_null() {
+            if (max_length as usize) <= location.len() {
+                return Err(E_INVALIDARG.into());
+            }
+
+            unsafe {
+                namespace
+                    .0
+                    .copy_from_nonoverlapping(location.as_ptr(), location.len());
+                namespace.0.add(location.len()).write(0);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn GetMetadataByName(
+        &self,
+        name: &PCWSTR,
+        value: *mut PROPVARIANT,
+    ) -> windows::core::Result<()> {
+        if name.is_null() {
+            return Err(E_INVALIDARG.into());
+        }
+
+        let name = unsafe { name.to_string() }.map_err(|_| E_INVALIDARG)?;
+
+        let result = match (name.as_str(), self.crc32) {
+            (INTEGRITY, _) => PROPVARIANT::from(self.integrity.to_string().as_str()),
+            (CRC32, Some(crc32)) => PROPVARIANT::from(crc32),
+            (PAL_USED, _) => PROPVARIANT::from(self.pal_used),
+            (PAL_START, _) => PROPVARIANT::from(self.pal_start),
+            (NEWER_VERSION, _) => PROPVARIANT::from(self.newer_version),
+            _ => return Err(WINCODEC_ERR_PROPERTYNOTFOUND.into()),
+        };
+
+        if !value.is_null() {
+            unsafe { value.write(result) };
+        }
+
+        Ok(())
+    }
+
+    fn GetEnumerator(&self) -> windows::core::Result<IEnumString> {
+        Err(E_NOTIMPL.into())
+    }
+}
@@ -1,14 +1,18 @@
-use std::sync::RwLock;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::Mutex;
 
-use windows::Win32::Foundation::{E_NOTIMPL, E_UNEXPECTED, WINCODEC_ERR_INSUFFICIENTBUFFER};
+use windows::Win32::Foundation::{E_NOTIMPL, E_UNEXPECTED};
 use windows::Win32::Graphics::Imaging::{
-    IWICMetadataBlockReader_Impl, IWICMetadataReader, IWICStream, WICRect,
+    GUID_WICPixelFormat32bppBGRA, IWICBitmapCodecProgressNotification,
+    IWICBitmapCodecProgressNotification_Impl, IWICMetadataBlockReader_Impl, IWICMetadataReader,
+    IWICMetadataWriter, IWICStream, PFNProgressNotification, WICProgressOperationCopyPixels,
+    WICRect,
 };
-use windows::Win32::System::Com::IEnumUnknown;
+use windows::Win32::System::Com::{IEnumUnknown, Marshal::IMarshal};
 use windows::{
-    core::{implement, ComObject, IUnknownImpl, Interface, GUID, HRESULT},
+    core::{implement, ComObject, IUnknownImpl, Interface, GUID},
     Win32::{
-        Foundation::{ERROR_ALREADY_INITIALIZED, E_INVALIDARG},
+        Foundation::E_INVALIDARG,
         Graphics::Imaging::{
             CLSID_WICImagingFactory, IWICBitmapDecoder, IWICBitmapDecoderInfo,
             IWICBitmapDecoder_Impl, IWICBitmapFrameDecode, IWICBitmapFrameDecode_Impl,
@@ -17,58 +21,329 @@ use windows::{
             WICBitmapDecoderCapabilityCanDecodeAllImages,
             WICBitmapDecoderCapabilityCanDecodeSomeImages, WICDecodeOptions,
         },
-        System::Com::{CoCreateInstance, IStream, CLSCTX_INPROC_SERVER, STREAM_SEEK_SET},
+        System::Com::{CoCreateInstance, IStream, CLSCTX_INPROC_SERVER},
     },
 };
 use windows_core::{w, PCWSTR};
 
-use super::super::wic::util::bytes_per_line;
 use super::super::wic::util::StreamPositionPreserver;
-use crate::bmx::{FileHeader, PaletteEntry};
-use crate::com::{stream_read_exact, stream_read_exact_items, stream_tell, FileHeaderExt};
+use super::super::wic::util::{
+    bytes_per_line, checked_buffer_size, checked_image_size, checked_u32,
+};
+use crate::bmx::limits::Limits;
+use crate::bmx::reader::BmxReader;
+use crate::bmx::{BmxError, BmxImage, FileHeader, Integrity, PaletteEntry};
+use crate::com::hresult::Condition;
+use crate::com::util::{impl_free_threaded_marshaler, ComState, FreeThreadedMarshaler};
+use crate::com::{
+    stream_size, stream_sniff, stream_tell, BmxReadErrorExt, BmxReaderExt, IoErrorExt,
+    LimitErrorExt, StreamReadWriteWrapper,
+};
+use crate::registry::get_class_setting;
+use crate::settings::{self, ThumbnailBackground};
 use crate::util::guid;
 
 use super::super::CoClass;
 use super::com::CONTAINER_FORMAT;
+use super::progress::ProgressNotification;
+use super::reserved::ReservedMetadata;
 use super::util::bit_depth_to_pixel_format;
+use metadata::MetadataQueryReader;
+
+pub mod aggregated;
+pub mod metadata;
 
 struct BitmapDecoderData {
     imaging_factory: IWICImagingFactory,
-    stream: IWICStream,
+    source: IStream,
+    // Where the BMX starts in `source`, which needn't be at 0 when it's embedded in another
+    // container. Every other offset, data_start included, is relative to it.
+    region_offset: u64,
+    region_size: u64,
     header: FileHeader,
     palette: IWICPalette,
+    pixel_data_available: u64,
+    // The palette as WIC colors, for applying it during CopyPixels.
+    colors: Vec<u32>,
+    output_format: OutputFormat,
+    // Sprite sheets are split into frames of this height; otherwise it's the image height.
+    frame_height: u16,
+}
+
+pub const TOLERATE_TRUNCATION: PCWSTR = w!("TolerateTruncation");
+// Reports every image as 8bpp indexed for applications that can't handle 1/2/4bpp sources.
+pub const EXPAND_TO_8BPP: PCWSTR = w!("ExpandTo8bpp");
+// Applies the palette and hands out 32bpp BGRA, saving consumers a format converter. Takes
+// precedence over EXPAND_TO_8BPP.
+pub const DECODE_TO_BGRA: PCWSTR = w!("DecodeToBgra");
+
+// Splits images that are a whole number of sprites of this height into one frame per sprite. 0, the
+// default, keeps every image a single frame.
+pub const SPRITE_HEIGHT: PCWSTR = w!("SpriteHeight");
+
+pub(crate) fn sprite_height_setting() -> Option<u32> {
+    get_class_setting::<BitmapDecoder>(SPRITE_HEIGHT)
+}
+
+pub(crate) fn frame_height(image_height: u16, sprite_height: Option<u32>) -> u16 {
+    match sprite_height.map(u16::try_from) {
+        Some(Ok(sprite_height))
+            if sprite_height > 0
+                && sprite_height < image_height
+                && image_height.is_multiple_of(sprite_height) =>
+        {
+            sprite_height
+        }
+        _ => image_height,
+    }
+}
+
+// What frames hand out, as opposed to what the file stores.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Native,
+    Indexed8,
+    Bgra32,
+}
+
+impl OutputFormat {
+    fn from_settings(bit_depth: u8) -> Self {
+        if get_class_setting::<BitmapDecoder>(DECODE_TO_BGRA).unwrap_or(0) != 0 {
+            Self::Bgra32
+        } else if bit_depth < 8 && settings::expand_to_8bpp() {
+            Self::Indexed8
+        } else {
+            Self::Native
+        }
+    }
+
+    fn pixel_format(self, bit_depth: u8) -> Option<GUID> {
+        match self {
+            Self::Native => bit_depth_to_pixel_format(bit_depth),
+            Self::Indexed8 => bit_depth_to_pixel_format(8),
+            Self::Bgra32 => Some(GUID_WICPixelFormat32bppBGRA),
+        }
+    }
+
+    fn line_len(self, width: u16, bit_depth: u8) -> usize {
+        match self {
+            Self::Native => bytes_per_line(width, bit_depth) as usize,
+            Self::Indexed8 => width as usize,
+            Self::Bgra32 => width as usize * 4,
+        }
+    }
+}
+
+// A stream over `size` bytes of `source` from `offset` on, reading through a clone of the source so
+// it doesn't share a seek pointer with it. Streams that can't be cloned fall back to sharing it.
+fn create_region(
+    imaging_factory: &IWICImagingFactory,
+    source: &IStream,
+    offset: u64,
+    size: u64,
+) -> windows::core::Result<IWICStream> {
+    let source = unsafe { source.Clone() }.unwrap_or_else(|_| source.clone());
+    let stream = unsafe { imaging_factory.CreateStream()? };
+
+    unsafe {
+        stream.InitializeFromIStreamRegion(&source, offset, size)?;
+    }
+
+    Ok(stream)
+}
+
+impl BitmapDecoderData {
+    // Each frame gets its own region, so frames don't share a seek pointer.
+    fn create_stream(&self) -> windows::core::Result<IWICStream> {
+        create_region(
+            &self.imaging_factory,
+            &self.source,
+            self.region_offset,
+            self.region_size,
+        )
+    }
+}
+
+// Upper bound for the scratch buffer of CopyPixels; large images are read in several chunks.
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+// Previews match the 4:3 VERA display, thumbnails are square.
+const PREVIEW_ASPECT: (u16, u16) = (4, 3);
+const THUMBNAIL_ASPECT: (u16, u16) = (1, 1);
+
+// Reads `buffer.len()` bytes, filling whatever lies past the end of a truncated file with `fill`.
+fn read_pixels<R: Read>(
+    reader: &mut R,
+    buffer: &mut [u8],
+    available: &mut u64,
+    fill: u8,
+) -> windows::core::Result<()> {
+    let read = (*available).min(buffer.len() as u64) as usize;
+    let (data, missing) = buffer.split_at_mut(read);
+
+    reader.read_exact(data).map_err(IoErrorExt::to_win_error)?;
+
+    missing.fill(fill);
+    *available -= read as u64;
+    Ok(())
+}
+
+// Reads the header and palette of the BMX file at the current stream position, leaving the stream
+// right after the palette. The palette is the one CopyPalette returns, without needing a decoder.
+pub fn read_palette(stream: &IStream) -> windows::core::Result<(FileHeader, Vec<PaletteEntry>)> {
+    let mut reader = BmxReader::from_stream(stream)?;
+    let palette = reader
+        .palette()
+        .map_err(BmxReadErrorExt::to_win_error)?
+        .to_vec();
+
+    Ok((reader.header().clone(), palette))
+}
+
+fn index_at(source: &[u8], x: usize, bit_depth: u8) -> u8 {
+    let bit_depth = bit_depth as usize;
+    let bit = x * bit_depth;
+    let mask = ((1u16 << bit_depth) - 1) as u8;
+
+    (source[bit / 8] >> (8 - bit_depth - bit % 8)) & mask
+}
+
+// Widens the indices starting at pixel `x` of a packed row to one byte each, filling
+// `destination`.
+fn expand_indices(source: &[u8], x: usize, bit_depth: u8, destination: &mut [u8]) {
+    for (i, index) in destination.iter_mut().enumerate() {
+        *index = index_at(source, x + i, bit_depth);
+    }
+}
+
+// Like expand_indices, but looks the indices up in `colors` and writes BGRA pixels.
+fn expand_to_bgra(source: &[u8], x: usize, bit_depth: u8, colors: &[u32], destination: &mut [u8]) {
+    for (i, pixel) in destination.chunks_exact_mut(4).enumerate() {
+        let index = index_at(source, x + i, bit_depth) as usize;
+        let color = colors.get(index).copied().unwrap_or(0xFF000000);
+
+        pixel.copy_from_slice(&color.to_le_bytes());
+    }
+}
+
+// Copies `len` bits starting at bit `offset` of `source` to the start of `destination`, zeroing
+// the unused low bits of the last byte.
+fn copy_bits(source: &[u8], offset: usize, len: usize, destination: &mut [u8]) {
+    let start = offset / 8;
+    let shift = offset % 8;
+
+    for (i, byte) in destination.iter_mut().enumerate() {
+        let high = source[start + i] << shift;
+        let low = match source.get(start + i + 1) {
+            Some(next) if shift > 0 => next >> (8 - shift),
+            _ => 0,
+        };
+
+        *byte = high | low;
+    }
+
+    if !len.is_multiple_of(8) {
+        if let Some(last) = destination.last_mut() {
+            *last &= 0xFF << (8 - len % 8);
+        }
+    }
 }
 
 #[derive(Default)]
-#[implement(IWICBitmapDecoder)]
+#[implement(IWICBitmapDecoder, IWICBitmapCodecProgressNotification, IMarshal)]
 pub struct BitmapDecoder {
-    inner: RwLock<Option<BitmapDecoderData>>,
+    inner: ComState<BitmapDecoderData>,
+    progress: ProgressNotification,
+    marshaler: FreeThreadedMarshaler,
+    // Overrides SPRITE_HEIGHT.
+    sprite_height: Option<u32>,
+    // Overrides TOLERATE_TRUNCATION.
+    tolerate_truncation: Option<bool>,
 }
 
 impl BitmapDecoder {
     pub fn new() -> Self {
         Default::default()
     }
+
+    #[cfg(all(test, windows))]
+    pub(crate) fn with_sprite_height(sprite_height: u32) -> Self {
+        Self {
+            sprite_height: Some(sprite_height),
+            ..Default::default()
+        }
+    }
+
+    #[cfg(all(test, windows))]
+    pub(crate) fn with_tolerate_truncation(tolerate_truncation: bool) -> Self {
+        Self {
+            tolerate_truncation: Some(tolerate_truncation),
+            ..Default::default()
+        }
+    }
 }
 
 impl CoClass for BitmapDecoder {
     const CLSID: GUID = guid::from_str("5c8a66da-1c32-4d8e-8ead-c579214a6522");
     const PROG_ID: PCWSTR = w!("X16BMX.BMXDecoder.1");
     const VERSION_INDEPENDENT_PROG_ID: PCWSTR = w!("X16BMX.BMXDecoder");
+    const FRIENDLY_NAME: PCWSTR = w!("BMX Decoder");
+    const DESCRIPTION: PCWSTR = w!("BMX File");
+}
+
+impl_free_threaded_marshaler!(BitmapDecoder_Impl, marshaler);
+
+impl BitmapDecoder_Impl {
+    fn frame(&self, index: u32) -> windows::core::Result<ComObject<FrameDecoder>> {
+        if index >= self.GetFrameCount()? {
+            return Err(E_INVALIDARG.into());
+        }
+
+        let inner = self.inner.get()?;
+        let stream = inner.create_stream()?;
+        let first_row = index as usize * inner.frame_height as usize;
+
+        Ok(ComObject::new(FrameDecoder::new(
+            self.to_object(),
+            stream,
+            first_row,
+        )))
+    }
+}
+
+impl IWICBitmapCodecProgressNotification_Impl for BitmapDecoder_Impl {
+    fn RegisterProgressNotification(
+        &self,
+        callback: PFNProgressNotification,
+        data: *const core::ffi::c_void,
+        flags: u32,
+    ) -> windows::core::Result<()> {
+        self.progress.register(callback, data, flags);
+        Ok(())
+    }
 }
 
 impl IWICBitmapDecoder_Impl for BitmapDecoder_Impl {
     fn QueryCapability(&self, stream: Option<&IStream>) -> windows::core::Result<u32> {
         let stream = stream.ok_or(E_INVALIDARG)?;
 
+        // WIC asks every decoder whose pattern matched, or all of them when nothing did; the
+        // latter must not fail just because the stream isn't a BMX.
+        if !stream_sniff(stream)? {
+            return Ok(0);
+        }
+
         let _position_preserver = StreamPositionPreserver::new(stream.clone())?;
-        let header = FileHeader::from_stream(stream)?;
+        let reader = BmxReader::from_stream(stream)?;
+        let header = reader.header();
 
-        if header.compressed == 0 {
+        if header.compressed != 0 {
+            Ok(0)
+        } else if header.is_newer_version() {
+            Ok(WICBitmapDecoderCapabilityCanDecodeSomeImages.0 as u32)
+        } else {
             Ok(WICBitmapDecoderCapabilityCanDecodeAllImages.0 as u32
                 | WICBitmapDecoderCapabilityCanDecodeSomeImages.0 as u32)
-        } else {
-            Ok(0)
         }
     }
 
@@ -79,73 +354,81 @@ impl IWICBitmapDecoder_Impl for BitmapDecoder_Impl {
     ) -> windows::core::Result<()> {
         let stream = stream.ok_or(E_INVALIDARG)?;
 
-        let mut inner = self.inner.write().unwrap();
-        if inner.is_some() {
-            return Err(HRESULT::from_win32(ERROR_ALREADY_INITIALIZED.0).into());
-        }
-
-        let stream_position_preserver = StreamPositionPreserver::new(stream.clone())?;
+        self.inner.ensure_uninitialized()?;
 
-        let begin_position = stream_tell(stream)?;
+        // The source's position is left alone even if the region has to share its seek pointer.
+        let _position_preserver = StreamPositionPreserver::new(stream.clone())?;
 
-        let header = FileHeader::from_stream(stream)?;
+        let region_offset = stream_tell(stream)?;
+        let stream_size = stream_size(stream)?.saturating_sub(region_offset);
 
         let imaging_factory: IWICImagingFactory =
             unsafe { CoCreateInstance(&CLSID_WICImagingFactory, None, CLSCTX_INPROC_SERVER)? };
 
-        let image_size = header.data_start as u64
-            + bytes_per_line(header.width, header.bit_depth) as u64 * header.height as u64;
-
-        let stream = {
-            let wic_stream = unsafe { imaging_factory.CreateStream()? };
+        // Everything is read through a region starting at the header, so offsets from the header
+        // can be used as they are, wherever the BMX is embedded.
+        let region: IStream =
+            create_region(&imaging_factory, stream, region_offset, stream_size)?.cast()?;
 
-            unsafe {
-                wic_stream.InitializeFromIStreamRegion(
-                    stream,
-                    stream_position_preserver.position,
-                    image_size,
-                )?
-            };
+        let mut reader = BmxReader::from_stream(&region)?;
+        let header = reader.header().clone();
 
-            wic_stream
+        let pixel_data_len = checked_image_size(header.bytes_per_line(), header.height as usize)?;
+        let image_size = header.data_start as u64 + pixel_data_len as u64;
+        let required_size = if header.compressed == 0 {
+            image_size
+        } else {
+            header.data_start as u64
         };
 
-        unsafe {
-            stream.Seek(std::mem::size_of_val(&header) as _, STREAM_SEEK_SET, None)?;
-        }
+        Limits::DEFAULT
+            .check(&header, Some(stream_size))
+            .map_err(LimitErrorExt::to_win_error)?;
 
-        let palette = unsafe { imaging_factory.CreatePalette()? };
+        if stream_size < required_size
+            && (header.compressed != 0
+                || stream_size < header.data_start as u64
+                || !self
+                    .tolerate_truncation
+                    .unwrap_or_else(settings::tolerate_truncation))
+        {
+            return Err(Condition::BadImage
+                .error("Stream is shorter than the image data described by the header"));
+        }
 
-        let palette_entry_count = header.palette_entry_count();
-        let mut palette_entries: [PaletteEntry; 256] = [Default::default(); 256];
-        let palette_entries = &mut palette_entries[..palette_entry_count];
+        let image_size = image_size.min(stream_size);
 
-        stream_read_exact_items(&stream, palette_entries)?;
+        let palette = unsafe { imaging_factory.CreatePalette()? };
 
-        let mut wic_colors = [0u32; 256];
-
-        for i in 0..palette_entry_count {
-            wic_colors[i] = palette_entries[i].to_wic();
-        }
+        let wic_colors = reader
+            .palette()
+            .map_err(BmxReadErrorExt::to_win_error)?
+            .iter()
+            .map(PaletteEntry::to_wic)
+            .collect::<Vec<_>>();
 
         unsafe {
-            palette.InitializeCustom(&wic_colors[..palette_entry_count])?;
+            palette.InitializeCustom(&wic_colors)?;
         }
 
-        unsafe {
-            stream.Seek(
-                (begin_position + (header.data_start as u64)) as i64,
-                STREAM_SEEK_SET,
-                None,
-            )?;
-        }
+        let output_format = OutputFormat::from_settings(header.bit_depth);
+        let frame_height = frame_height(
+            header.height,
+            self.sprite_height.or_else(sprite_height_setting),
+        );
 
-        inner.replace(BitmapDecoderData {
+        self.inner.initialize(BitmapDecoderData {
             imaging_factory,
-            stream,
+            source: stream.clone(),
+            region_offset,
+            region_size: image_size,
+            pixel_data_available: image_size - header.data_start as u64,
             header,
             palette,
-        });
+            colors: wic_colors,
+            output_format,
+            frame_height,
+        })?;
 
         Ok(())
     }
@@ -155,8 +438,7 @@ impl IWICBitmapDecoder_Impl for BitmapDecoder_Impl {
     }
 
     fn GetDecoderInfo(&self) -> windows::core::Result<IWICBitmapDecoderInfo> {
-        let inner = self.inner.read().unwrap();
-        let inner = inner.as_ref().ok_or(E_UNEXPECTED)?;
+        let inner = self.inner.get()?;
 
         let component_info: IWICComponentInfo = unsafe {
             inner
@@ -168,23 +450,20 @@ impl IWICBitmapDecoder_Impl for BitmapDecoder_Impl {
     }
 
     fn GetFrameCount(&self) -> windows::core::Result<u32> {
-        Ok(1)
+        let inner = self.inner.get()?;
+        Ok((inner.header.height / inner.frame_height.max(1)).max(1) as u32)
     }
 
     fn GetFrame(&self, index: u32) -> windows::core::Result<IWICBitmapFrameDecode> {
-        if index > 0 {
-            Err(E_INVALIDARG.into())
-        } else {
-            Ok(ComObject::new(FrameDecoder::new(self.to_object())).into_interface())
-        }
+        Ok(self.frame(index)?.into_interface())
     }
 
     fn GetPreview(&self) -> windows::core::Result<IWICBitmapSource> {
-        self.GetFrame(0)?.cast()
+        self.frame(0)?.thumbnail(PREVIEW_ASPECT)
     }
 
     fn GetThumbnail(&self) -> windows::core::Result<IWICBitmapSource> {
-        self.GetFrame(0)?.cast()
+        self.frame(0)?.thumbnail(THUMBNAIL_ASPECT)
     }
 
     #[allow(clippy::not_unsafe_ptr_arg_deref)]
@@ -218,8 +497,7 @@ impl IWICBitmapDecoder_Impl for BitmapDecoder_Impl {
     fn CopyPalette(&self, palette: Option<&IWICPalette>) -> windows::core::Result<()> {
         let palette = palette.ok_or(E_INVALIDARG)?;
 
-        let inner = self.inner.read().unwrap();
-        let inner = inner.as_ref().ok_or(E_UNEXPECTED)?;
+        let inner = self.inner.get()?;
 
         let mut colors = [0u32; 256];
         let mut actual_colors = 0;
@@ -235,175 +513,287 @@ impl IWICBitmapDecoder_Impl for BitmapDecoder_Impl {
 
 struct FrameDecoderData {
     parent: ComObject<BitmapDecoder>,
+    // Frames may be used from several threads at once; every access seeks explicitly while
+    // holding this lock instead of relying on the stream position left by a previous call.
+    stream: Mutex<IWICStream>,
+    // Where this frame starts in the image, for sprite sheets.
+    first_row: usize,
 }
 
 #[implement(IWICBitmapFrameDecode, IWICMetadataBlockReader)]
 pub struct FrameDecoder {
-    inner: RwLock<FrameDecoderData>,
+    inner: FrameDecoderData,
 }
 
 impl FrameDecoder {
-    pub fn new(parent: ComObject<BitmapDecoder>) -> FrameDecoder {
+    pub fn new(
+        parent: ComObject<BitmapDecoder>,
+        stream: IWICStream,
+        first_row: usize,
+    ) -> FrameDecoder {
         FrameDecoder {
-            inner: RwLock::new(FrameDecoderData { parent }),
+            inner: FrameDecoderData {
+                parent,
+                stream: Mutex::new(stream),
+                first_row,
+            },
         }
     }
-}
 
-impl IWICBitmapSource_Impl for FrameDecoder_Impl {
-    fn GetPixelFormat(&self) -> windows::core::Result<windows::core::GUID> {
-        let inner = self.inner.read().unwrap();
-        let parent_inner = inner.parent.inner.read().unwrap();
-        let parent_inner = parent_inner.as_ref().ok_or(E_UNEXPECTED)?;
-
-        bit_depth_to_pixel_format(parent_inner.header.bit_depth).ok_or(E_UNEXPECTED.into())
-    }
+    // The frame on a canvas with the given aspect ratio, filled with the border color, the way the
+    // hardware would show it.
+    fn letterboxed(&self, aspect: (u16, u16)) -> windows::core::Result<IWICBitmapSource> {
+        let parent_inner = self.inner.parent.inner.get()?;
+        let header = &parent_inner.header;
+        let (width, height) = (header.width, parent_inner.frame_height);
+
+        let line_len = bytes_per_line(width, header.bit_depth);
+        let mut data = vec![0u8; checked_image_size(line_len as usize, height as usize)?];
+        self.copy_pixels(
+            std::ptr::null(),
+            line_len as u32,
+            checked_u32(data.len())?,
+            data.as_mut_ptr(),
+            OutputFormat::Native,
+        )?;
+
+        let palette = parent_inner
+            .colors
+            .iter()
+            .map(|&color| PaletteEntry::from_wic(color))
+            .collect();
+
+        let mut image = BmxImage::new(width, height, header.bit_depth, palette, data)
+            .map_err(BmxError::from)?;
+        image.header.vera_border_color = header.vera_border_color;
+
+        let image = image.letterbox(aspect);
+        let pixel_format = bit_depth_to_pixel_format(header.bit_depth).ok_or(E_UNEXPECTED)?;
 
-    #[allow(clippy::not_unsafe_ptr_arg_deref)]
-    fn GetResolution(&self, x: *mut f64, y: *mut f64) -> windows::core::Result<()> {
         unsafe {
-            *x = 96.0f64;
-            *y = 96.0f64;
-        }
-
-        Ok(())
-    }
-
-    #[allow(clippy::not_unsafe_ptr_arg_deref)]
-    fn GetSize(&self, width: *mut u32, height: *mut u32) -> windows::core::Result<()> {
-        let inner = self.inner.read().unwrap();
-        let parent_inner = inner.parent.inner.read().unwrap();
-        let parent_inner = parent_inner.as_ref().ok_or(E_UNEXPECTED)?;
+            let bitmap = parent_inner.imaging_factory.CreateBitmapFromMemory(
+                image.header.width as u32,
+                image.header.height as u32,
+                &pixel_format,
+                image.header.bytes_per_line() as u32,
+                &image.data,
+            )?;
 
-        unsafe {
-            *width = parent_inner.header.width as _;
-            *height = parent_inner.header.height as _;
+            bitmap.SetPalette(&parent_inner.palette)?;
+            bitmap.cast()
         }
-
-        Ok(())
     }
 
-    #[allow(clippy::not_unsafe_ptr_arg_deref)]
-    fn CopyPixels(
+    // CopyPixels, but in the given format rather than the configured one.
+    fn copy_pixels(
         &self,
         rect: *const WICRect,
         stride: u32,
         buffer_size: u32,
         buffer: *mut u8,
+        output_format: OutputFormat,
     ) -> windows::core::Result<()> {
-        let inner = self.inner.read().unwrap();
-        let parent_inner = inner.parent.inner.read().unwrap();
-        let parent_inner = parent_inner.as_ref().ok_or(E_UNEXPECTED)?;
-
-        if (stride as u16)
-            < bytes_per_line(
-                parent_inner.header.width as _,
-                parent_inner.header.bit_depth as _,
-            )
-        {
-            return Err(WINCODEC_ERR_INSUFFICIENTBUFFER.into());
-        }
+        let inner = &self.inner;
+        let parent_inner = inner.parent.inner.get()?;
 
-        let rect = if rect.is_null() {
-            None
-        } else {
-            Some(unsafe { &*rect })
-        };
+        let header = &parent_inner.header;
 
-        let min_buffer_size = match rect {
+        let (x, y, width, height) = match unsafe { rect.as_ref() } {
             Some(rect) => {
-                bytes_per_line(rect.Width as u16, parent_inner.header.bit_depth) as u32
-                    * rect.Height as u32
-            }
-            None => {
-                bytes_per_line(parent_inner.header.width, parent_inner.header.bit_depth) as u32
-                    * parent_inner.header.height as u32
+                if rect.X < 0
+                    || rect.Y < 0
+                    || rect.Width < 0
+                    || rect.Height < 0
+                    || rect.X as i64 + rect.Width as i64 > header.width as i64
+                    || rect.Y as i64 + rect.Height as i64 > parent_inner.frame_height as i64
+                {
+                    return Err(E_INVALIDARG.into());
+                }
+
+                (
+                    rect.X as usize,
+                    rect.Y as usize,
+                    rect.Width as u16,
+                    rect.Height as usize,
+                )
             }
+            None => (0, 0, header.width, parent_inner.frame_height as usize),
         };
 
-        if buffer_size < min_buffer_size {
-            return Err(WINCODEC_ERR_INSUFFICIENTBUFFER.into());
+        // The rectangle is relative to the frame; everything below works on rows of the image.
+        let y = inner.first_row + y;
+
+        let line_len = bytes_per_line(header.width, header.bit_depth) as usize;
+        let rect_line_len = output_format.line_len(width, header.bit_depth);
+        // Narrower rectangles can still take up as many bytes as a line at sub-byte depths, so
+        // only full lines can be copied without shifting.
+        let full_lines = width == header.width;
+
+        if (stride as usize) < rect_line_len {
+            return Err(Condition::InsufficientBuffer.into());
         }
 
-        let stream = &parent_inner.stream;
+        if (buffer_size as usize) < checked_buffer_size(stride, height, rect_line_len)? {
+            return Err(Condition::InsufficientBuffer.into());
+        }
 
-        match rect {
-            Some(rect) => {
-                if rect.X < 0
-                    || rect.Y < 0
-                    || rect.X + rect.Width > parent_inner.header.width as i32
-                    || rect.Y + rect.Height > parent_inner.header.height as i32
-                {
-                    return Err(E_INVALIDARG.into());
-                }
+        let stream = &*inner.stream.lock().unwrap();
+        let fill = header.border_fill_byte();
 
-                let offset =
-                    bytes_per_line(parent_inner.header.width, parent_inner.header.bit_depth) as u32
-                        * (rect.Y as u32)
-                        + (rect.X as u32);
+        let progress = inner
+            .parent
+            .progress
+            .reporter(WICProgressOperationCopyPixels);
+        progress.begin()?;
 
-                unsafe {
-                    stream.Seek(
-                        parent_inner.header.data_start as i64 + offset as i64,
-                        STREAM_SEEK_SET,
-                        None,
-                    )?;
-                }
+        let mut reader = StreamReadWriteWrapper::new(stream);
 
-                let bytes_per_line =
-                    bytes_per_line(rect.Width as u16, parent_inner.header.bit_depth);
+        let skipped = checked_image_size(y, line_len)? as u64;
 
-                let mut buffer = buffer;
+        reader
+            .seek(SeekFrom::Start(header.data_start as u64 + skipped))
+            .map_err(IoErrorExt::to_win_error)?;
 
-                for i in 0..rect.Height {
-                    if i > 0 && rect.X > 0 {
-                        // skip the first bytes
-                        stream_read_exact(stream, unsafe {
-                            std::slice::from_raw_parts_mut(buffer, rect.X as _)
-                        })?;
-                    }
+        let mut available = parent_inner.pixel_data_available.saturating_sub(skipped);
 
-                    stream_read_exact(stream, unsafe {
-                        std::slice::from_raw_parts_mut(buffer, bytes_per_line as _)
-                    })?;
+        // Full-width requests into a tightly packed buffer need no copying at all.
+        if output_format == OutputFormat::Native && full_lines && stride as usize == line_len {
+            let destination = unsafe {
+                std::slice::from_raw_parts_mut(buffer, checked_image_size(height, line_len)?)
+            };
 
-                    unsafe {
-                        buffer = buffer.add(stride as _);
+            read_pixels(&mut reader, destination, &mut available, fill)?;
+            progress.progress(height, height)?;
+
+            return progress.end();
+        }
+
+        // Everything else is read in chunks of whole rows and copied row by row. Partial rows are
+        // shifted into place, since sub-byte formats don't necessarily start the rectangle on a
+        // byte boundary.
+        let rows_per_chunk = (READ_CHUNK_SIZE / line_len.max(1)).clamp(1, height.max(1));
+        let mut chunk = vec![0u8; rows_per_chunk * line_len];
+        let mut row = 0;
+
+        while row < height {
+            let rows = rows_per_chunk.min(height - row);
+            let chunk = &mut chunk[..rows * line_len];
+
+            read_pixels(&mut reader, chunk, &mut available, fill)?;
+
+            for (i, line) in chunk.chunks_exact(line_len).enumerate() {
+                let destination = unsafe {
+                    std::slice::from_raw_parts_mut(
+                        buffer.add((row + i) * stride as usize),
+                        rect_line_len,
+                    )
+                };
+
+                match output_format {
+                    OutputFormat::Native if full_lines => {
+                        destination.copy_from_slice(line);
+                    }
+                    OutputFormat::Native => copy_bits(
+                        line,
+                        x * header.bit_depth as usize,
+                        width as usize * header.bit_depth as usize,
+                        destination,
+                    ),
+                    OutputFormat::Indexed8 => {
+                        expand_indices(line, x, header.bit_depth, destination);
+                    }
+                    OutputFormat::Bgra32 => {
+                        expand_to_bgra(line, x, header.bit_depth, &parent_inner.colors, destination)
                     }
                 }
             }
-            None => {
-                let bytes_per_line =
-                    bytes_per_line(parent_inner.header.width, parent_inner.header.bit_depth);
 
-                let mut buffer = buffer;
+            row += rows;
+            progress.progress(row, height)?;
+        }
 
-                for _ in 0..parent_inner.header.height {
-                    stream_read_exact(stream, unsafe {
-                        std::slice::from_raw_parts_mut(buffer, bytes_per_line as _)
-                    })?;
+        progress.end()
+    }
+}
 
-                    unsafe {
-                        buffer = buffer.add(stride as _);
-                    }
-                }
-            }
+impl IWICBitmapSource_Impl for FrameDecoder_Impl {
+    fn GetPixelFormat(&self) -> windows::core::Result<windows::core::GUID> {
+        let inner = &self.inner;
+        let parent_inner = inner.parent.inner.get()?;
+
+        parent_inner
+            .output_format
+            .pixel_format(parent_inner.header.bit_depth)
+            .ok_or(E_UNEXPECTED.into())
+    }
+
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn GetResolution(&self, x: *mut f64, y: *mut f64) -> windows::core::Result<()> {
+        if x.is_null() || y.is_null() {
+            return Err(E_INVALIDARG.into());
+        }
+
+        let parent_inner = self.inner.parent.inner.get()?;
+        let (dpi_x, dpi_y) = parent_inner.header.resolution().unwrap_or((96, 96));
+
+        unsafe {
+            *x = dpi_x as f64;
+            *y = dpi_y as f64;
         }
 
         Ok(())
     }
 
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn GetSize(&self, width: *mut u32, height: *mut u32) -> windows::core::Result<()> {
+        let inner = &self.inner;
+        let parent_inner = inner.parent.inner.get()?;
+
+        unsafe {
+            *width = parent_inner.header.width as _;
+            *height = parent_inner.frame_height as _;
+        }
+
+        Ok(())
+    }
+
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn CopyPixels(
+        &self,
+        rect: *const WICRect,
+        stride: u32,
+        buffer_size: u32,
+        buffer: *mut u8,
+    ) -> windows::core::Result<()> {
+        let output_format = self.inner.parent.inner.get()?.output_format;
+        self.copy_pixels(rect, stride, buffer_size, buffer, output_format)
+    }
+
     fn CopyPalette(&self, palette: Option<&IWICPalette>) -> windows::core::Result<()> {
         let palette = palette.ok_or(E_INVALIDARG)?;
 
-        let inner = self.inner.read().unwrap();
+        let inner = &self.inner;
+
+        if inner.parent.inner.get()?.output_format == OutputFormat::Bgra32 {
+            return Err(Condition::PaletteUnavailable.into());
+        }
+
         inner.parent.CopyPalette(Some(palette))
     }
 }
 
+impl FrameDecoder_Impl {
+    fn thumbnail(&self, aspect: (u16, u16)) -> windows::core::Result<IWICBitmapSource> {
+        match settings::thumbnail_background() {
+            ThumbnailBackground::BorderColor => self.letterboxed(aspect),
+            ThumbnailBackground::None => self.to_interface::<IWICBitmapFrameDecode>().cast(),
+        }
+    }
+}
+
 impl IWICBitmapFrameDecode_Impl for FrameDecoder_Impl {
     fn GetThumbnail(&self) -> windows::core::Result<IWICBitmapSource> {
-        unsafe { self.cast() }
+        self.thumbnail(THUMBNAIL_ASPECT)
     }
 
     #[allow(clippy::not_unsafe_ptr_arg_deref)]
@@ -431,7 +821,33 @@ impl IWICBitmapFrameDecode_Impl for FrameDecoder_Impl {
     }
 
     fn GetMetadataQueryReader(&self) -> windows::core::Result<IWICMetadataQueryReader> {
-        Err(E_NOTIMPL.into())
+        let inner = &self.inner;
+        let parent_inner = inner.parent.inner.get()?;
+        let header = &parent_inner.header;
+
+        let integrity = if header.crc32().is_some() {
+            let stream = &*inner.stream.lock().unwrap();
+            let mut reader = StreamReadWriteWrapper::new(stream);
+            let mut data = Vec::new();
+
+            reader
+                .seek(SeekFrom::Start(header.data_start as u64))
+                .and_then(|_| reader.read_to_end(&mut data))
+                .map_err(IoErrorExt::to_win_error)?;
+
+            header.check_integrity(&data)
+        } else {
+            Integrity::Absent
+        };
+
+        Ok(ComObject::new(MetadataQueryReader::new(
+            integrity,
+            header.crc32(),
+            header.pal_used,
+            header.pal_start,
+            header.is_newer_version(),
+        ))
+        .into_interface())
     }
 }
 
@@ -441,14 +857,115 @@ impl IWICMetadataBlockReader_Impl for FrameDecoder_Impl {
     }
 
     fn GetCount(&self) -> windows::core::Result<u32> {
-        Ok(0)
+        Ok(1)
     }
 
     fn GetEnumerator(&self) -> windows::core::Result<IEnumUnknown> {
         Err(E_NOTIMPL.into())
     }
 
-    fn GetReaderByIndex(&self, _index: u32) -> windows::core::Result<IWICMetadataReader> {
-        Err(E_INVALIDARG.into())
+    fn GetReaderByIndex(&self, index: u32) -> windows::core::Result<IWICMetadataReader> {
+        if index != 0 {
+            return Err(E_INVALIDARG.into());
+        }
+
+        let header = &self.inner.parent.inner.get()?.header;
+        let writer: IWICMetadataWriter =
+            ComObject::new(ReservedMetadata::new(header.reserved)).into_interface();
+
+        Ok(writer.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand_sub_byte_indices() {
+        let mut destination = [0u8; 5];
+
+        expand_indices(&[0b1011_0010, 0b1100_0000], 3, 1, &mut destination);
+        assert_eq!(destination, [1, 0, 0, 1, 0]);
+
+        expand_indices(&[0b00_01_10_11, 0b10_00_00_00], 1, 2, &mut destination);
+        assert_eq!(destination, [1, 2, 3, 2, 0]);
+
+        expand_indices(&[0x12, 0x34, 0x56], 1, 4, &mut destination);
+        assert_eq!(destination, [2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn expand_to_bgra_pixels() {
+        let mut destination = [0u8; 12];
+
+        expand_to_bgra(
+            &[0b0110_0000],
+            1,
+            1,
+            &[0xFF000000, 0xFF123456],
+            &mut destination,
+        );
+        assert_eq!(
+            destination,
+            [0x56, 0x34, 0x12, 0xFF, 0x56, 0x34, 0x12, 0xFF, 0x00, 0x00, 0x00, 0xFF]
+        );
+
+        // Index 2 is past the end of the palette.
+        expand_to_bgra(
+            &[0x02, 0x01, 0x00],
+            0,
+            8,
+            &[0xFFAABBCC, 0xFF112233],
+            &mut destination,
+        );
+        assert_eq!(
+            destination,
+            [0x00, 0x00, 0x00, 0xFF, 0x33, 0x22, 0x11, 0xFF, 0xCC, 0xBB, 0xAA, 0xFF]
+        );
+    }
+}
+
+#[cfg(all(test, windows))]
+mod windows_tests {
+    use windows::Win32::Foundation::{WINCODEC_ERR_BADHEADER, WINCODEC_ERR_IMAGESIZEOUTOFRANGE};
+    use windows::Win32::Graphics::Imaging::{IWICBitmapDecoder, WICDecodeMetadataCacheOnDemand};
+    use windows::Win32::UI::Shell::SHCreateMemStream;
+    use windows_core::{ComObject, HRESULT};
+
+    use super::*;
+
+    fn initialize(header: &FileHeader) -> HRESULT {
+        let mut bytes = header.to_bytes().to_vec();
+        bytes.resize(FileHeader::SIZE + PaletteEntry::SIZE * 256 + 64, 0);
+        let stream = unsafe { SHCreateMemStream(Some(&bytes)) }.unwrap();
+
+        let decoder: IWICBitmapDecoder = ComObject::new(BitmapDecoder::new()).into_interface();
+        match unsafe { decoder.Initialize(&stream, WICDecodeMetadataCacheOnDemand) } {
+            Ok(()) => HRESULT(0),
+            Err(err) => err.code(),
+        }
+    }
+
+    #[test]
+    fn refuses_oversized_headers() {
+        let header = FileHeader::builder()
+            .width(u16::MAX)
+            .height(u16::MAX)
+            .bit_depth(8)
+            .palette_len(256)
+            .build()
+            .unwrap();
+        assert_eq!(initialize(&header), WINCODEC_ERR_IMAGESIZEOUTOFRANGE);
+
+        let mut header = FileHeader::builder()
+            .width(16)
+            .height(16)
+            .bit_depth(8)
+            .palette_len(256)
+            .build()
+            .unwrap();
+        header.data_start = u16::MAX;
+        assert_eq!(initialize(&header), WINCODEC_ERR_BADHEADER);
     }
 }
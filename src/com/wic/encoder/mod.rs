@@ -1,17 +1,25 @@
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::RwLock;
 
-use windows::Win32::Foundation::{
-    E_ILLEGAL_STATE_CHANGE, E_NOTIMPL, E_POINTER, E_UNEXPECTED, WINCODEC_ERR_CODECTOOMANYSCANLINES,
-    WINCODEC_ERR_SOURCERECTDOESNOTMATCHDIMENSIONS, WINCODEC_ERR_UNEXPECTEDSIZE,
-    WINCODEC_ERR_UNSUPPORTEDOPERATION,
-};
+use std::io::Write;
+use windows::Win32::Foundation::{E_ILLEGAL_STATE_CHANGE, E_NOTIMPL, E_POINTER, E_UNEXPECTED};
 use windows::Win32::Graphics::Imaging::{
     GUID_WICPixelFormat1bppIndexed, GUID_WICPixelFormat2bppIndexed, GUID_WICPixelFormat4bppIndexed,
-    GUID_WICPixelFormat8bppIndexed, IWICBitmapEncoderInfo, IWICBitmapFrameEncode,
-    IWICBitmapFrameEncode_Impl, IWICMetadataQueryWriter, WICBitmapEncoderCacheOption,
-    WICBitmapPaletteTypeFixedHalftone256, WICRect,
+    GUID_WICPixelFormat8bppIndexed, IWICBitmapCodecProgressNotification,
+    IWICBitmapCodecProgressNotification_Impl, IWICBitmapEncoderInfo, IWICBitmapFrameDecode,
+    IWICBitmapFrameEncode, IWICBitmapFrameEncode_Impl, IWICComponentFactory,
+    IWICMetadataBlockReader, IWICMetadataBlockReader_Impl, IWICMetadataBlockWriter,
+    IWICMetadataBlockWriter_Impl, IWICMetadataQueryWriter, IWICMetadataReader, IWICMetadataWriter,
+    PFNProgressNotification, WICBitmapEncoderCacheOption, WICProgressOperationWritePixels, WICRect,
+};
+use windows::Win32::System::Com::{
+    IEnumUnknown,
+    Marshal::IMarshal,
+    StructuredStorage::{IPropertyBag2, PROPBAG2},
 };
-use windows::Win32::System::Com::StructuredStorage::IPropertyBag2;
+use windows::Win32::System::Ole::PROPBAG2_TYPE_DATA;
+use windows::Win32::System::Variant::{VT_BOOL, VT_UI1};
+
 use windows::{
     core::{implement, ComObject, IUnknownImpl, Interface, GUID, HRESULT},
     Win32::{
@@ -23,20 +31,24 @@ use windows::{
         System::Com::{CoCreateInstance, IStream, CLSCTX_INPROC_SERVER},
     },
 };
-use windows_core::{w, PCWSTR};
+use windows_core::{w, HSTRING, PCWSTR, PROPVARIANT, PWSTR, VARIANT};
 
-use super::util::{bytes_per_line, pixel_format_to_bit_depth};
-use crate::bmx::{FileHeader, PaletteEntry};
-use crate::com::stream_write_exact_items;
+use super::util::{
+    bytes_per_line, checked_buffer_size, checked_image_size, checked_u32, pixel_format_to_bit_depth,
+};
+use crate::bmx::{palette::VERA_DEFAULT, FileHeader, PaletteEntry};
+use crate::com::hresult::Condition;
+use crate::com::util::{impl_free_threaded_marshaler, ComState, FreeThreadedMarshaler};
+use crate::com::{FileHeaderErrorExt, IoErrorExt, StreamReadWriteWrapper};
+use crate::crc32::Crc32;
+use crate::registry::get_class_setting;
 use crate::util::guid;
 
 use super::super::CoClass;
-use super::com::CONTAINER_FORMAT;
-
-enum PaletteToUse {
-    Frame(IWICPalette),
-    BitmapSource(IWICPalette),
-}
+use super::com::{CONTAINER_FORMAT, RESERVED_METADATA_FORMAT};
+use super::decoder::metadata::PAL_START;
+use super::progress::ProgressNotification;
+use super::reserved::{read_reserved, ReservedMetadata};
 
 struct Chunk {
     data: Vec<u8>,
@@ -44,17 +56,228 @@ struct Chunk {
     lines: u16,
 }
 
+// Palette precedence: the frame's own palette, then the encoder's, then the palette of the first
+// source written with WriteSource. Only if none of them exists is one generated.
+fn select_palette<T: Clone>(
+    frame: Option<&T>,
+    encoder: Option<&T>,
+    source: Option<&T>,
+) -> Option<T> {
+    frame.or(encoder).or(source).cloned()
+}
+
+// Highest palette index referenced by the written pixels.
+fn highest_index(chunks: &[Chunk], width: u16, bit_depth: u8) -> u8 {
+    let bit_depth = bit_depth as usize;
+    let mask = ((1u16 << bit_depth) - 1) as u8;
+
+    chunks
+        .iter()
+        .flat_map(|chunk| {
+            chunk
+                .data
+                .chunks_exact(chunk.stride as _)
+                .take(chunk.lines as _)
+        })
+        .flat_map(|line| {
+            (0..width as usize).map(move |x| {
+                let bit = x * bit_depth;
+                (line[bit / 8] >> (8 - bit_depth - bit % 8)) & mask
+            })
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+fn generate_palette(
+    imaging_factory: &IWICImagingFactory,
+    bit_depth: u8,
+    highest_index: u8,
+) -> windows::core::Result<IWICPalette> {
+    let palette = unsafe { imaging_factory.CreatePalette()? };
+
+    let colors = if get_class_setting::<BitmapEncoder>(USE_VERA_DEFAULT_PALETTE).unwrap_or(0) != 0 {
+        VERA_DEFAULT[..1 << bit_depth]
+            .iter()
+            .map(PaletteEntry::to_wic)
+            .collect::<Vec<_>>()
+    } else {
+        // The pixels are bare indices, so there are no colors to go by. Spreading the indices that
+        // are actually used over a gray ramp keeps them distinguishable and in order, and the
+        // palette no larger than the image needs.
+        let count = highest_index as u32 + 1;
+
+        (0..count)
+            .map(|i| {
+                let level = (i * 255).checked_div(count - 1).unwrap_or(0);
+                0xFF000000 | (level << 16) | (level << 8) | level
+            })
+            .collect::<Vec<_>>()
+    };
+
+    unsafe { palette.InitializeCustom(&colors)? };
+    Ok(palette)
+}
+
+pub const TARGET_VERSION: PCWSTR = w!("TargetVersion");
+pub const USE_VERA_DEFAULT_PALETTE: PCWSTR = w!("UseVeraDefaultPalette");
+pub const WRITE_CHECKSUM: PCWSTR = w!("WriteChecksum");
+pub const TRUNCATE_PALETTE: PCWSTR = w!("TruncatePalette");
+// Both a registry default and an encoder option.
+pub const STRICT_VERA: PCWSTR = w!("StrictVera");
+// Accept and drop thumbnails and previews instead of failing, for hosts that always set them.
+pub const IGNORE_THUMBNAILS: PCWSTR = w!("IgnoreThumbnails");
+
+// BMX has nowhere to store either.
+fn ignore_thumbnail() -> windows::core::Result<()> {
+    if get_class_setting::<BitmapEncoder>(IGNORE_THUMBNAILS).unwrap_or(0) != 0 {
+        Ok(())
+    } else {
+        Err(Condition::UnsupportedOperation.into())
+    }
+}
+
+// What the Commander X16 can display from a bitmap layer.
+const VERA_MAX_WIDTH: u16 = 640;
+const VERA_MAX_HEIGHT: u16 = 480;
+const VERA_VRAM_SIZE: usize = 128 * 1024;
+
+fn check_vera_constraints(width: u16, height: u16, bit_depth: u8) -> Result<(), String> {
+    if width > VERA_MAX_WIDTH {
+        return Err(format!(
+            "Width {} exceeds the VERA maximum of {}",
+            width, VERA_MAX_WIDTH
+        ));
+    }
+
+    if height > VERA_MAX_HEIGHT {
+        return Err(format!(
+            "Height {} exceeds the VERA maximum of {}",
+            height, VERA_MAX_HEIGHT
+        ));
+    }
+
+    if bit_depth < 8 && !width.is_multiple_of(8) {
+        return Err(format!(
+            "Width {} must be a multiple of 8 at {} bpp",
+            width, bit_depth
+        ));
+    }
+
+    let size = bytes_per_line(width, bit_depth) as usize * height as usize;
+    if size > VERA_VRAM_SIZE {
+        return Err(format!(
+            "Bitmap needs {} bytes, but VERA only has {} bytes of video RAM",
+            size, VERA_VRAM_SIZE
+        ));
+    }
+
+    Ok(())
+}
+
+fn create_encoder_options(
+    imaging_factory: &IWICImagingFactory,
+) -> windows::core::Result<IPropertyBag2> {
+    let options = [
+        PROPBAG2 {
+            dwType: PROPBAG2_TYPE_DATA.0 as _,
+            vt: VT_BOOL,
+            pstrName: PWSTR(STRICT_VERA.0 as *mut _),
+            ..Default::default()
+        },
+        PROPBAG2 {
+            dwType: PROPBAG2_TYPE_DATA.0 as _,
+            vt: VT_UI1,
+            pstrName: PWSTR(TARGET_VERSION.0 as *mut _),
+            ..Default::default()
+        },
+    ];
+
+    unsafe {
+        imaging_factory
+            .cast::<IWICComponentFactory>()?
+            .CreateEncoderPropertyBag(&options)
+    }
+}
+
+// pal_start of a source decoded from a BMX file. WIC palettes have no offset, so it travels
+// through the frame's metadata instead.
+fn source_pal_start(source: &IWICBitmapSource) -> Option<u8> {
+    let frame = source.cast::<IWICBitmapFrameDecode>().ok()?;
+
+    unsafe {
+        let reader = frame.GetMetadataQueryReader().ok()?;
+        if reader.GetContainerFormat().ok()? != CONTAINER_FORMAT {
+            return None;
+        }
+
+        let mut value = PROPVARIANT::default();
+        reader
+            .GetMetadataByName(&HSTRING::from(PAL_START), &mut value)
+            .ok()?;
+
+        u32::try_from(&value).ok()?.try_into().ok()
+    }
+}
+
+// None if the option is missing or hasn't been set, so the registry default applies.
+fn read_option<T: for<'a> TryFrom<&'a VARIANT>>(
+    options: &IPropertyBag2,
+    name: PCWSTR,
+) -> Option<T> {
+    let option = PROPBAG2 {
+        pstrName: PWSTR(name.0 as *mut _),
+        ..Default::default()
+    };
+
+    let mut value = VARIANT::default();
+    let mut result = HRESULT::default();
+
+    unsafe { options.Read(1, &option, None, &mut value, &mut result) }.ok()?;
+    result.ok().ok()?;
+
+    if value.is_empty() {
+        None
+    } else {
+        T::try_from(&value).ok()
+    }
+}
+
+// Only versions the decoder reads without falling back to best effort can be written.
+fn target_version(options: Option<&IPropertyBag2>) -> windows::core::Result<u8> {
+    let Some(version) = options
+        .and_then(|options| read_option::<u32>(options, TARGET_VERSION))
+        .or_else(|| get_class_setting::<BitmapEncoder>(TARGET_VERSION))
+    else {
+        return Ok(FileHeader::VERSION);
+    };
+
+    u8::try_from(version)
+        .ok()
+        .filter(|version| (FileHeader::MIN_VERSION..=FileHeader::VERSION).contains(version))
+        .ok_or_else(|| {
+            Condition::InvalidParameter.error(format!(
+                "Target version {} is not between {} and {}",
+                version,
+                FileHeader::MIN_VERSION,
+                FileHeader::VERSION
+            ))
+        })
+}
+
 struct BitmapEncoderData {
     imaging_factory: IWICImagingFactory,
     stream: IStream,
-    palette: Option<IWICPalette>,
-    has_frame: bool,
+    palette: RwLock<Option<IWICPalette>>,
+    has_frame: AtomicBool,
 }
 
 #[derive(Default)]
-#[implement(IWICBitmapEncoder)]
+#[implement(IWICBitmapEncoder, IWICBitmapCodecProgressNotification, IMarshal)]
 pub struct BitmapEncoder {
-    inner: RwLock<Option<BitmapEncoderData>>,
+    inner: ComState<BitmapEncoderData>,
+    progress: ProgressNotification,
+    marshaler: FreeThreadedMarshaler,
 }
 
 impl BitmapEncoder {
@@ -67,6 +290,22 @@ impl CoClass for BitmapEncoder {
     const CLSID: GUID = guid::from_str("9d718e6d-4c95-4dc9-abd1-156a50488ebd");
     const PROG_ID: PCWSTR = w!("X16BMX.BMXEncoder.1");
     const VERSION_INDEPENDENT_PROG_ID: PCWSTR = w!("X16BMX.BMXEncoder");
+    const FRIENDLY_NAME: PCWSTR = w!("BMX Encoder");
+    const DESCRIPTION: PCWSTR = w!("BMX File");
+}
+
+impl_free_threaded_marshaler!(BitmapEncoder_Impl, marshaler);
+
+impl IWICBitmapCodecProgressNotification_Impl for BitmapEncoder_Impl {
+    fn RegisterProgressNotification(
+        &self,
+        callback: PFNProgressNotification,
+        data: *const core::ffi::c_void,
+        flags: u32,
+    ) -> windows::core::Result<()> {
+        self.progress.register(callback, data, flags);
+        Ok(())
+    }
 }
 
 impl IWICBitmapEncoder_Impl for BitmapEncoder_Impl {
@@ -77,20 +316,17 @@ impl IWICBitmapEncoder_Impl for BitmapEncoder_Impl {
     ) -> windows::core::Result<()> {
         let stream = stream.ok_or(E_INVALIDARG)?;
 
-        let mut inner = self.inner.write().unwrap();
-        if inner.is_some() {
-            return Err(HRESULT::from_win32(ERROR_ALREADY_INITIALIZED.0).into());
-        }
+        self.inner.ensure_uninitialized()?;
 
         let imaging_factory: IWICImagingFactory =
             unsafe { CoCreateInstance(&CLSID_WICImagingFactory, None, CLSCTX_INPROC_SERVER)? };
 
-        inner.replace(BitmapEncoderData {
+        self.inner.initialize(BitmapEncoderData {
             imaging_factory,
             stream: stream.clone(),
-            palette: None,
-            has_frame: false,
-        });
+            palette: RwLock::new(None),
+            has_frame: AtomicBool::new(false),
+        })?;
 
         Ok(())
     }
@@ -100,8 +336,7 @@ impl IWICBitmapEncoder_Impl for BitmapEncoder_Impl {
     }
 
     fn GetEncoderInfo(&self) -> windows::core::Result<IWICBitmapEncoderInfo> {
-        let inner = self.inner.read().unwrap();
-        let inner = inner.as_ref().ok_or(E_UNEXPECTED)?;
+        let inner = self.inner.get()?;
         let component_info = unsafe {
             inner
                 .imaging_factory
@@ -116,26 +351,24 @@ impl IWICBitmapEncoder_Impl for BitmapEncoder_Impl {
         _count: u32,
         _colorcontext: *const Option<IWICColorContext>,
     ) -> windows::core::Result<()> {
-        Err(WINCODEC_ERR_UNSUPPORTEDOPERATION.into())
+        Err(Condition::UnsupportedOperation.into())
     }
 
     fn SetPalette(&self, palette: Option<&IWICPalette>) -> windows::core::Result<()> {
         let palette = palette.ok_or(E_POINTER)?;
 
-        let mut inner = self.inner.write().unwrap();
-        let inner = inner.as_mut().ok_or(E_UNEXPECTED)?;
-
-        inner.palette = Some(palette.clone());
+        let inner = self.inner.get()?;
+        *inner.palette.write().unwrap() = Some(palette.clone());
 
         Ok(())
     }
 
     fn SetThumbnail(&self, _thumbnail: Option<&IWICBitmapSource>) -> windows::core::Result<()> {
-        Err(WINCODEC_ERR_UNSUPPORTEDOPERATION.into())
+        ignore_thumbnail()
     }
 
     fn SetPreview(&self, _preview: Option<&IWICBitmapSource>) -> windows::core::Result<()> {
-        Err(WINCODEC_ERR_UNSUPPORTEDOPERATION.into())
+        ignore_thumbnail()
     }
 
     #[allow(clippy::not_unsafe_ptr_arg_deref)]
@@ -144,10 +377,13 @@ impl IWICBitmapEncoder_Impl for BitmapEncoder_Impl {
         frame_encode: *mut Option<IWICBitmapFrameEncode>,
         encoder_options: *mut Option<IPropertyBag2>,
     ) -> windows::core::Result<()> {
-        let mut inner = self.inner.write().unwrap();
-        let inner = inner.as_mut().ok_or(E_UNEXPECTED)?;
+        let inner = self.inner.get()?;
 
-        if inner.has_frame {
+        if inner
+            .has_frame
+            .compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire)
+            .is_err()
+        {
             if !frame_encode.is_null() {
                 unsafe { frame_encode.write(None) };
             }
@@ -156,10 +392,12 @@ impl IWICBitmapEncoder_Impl for BitmapEncoder_Impl {
                 unsafe { encoder_options.write(None) };
             }
 
-            Err(WINCODEC_ERR_UNSUPPORTEDOPERATION.into())
+            Err(Condition::UnsupportedOperation.into())
         } else {
             if !encoder_options.is_null() {
-                unsafe { encoder_options.write(None) };
+                let options = create_encoder_options(&inner.imaging_factory)
+                    .inspect_err(|_| inner.has_frame.store(false, Ordering::Release))?;
+                unsafe { encoder_options.write(Some(options)) };
             }
 
             let frame_encoder: IWICBitmapFrameEncode =
@@ -167,8 +405,6 @@ impl IWICBitmapEncoder_Impl for BitmapEncoder_Impl {
 
             unsafe { frame_encode.write(Some(frame_encoder)) };
 
-            inner.has_frame = true;
-
             Ok(())
         }
     }
@@ -185,12 +421,18 @@ impl IWICBitmapEncoder_Impl for BitmapEncoder_Impl {
 struct FrameEncoderData {
     parent: ComObject<BitmapEncoder>,
     header: Option<FileHeader>,
-    palette: Option<PaletteToUse>,
+    palette: Option<IWICPalette>,
+    source_palette: Option<IWICPalette>,
+    source_pal_start: Option<u8>,
     image_data: Vec<Chunk>,
     accumulated_height: u16,
+    resolution: Option<(f64, f64)>,
+    target_version: u8,
+    strict_vera: bool,
+    metadata_writers: Vec<IWICMetadataWriter>,
 }
 
-#[implement(IWICBitmapFrameEncode)]
+#[implement(IWICBitmapFrameEncode, IWICMetadataBlockWriter)]
 struct FrameEncoder {
     inner: RwLock<FrameEncoderData>,
 }
@@ -202,20 +444,31 @@ impl FrameEncoder {
                 parent,
                 header: None,
                 palette: None,
+                source_palette: None,
+                source_pal_start: None,
                 image_data: Vec::new(),
                 accumulated_height: 0,
+                resolution: None,
+                target_version: FileHeader::VERSION,
+                strict_vera: false,
+                metadata_writers: Vec::new(),
             }),
         }
     }
 }
 
 impl IWICBitmapFrameEncode_Impl for FrameEncoder_Impl {
-    fn Initialize(&self, _encoder_options: Option<&IPropertyBag2>) -> windows::core::Result<()> {
+    fn Initialize(&self, encoder_options: Option<&IPropertyBag2>) -> windows::core::Result<()> {
         let mut inner = self.inner.write().unwrap();
         if inner.header.is_some() {
             return Err(HRESULT::from_win32(ERROR_ALREADY_INITIALIZED.0).into());
         }
 
+        inner.target_version = target_version(encoder_options)?;
+        inner.strict_vera = encoder_options
+            .and_then(|options| read_option::<bool>(options, STRICT_VERA))
+            .unwrap_or_else(|| get_class_setting::<BitmapEncoder>(STRICT_VERA).unwrap_or(0) != 0);
+
         inner.header.replace(FileHeader::default());
         Ok(())
     }
@@ -260,7 +513,15 @@ impl IWICBitmapFrameEncode_Impl for FrameEncoder_Impl {
         Ok(())
     }
 
-    fn SetResolution(&self, _x: f64, _y: f64) -> windows::core::Result<()> {
+    fn SetResolution(&self, x: f64, y: f64) -> windows::core::Result<()> {
+        if !(x.is_finite() && y.is_finite() && x > 0.0 && y > 0.0) {
+            return Err(windows::core::Error::new(
+                E_INVALIDARG,
+                "Resolution must be positive",
+            ));
+        }
+
+        self.inner.write().unwrap().resolution = Some((x, y));
         Ok(())
     }
 
@@ -296,20 +557,20 @@ impl IWICBitmapFrameEncode_Impl for FrameEncoder_Impl {
         _count: u32,
         _color_contexts: *const Option<IWICColorContext>,
     ) -> windows::core::Result<()> {
-        Err(WINCODEC_ERR_UNSUPPORTEDOPERATION.into())
+        Err(Condition::UnsupportedOperation.into())
     }
 
     fn SetPalette(&self, palette: Option<&IWICPalette>) -> windows::core::Result<()> {
         let palette = palette.ok_or(E_POINTER)?;
 
         let mut inner = self.inner.write().unwrap();
-        inner.palette = Some(PaletteToUse::Frame(palette.clone()));
+        inner.palette = Some(palette.clone());
 
         Ok(())
     }
 
     fn SetThumbnail(&self, _thumbnail: Option<&IWICBitmapSource>) -> windows::core::Result<()> {
-        Err(WINCODEC_ERR_UNSUPPORTEDOPERATION.into())
+        ignore_thumbnail()
     }
 
     fn WritePixels(
@@ -323,13 +584,6 @@ impl IWICBitmapFrameEncode_Impl for FrameEncoder_Impl {
             return Err(E_POINTER.into());
         }
 
-        if buffer_size < stride {
-            return Err(windows::core::Error::new(
-                E_INVALIDARG,
-                "Buffer size must not be smaller than stride",
-            ));
-        }
-
         let line_count: u16 = line_count
             .try_into()
             .map_err(|_| windows::core::Error::new(E_INVALIDARG, "line count out of range"))?;
@@ -351,17 +605,41 @@ impl IWICBitmapFrameEncode_Impl for FrameEncoder_Impl {
             ));
         }
 
-        if inner.accumulated_height + line_count > header.height {
-            return Err(windows::core::Error::new(
-                WINCODEC_ERR_CODECTOOMANYSCANLINES,
-                "Too many scanlines",
-            ));
+        if inner.accumulated_height as u32 + line_count as u32 > header.height as u32 {
+            return Err(Condition::TooManyScanlines.error("Too many scanlines"));
+        }
+
+        let line_len = header.bytes_per_line();
+
+        if (stride as usize) < line_len {
+            return Err(Condition::InsufficientBuffer.error(format!(
+                "Stride {} is smaller than a line of {} bytes",
+                stride, line_len
+            )));
+        }
+
+        // Like CopyPixels, the last line doesn't need to be padded to the full stride.
+        let required_size = checked_buffer_size(stride, line_count as usize, line_len)?;
+
+        if (buffer_size as usize) < required_size {
+            return Err(Condition::InsufficientBuffer.error(format!(
+                "Buffer of {} bytes is too small for {} lines with stride {}",
+                buffer_size, line_count, stride
+            )));
+        }
+
+        // Only the pixel bytes of each line are kept, so the frame never holds more than the image
+        // itself, whatever stride and buffer size the caller passes.
+        let pixels = unsafe { std::slice::from_raw_parts(pixels, required_size) };
+
+        let mut data = Vec::with_capacity(checked_image_size(line_len, line_count as usize)?);
+        for line in 0..line_count as usize {
+            data.extend_from_slice(&pixels[line * stride as usize..][..line_len]);
         }
 
-        let data = unsafe { std::slice::from_raw_parts(pixels, buffer_size as _) }.to_vec();
         inner.image_data.push(Chunk {
             data,
-            stride: stride as _,
+            stride: line_len as _,
             lines: line_count as _,
         });
 
@@ -383,8 +661,13 @@ impl IWICBitmapFrameEncode_Impl for FrameEncoder_Impl {
             fn intersect(&self, other: &Self) -> Self {
                 let x = self.X.max(other.X);
                 let y = self.Y.max(other.Y);
-                let width = (self.X + self.Width).min(other.X + other.Width) - x;
-                let height = (self.Y + self.Height).min(other.Y + other.Height) - y;
+                // Saturating, since callers may pass rectangles reaching past i32::MAX.
+                let width = (self.X.saturating_add(self.Width))
+                    .min(other.X.saturating_add(other.Width))
+                    .saturating_sub(x);
+                let height = (self.Y.saturating_add(self.Height))
+                    .min(other.Y.saturating_add(other.Height))
+                    .saturating_sub(y);
 
                 if width < 0 || height < 0 {
                     Default::default()
@@ -426,119 +709,133 @@ impl IWICBitmapFrameEncode_Impl for FrameEncoder_Impl {
 
         let pixel_format = unsafe { bitmap_source.GetPixelFormat()? };
         let pixel_format_bit_depth = pixel_format_to_bit_depth(&pixel_format)
-            .ok_or(windows::core::Error::new(
-                WINCODEC_ERR_UNSUPPORTEDOPERATION,
-                "Invalid pixel format",
-            ))?
+            .ok_or(Condition::UnsupportedOperation.error("Invalid pixel format"))?
             .get();
 
-        let mut inner = self.inner.write().unwrap();
+        // BMX has no resolution of its own, so any source DPI is fine; it's kept unless the caller
+        // set one explicitly.
+        let source_resolution = {
+            let (mut x, mut y) = (0.0, 0.0);
+            unsafe { bitmap_source.GetResolution(&mut x, &mut y) }
+                .ok()
+                .filter(|()| x > 0.0 && y > 0.0)
+                .map(|()| (x, y))
+        };
 
-        let inner_accumulated_height = inner.accumulated_height;
+        let mut inner = self.inner.write().unwrap();
+        let header = inner.header.as_ref().ok_or(E_UNEXPECTED)?;
 
-        let (effective_source_rect, header_width_zero) = {
-            let header = inner.header.as_mut().ok_or(E_UNEXPECTED)?;
-            let header_width_zero = header.width == 0;
+        if header.bit_depth != 0 && header.bit_depth != pixel_format_bit_depth {
+            return Err(windows::core::Error::new(
+                E_INVALIDARG,
+                format!(
+                    "Mismatch between pixel format and bit depth (header: {}, pixel format: {}",
+                    header.bit_depth, pixel_format_bit_depth
+                ),
+            ));
+        }
 
-            if header.bit_depth != 0 && header.bit_depth != pixel_format_bit_depth {
-                return Err(windows::core::Error::new(
-                    E_INVALIDARG,
-                    format!(
-                        "Mismatch between pixel format and bit depth (header: {}, pixel format: {}",
-                        header.bit_depth, pixel_format_bit_depth
-                    ),
-                ));
-            }
+        let effective_source_rect = WICRect {
+            X: 0,
+            Y: 0,
+            Width: source_width as _,
+            Height: source_height as _,
+        };
 
-            let effective_source_rect = WICRect {
-                X: 0,
-                Y: 0,
-                Width: source_width as _,
-                Height: source_height as _,
-            };
+        let effective_source_rect = if let Some(rect) = rect {
+            effective_source_rect.intersect(rect)
+        } else {
+            effective_source_rect
+        };
 
-            let effective_source_rect = if let Some(rect) = rect {
-                effective_source_rect.intersect(rect)
-            } else {
-                effective_source_rect
-            };
+        if effective_source_rect.Width <= 0 || effective_source_rect.Height <= 0 {
+            return Err(windows::core::Error::new(
+                E_INVALIDARG,
+                "Rect doesn't overlap the source",
+            ));
+        }
 
-            if !header_width_zero {
-                if header.width != effective_source_rect.Width as _ {
-                    return Err(windows::core::Error::new(
-                        WINCODEC_ERR_SOURCERECTDOESNOTMATCHDIMENSIONS,
-                        "Width mismatch between source and frame",
-                    ));
-                }
-                if inner_accumulated_height + effective_source_rect.Height as u16 > header.height {
-                    return Err(windows::core::Error::new(
-                        WINCODEC_ERR_CODECTOOMANYSCANLINES,
-                        "Too many scanlines",
-                    ));
-                }
-            }
+        if effective_source_rect.Width > u16::MAX as _
+            || effective_source_rect.Height > u16::MAX as _
+        {
+            return Err(Condition::ValueOutOfRange.error("Source too large"));
+        }
 
-            (effective_source_rect, header_width_zero)
+        // Sources and WritePixels calls append bands until the frame height is reached. Like the
+        // built-in encoders, a source written before SetSize determines the frame size.
+        let (width, height) = match header.width {
+            0 => (
+                effective_source_rect.Width as u16,
+                effective_source_rect.Height as u16,
+            ),
+            width => (width, header.height),
         };
 
-        let source_palette = if inner.palette.is_none() {
-            let parent = inner.parent.inner.read().unwrap();
-            let parent = parent.as_ref().ok_or(E_UNEXPECTED)?;
+        if width != effective_source_rect.Width as u16 {
+            return Err(
+                Condition::SourceRectMismatch.error("Width mismatch between source and frame")
+            );
+        }
+
+        if inner.accumulated_height as u32 + effective_source_rect.Height as u32 > height as u32 {
+            return Err(Condition::TooManyScanlines.error("Too many scanlines"));
+        }
+
+        // Kept from the first source only, and only used if neither the frame nor the encoder get a
+        // palette, so sources without one are fine.
+        let source_palette = if inner.source_palette.is_none() {
+            let parent = inner.parent.inner.get()?;
             let palette = unsafe { parent.imaging_factory.CreatePalette()? };
-            unsafe {
-                bitmap_source.CopyPalette(&palette)?;
-            }
 
-            Some(palette)
+            unsafe { bitmap_source.CopyPalette(&palette) }
+                .ok()
+                .map(|()| palette)
         } else {
             None
         };
 
-        let bytes_per_line = bytes_per_line(
-            effective_source_rect.Width as _,
-            pixel_format_bit_depth as _,
-        );
-
-        let stride = (bytes_per_line + 3) & !3;
+        // Stored without padding, like WritePixels does.
+        let line_len = bytes_per_line(width, pixel_format_bit_depth) as usize;
 
-        let mut data = vec![0; stride as usize * effective_source_rect.Height as usize];
+        let mut data =
+            vec![0; checked_image_size(line_len, effective_source_rect.Height as usize)?];
         unsafe {
             bitmap_source.CopyPixels(
                 rect.map_or(std::ptr::null(), |f| f),
-                stride as _,
+                checked_u32(line_len)?,
                 &mut data,
             )?;
         }
 
-        if header_width_zero {
-            inner.image_data.clear();
-            inner.accumulated_height = 0;
+        // Nothing is touched before the source has been read, so a failed call leaves the frame as
+        // it was.
+        let header = inner.header.as_mut().unwrap();
+        header.width = width;
+        header.height = height;
+        header.bit_depth = pixel_format_bit_depth;
+
+        if inner.resolution.is_none() {
+            inner.resolution = source_resolution;
+        }
+
+        if source_palette.is_some() {
+            inner.source_palette = source_palette;
+            inner.source_pal_start = source_pal_start(bitmap_source);
         }
 
         inner.image_data.push(Chunk {
             data,
-            stride: stride as _,
+            stride: line_len as _,
             lines: effective_source_rect.Height as _,
         });
 
-        if header_width_zero {
-            let header = inner.header.as_mut().unwrap();
-            header.width = effective_source_rect.Width as _;
-            header.height = effective_source_rect.Height as _;
-            header.bit_depth = pixel_format_bit_depth;
-        }
-
-        if inner.palette.is_none() {
-            inner.palette = Some(PaletteToUse::BitmapSource(source_palette.unwrap()));
-        }
-
         inner.accumulated_height += effective_source_rect.Height as u16;
 
         Ok(())
     }
 
     fn Commit(&self) -> windows::core::Result<()> {
-        let mut inner = self.inner.write().unwrap();
+        let inner = self.inner.read().unwrap();
         let (width, height, bit_depth) = {
             let header = inner.header.as_ref().ok_or(E_UNEXPECTED)?;
             (header.width, header.height, header.bit_depth)
@@ -552,10 +849,7 @@ impl IWICBitmapFrameEncode_Impl for FrameEncoder_Impl {
         }
 
         if width == 0 {
-            return Err(windows::core::Error::new(
-                WINCODEC_ERR_UNEXPECTEDSIZE,
-                "Size must be set before committing",
-            ));
+            return Err(Condition::MissingScanlines.error("Size must be set before committing"));
         }
 
         if inner
@@ -565,53 +859,41 @@ impl IWICBitmapFrameEncode_Impl for FrameEncoder_Impl {
             .sum::<u16>()
             != height
         {
-            return Err(windows::core::Error::new(
-                WINCODEC_ERR_UNEXPECTEDSIZE,
-                "Not enough scanlines written",
-            ));
+            return Err(Condition::MissingScanlines.error("Not enough scanlines written"));
         }
 
-        {
-            let header = inner.header.as_mut().unwrap();
-            header.vera_color_depth_register = match header.bit_depth {
-                1 => 0,
-                2 => 1,
-                4 => 2,
-                8 => 3,
-                _ => unreachable!(),
-            };
+        if inner.strict_vera {
+            check_vera_constraints(width, height, bit_depth)
+                .map_err(|message| Condition::ValueOutOfRange.error(message))?;
         }
 
-        let (palette_to_use, stream) = {
-            let parent = inner.parent.inner.read().unwrap();
-            let parent = parent.as_ref().ok_or(E_UNEXPECTED)?;
+        let (palette_to_use, pal_start, stream) = {
+            let parent = inner.parent.inner.get()?;
+            let parent_palette = parent.palette.read().unwrap();
 
             let stream = parent.stream.clone();
 
-            let palette_to_use = match inner.palette {
-                Some(PaletteToUse::Frame(ref palette)) => palette.clone(),
-                Some(PaletteToUse::BitmapSource(ref palette)) => match parent.palette {
-                    Some(ref parent_palette) => parent_palette.clone(),
-                    None => palette.clone(),
-                },
-                None => match parent.palette {
-                    Some(ref palette) => palette.clone(),
-                    None => {
-                        let palette = unsafe { parent.imaging_factory.CreatePalette()? };
-
-                        unsafe {
-                            palette.InitializePredefined(
-                                WICBitmapPaletteTypeFixedHalftone256,
-                                false,
-                            )?;
-                        }
-
-                        palette
-                    }
-                },
+            // pal_start belongs to the source palette, so it's only kept along with it.
+            let pal_start = if inner.palette.is_none() && parent_palette.is_none() {
+                inner.source_pal_start
+            } else {
+                None
+            };
+
+            let palette_to_use = match select_palette(
+                inner.palette.as_ref(),
+                parent_palette.as_ref(),
+                inner.source_palette.as_ref(),
+            ) {
+                Some(palette) => palette,
+                None => generate_palette(
+                    &parent.imaging_factory,
+                    bit_depth,
+                    highest_index(&inner.image_data, width, bit_depth),
+                )?,
             };
 
-            (palette_to_use, stream)
+            (palette_to_use, pal_start, stream)
         };
 
         let mut colors = [0u32; 256];
@@ -620,46 +902,280 @@ impl IWICBitmapFrameEncode_Impl for FrameEncoder_Impl {
             palette_to_use.GetColors(&mut colors, &raw mut actual_colors)?;
         }
 
-        let actual_colors = actual_colors as usize;
+        let max_colors = 1usize << bit_depth;
+        let actual_colors = match actual_colors as usize {
+            colors if colors <= max_colors => colors,
+            // Written back as it was read, entries past 2^bpp included, so that a decoded BMX
+            // round trips exactly.
+            colors if pal_start.is_some() => colors,
+            // Pixels can't index past 2^bpp anyway, so the extra colors are simply dropped.
+            _ if get_class_setting::<BitmapEncoder>(TRUNCATE_PALETTE).unwrap_or(0) != 0 => {
+                max_colors
+            }
+            colors => {
+                return Err(Condition::ValueOutOfRange.error(format!(
+                    "Palette has {} colors, but {} bpp allows at most {}",
+                    colors, bit_depth, max_colors
+                )));
+            }
+        };
 
         let mut bmx_palette = [PaletteEntry::default(); 256];
         for i in 0..actual_colors {
             bmx_palette[i] = PaletteEntry::from_wic(colors[i]);
         }
 
-        let header = inner.header.as_mut().unwrap();
+        // Copied first, so that the resolution and checksum below are kept up to date.
+        let mut reserved = None;
+        for writer in &inner.metadata_writers {
+            reserved = read_reserved(writer)?.or(reserved);
+        }
 
-        header.pal_used = if actual_colors == 256 {
-            0
-        } else {
-            actual_colors as u8
-        };
+        let mut header = FileHeader::builder()
+            .version(inner.target_version)
+            .width(width)
+            .height(height)
+            .bit_depth(bit_depth)
+            .palette_len(actual_colors)
+            .pal_start(pal_start.unwrap_or(0))
+            .build()
+            .map_err(FileHeaderErrorExt::to_win_error)?;
+
+        if let Some(reserved) = reserved {
+            header.reserved = reserved;
+        }
 
-        header.data_start = (std::mem::size_of_val(header)
-            + std::mem::size_of_val(&bmx_palette[..actual_colors]))
-            as _;
+        if let Some((x, y)) = inner.resolution {
+            let dpi = |value: f64| value.round().clamp(1.0, u16::MAX as f64) as u16;
 
-        assert!(header.validate().is_ok());
+            let resolution = (dpi(x), dpi(y));
 
-        stream_write_exact_items(&stream, std::slice::from_ref(header))?;
-        stream_write_exact_items(&stream, &bmx_palette[..actual_colors])?;
+            // Absent means 96 DPI, so a tag copied from the metadata writers is cleared instead.
+            if resolution != (96, 96) {
+                header.set_resolution(Some(resolution));
+            } else if header.resolution().is_some() {
+                header.set_resolution(None);
+            }
+        }
 
         let bytes_per_line = bytes_per_line(header.width, header.bit_depth);
 
+        if get_class_setting::<BitmapEncoder>(WRITE_CHECKSUM).unwrap_or(0) != 0
+            || header.crc32().is_some()
+        {
+            let mut crc = Crc32::new();
+
+            for chunk in &inner.image_data {
+                for line in chunk.data.chunks_exact(chunk.stride as _) {
+                    crc.update(&line[..bytes_per_line as _]);
+                }
+            }
+
+            header.set_crc32(Some(crc.finish()));
+        }
+
+        let progress = inner
+            .parent
+            .progress
+            .reporter(WICProgressOperationWritePixels);
+        progress.begin()?;
+
+        let mut writer = StreamReadWriteWrapper::buffered_writer(&stream);
+
+        writer
+            .write_all(&header.to_bytes())
+            .map_err(IoErrorExt::to_win_error)?;
+
+        for entry in &bmx_palette[..actual_colors] {
+            writer
+                .write_all(&entry.to_bytes())
+                .map_err(IoErrorExt::to_win_error)?;
+        }
+
+        let mut lines_written = 0;
+
         for chunk in &inner.image_data {
             if chunk.stride == bytes_per_line {
-                stream_write_exact_items(&stream, &chunk.data)?;
+                writer
+                    .write_all(&chunk.data)
+                    .map_err(IoErrorExt::to_win_error)?;
             } else {
                 for line in chunk.data.chunks_exact(chunk.stride as _) {
-                    stream_write_exact_items(&stream, &line[..bytes_per_line as _])?;
+                    writer
+                        .write_all(&line[..bytes_per_line as _])
+                        .map_err(IoErrorExt::to_win_error)?;
                 }
             }
+
+            lines_written += chunk.lines as usize;
+            progress.progress(lines_written, height as usize)?;
         }
 
-        Ok(())
+        // Only empties the buffer; committing the stream is up to whoever handed it to us.
+        writer
+            .into_inner()
+            .map_err(|err| err.into_error().to_win_error())?;
+
+        progress.end()
     }
 
     fn GetMetadataQueryWriter(&self) -> windows::core::Result<IWICMetadataQueryWriter> {
         Err(E_NOTIMPL.into())
     }
 }
+
+impl IWICMetadataBlockReader_Impl for FrameEncoder_Impl {
+    fn GetContainerFormat(&self) -> windows::core::Result<GUID> {
+        Ok(CONTAINER_FORMAT)
+    }
+
+    fn GetCount(&self) -> windows::core::Result<u32> {
+        Ok(self.inner.read().unwrap().metadata_writers.len() as _)
+    }
+
+    fn GetReaderByIndex(&self, index: u32) -> windows::core::Result<IWICMetadataReader> {
+        let inner = self.inner.read().unwrap();
+        let writer = inner
+            .metadata_writers
+            .get(index as usize)
+            .ok_or(E_INVALIDARG)?;
+
+        Ok(writer.clone().into())
+    }
+
+    fn GetEnumerator(&self) -> windows::core::Result<IEnumUnknown> {
+        Err(E_NOTIMPL.into())
+    }
+}
+
+// BMX has nowhere to put metadata but its reserved header bytes, so that's the only block that is
+// accepted; InitializeFromBlockReader skips everything else.
+fn check_metadata_writer(
+    writer: Option<&IWICMetadataWriter>,
+) -> windows::core::Result<IWICMetadataWriter> {
+    let writer = writer.ok_or(E_INVALIDARG)?;
+
+    if unsafe { writer.GetMetadataFormat()? } != RESERVED_METADATA_FORMAT {
+        return Err(
+            Condition::UnsupportedOperation.error("Only the reserved header bytes can be stored")
+        );
+    }
+
+    Ok(writer.clone())
+}
+
+impl IWICMetadataBlockWriter_Impl for FrameEncoder_Impl {
+    fn InitializeFromBlockReader(
+        &self,
+        block_reader: Option<&IWICMetadataBlockReader>,
+    ) -> windows::core::Result<()> {
+        let block_reader = block_reader.ok_or(E_INVALIDARG)?;
+
+        let mut writers = Vec::new();
+        for index in 0..unsafe { block_reader.GetCount()? } {
+            let reader = unsafe { block_reader.GetReaderByIndex(index)? };
+
+            if let Some(reserved) = read_reserved(&reader)? {
+                writers.push(ComObject::new(ReservedMetadata::new(reserved)).into_interface());
+            }
+        }
+
+        self.inner.write().unwrap().metadata_writers = writers;
+        Ok(())
+    }
+
+    fn GetWriterByIndex(&self, index: u32) -> windows::core::Result<IWICMetadataWriter> {
+        let inner = self.inner.read().unwrap();
+        let writer = inner
+            .metadata_writers
+            .get(index as usize)
+            .ok_or(E_INVALIDARG)?;
+
+        Ok(writer.clone())
+    }
+
+    fn AddWriter(&self, writer: Option<&IWICMetadataWriter>) -> windows::core::Result<()> {
+        let writer = check_metadata_writer(writer)?;
+
+        self.inner.write().unwrap().metadata_writers.push(writer);
+        Ok(())
+    }
+
+    fn SetWriterByIndex(
+        &self,
+        index: u32,
+        writer: Option<&IWICMetadataWriter>,
+    ) -> windows::core::Result<()> {
+        let writer = check_metadata_writer(writer)?;
+
+        let mut inner = self.inner.write().unwrap();
+        *inner
+            .metadata_writers
+            .get_mut(index as usize)
+            .ok_or(E_INVALIDARG)? = writer;
+
+        Ok(())
+    }
+
+    fn RemoveWriterByIndex(&self, index: u32) -> windows::core::Result<()> {
+        let mut inner = self.inner.write().unwrap();
+
+        if index as usize >= inner.metadata_writers.len() {
+            return Err(E_INVALIDARG.into());
+        }
+
+        inner.metadata_writers.remove(index as _);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn palette_precedence() {
+        let (frame, encoder, source) = (Some(&1), Some(&2), Some(&3));
+
+        assert_eq!(select_palette(frame, encoder, source), Some(1));
+        assert_eq!(select_palette(frame, None, source), Some(1));
+        assert_eq!(select_palette(None, encoder, source), Some(2));
+        assert_eq!(select_palette(None, encoder, None), Some(2));
+        assert_eq!(select_palette(None, None, source), Some(3));
+        assert_eq!(select_palette::<i32>(None, None, None), None);
+    }
+
+    #[test]
+    fn vera_constraints() {
+        assert_eq!(check_vera_constraints(640, 480, 1), Ok(()));
+        assert_eq!(check_vera_constraints(320, 240, 8), Ok(()));
+        assert_eq!(check_vera_constraints(3, 200, 8), Ok(()));
+
+        assert!(check_vera_constraints(641, 8, 8).is_err());
+        assert!(check_vera_constraints(8, 481, 8).is_err());
+        assert!(check_vera_constraints(12, 8, 4).is_err());
+        // 640x480 needs 150 KiB at 4 bpp.
+        assert!(check_vera_constraints(640, 480, 4).is_err());
+    }
+
+    #[test]
+    fn highest_index_ignores_padding() {
+        // 3 pixels of 2 bpp per line, padded to 2 bytes; the padding bits are all set.
+        let chunks = [
+            Chunk {
+                data: vec![0b01_00_10_11, 0xFF, 0b00_01_00_11, 0xFF],
+                stride: 2,
+                lines: 2,
+            },
+            Chunk {
+                data: vec![0b00_00_01_11],
+                stride: 1,
+                lines: 1,
+            },
+        ];
+
+        assert_eq!(highest_index(&chunks, 3, 2), 2);
+        assert_eq!(highest_index(&chunks[1..], 3, 2), 1);
+        assert_eq!(highest_index(&[], 3, 2), 0);
+    }
+}
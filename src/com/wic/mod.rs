@@ -1,18 +1,27 @@
 use std::iter::FusedIterator;
 
-use imp::Sealed;
 use windows::Win32::{
     Foundation::{E_POINTER, S_FALSE, S_OK},
     Graphics::Imaging::*,
     System::Com::{CoCreateInstance, IEnumUnknown, CLSCTX_INPROC_SERVER},
 };
-use windows_core::{w, IUnknown, Interface, GUID, PCWSTR, PWSTR};
+use windows_core::{w, IUnknown, Interface, GUID, PCWSTR};
 
 pub mod class_factory;
+pub mod codec_info;
 pub mod com;
+pub mod conformance;
 pub mod decoder;
 pub mod encoder;
-mod util;
+mod progress;
+pub mod raw;
+pub mod reserved;
+#[cfg(all(test, windows))]
+mod tests;
+pub(crate) mod util;
+
+pub use codec_info::{enumerate_decoders, enumerate_encoders, CodecInfo};
+pub use util::bit_depth_to_pixel_format;
 
 pub fn create_imaging_factory() -> windows::core::Result<IWICImagingFactory> {
     unsafe { CoCreateInstance(&CLSID_WICImagingFactory, None, CLSCTX_INPROC_SERVER) }
@@ -91,90 +100,32 @@ pub fn get_component_iterator<T: Interface>(
     }))
 }
 
-/*pub fn get_with_buffer<T: Clone + Default>(
-    op: impl Fn(&mut [T], *mut u32) -> windows::core::Result<()>,
-) -> windows::core::Result<Vec<T>> {
+// Reads a WIC property with the two-call pattern: the first call only asks for the length, the
+// second one fills a buffer of that length. Strings include their terminating nul.
+pub fn read_counted_buffer<T, F>(mut read: F) -> windows::core::Result<Vec<T>>
+where
+    T: Clone + Default,
+    F: FnMut(&mut [T], *mut u32) -> windows::core::Result<()>,
+{
     let mut actual = 0;
 
-    _ = op(&mut [], &raw mut actual);
-
-    let mut buffer = vec![Default::default(); actual as usize];
-    op(&mut buffer, &raw mut actual)?;
-
-    buffer.resize(actual as usize, Default::default());
-    Ok(buffer)
-}*/
-
-mod imp {
-    use windows_core::PWSTR;
-
-    pub trait Sealed {}
-
-    impl<T> Sealed for *const T {}
-    impl<T> Sealed for *mut T {}
-    impl Sealed for PWSTR {}
-}
-
-pub trait AsNullPtr: Copy + Sealed {
-    fn null() -> Self;
-}
-
-impl<T> AsNullPtr for *const T {
-    fn null() -> Self {
-        std::ptr::null()
-    }
-}
-
-impl<T> AsNullPtr for *mut T {
-    fn null() -> Self {
-        std::ptr::null_mut()
+    // Usually fails with WINCODEC_ERR_INSUFFICIENTBUFFER, which is fine as long as the length is
+    // reported.
+    if let Err(err) = read(&mut [], &raw mut actual) {
+        if actual == 0 {
+            return Err(err);
+        }
     }
-}
 
-impl AsNullPtr for PWSTR {
-    fn null() -> Self {
-        PWSTR::null()
+    if actual == 0 {
+        return Ok(Vec::new());
     }
-}
-
-#[macro_export]
-macro_rules! get_with_buffer {
-    ($com_object:expr, $method:ident) => {{
-        let vtable = Interface::vtable($com_object);
-
-        let mut buffer = vec![];
-        let mut actual = 0;
-
-        fn __null_ptr<T: $crate::com::wic::AsNullPtr>() -> T {
-            $crate::com::wic::AsNullPtr::null()
-        }
 
-        unsafe {
-            _ = (vtable.$method)(
-                Interface::as_raw($com_object),
-                0,
-                __null_ptr(),
-                &raw mut actual,
-            )
-        };
+    let mut buffer = vec![T::default(); actual as usize];
+    read(&mut buffer, &raw mut actual)?;
 
-        buffer.resize(actual as usize, Default::default());
-
-        let result = unsafe { $com_object.$method(&mut buffer, &raw mut actual) };
-
-        match result {
-            Ok(()) => {
-                buffer.resize(actual as usize, Default::default());
-                Ok(buffer)
-            }
-            Err(err) => Err(err),
-        }
-    }};
-}
-
-pub fn codec_mime_types(codec: &IWICBitmapCodecInfo) -> windows::core::Result<Vec<u16>> {
-    //get_with_buffer(codec, method_index!(IWICBitmapCodecInfo_Vtbl::GetMimeTypes))
-    get_with_buffer!(codec, GetMimeTypes)
+    buffer.truncate(actual as usize);
+    Ok(buffer)
 }
 
 pub fn pixel_format_is_known(pixel_format: &GUID) -> bool {
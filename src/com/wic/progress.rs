@@ -0,0 +1,74 @@
+use std::ffi::c_void;
+use std::sync::Mutex;
+
+use windows::Win32::Graphics::Imaging::{
+    PFNProgressNotification, WICProgressNotification, WICProgressNotificationBegin,
+    WICProgressNotificationEnd, WICProgressNotificationFrequent, WICProgressOperation,
+};
+use windows_core::HRESULT;
+
+type Callback = unsafe extern "system" fn(*const c_void, u32, WICProgressOperation, f64) -> HRESULT;
+
+#[derive(Clone, Copy)]
+struct Registration {
+    callback: Callback,
+    data: *const c_void,
+    flags: u32,
+}
+
+#[derive(Default)]
+pub struct ProgressNotification(Mutex<Option<Registration>>);
+
+impl ProgressNotification {
+    pub fn register(&self, callback: PFNProgressNotification, data: *const c_void, flags: u32) {
+        *self.0.lock().unwrap() = callback.map(|callback| Registration {
+            callback,
+            data,
+            flags,
+        });
+    }
+
+    pub fn reporter(&self, operation: WICProgressOperation) -> ProgressReporter {
+        ProgressReporter {
+            registration: self
+                .0
+                .lock()
+                .unwrap()
+                .filter(|registration| registration.flags & operation.0 as u32 != 0),
+            operation,
+        }
+    }
+}
+
+// A failed HRESULT from the callback cancels the operation and is returned to the caller.
+pub struct ProgressReporter {
+    registration: Option<Registration>,
+    operation: WICProgressOperation,
+}
+
+impl ProgressReporter {
+    fn notify(
+        &self,
+        notification: WICProgressNotification,
+        progress: f64,
+    ) -> windows::core::Result<()> {
+        match self.registration {
+            Some(registration) if registration.flags & notification.0 as u32 != 0 => unsafe {
+                (registration.callback)(registration.data, 0, self.operation, progress).ok()
+            },
+            _ => Ok(()),
+        }
+    }
+
+    pub fn begin(&self) -> windows::core::Result<()> {
+        self.notify(WICProgressNotificationBegin, 0.0)
+    }
+
+    pub fn progress(&self, done: usize, total: usize) -> windows::core::Result<()> {
+        self.notify(WICProgressNotificationFrequent, done as f64 / total as f64)
+    }
+
+    pub fn end(&self) -> windows::core::Result<()> {
+        self.notify(WICProgressNotificationEnd, 1.0)
+    }
+}
@@ -0,0 +1,194 @@
+use std::io::Read;
+
+use windows::Win32::Foundation::{E_INVALIDARG, E_OUTOFMEMORY};
+use windows::Win32::Graphics::Imaging::{
+    IWICBitmapCodecProgressNotification, IWICBitmapCodecProgressNotification_Impl,
+    IWICBitmapDecoder, IWICBitmapDecoderInfo, IWICBitmapDecoder_Impl, IWICBitmapFrameDecode,
+    IWICBitmapSource, IWICColorContext, IWICMetadataQueryReader, IWICPalette,
+    PFNProgressNotification, WICDecodeOptions,
+};
+use windows::Win32::System::Com::{IStream, Marshal::IMarshal};
+use windows::Win32::UI::Shell::SHCreateMemStream;
+use windows_core::{implement, w, ComObject, Interface, GUID, PCWSTR};
+
+use super::com::RAW_CONTAINER_FORMAT;
+use super::create_imaging_factory;
+use super::decoder::BitmapDecoder;
+use super::util::StreamPositionPreserver;
+use crate::bmx::limits::Limits;
+use crate::bmx::raw::{decode_raw, RawGeometry, RawLayout};
+use crate::bmx::BmxError;
+use crate::bmx::BmxImage;
+use crate::com::hresult::Condition;
+use crate::com::util::{impl_free_threaded_marshaler, FreeThreadedMarshaler};
+use crate::com::{CoClass, IoErrorExt, StreamReadWriteWrapper};
+use crate::registry::get_class_setting;
+use crate::util::guid;
+
+pub const BIT_DEPTH: PCWSTR = w!("BitDepth");
+// Width of bitmap data in pixels.
+pub const WIDTH: PCWSTR = w!("Width");
+// Tile data is assumed if both are set.
+pub const TILE_WIDTH: PCWSTR = w!("TileWidth");
+pub const TILE_HEIGHT: PCWSTR = w!("TileHeight");
+pub const TILE_COLUMNS: PCWSTR = w!("TileColumns");
+pub const SKIP_BYTES: PCWSTR = w!("SkipBytes");
+
+// Defaults to a full 320x240 8bpp bitmap layer.
+fn geometry() -> RawGeometry {
+    let setting = |name, default| get_class_setting::<RawDecoder>(name).unwrap_or(default);
+
+    let layout = match (setting(TILE_WIDTH, 0), setting(TILE_HEIGHT, 0)) {
+        (0, _) | (_, 0) => RawLayout::Bitmap {
+            width: setting(WIDTH, 320).try_into().unwrap_or(0),
+        },
+        (tile_width, tile_height) => RawLayout::Tiles {
+            tile_width: tile_width.try_into().unwrap_or(0),
+            tile_height: tile_height.try_into().unwrap_or(0),
+            columns: setting(TILE_COLUMNS, 16).try_into().unwrap_or(0),
+        },
+    };
+
+    RawGeometry {
+        bit_depth: setting(BIT_DEPTH, 8).try_into().unwrap_or(0),
+        layout,
+        skip: setting(SKIP_BYTES, 0) as usize,
+    }
+}
+
+// The image is as large as the data, so anything longer than the largest image allowed is refused
+// before it's read into memory.
+fn read_image(stream: &IStream) -> windows::core::Result<BmxImage> {
+    let _position_preserver = StreamPositionPreserver::new(stream.clone())?;
+
+    let geometry = geometry();
+    let max_len = Limits::DEFAULT
+        .max_stored_data_len
+        .saturating_add(geometry.skip as u64);
+
+    let mut data = Vec::new();
+    StreamReadWriteWrapper::new(stream)
+        .take(max_len.saturating_add(1))
+        .read_to_end(&mut data)
+        .map_err(IoErrorExt::to_win_error)?;
+
+    if data.len() as u64 > max_len {
+        return Err(Condition::ImageTooLarge.error("Raw data is larger than the largest image"));
+    }
+
+    Ok(decode_raw(&data, &geometry).map_err(BmxError::from)?)
+}
+
+// Decodes raw VERA tile or bitmap data with a geometry configured in the registry. There's no
+// signature to go by, and any data fits some geometry, so the decoder never claims a stream in
+// QueryCapability; it has to be created explicitly by CLSID. The data is converted to an in-memory
+// BMX, which a regular decoder then takes care of.
+#[implement(IWICBitmapDecoder, IWICBitmapCodecProgressNotification, IMarshal)]
+pub struct RawDecoder {
+    decoder: ComObject<BitmapDecoder>,
+    marshaler: FreeThreadedMarshaler,
+}
+
+impl RawDecoder {
+    pub fn new() -> Self {
+        Self {
+            decoder: ComObject::new(BitmapDecoder::new()),
+            marshaler: Default::default(),
+        }
+    }
+}
+
+impl Default for RawDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CoClass for RawDecoder {
+    const CLSID: GUID = guid::from_str("0a4f4c5e-8d3b-4f0e-b2f6-6c1d93e7a214");
+    const PROG_ID: PCWSTR = w!("X16BMX.RawDecoder.1");
+    const VERSION_INDEPENDENT_PROG_ID: PCWSTR = w!("X16BMX.RawDecoder");
+    const FRIENDLY_NAME: PCWSTR = w!("VERA Raw Data Decoder");
+    const DESCRIPTION: PCWSTR = w!("VERA Raw Data");
+}
+
+impl_free_threaded_marshaler!(RawDecoder_Impl, marshaler);
+
+impl IWICBitmapCodecProgressNotification_Impl for RawDecoder_Impl {
+    fn RegisterProgressNotification(
+        &self,
+        callback: PFNProgressNotification,
+        data: *const core::ffi::c_void,
+        flags: u32,
+    ) -> windows::core::Result<()> {
+        self.decoder
+            .RegisterProgressNotification(callback, data, flags)
+    }
+}
+
+impl IWICBitmapDecoder_Impl for RawDecoder_Impl {
+    fn QueryCapability(&self, stream: Option<&IStream>) -> windows::core::Result<u32> {
+        stream.ok_or(E_INVALIDARG)?;
+
+        // Any data long enough fits the geometry, so claiming it would have WIC hand us every image
+        // no other decoder recognizes.
+        Ok(0)
+    }
+
+    fn Initialize(
+        &self,
+        stream: Option<&IStream>,
+        cacheoptions: WICDecodeOptions,
+    ) -> windows::core::Result<()> {
+        let stream = stream.ok_or(E_INVALIDARG)?;
+
+        let bytes = read_image(stream)?
+            .to_bytes(false)
+            .map_err(BmxError::from)?;
+
+        let bmx = unsafe { SHCreateMemStream(Some(&bytes)) }.ok_or(E_OUTOFMEMORY)?;
+        self.decoder.Initialize(Some(&bmx), cacheoptions)
+    }
+
+    fn GetContainerFormat(&self) -> windows::core::Result<GUID> {
+        Ok(RAW_CONTAINER_FORMAT)
+    }
+
+    fn GetDecoderInfo(&self) -> windows::core::Result<IWICBitmapDecoderInfo> {
+        unsafe { create_imaging_factory()?.CreateComponentInfo(&RawDecoder::CLSID)? }.cast()
+    }
+
+    fn CopyPalette(&self, palette: Option<&IWICPalette>) -> windows::core::Result<()> {
+        self.decoder.CopyPalette(palette)
+    }
+
+    fn GetMetadataQueryReader(&self) -> windows::core::Result<IWICMetadataQueryReader> {
+        self.decoder.GetMetadataQueryReader()
+    }
+
+    fn GetPreview(&self) -> windows::core::Result<IWICBitmapSource> {
+        self.decoder.GetPreview()
+    }
+
+    fn GetColorContexts(
+        &self,
+        count: u32,
+        color_contexts: *mut Option<IWICColorContext>,
+        actual_count: *mut u32,
+    ) -> windows::core::Result<()> {
+        self.decoder
+            .GetColorContexts(count, color_contexts, actual_count)
+    }
+
+    fn GetThumbnail(&self) -> windows::core::Result<IWICBitmapSource> {
+        self.decoder.GetThumbnail()
+    }
+
+    fn GetFrameCount(&self) -> windows::core::Result<u32> {
+        self.decoder.GetFrameCount()
+    }
+
+    fn GetFrame(&self, index: u32) -> windows::core::Result<IWICBitmapFrameDecode> {
+        self.decoder.GetFrame(index)
+    }
+}
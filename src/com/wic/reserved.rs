@@ -0,0 +1,288 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+
+use windows::Win32::Foundation::{
+    BOOL, E_INVALIDARG, E_NOTIMPL, S_FALSE, S_OK, WINCODEC_ERR_BADMETADATAHEADER,
+    WINCODEC_ERR_PROPERTYNOTFOUND,
+};
+use windows::Win32::Graphics::Imaging::{
+    IWICEnumMetadataItem, IWICMetadataHandlerInfo, IWICMetadataReader, IWICMetadataReader_Impl,
+    IWICMetadataWriter, IWICMetadataWriter_Impl, IWICPersistStream, IWICPersistStream_Impl,
+};
+use windows::Win32::System::Com::StructuredStorage::{
+    InitPropVariantFromBuffer, PropVariantToBuffer,
+};
+use windows::Win32::System::Com::{IPersistStream_Impl, IPersist_Impl, IStream};
+use windows_core::{implement, w, Interface, GUID, HRESULT, PCWSTR, PROPVARIANT};
+
+use super::com::RESERVED_METADATA_FORMAT;
+use super::create_imaging_factory;
+use crate::com::{stream_read_exact, stream_write_exact_items, CoClass};
+use crate::util::guid;
+
+const RESERVED: &str = "Reserved";
+
+type Reserved = [u8; 16];
+
+fn is_reserved_id(id: *const PROPVARIANT) -> windows::core::Result<bool> {
+    if id.is_null() {
+        return Err(E_INVALIDARG.into());
+    }
+
+    Ok(unsafe { &*id }.to_string() == RESERVED)
+}
+
+fn to_reserved(value: *const PROPVARIANT) -> windows::core::Result<Reserved> {
+    if value.is_null() {
+        return Err(E_INVALIDARG.into());
+    }
+
+    let mut reserved = Reserved::default();
+    unsafe { PropVariantToBuffer(value, reserved.as_mut_ptr().cast(), reserved.len() as _) }
+        .map_err(|_| windows::core::Error::new(E_INVALIDARG, "Expected 16 bytes"))?;
+
+    Ok(reserved)
+}
+
+// WIC registers metadata readers and writers as separate classes; ReservedMetadata serves both.
+pub struct ReservedMetadataReader;
+
+impl CoClass for ReservedMetadataReader {
+    const CLSID: GUID = guid::from_str("ecf62ce9-63b2-4418-91b2-0d87de694415");
+    const PROG_ID: PCWSTR = w!("X16BMX.ReservedMetadataReader.1");
+    const VERSION_INDEPENDENT_PROG_ID: PCWSTR = w!("X16BMX.ReservedMetadataReader");
+    const FRIENDLY_NAME: PCWSTR = w!("BMX Reserved Metadata Reader");
+}
+
+pub struct ReservedMetadataWriter;
+
+impl CoClass for ReservedMetadataWriter {
+    const CLSID: GUID = guid::from_str("be012fa7-1271-41e2-815d-81246541b321");
+    const PROG_ID: PCWSTR = w!("X16BMX.ReservedMetadataWriter.1");
+    const VERSION_INDEPENDENT_PROG_ID: PCWSTR = w!("X16BMX.ReservedMetadataWriter");
+    const FRIENDLY_NAME: PCWSTR = w!("BMX Reserved Metadata Writer");
+}
+
+// The reserved header bytes as a metadata block with a single item, so that WIC's metadata copying
+// carries them from a decoded BMX over to an encoded one.
+#[derive(Default)]
+#[implement(IWICMetadataWriter, IWICPersistStream)]
+pub struct ReservedMetadata {
+    reserved: RwLock<Reserved>,
+    dirty: AtomicBool,
+}
+
+impl ReservedMetadata {
+    pub fn new(reserved: Reserved) -> Self {
+        Self {
+            reserved: RwLock::new(reserved),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    fn set(&self, reserved: Reserved) {
+        *self.reserved.write().unwrap() = reserved;
+        self.dirty.store(true, Ordering::Release);
+    }
+}
+
+// Works with any reader of the format, not just ours.
+pub fn read_reserved(reader: &IWICMetadataReader) -> windows::core::Result<Option<Reserved>> {
+    if unsafe { reader.GetMetadataFormat()? } != RESERVED_METADATA_FORMAT {
+        return Ok(None);
+    }
+
+    let mut value = PROPVARIANT::default();
+    unsafe { reader.GetValue(std::ptr::null(), &PROPVARIANT::from(RESERVED), &mut value)? };
+
+    to_reserved(&value).map(Some)
+}
+
+impl IWICMetadataReader_Impl for ReservedMetadata_Impl {
+    fn GetMetadataFormat(&self) -> windows::core::Result<GUID> {
+        Ok(RESERVED_METADATA_FORMAT)
+    }
+
+    fn GetMetadataHandlerInfo(&self) -> windows::core::Result<IWICMetadataHandlerInfo> {
+        unsafe { create_imaging_factory()?.CreateComponentInfo(&ReservedMetadataReader::CLSID)? }
+            .cast()
+    }
+
+    fn GetCount(&self) -> windows::core::Result<u32> {
+        Ok(1)
+    }
+
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn GetValueByIndex(
+        &self,
+        index: u32,
+        schema: *mut PROPVARIANT,
+        id: *mut PROPVARIANT,
+        value: *mut PROPVARIANT,
+    ) -> windows::core::Result<()> {
+        if index != 0 {
+            return Err(E_INVALIDARG.into());
+        }
+
+        let reserved = *self.reserved.read().unwrap();
+
+        unsafe {
+            if !schema.is_null() {
+                schema.write(PROPVARIANT::default());
+            }
+
+            if !id.is_null() {
+                id.write(PROPVARIANT::from(RESERVED));
+            }
+
+            if !value.is_null() {
+                value.write(InitPropVariantFromBuffer(
+                    reserved.as_ptr().cast(),
+                    reserved.len() as _,
+                )?);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn GetValue(
+        &self,
+        _schema: *const PROPVARIANT,
+        id: *const PROPVARIANT,
+        value: *mut PROPVARIANT,
+    ) -> windows::core::Result<()> {
+        if !is_reserved_id(id)? {
+            return Err(WINCODEC_ERR_PROPERTYNOTFOUND.into());
+        }
+
+        self.GetValueByIndex(0, std::ptr::null_mut(), std::ptr::null_mut(), value)
+    }
+
+    fn GetEnumerator(&self) -> windows::core::Result<IWICEnumMetadataItem> {
+        Err(E_NOTIMPL.into())
+    }
+}
+
+impl IWICMetadataWriter_Impl for ReservedMetadata_Impl {
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn SetValue(
+        &self,
+        _schema: *const PROPVARIANT,
+        id: *const PROPVARIANT,
+        value: *const PROPVARIANT,
+    ) -> windows::core::Result<()> {
+        if !is_reserved_id(id)? {
+            return Err(WINCODEC_ERR_PROPERTYNOTFOUND.into());
+        }
+
+        self.set(to_reserved(value)?);
+        Ok(())
+    }
+
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn SetValueByIndex(
+        &self,
+        index: u32,
+        _schema: *const PROPVARIANT,
+        _id: *const PROPVARIANT,
+        value: *const PROPVARIANT,
+    ) -> windows::core::Result<()> {
+        if index != 0 {
+            return Err(E_INVALIDARG.into());
+        }
+
+        self.set(to_reserved(value)?);
+        Ok(())
+    }
+
+    // The bytes are always part of the header, so removing them only clears them.
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    fn RemoveValue(
+        &self,
+        _schema: *const PROPVARIANT,
+        id: *const PROPVARIANT,
+    ) -> windows::core::Result<()> {
+        if !is_reserved_id(id)? {
+            return Err(WINCODEC_ERR_PROPERTYNOTFOUND.into());
+        }
+
+        self.RemoveValueByIndex(0)
+    }
+
+    fn RemoveValueByIndex(&self, index: u32) -> windows::core::Result<()> {
+        if index != 0 {
+            return Err(E_INVALIDARG.into());
+        }
+
+        self.set(Reserved::default());
+        Ok(())
+    }
+}
+
+impl IPersist_Impl for ReservedMetadata_Impl {
+    fn GetClassID(&self) -> windows::core::Result<GUID> {
+        Ok(ReservedMetadataWriter::CLSID)
+    }
+}
+
+impl IPersistStream_Impl for ReservedMetadata_Impl {
+    fn IsDirty(&self) -> HRESULT {
+        if self.dirty.load(Ordering::Acquire) {
+            S_OK
+        } else {
+            S_FALSE
+        }
+    }
+
+    fn Load(&self, stream: Option<&IStream>) -> windows::core::Result<()> {
+        self.LoadEx(stream, std::ptr::null(), 0)
+    }
+
+    fn Save(&self, stream: Option<&IStream>, clear_dirty: BOOL) -> windows::core::Result<()> {
+        self.SaveEx(stream, 0, clear_dirty)
+    }
+
+    fn GetSizeMax(&self) -> windows::core::Result<u64> {
+        Ok(std::mem::size_of::<Reserved>() as _)
+    }
+}
+
+// The block is just the 16 bytes, without a header of its own.
+impl IWICPersistStream_Impl for ReservedMetadata_Impl {
+    fn LoadEx(
+        &self,
+        stream: Option<&IStream>,
+        _preferred_vendor: *const GUID,
+        _options: u32,
+    ) -> windows::core::Result<()> {
+        let stream = stream.ok_or(E_INVALIDARG)?;
+
+        let mut reserved = Reserved::default();
+        if stream_read_exact(stream, &mut reserved)? != reserved.len() {
+            return Err(WINCODEC_ERR_BADMETADATAHEADER.into());
+        }
+
+        *self.reserved.write().unwrap() = reserved;
+        self.dirty.store(false, Ordering::Release);
+        Ok(())
+    }
+
+    fn SaveEx(
+        &self,
+        stream: Option<&IStream>,
+        _options: u32,
+        clear_dirty: BOOL,
+    ) -> windows::core::Result<()> {
+        let stream = stream.ok_or(E_INVALIDARG)?;
+
+        stream_write_exact_items(stream, &*self.reserved.read().unwrap())?;
+
+        if clear_dirty.as_bool() {
+            self.dirty.store(false, Ordering::Release);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1600 @@
+// Round trips synthetic images through our encoder and decoder using in-memory streams, and checks
+// that the built module can be activated without registration.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use windows::Win32::Foundation::{
+    E_INVALIDARG, HANDLE, STG_E_INVALIDFUNCTION, STG_E_MEDIUMFULL, WINCODEC_ERR_BADIMAGE,
+    WINCODEC_ERR_CODECTOOMANYSCANLINES, WINCODEC_ERR_INSUFFICIENTBUFFER, WINCODEC_ERR_STREAMREAD,
+    WINCODEC_ERR_VALUEOUTOFRANGE,
+};
+use windows::Win32::Graphics::Imaging::{
+    IWICBitmap, IWICBitmapDecoder, IWICBitmapEncoder, IWICBitmapFrameEncode, IWICImagingFactory,
+    IWICMetadataBlockReader, IWICMetadataBlockWriter, IWICPalette, WICBitmapEncoderNoCache,
+    WICBitmapPaletteTypeFixedHalftone256, WICDecodeMetadataCacheOnDemand, WICRect,
+    WINCODEC_ERR_INVALIDPARAMETER,
+};
+use windows::Win32::System::ApplicationInstallationAndServicing::{
+    ActivateActCtx, CreateActCtxW, DeactivateActCtx, ReleaseActCtx, ACTCTXW,
+};
+use windows::Win32::System::Com::StructuredStorage::PROPBAG2;
+use windows::Win32::System::Com::{
+    CoCreateInstance, CoInitializeEx, CoUninitialize, IStream, CLSCTX_INPROC_SERVER,
+    COINIT_MULTITHREADED, STREAM_SEEK_CUR, STREAM_SEEK_SET,
+};
+use windows::Win32::System::WindowsProgramming::ACTCTX_FLAG_ASSEMBLY_DIRECTORY_VALID;
+use windows::Win32::UI::Shell::SHCreateMemStream;
+use windows_core::{
+    ComObject, Interface, GUID, HRESULT, HSTRING, PCWSTR, PROPVARIANT, PWSTR, VARIANT,
+};
+
+use self::fault_stream::{FaultStream, Faults};
+use super::com::{CONTAINER_FORMAT, RAW_CONTAINER_FORMAT};
+use super::decoder::metadata::NEWER_VERSION;
+use super::decoder::{read_palette, BitmapDecoder};
+use super::encoder::{BitmapEncoder, STRICT_VERA, TARGET_VERSION};
+use super::raw::RawDecoder;
+use super::{bit_depth_to_pixel_format, create_imaging_factory, read_counted_buffer};
+use crate::bmx::{BmxImage, FileHeader, PaletteEntry};
+use crate::com::{
+    stream_read_exact, stream_read_to_end, CoClass, StreamOperation, StreamReadWriteWrapper,
+};
+use crate::crc32::crc32;
+use crate::registry::activation_manifest;
+
+mod fault_stream;
+
+const WIDTH: u32 = 13;
+const HEIGHT: u32 = 7;
+
+struct ComApartment;
+
+impl ComApartment {
+    fn new() -> Self {
+        unsafe { CoInitializeEx(None, COINIT_MULTITHREADED) }
+            .ok()
+            .unwrap();
+        Self
+    }
+}
+
+impl Drop for ComApartment {
+    fn drop(&mut self) {
+        unsafe { CoUninitialize() };
+    }
+}
+
+// Activates the built module through a generated side-by-side manifest, so its classes can be
+// created with CoCreateInstance without registering anything. The module defaults to the DLL cargo
+// places next to the test executable's deps directory; BMX_SHELL_DLL overrides it.
+struct ActivationContext {
+    handle: HANDLE,
+    cookie: usize,
+    manifest: PathBuf,
+}
+
+impl ActivationContext {
+    fn new() -> Self {
+        let module = std::env::var_os("BMX_SHELL_DLL")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| {
+                let executable = std::env::current_exe().unwrap();
+                executable
+                    .parent()
+                    .and_then(|deps| deps.parent())
+                    .unwrap()
+                    .join("bmx_shell.dll")
+            });
+
+        assert!(module.exists(), "{} does not exist", module.display());
+
+        let manifest = std::env::temp_dir().join(format!(
+            "bmx-shell-{}-{:?}.manifest",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+
+        let module_name = module.file_name().unwrap().to_string_lossy();
+        std::fs::write(&manifest, activation_manifest(&module_name)).unwrap();
+
+        let source = HSTRING::from(manifest.as_os_str());
+        let directory = HSTRING::from(module.parent().unwrap().as_os_str());
+
+        let context = ACTCTXW {
+            cbSize: std::mem::size_of::<ACTCTXW>() as u32,
+            dwFlags: ACTCTX_FLAG_ASSEMBLY_DIRECTORY_VALID,
+            lpSource: PCWSTR::from_raw(source.as_ptr()),
+            lpAssemblyDirectory: PCWSTR::from_raw(directory.as_ptr()),
+            ..Default::default()
+        };
+
+        let handle = unsafe { CreateActCtxW(&context) }.unwrap();
+
+        let mut cookie = 0;
+        unsafe { ActivateActCtx(handle, &mut cookie) }.unwrap();
+
+        Self {
+            handle,
+            cookie,
+            manifest,
+        }
+    }
+}
+
+impl Drop for ActivationContext {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = DeactivateActCtx(0, self.cookie);
+            ReleaseActCtx(self.handle);
+        }
+
+        let _ = std::fs::remove_file(&self.manifest);
+    }
+}
+
+struct TestImage {
+    bit_depth: u8,
+    palette: Vec<u32>,
+    indices: Vec<u8>,
+}
+
+impl TestImage {
+    fn new(bit_depth: u8) -> Self {
+        let colors = 1usize << bit_depth;
+
+        // BMX stores 4 bits per channel, so only use colors that survive the round trip.
+        let palette = (0..colors as u32)
+            .map(|i| {
+                let (r, g, b) = (i % 16, (i / 16) % 16, (i * 7) % 16);
+                0xFF000000 | ((r * 0x11) << 16) | ((g * 0x11) << 8) | (b * 0x11)
+            })
+            .collect();
+
+        let indices = (0..HEIGHT)
+            .flat_map(|y| (0..WIDTH).map(move |x| ((x * 3 + y * 5) as usize % colors) as u8))
+            .collect();
+
+        Self {
+            bit_depth,
+            palette,
+            indices,
+        }
+    }
+
+    fn stride(&self) -> usize {
+        (WIDTH as usize * self.bit_depth as usize).div_ceil(8)
+    }
+
+    fn pack(&self, stride: usize) -> Vec<u8> {
+        let mut data = vec![0u8; stride * HEIGHT as usize];
+
+        for (y, row) in self.indices.chunks_exact(WIDTH as _).enumerate() {
+            for (x, index) in row.iter().enumerate() {
+                let bit = x * self.bit_depth as usize;
+                data[y * stride + bit / 8] |= index << (8 - self.bit_depth as usize - bit % 8);
+            }
+        }
+
+        data
+    }
+
+    fn unpack(&self, data: &[u8]) -> Vec<u8> {
+        let stride = self.stride();
+        let mask = ((1u16 << self.bit_depth) - 1) as u8;
+
+        (0..HEIGHT as usize)
+            .flat_map(|y| {
+                (0..WIDTH as usize).map(move |x| {
+                    let bit = x * self.bit_depth as usize;
+                    (data[y * stride + bit / 8] >> (8 - self.bit_depth as usize - bit % 8)) & mask
+                })
+            })
+            .collect()
+    }
+}
+
+fn encode_into(
+    imaging_factory: &IWICImagingFactory,
+    image: &TestImage,
+    stride: usize,
+    stream: &IStream,
+) -> windows::core::Result<()> {
+    let encoder: IWICBitmapEncoder = ComObject::new(BitmapEncoder::new()).into_interface();
+
+    unsafe {
+        encoder.Initialize(stream, WICBitmapEncoderNoCache).unwrap();
+
+        let mut frame = None;
+        encoder
+            .CreateNewFrame(&mut frame, std::ptr::null_mut())
+            .unwrap();
+        let frame = frame.unwrap();
+
+        frame.Initialize(None).unwrap();
+        frame.SetSize(WIDTH, HEIGHT).unwrap();
+
+        let mut pixel_format = bit_depth_to_pixel_format(image.bit_depth).unwrap();
+        frame.SetPixelFormat(&mut pixel_format).unwrap();
+        assert_eq!(
+            pixel_format,
+            bit_depth_to_pixel_format(image.bit_depth).unwrap()
+        );
+
+        let palette = imaging_factory.CreatePalette().unwrap();
+        palette.InitializeCustom(&image.palette).unwrap();
+        frame.SetPalette(&palette).unwrap();
+
+        frame
+            .WritePixels(HEIGHT, stride as _, &image.pack(stride))
+            .unwrap();
+
+        frame.Commit()?;
+        encoder.Commit()
+    }
+}
+
+fn encode(imaging_factory: &IWICImagingFactory, image: &TestImage) -> IStream {
+    let stream = unsafe { SHCreateMemStream(None) }.expect("SHCreateMemStream failed");
+    encode_into(imaging_factory, image, image.stride(), &stream).unwrap();
+
+    unsafe { stream.Seek(0, STREAM_SEEK_SET, None) }.unwrap();
+    stream
+}
+
+fn decode(
+    imaging_factory: &IWICImagingFactory,
+    stream: &IStream,
+    image: &TestImage,
+) -> (GUID, Vec<u32>, Vec<u8>) {
+    let decoder: IWICBitmapDecoder = ComObject::new(BitmapDecoder::new()).into_interface();
+
+    unsafe {
+        decoder
+            .Initialize(stream, WICDecodeMetadataCacheOnDemand)
+            .unwrap();
+
+        assert_eq!(decoder.GetFrameCount().unwrap(), 1);
+        let frame = decoder.GetFrame(0).unwrap();
+
+        let (mut width, mut height) = (0, 0);
+        frame.GetSize(&mut width, &mut height).unwrap();
+        assert_eq!((width, height), (WIDTH, HEIGHT));
+
+        let palette = imaging_factory.CreatePalette().unwrap();
+        frame.CopyPalette(&palette).unwrap();
+
+        let mut colors = vec![0u32; 256];
+        let mut actual_colors = 0;
+        palette.GetColors(&mut colors, &mut actual_colors).unwrap();
+        colors.truncate(actual_colors as _);
+
+        let mut data = vec![0u8; image.stride() * HEIGHT as usize];
+        frame
+            .CopyPixels(std::ptr::null(), image.stride() as _, &mut data)
+            .unwrap();
+
+        (frame.GetPixelFormat().unwrap(), colors, data)
+    }
+}
+
+fn round_trip(bit_depth: u8) {
+    let _apartment = ComApartment::new();
+    let imaging_factory = create_imaging_factory().unwrap();
+
+    let image = TestImage::new(bit_depth);
+    let stream = encode(&imaging_factory, &image);
+    let (pixel_format, palette, data) = decode(&imaging_factory, &stream, &image);
+
+    assert_eq!(pixel_format, bit_depth_to_pixel_format(bit_depth).unwrap());
+    assert!(palette.len() >= image.palette.len());
+    assert_eq!(palette[..image.palette.len()], image.palette);
+    assert_eq!(image.unpack(&data), image.indices);
+}
+
+#[test]
+fn round_trip_1bpp() {
+    round_trip(1);
+}
+
+#[test]
+fn round_trip_2bpp() {
+    round_trip(2);
+}
+
+#[test]
+fn round_trip_4bpp() {
+    round_trip(4);
+}
+
+#[test]
+fn round_trip_8bpp() {
+    round_trip(8);
+}
+
+#[test]
+fn letterboxed_thumbnail_and_preview() {
+    let _apartment = ComApartment::new();
+    let imaging_factory = create_imaging_factory().unwrap();
+
+    let image = TestImage::new(8);
+    let stream = encode(&imaging_factory, &image);
+
+    let decoder: IWICBitmapDecoder = ComObject::new(BitmapDecoder::new()).into_interface();
+
+    unsafe {
+        decoder
+            .Initialize(&stream, WICDecodeMetadataCacheOnDemand)
+            .unwrap();
+
+        // The encoder leaves the border color at index 0.
+        for (source, (width, height), top) in [
+            (decoder.GetThumbnail().unwrap(), (WIDTH, WIDTH), 3),
+            (decoder.GetPreview().unwrap(), (WIDTH, 10), 1),
+        ] {
+            let (mut actual_width, mut actual_height) = (0, 0);
+            source
+                .GetSize(&mut actual_width, &mut actual_height)
+                .unwrap();
+            assert_eq!((actual_width, actual_height), (width, height));
+
+            let mut pixels = vec![0xFFu8; (width * height) as usize];
+            source
+                .CopyPixels(std::ptr::null(), width, &mut pixels)
+                .unwrap();
+
+            let (border, rest) = pixels.split_at(top * WIDTH as usize);
+            assert!(border.iter().all(|&index| index == 0));
+            assert_eq!(rest[..image.indices.len()], image.indices);
+        }
+    }
+}
+
+#[test]
+fn raw_decoder_default_geometry() {
+    let _apartment = ComApartment::new();
+    let imaging_factory = create_imaging_factory().unwrap();
+
+    // A 320 pixel wide 8bpp bitmap, three rows high.
+    let data = (0..320 * 3).map(|i| i as u8).collect::<Vec<_>>();
+    let stream = unsafe { SHCreateMemStream(Some(&data)) }.unwrap();
+
+    let decoder: IWICBitmapDecoder = ComObject::new(RawDecoder::new()).into_interface();
+
+    unsafe {
+        // Never claims a stream on its own, however well the data fits.
+        assert_eq!(decoder.QueryCapability(&stream).unwrap(), 0);
+
+        decoder
+            .Initialize(&stream, WICDecodeMetadataCacheOnDemand)
+            .unwrap();
+        assert_eq!(decoder.GetContainerFormat().unwrap(), RAW_CONTAINER_FORMAT);
+
+        let frame = decoder.GetFrame(0).unwrap();
+
+        let (mut width, mut height) = (0, 0);
+        frame.GetSize(&mut width, &mut height).unwrap();
+        assert_eq!((width, height), (320, 3));
+
+        let palette = imaging_factory.CreatePalette().unwrap();
+        frame.CopyPalette(&palette).unwrap();
+        assert_eq!(palette.GetColorCount().unwrap(), 256);
+
+        let mut pixels = vec![0u8; data.len()];
+        frame
+            .CopyPixels(std::ptr::null(), 320, &mut pixels)
+            .unwrap();
+        assert_eq!(pixels, data);
+    }
+}
+
+// Only the pixel bytes of each line are kept, so padding in the caller's buffer must not leak into
+// the file.
+#[test]
+fn write_pixels_with_padded_stride() {
+    let _apartment = ComApartment::new();
+    let imaging_factory = create_imaging_factory().unwrap();
+
+    for bit_depth in [1, 4, 8] {
+        let image = TestImage::new(bit_depth);
+        let stream = unsafe { SHCreateMemStream(None) }.unwrap();
+        encode_into(&imaging_factory, &image, image.stride() + 11, &stream).unwrap();
+        unsafe { stream.Seek(0, STREAM_SEEK_SET, None) }.unwrap();
+
+        let bytes = stream_read_to_end(&stream).unwrap();
+        let header = FileHeader::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            bytes.len(),
+            header.data_start as usize + header.pixel_data_len()
+        );
+
+        unsafe { stream.Seek(0, STREAM_SEEK_SET, None) }.unwrap();
+        let (_, _, data) = decode(&imaging_factory, &stream, &image);
+        assert_eq!(image.unpack(&data), image.indices);
+    }
+}
+
+#[test]
+fn read_palette_matches_decoder() {
+    let _apartment = ComApartment::new();
+    let imaging_factory = create_imaging_factory().unwrap();
+
+    let image = TestImage::new(4);
+    let stream = encode(&imaging_factory, &image);
+
+    let (header, palette) = read_palette(&stream).unwrap();
+    assert_eq!(header.bit_depth, 4);
+    assert_eq!(
+        palette.iter().map(PaletteEntry::to_wic).collect::<Vec<_>>(),
+        image.palette
+    );
+
+    unsafe { stream.Seek(0, STREAM_SEEK_SET, None) }.unwrap();
+
+    // Available straight after Initialize, without getting a frame first.
+    let decoder: IWICBitmapDecoder = ComObject::new(BitmapDecoder::new()).into_interface();
+    let wic_palette = unsafe { imaging_factory.CreatePalette() }.unwrap();
+
+    let mut colors = vec![0u32; 256];
+    let mut actual_colors = 0;
+
+    unsafe {
+        decoder
+            .Initialize(&stream, WICDecodeMetadataCacheOnDemand)
+            .unwrap();
+        decoder.CopyPalette(&wic_palette).unwrap();
+        wic_palette
+            .GetColors(&mut colors, &mut actual_colors)
+            .unwrap();
+    }
+
+    assert_eq!(colors[..actual_colors as usize], image.palette);
+}
+
+#[test]
+fn write_pixels_validates_stride_and_buffer() {
+    let _apartment = ComApartment::new();
+
+    let image = TestImage::new(4);
+    let stride = image.stride();
+    let pixels = image.pack(stride + 2);
+
+    let stream = unsafe { SHCreateMemStream(None) }.unwrap();
+    let encoder: IWICBitmapEncoder = ComObject::new(BitmapEncoder::new()).into_interface();
+
+    unsafe {
+        encoder
+            .Initialize(&stream, WICBitmapEncoderNoCache)
+            .unwrap();
+
+        let mut frame = None;
+        encoder
+            .CreateNewFrame(&mut frame, std::ptr::null_mut())
+            .unwrap();
+        let frame = frame.unwrap();
+
+        frame.Initialize(None).unwrap();
+        frame.SetSize(WIDTH, HEIGHT).unwrap();
+        let mut pixel_format = bit_depth_to_pixel_format(4).unwrap();
+        frame.SetPixelFormat(&mut pixel_format).unwrap();
+
+        assert_eq!(
+            frame
+                .WritePixels(HEIGHT, stride as u32 - 1, &pixels)
+                .unwrap_err()
+                .code(),
+            WINCODEC_ERR_INSUFFICIENTBUFFER
+        );
+
+        let required = (stride + 2) * (HEIGHT as usize - 1) + stride;
+        assert_eq!(
+            frame
+                .WritePixels(HEIGHT, (stride + 2) as u32, &pixels[..required - 1])
+                .unwrap_err()
+                .code(),
+            WINCODEC_ERR_INSUFFICIENTBUFFER
+        );
+
+        // The last line doesn't need its padding.
+        frame
+            .WritePixels(HEIGHT, (stride + 2) as u32, &pixels[..required])
+            .unwrap();
+    }
+}
+
+fn custom_palette(imaging_factory: &IWICImagingFactory, gray: u32) -> (IWICPalette, Vec<u32>) {
+    let colors = (0..16)
+        .map(|i| 0xFF000000 | ((gray * 0x11) << 16) | ((i * 0x11) << 8) | ((15 - i) * 0x11))
+        .collect::<Vec<_>>();
+
+    let palette = unsafe { imaging_factory.CreatePalette() }.unwrap();
+    unsafe { palette.InitializeCustom(&colors) }.unwrap();
+    (palette, colors)
+}
+
+// Encodes the 4bpp test image with the given palettes and returns the palette that ended up in the
+// file. Pixels are written with WriteSource when `source` is set, and with WritePixels otherwise.
+fn encoded_palette(
+    imaging_factory: &IWICImagingFactory,
+    frame_palette: Option<&IWICPalette>,
+    encoder_palette: Option<&IWICPalette>,
+    source: Option<Option<&IWICPalette>>,
+) -> Vec<u32> {
+    let image = TestImage::new(4);
+    let pixels = image.pack(image.stride());
+    let pixel_format = bit_depth_to_pixel_format(4).unwrap();
+
+    let stream = unsafe { SHCreateMemStream(None) }.unwrap();
+    let encoder: IWICBitmapEncoder = ComObject::new(BitmapEncoder::new()).into_interface();
+
+    unsafe {
+        encoder
+            .Initialize(&stream, WICBitmapEncoderNoCache)
+            .unwrap();
+        if let Some(palette) = encoder_palette {
+            encoder.SetPalette(palette).unwrap();
+        }
+
+        let mut frame = None;
+        encoder
+            .CreateNewFrame(&mut frame, std::ptr::null_mut())
+            .unwrap();
+        let frame = frame.unwrap();
+
+        frame.Initialize(None).unwrap();
+        frame.SetSize(WIDTH, HEIGHT).unwrap();
+        frame.SetPixelFormat(&mut pixel_format.clone()).unwrap();
+        if let Some(palette) = frame_palette {
+            frame.SetPalette(palette).unwrap();
+        }
+
+        match source {
+            Some(source_palette) => {
+                let bitmap = imaging_factory
+                    .CreateBitmapFromMemory(
+                        WIDTH,
+                        HEIGHT,
+                        &pixel_format,
+                        image.stride() as u32,
+                        &pixels,
+                    )
+                    .unwrap();
+                if let Some(palette) = source_palette {
+                    bitmap.SetPalette(palette).unwrap();
+                }
+
+                frame.WriteSource(&bitmap, std::ptr::null()).unwrap();
+            }
+            None => frame
+                .WritePixels(HEIGHT, image.stride() as u32, &pixels)
+                .unwrap(),
+        }
+
+        frame.Commit().unwrap();
+        encoder.Commit().unwrap();
+        stream.Seek(0, STREAM_SEEK_SET, None).unwrap();
+    }
+
+    decode(imaging_factory, &stream, &image).1
+}
+
+#[test]
+fn palette_precedence() {
+    let _apartment = ComApartment::new();
+    let imaging_factory = create_imaging_factory().unwrap();
+
+    let (frame, frame_colors) = custom_palette(&imaging_factory, 1);
+    let (encoder, encoder_colors) = custom_palette(&imaging_factory, 2);
+    let (source, source_colors) = custom_palette(&imaging_factory, 3);
+
+    let cases = [
+        (
+            Some(&frame),
+            Some(&encoder),
+            Some(Some(&source)),
+            &frame_colors,
+        ),
+        (Some(&frame), None, None, &frame_colors),
+        (Some(&frame), None, Some(Some(&source)), &frame_colors),
+        (None, Some(&encoder), None, &encoder_colors),
+        (None, Some(&encoder), Some(Some(&source)), &encoder_colors),
+        (None, Some(&encoder), Some(None), &encoder_colors),
+        (None, None, Some(Some(&source)), &source_colors),
+    ];
+
+    for (i, (frame, encoder, source, expected)) in cases.into_iter().enumerate() {
+        assert_eq!(
+            &encoded_palette(&imaging_factory, frame, encoder, source),
+            expected,
+            "case {}",
+            i
+        );
+    }
+
+    // Without any palette, the indices in use are spread over a gray ramp.
+    let ramp = (0..16)
+        .map(|i| 0xFF000000 | ((i * 0x11) << 16) | ((i * 0x11) << 8) | (i * 0x11))
+        .collect::<Vec<u32>>();
+
+    for source in [None, Some(None)] {
+        assert_eq!(encoded_palette(&imaging_factory, None, None, source), ramp);
+    }
+}
+
+#[test]
+fn commit_rejects_oversized_palette() {
+    let _apartment = ComApartment::new();
+    let imaging_factory = create_imaging_factory().unwrap();
+
+    let image = TestImage::new(4);
+    let stream = unsafe { SHCreateMemStream(None) }.unwrap();
+    let encoder: IWICBitmapEncoder = ComObject::new(BitmapEncoder::new()).into_interface();
+
+    unsafe {
+        encoder
+            .Initialize(&stream, WICBitmapEncoderNoCache)
+            .unwrap();
+
+        let mut frame = None;
+        encoder
+            .CreateNewFrame(&mut frame, std::ptr::null_mut())
+            .unwrap();
+        let frame = frame.unwrap();
+
+        frame.Initialize(None).unwrap();
+        frame.SetSize(WIDTH, HEIGHT).unwrap();
+        frame
+            .SetPixelFormat(&mut bit_depth_to_pixel_format(4).unwrap())
+            .unwrap();
+
+        let palette = imaging_factory.CreatePalette().unwrap();
+        palette
+            .InitializePredefined(WICBitmapPaletteTypeFixedHalftone256, false)
+            .unwrap();
+        frame.SetPalette(&palette).unwrap();
+
+        frame
+            .WritePixels(HEIGHT, image.stride() as u32, &image.pack(image.stride()))
+            .unwrap();
+
+        assert_eq!(
+            frame.Commit().unwrap_err().code(),
+            WINCODEC_ERR_VALUEOUTOFRANGE
+        );
+    }
+}
+
+fn new_frame(encoder: &IWICBitmapEncoder, stream: &IStream) -> IWICBitmapFrameEncode {
+    unsafe {
+        encoder.Initialize(stream, WICBitmapEncoderNoCache).unwrap();
+
+        let mut frame = None;
+        encoder
+            .CreateNewFrame(&mut frame, std::ptr::null_mut())
+            .unwrap();
+        let frame = frame.unwrap();
+
+        frame.Initialize(None).unwrap();
+        frame
+    }
+}
+
+fn test_bitmap(imaging_factory: &IWICImagingFactory, image: &TestImage) -> IWICBitmap {
+    unsafe {
+        let bitmap = imaging_factory
+            .CreateBitmapFromMemory(
+                WIDTH,
+                HEIGHT,
+                &bit_depth_to_pixel_format(image.bit_depth).unwrap(),
+                image.stride() as u32,
+                &image.pack(image.stride()),
+            )
+            .unwrap();
+
+        let palette = imaging_factory.CreatePalette().unwrap();
+        palette.InitializeCustom(&image.palette).unwrap();
+        bitmap.SetPalette(&palette).unwrap();
+        bitmap
+    }
+}
+
+#[test]
+fn write_mixed_bands() {
+    let _apartment = ComApartment::new();
+    let imaging_factory = create_imaging_factory().unwrap();
+
+    let image = TestImage::new(4);
+    let stride = image.stride();
+    let pixels = image.pack(stride);
+    let bitmap = test_bitmap(&imaging_factory, &image);
+
+    let stream = unsafe { SHCreateMemStream(None) }.unwrap();
+    let encoder: IWICBitmapEncoder = ComObject::new(BitmapEncoder::new()).into_interface();
+    let frame = new_frame(&encoder, &stream);
+
+    let band = |y: i32, height: i32| WICRect {
+        X: 0,
+        Y: y,
+        Width: WIDTH as _,
+        Height: height,
+    };
+
+    unsafe {
+        frame.SetSize(WIDTH, HEIGHT).unwrap();
+        frame
+            .SetPixelFormat(&mut bit_depth_to_pixel_format(4).unwrap())
+            .unwrap();
+
+        frame
+            .WritePixels(2, stride as u32, &pixels[..stride * 2])
+            .unwrap();
+        frame.WriteSource(&bitmap, &band(2, 3)).unwrap();
+        frame
+            .WritePixels(1, stride as u32, &pixels[stride * 5..][..stride])
+            .unwrap();
+        frame.WriteSource(&bitmap, &band(6, 1)).unwrap();
+
+        assert_eq!(
+            frame
+                .WritePixels(1, stride as u32, &pixels[..stride])
+                .unwrap_err()
+                .code(),
+            WINCODEC_ERR_CODECTOOMANYSCANLINES
+        );
+        assert_eq!(
+            frame.WriteSource(&bitmap, &band(0, 1)).unwrap_err().code(),
+            WINCODEC_ERR_CODECTOOMANYSCANLINES
+        );
+
+        frame.Commit().unwrap();
+        encoder.Commit().unwrap();
+        stream.Seek(0, STREAM_SEEK_SET, None).unwrap();
+    }
+
+    let (_, colors, data) = decode(&imaging_factory, &stream, &image);
+    assert_eq!(colors, image.palette);
+    assert_eq!(image.unpack(&data), image.indices);
+}
+
+#[test]
+fn write_source_sets_size() {
+    let _apartment = ComApartment::new();
+    let imaging_factory = create_imaging_factory().unwrap();
+
+    let image = TestImage::new(2);
+    let bitmap = test_bitmap(&imaging_factory, &image);
+
+    let stream = unsafe { SHCreateMemStream(None) }.unwrap();
+    let encoder: IWICBitmapEncoder = ComObject::new(BitmapEncoder::new()).into_interface();
+    let frame = new_frame(&encoder, &stream);
+
+    unsafe {
+        // The first source determines size and pixel format, so it is the whole frame.
+        frame.WriteSource(&bitmap, std::ptr::null()).unwrap();
+        assert_eq!(
+            frame
+                .WriteSource(&bitmap, std::ptr::null())
+                .unwrap_err()
+                .code(),
+            WINCODEC_ERR_CODECTOOMANYSCANLINES
+        );
+
+        frame.Commit().unwrap();
+        encoder.Commit().unwrap();
+        stream.Seek(0, STREAM_SEEK_SET, None).unwrap();
+    }
+
+    let (pixel_format, _, data) = decode(&imaging_factory, &stream, &image);
+    assert_eq!(pixel_format, bit_depth_to_pixel_format(2).unwrap());
+    assert_eq!(image.unpack(&data), image.indices);
+}
+
+#[test]
+fn strict_vera_option() {
+    let _apartment = ComApartment::new();
+
+    // 12 pixels at 4 bpp aren't a multiple of 8.
+    let image = TestImage::new(4);
+    let stream = unsafe { SHCreateMemStream(None) }.unwrap();
+    let encoder: IWICBitmapEncoder = ComObject::new(BitmapEncoder::new()).into_interface();
+
+    unsafe {
+        encoder
+            .Initialize(&stream, WICBitmapEncoderNoCache)
+            .unwrap();
+
+        let (mut frame, mut options) = (None, None);
+        encoder.CreateNewFrame(&mut frame, &mut options).unwrap();
+        let (frame, options) = (frame.unwrap(), options.unwrap());
+
+        let option = PROPBAG2 {
+            pstrName: PWSTR(STRICT_VERA.0 as *mut _),
+            ..Default::default()
+        };
+        options.Write(1, &option, &VARIANT::from(true)).unwrap();
+
+        frame.Initialize(&options).unwrap();
+        frame.SetSize(12, HEIGHT).unwrap();
+        frame
+            .SetPixelFormat(&mut bit_depth_to_pixel_format(4).unwrap())
+            .unwrap();
+        frame
+            .WritePixels(HEIGHT, image.stride() as u32, &image.pack(image.stride()))
+            .unwrap();
+
+        assert_eq!(
+            frame.Commit().unwrap_err().code(),
+            WINCODEC_ERR_VALUEOUTOFRANGE
+        );
+    }
+}
+
+#[test]
+fn target_version_option() {
+    let _apartment = ComApartment::new();
+
+    let image = TestImage::new(4);
+
+    for version in [0, FileHeader::MIN_VERSION, FileHeader::VERSION + 1] {
+        let stream = unsafe { SHCreateMemStream(None) }.unwrap();
+        let encoder: IWICBitmapEncoder = ComObject::new(BitmapEncoder::new()).into_interface();
+
+        unsafe {
+            encoder
+                .Initialize(&stream, WICBitmapEncoderNoCache)
+                .unwrap();
+
+            let (mut frame, mut options) = (None, None);
+            encoder.CreateNewFrame(&mut frame, &mut options).unwrap();
+            let (frame, options) = (frame.unwrap(), options.unwrap());
+
+            let option = PROPBAG2 {
+                pstrName: PWSTR(TARGET_VERSION.0 as *mut _),
+                ..Default::default()
+            };
+            options.Write(1, &option, &VARIANT::from(version)).unwrap();
+
+            if !(FileHeader::MIN_VERSION..=FileHeader::VERSION).contains(&version) {
+                assert_eq!(
+                    frame.Initialize(&options).unwrap_err().code(),
+                    HRESULT(WINCODEC_ERR_INVALIDPARAMETER),
+                    "version {}",
+                    version
+                );
+                continue;
+            }
+
+            frame.Initialize(&options).unwrap();
+            frame.SetSize(WIDTH, HEIGHT).unwrap();
+            frame
+                .SetPixelFormat(&mut bit_depth_to_pixel_format(4).unwrap())
+                .unwrap();
+            frame
+                .WritePixels(HEIGHT, image.stride() as u32, &image.pack(image.stride()))
+                .unwrap();
+            frame.Commit().unwrap();
+            encoder.Commit().unwrap();
+            stream.Seek(0, STREAM_SEEK_SET, None).unwrap();
+        }
+
+        let bytes = stream_read_to_end(&stream).unwrap();
+        let header = FileHeader::from_bytes(&bytes[..FileHeader::SIZE]).unwrap();
+        assert_eq!(header.version, version);
+    }
+}
+
+// Files from newer versions are decoded best-effort, which the frame metadata says.
+#[test]
+fn newer_version_metadata() {
+    let _apartment = ComApartment::new();
+
+    let palette = vec![PaletteEntry::default(); 2];
+
+    for version in [FileHeader::VERSION, FileHeader::VERSION + 1] {
+        let mut image = BmxImage::new(8, 2, 1, palette.clone(), vec![0xA5, 0x5A]).unwrap();
+        image.header.version = version;
+
+        let stream = unsafe { SHCreateMemStream(Some(&image.to_bytes(false).unwrap())) }.unwrap();
+        let decoder: IWICBitmapDecoder = ComObject::new(BitmapDecoder::new()).into_interface();
+
+        unsafe {
+            decoder
+                .Initialize(&stream, WICDecodeMetadataCacheOnDemand)
+                .unwrap();
+
+            let mut value = PROPVARIANT::default();
+            decoder
+                .GetFrame(0)
+                .unwrap()
+                .GetMetadataQueryReader()
+                .unwrap()
+                .GetMetadataByName(&HSTRING::from(NEWER_VERSION), &mut value)
+                .unwrap();
+
+            assert_eq!(
+                value,
+                PROPVARIANT::from(version > FileHeader::VERSION),
+                "version {}",
+                version
+            );
+        }
+    }
+}
+
+// Re-encoding a decoded BMX keeps palettes that are longer than the bit depth needs, as well as
+// pal_start.
+#[test]
+fn palette_layout_round_trip() {
+    let _apartment = ComApartment::new();
+
+    for (bit_depth, palette_len, pal_start) in [(4, 256, 16), (2, 3, 200), (8, 256, 0), (1, 2, 1)] {
+        let palette = (0..palette_len)
+            .map(|i: usize| {
+                let i = i as u8;
+                PaletteEntry::from_rgb(i, i.wrapping_mul(7), !i)
+            })
+            .collect();
+        let len = (WIDTH as usize * bit_depth as usize).div_ceil(8) * HEIGHT as usize;
+        // Indices 0 to 2 at every bit depth.
+        let data = vec![0b0001_0010; len];
+
+        let mut image = BmxImage::new(WIDTH as _, HEIGHT as _, bit_depth, palette, data).unwrap();
+        image.header.pal_start = pal_start;
+
+        let source = unsafe { SHCreateMemStream(Some(&image.to_bytes(false).unwrap())) }.unwrap();
+        let decoder: IWICBitmapDecoder = ComObject::new(BitmapDecoder::new()).into_interface();
+
+        let stream = unsafe { SHCreateMemStream(None) }.unwrap();
+        let encoder: IWICBitmapEncoder = ComObject::new(BitmapEncoder::new()).into_interface();
+        let frame = new_frame(&encoder, &stream);
+
+        unsafe {
+            decoder
+                .Initialize(&source, WICDecodeMetadataCacheOnDemand)
+                .unwrap();
+
+            frame
+                .WriteSource(&decoder.GetFrame(0).unwrap(), std::ptr::null())
+                .unwrap();
+            frame.Commit().unwrap();
+            encoder.Commit().unwrap();
+            stream.Seek(0, STREAM_SEEK_SET, None).unwrap();
+        }
+
+        let encoded = BmxImage::from_bytes(&stream_read_to_end(&stream).unwrap()).unwrap();
+
+        assert_eq!(
+            encoded.header.pal_used, image.header.pal_used,
+            "{} bpp",
+            bit_depth
+        );
+        assert_eq!(encoded.header.pal_start, pal_start, "{} bpp", bit_depth);
+        assert_eq!(encoded.palette, image.palette, "{} bpp", bit_depth);
+        assert_eq!(encoded.data, image.data, "{} bpp", bit_depth);
+    }
+}
+
+// The reserved header bytes travel through WIC's metadata block copy, and known tags in them are
+// kept up to date.
+#[test]
+fn metadata_block_copy() {
+    let _apartment = ComApartment::new();
+
+    let image = TestImage::new(8);
+    let palette = image
+        .palette
+        .iter()
+        .map(|&color| PaletteEntry::from_wic(color))
+        .collect();
+    let data = image.pack(image.stride());
+
+    let mut tagged = BmxImage::new(WIDTH as _, HEIGHT as _, 8, palette, data).unwrap();
+    let mut untagged = tagged.clone();
+
+    tagged.header.set_resolution(Some((72, 144)));
+    tagged.header.set_crc32(Some(crc32(&tagged.data)));
+    untagged.header.reserved = *b"0123456789abcdef";
+
+    for source in [tagged, untagged] {
+        let stream = unsafe { SHCreateMemStream(Some(&source.to_bytes(false).unwrap())) }.unwrap();
+        let decoder: IWICBitmapDecoder = ComObject::new(BitmapDecoder::new()).into_interface();
+
+        let output = unsafe { SHCreateMemStream(None) }.unwrap();
+        let encoder: IWICBitmapEncoder = ComObject::new(BitmapEncoder::new()).into_interface();
+        let frame = new_frame(&encoder, &output);
+
+        unsafe {
+            decoder
+                .Initialize(&stream, WICDecodeMetadataCacheOnDemand)
+                .unwrap();
+            let frame_decode = decoder.GetFrame(0).unwrap();
+
+            let block_writer = frame.cast::<IWICMetadataBlockWriter>().unwrap();
+            block_writer
+                .InitializeFromBlockReader(&frame_decode.cast::<IWICMetadataBlockReader>().unwrap())
+                .unwrap();
+            assert_eq!(block_writer.GetCount().unwrap(), 1);
+
+            frame.WriteSource(&frame_decode, std::ptr::null()).unwrap();
+            frame.Commit().unwrap();
+            encoder.Commit().unwrap();
+            output.Seek(0, STREAM_SEEK_SET, None).unwrap();
+        }
+
+        let encoded = BmxImage::from_bytes(&stream_read_to_end(&output).unwrap()).unwrap();
+        assert_eq!(encoded.header.reserved, source.header.reserved);
+        assert_eq!(encoded.data, source.data);
+    }
+}
+
+// Setting the default resolution drops the tag copied with the reserved bytes rather than keeping
+// the source's.
+#[test]
+fn default_resolution_clears_copied_tag() {
+    let _apartment = ComApartment::new();
+
+    let image = TestImage::new(8);
+    let palette = image
+        .palette
+        .iter()
+        .map(|&color| PaletteEntry::from_wic(color))
+        .collect();
+    let data = image.pack(image.stride());
+
+    let mut source = BmxImage::new(WIDTH as _, HEIGHT as _, 8, palette, data).unwrap();
+    source.header.set_resolution(Some((72, 144)));
+    source.header.reserved[8..].copy_from_slice(b"abcdefgh");
+
+    let stream = unsafe { SHCreateMemStream(Some(&source.to_bytes(false).unwrap())) }.unwrap();
+    let decoder: IWICBitmapDecoder = ComObject::new(BitmapDecoder::new()).into_interface();
+
+    let output = unsafe { SHCreateMemStream(None) }.unwrap();
+    let encoder: IWICBitmapEncoder = ComObject::new(BitmapEncoder::new()).into_interface();
+    let frame = new_frame(&encoder, &output);
+
+    unsafe {
+        decoder
+            .Initialize(&stream, WICDecodeMetadataCacheOnDemand)
+            .unwrap();
+        let frame_decode = decoder.GetFrame(0).unwrap();
+
+        frame
+            .cast::<IWICMetadataBlockWriter>()
+            .unwrap()
+            .InitializeFromBlockReader(&frame_decode.cast::<IWICMetadataBlockReader>().unwrap())
+            .unwrap();
+
+        frame.SetResolution(96.0, 96.0).unwrap();
+        frame.WriteSource(&frame_decode, std::ptr::null()).unwrap();
+        frame.Commit().unwrap();
+        encoder.Commit().unwrap();
+        output.Seek(0, STREAM_SEEK_SET, None).unwrap();
+    }
+
+    let encoded = BmxImage::from_bytes(&stream_read_to_end(&output).unwrap()).unwrap();
+    assert_eq!(encoded.header.resolution(), None);
+    assert_eq!(encoded.header.reserved[..8], [0; 8]);
+    assert_eq!(encoded.header.reserved[8..], *b"abcdefgh");
+}
+
+// A rectangle one pixel in from the left is as many bytes wide as a full line at 1 bpp, but its
+// bits still have to be shifted into place.
+#[test]
+fn copy_pixels_shifts_sub_byte_rect() {
+    let _apartment = ComApartment::new();
+    let imaging_factory = create_imaging_factory().unwrap();
+
+    let image = TestImage::new(1);
+    let stream = encode(&imaging_factory, &image);
+    let decoder: IWICBitmapDecoder = ComObject::new(BitmapDecoder::new()).into_interface();
+
+    let rect = WICRect {
+        X: 1,
+        Y: 1,
+        Width: WIDTH as i32 - 2,
+        Height: HEIGHT as i32 - 2,
+    };
+    let stride = image.stride();
+    assert_eq!(stride, (rect.Width as usize).div_ceil(8));
+
+    let mut data = vec![0u8; stride * rect.Height as usize];
+    unsafe {
+        decoder
+            .Initialize(&stream, WICDecodeMetadataCacheOnDemand)
+            .unwrap();
+        decoder
+            .GetFrame(0)
+            .unwrap()
+            .CopyPixels(&rect, stride as _, &mut data)
+            .unwrap();
+    }
+
+    for (y, line) in data.chunks_exact(stride).enumerate() {
+        for x in 0..rect.Width as usize {
+            let expected = image.indices[(y + 1) * WIDTH as usize + x + 1];
+            assert_eq!((line[x / 8] >> (7 - x % 8)) & 1, expected, "({}, {})", x, y);
+        }
+    }
+}
+
+// Each row holds its own number, so every frame shows which rows of the sheet it covers.
+#[test]
+fn splits_sprite_sheets() {
+    const SPRITE_HEIGHT: u32 = 11;
+    const WIDTH: u16 = 5;
+
+    let _apartment = ComApartment::new();
+
+    let palette = (0..=255)
+        .map(|i| PaletteEntry::from_rgb(i, i, i))
+        .collect::<Vec<_>>();
+
+    // Heights that aren't a multiple of the sprite height, or no more than it, stay one frame.
+    for (height, frame_count) in [(33u16, 3u32), (34, 1), (11, 1), (7, 1)] {
+        let data = (0..height)
+            .flat_map(|y| [y as u8; WIDTH as usize])
+            .collect::<Vec<_>>();
+        let image = BmxImage::new(WIDTH, height, 8, palette.clone(), data.clone()).unwrap();
+
+        let stream = unsafe { SHCreateMemStream(Some(&image.to_bytes(false).unwrap())) }.unwrap();
+        let decoder: IWICBitmapDecoder =
+            ComObject::new(BitmapDecoder::with_sprite_height(SPRITE_HEIGHT)).into_interface();
+
+        unsafe {
+            decoder
+                .Initialize(&stream, WICDecodeMetadataCacheOnDemand)
+                .unwrap();
+            assert_eq!(decoder.GetFrameCount().unwrap(), frame_count, "{}", height);
+            assert!(decoder.GetFrame(frame_count).is_err());
+
+            let frame_height = height as u32 / frame_count;
+            let frame_len = WIDTH as usize * frame_height as usize;
+
+            for index in 0..frame_count {
+                let frame = decoder.GetFrame(index).unwrap();
+
+                let (mut width, mut height) = (0, 0);
+                frame.GetSize(&mut width, &mut height).unwrap();
+                assert_eq!((width, height), (WIDTH as u32, frame_height));
+
+                let mut pixels = vec![0u8; frame_len];
+                frame
+                    .CopyPixels(std::ptr::null(), WIDTH as u32, &mut pixels)
+                    .unwrap();
+                assert_eq!(
+                    pixels,
+                    data[index as usize * frame_len..][..frame_len],
+                    "frame {} of {} rows",
+                    index,
+                    height
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn fills_truncated_images_with_border_color() {
+    let _apartment = ComApartment::new();
+
+    let palette = vec![PaletteEntry::default(); 16];
+    let mut image = BmxImage::new(4, 4, 4, palette, vec![0x12; 8]).unwrap();
+    image.header.vera_border_color = 5;
+
+    let bytes = image.to_bytes(false).unwrap();
+    let bytes = &bytes[..bytes.len() - 3];
+
+    for tolerate_truncation in [false, true] {
+        let stream = unsafe { SHCreateMemStream(Some(bytes)) }.unwrap();
+        let decoder: IWICBitmapDecoder =
+            ComObject::new(BitmapDecoder::with_tolerate_truncation(tolerate_truncation))
+                .into_interface();
+
+        unsafe {
+            let result = decoder.Initialize(&stream, WICDecodeMetadataCacheOnDemand);
+
+            if !tolerate_truncation {
+                assert!(result.is_err());
+                continue;
+            }
+
+            result.unwrap();
+
+            let mut pixels = vec![0u8; 8];
+            decoder
+                .GetFrame(0)
+                .unwrap()
+                .CopyPixels(std::ptr::null(), 2, &mut pixels)
+                .unwrap();
+            assert_eq!(pixels, [0x12, 0x12, 0x12, 0x12, 0x12, 0x55, 0x55, 0x55]);
+        }
+    }
+}
+
+// Larger than one read chunk, so CopyPixels has to stitch several reads together.
+#[test]
+fn copy_pixels_across_chunks() {
+    const WIDTH: u16 = 320;
+    const HEIGHT: u16 = 240;
+
+    let _apartment = ComApartment::new();
+
+    let palette = (0..=255).map(|i| PaletteEntry::from_rgb(i, i, i)).collect();
+    let data = (0..WIDTH as usize * HEIGHT as usize)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>();
+    let image = BmxImage::new(WIDTH, HEIGHT, 8, palette, data.clone()).unwrap();
+
+    let stream = unsafe { SHCreateMemStream(Some(&image.to_bytes(false).unwrap())) }.unwrap();
+    let decoder: IWICBitmapDecoder = ComObject::new(BitmapDecoder::new()).into_interface();
+
+    unsafe {
+        decoder
+            .Initialize(&stream, WICDecodeMetadataCacheOnDemand)
+            .unwrap();
+        let frame = decoder.GetFrame(0).unwrap();
+
+        for stride in [WIDTH as usize, WIDTH as usize + 5] {
+            let mut buffer = vec![0u8; stride * HEIGHT as usize];
+            frame
+                .CopyPixels(std::ptr::null(), stride as _, &mut buffer)
+                .unwrap();
+
+            for (row, line) in buffer.chunks_exact(stride).enumerate() {
+                let expected = &data[row * WIDTH as usize..][..WIDTH as usize];
+                assert_eq!(
+                    &line[..WIDTH as usize],
+                    expected,
+                    "stride {}, row {}",
+                    stride,
+                    row
+                );
+            }
+        }
+    }
+}
+
+// A BMX inside another container: the decoder gets the stream positioned at the header, with
+// unrelated data before and after it.
+fn embedded_stream(bmx: &[u8], prefix_len: usize) -> IStream {
+    let mut bytes = vec![0xA5u8; prefix_len];
+    bytes.extend_from_slice(bmx);
+    bytes.extend_from_slice(&[0x5A; 19]);
+
+    let stream = unsafe { SHCreateMemStream(Some(&bytes)) }.unwrap();
+    unsafe { stream.Seek(prefix_len as i64, STREAM_SEEK_SET, None) }.unwrap();
+    stream
+}
+
+#[test]
+fn decode_at_stream_offset() {
+    let _apartment = ComApartment::new();
+    let imaging_factory = create_imaging_factory().unwrap();
+
+    for bit_depth in [1, 2, 4, 8] {
+        let image = TestImage::new(bit_depth);
+        let bytes = encoded_bytes(&imaging_factory, &image);
+
+        for prefix_len in [1, 37, 4096] {
+            let stream = embedded_stream(&bytes, prefix_len);
+            let (pixel_format, palette, data) = decode(&imaging_factory, &stream, &image);
+
+            assert_eq!(pixel_format, bit_depth_to_pixel_format(bit_depth).unwrap());
+            assert_eq!(palette[..image.palette.len()], image.palette);
+            assert_eq!(
+                image.unpack(&data),
+                image.indices,
+                "{} bpp at offset {}",
+                bit_depth,
+                prefix_len
+            );
+        }
+    }
+}
+
+#[test]
+fn embedded_decoder_stays_within_the_image() {
+    let _apartment = ComApartment::new();
+    let imaging_factory = create_imaging_factory().unwrap();
+
+    let image = TestImage::new(8);
+    let bytes = encoded_bytes(&imaging_factory, &image);
+    let prefix_len = 100;
+    let stream = embedded_stream(&bytes, prefix_len);
+    let decoder: IWICBitmapDecoder = ComObject::new(BitmapDecoder::new()).into_interface();
+
+    unsafe {
+        decoder
+            .Initialize(&stream, WICDecodeMetadataCacheOnDemand)
+            .unwrap();
+
+        // The caller's position is where it was, so the container can go on reading after it.
+        let mut position = 0;
+        stream
+            .Seek(0, STREAM_SEEK_CUR, Some(&mut position))
+            .unwrap();
+        assert_eq!(position, prefix_len as u64);
+
+        let frame = decoder.GetFrame(0).unwrap();
+
+        // Rows from the middle seek relative to the BMX, not to the start of the container.
+        let rect = WICRect {
+            X: 0,
+            Y: 3,
+            Width: WIDTH as i32,
+            Height: 2,
+        };
+        let mut data = vec![0u8; image.stride() * 2];
+        frame
+            .CopyPixels(&rect, image.stride() as _, &mut data)
+            .unwrap();
+        assert_eq!(
+            data,
+            image.pack(image.stride())[3 * image.stride()..][..2 * image.stride()]
+        );
+
+        let block_reader: IWICMetadataBlockReader = frame.cast().unwrap();
+        assert!(block_reader.GetCount().is_ok());
+        assert!(decoder.GetThumbnail().is_ok());
+    }
+}
+
+#[test]
+fn activate_without_registration() {
+    let _apartment = ComApartment::new();
+    let _context = ActivationContext::new();
+
+    unsafe {
+        let decoder: IWICBitmapDecoder =
+            CoCreateInstance(&BitmapDecoder::CLSID, None, CLSCTX_INPROC_SERVER).unwrap();
+        assert_eq!(decoder.GetContainerFormat().unwrap(), CONTAINER_FORMAT);
+
+        let encoder: IWICBitmapEncoder =
+            CoCreateInstance(&BitmapEncoder::CLSID, None, CLSCTX_INPROC_SERVER).unwrap();
+        assert_eq!(encoder.GetContainerFormat().unwrap(), CONTAINER_FORMAT);
+    }
+}
+
+fn fault_stream(data: Vec<u8>, faults: Faults) -> (ComObject<FaultStream>, IStream) {
+    let object = ComObject::new(FaultStream::new(data, faults));
+    let stream = object.to_interface();
+    (object, stream)
+}
+
+fn copy_pixels(stream: &IStream, image: &TestImage) -> windows::core::Result<Vec<u8>> {
+    let decoder: IWICBitmapDecoder = ComObject::new(BitmapDecoder::new()).into_interface();
+
+    unsafe {
+        decoder.Initialize(stream, WICDecodeMetadataCacheOnDemand)?;
+
+        let mut data = vec![0u8; image.stride() * HEIGHT as usize];
+        decoder
+            .GetFrame(0)?
+            .CopyPixels(std::ptr::null(), image.stride() as _, &mut data)?;
+
+        Ok(data)
+    }
+}
+
+fn encoded_bytes(imaging_factory: &IWICImagingFactory, image: &TestImage) -> Vec<u8> {
+    stream_read_to_end(&encode(imaging_factory, image)).unwrap()
+}
+
+#[test]
+fn decode_short_reads() {
+    let _apartment = ComApartment::new();
+    let imaging_factory = create_imaging_factory().unwrap();
+
+    let image = TestImage::new(4);
+    let bytes = encoded_bytes(&imaging_factory, &image);
+    let data_start = (FileHeader::SIZE + image.palette.len() * 2) as u64;
+
+    for read_limit in [10, FileHeader::SIZE as u64 + 3, data_start + 5] {
+        let faults = Faults {
+            read_limit: Some(read_limit),
+            ..Default::default()
+        };
+        let (_, stream) = fault_stream(bytes.clone(), faults);
+
+        assert!(
+            copy_pixels(&stream, &image).is_err(),
+            "read limit {}",
+            read_limit
+        );
+    }
+}
+
+#[test]
+fn decode_truncated_stream() {
+    let _apartment = ComApartment::new();
+    let imaging_factory = create_imaging_factory().unwrap();
+
+    let image = TestImage::new(8);
+    let bytes = encoded_bytes(&imaging_factory, &image);
+    let (_, stream) = fault_stream(bytes[..bytes.len() - 7].to_vec(), Faults::default());
+
+    assert_eq!(
+        copy_pixels(&stream, &image).unwrap_err().code(),
+        WINCODEC_ERR_BADIMAGE
+    );
+}
+
+#[test]
+fn decode_seek_failure() {
+    let _apartment = ComApartment::new();
+    let imaging_factory = create_imaging_factory().unwrap();
+
+    let image = TestImage::new(2);
+    let faults = Faults {
+        fail_seek: true,
+        ..Default::default()
+    };
+    let (_, stream) = fault_stream(encoded_bytes(&imaging_factory, &image), faults);
+
+    assert_eq!(
+        copy_pixels(&stream, &image).unwrap_err().code(),
+        STG_E_INVALIDFUNCTION
+    );
+}
+
+#[test]
+fn decode_slow_stream() {
+    let _apartment = ComApartment::new();
+    let imaging_factory = create_imaging_factory().unwrap();
+
+    let image = TestImage::new(1);
+    let faults = Faults {
+        delay: Some(Duration::from_millis(2)),
+        ..Default::default()
+    };
+    let (_, stream) = fault_stream(encoded_bytes(&imaging_factory, &image), faults);
+
+    assert_eq!(
+        image.unpack(&copy_pixels(&stream, &image).unwrap()),
+        image.indices
+    );
+}
+
+#[test]
+fn partial_transfers() {
+    let _apartment = ComApartment::new();
+    let imaging_factory = create_imaging_factory().unwrap();
+
+    let image = TestImage::new(4);
+    let bytes = encoded_bytes(&imaging_factory, &image);
+    let faults = Faults {
+        max_transfer: Some(7),
+        ..Default::default()
+    };
+
+    let (_, stream) = fault_stream(bytes.clone(), faults);
+    assert_eq!(
+        image.unpack(&copy_pixels(&stream, &image).unwrap()),
+        image.indices
+    );
+
+    let (object, stream) = fault_stream(Vec::new(), faults);
+    encode_into(&imaging_factory, &image, image.stride(), &stream).unwrap();
+    assert_eq!(object.data(), bytes);
+
+    let (_, stream) = fault_stream(bytes.clone(), faults);
+    let mut buffer = vec![0u8; bytes.len()];
+    assert_eq!(
+        stream_read_exact(&stream, &mut buffer).unwrap(),
+        bytes.len()
+    );
+    assert_eq!(buffer, bytes);
+}
+
+#[test]
+fn encode_write_failures() {
+    let _apartment = ComApartment::new();
+    let imaging_factory = create_imaging_factory().unwrap();
+
+    let image = TestImage::new(4);
+    let bytes = encoded_bytes(&imaging_factory, &image);
+    let data_start = (FileHeader::SIZE + image.palette.len() * 2) as u64;
+
+    for write_limit in [0, 10, data_start + 3, bytes.len() as u64 - 1] {
+        let faults = Faults {
+            write_limit: Some(write_limit),
+            ..Default::default()
+        };
+        let (object, stream) = fault_stream(Vec::new(), faults);
+
+        assert_eq!(
+            encode_into(&imaging_factory, &image, image.stride(), &stream)
+                .unwrap_err()
+                .code(),
+            STG_E_MEDIUMFULL,
+            "write limit {}",
+            write_limit
+        );
+        assert!(object.data().len() as u64 <= write_limit);
+    }
+
+    let faults = Faults {
+        write_limit: Some(bytes.len() as u64),
+        ..Default::default()
+    };
+    let (object, stream) = fault_stream(Vec::new(), faults);
+
+    encode_into(&imaging_factory, &image, image.stride(), &stream).unwrap();
+    assert_eq!(object.data(), bytes);
+}
+
+#[test]
+fn read_counted_buffer_two_calls() {
+    let value = [1u16, 2, 3];
+    let mut calls = 0;
+
+    let result = read_counted_buffer(|buffer: &mut [u16], actual| {
+        calls += 1;
+        unsafe { *actual = value.len() as u32 };
+
+        if buffer.len() < value.len() {
+            return Err(WINCODEC_ERR_INSUFFICIENTBUFFER.into());
+        }
+
+        buffer[..value.len()].copy_from_slice(&value);
+        Ok(())
+    });
+
+    assert_eq!(result.unwrap(), value);
+    assert_eq!(calls, 2);
+
+    let result = read_counted_buffer::<u16, _>(|_, _| Err(E_INVALIDARG.into()));
+    assert_eq!(result.unwrap_err().code(), E_INVALIDARG);
+}
+
+#[test]
+fn stream_wrapper_reads_writes_and_seeks() {
+    let stream = unsafe { SHCreateMemStream(None) }.unwrap();
+
+    {
+        let mut writer = StreamReadWriteWrapper::buffered_writer(&stream);
+        writer.write_all(b"0123456789").unwrap();
+        writer.into_inner().unwrap();
+    }
+
+    let mut wrapper = StreamReadWriteWrapper::new(&stream);
+    assert_eq!(wrapper.seek(SeekFrom::End(-4)).unwrap(), 6);
+
+    let mut tail = Vec::new();
+    wrapper.read_to_end(&mut tail).unwrap();
+    assert_eq!(tail, b"6789");
+
+    wrapper.seek(SeekFrom::Start(2)).unwrap();
+    assert_eq!(wrapper.seek(SeekFrom::Current(1)).unwrap(), 3);
+
+    let mut buffer = [0u8; 3];
+    wrapper.read_exact(&mut buffer).unwrap();
+    assert_eq!(&buffer, b"345");
+
+    assert!(wrapper.seek(SeekFrom::Current(-100)).is_err());
+}
+
+#[test]
+fn short_reads_report_position() {
+    let (_object, stream) = fault_stream(
+        (0..32).collect(),
+        Faults {
+            read_limit: Some(20),
+            ..Default::default()
+        },
+    );
+
+    let mut buffer = [0u8; 16];
+    assert_eq!(stream_read_exact(&stream, &mut buffer).unwrap(), 16);
+
+    let err = stream_read_exact(&stream, &mut buffer).unwrap_err();
+    assert_eq!(err.operation, StreamOperation::Read);
+    assert_eq!((err.requested, err.actual), (16, 4));
+    assert_eq!(err.position, Some(16));
+    assert!(err.error.is_none());
+
+    let err = windows::core::Error::from(err);
+    assert_eq!(err.code(), WINCODEC_ERR_STREAMREAD);
+    assert!(err.message().contains("at offset 16"));
+}
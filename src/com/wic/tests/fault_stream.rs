@@ -0,0 +1,227 @@
+// An in-memory IStream that can be told to misbehave, so the codec's error paths can be exercised
+// without a flaky network share.
+
+use std::ffi::c_void;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use windows::core::implement;
+use windows::Win32::Foundation::{
+    E_NOTIMPL, STG_E_INVALIDFUNCTION, STG_E_MEDIUMFULL, S_FALSE, S_OK,
+};
+use windows::Win32::System::Com::{
+    ISequentialStream_Impl, IStream, IStream_Impl, LOCKTYPE, STATFLAG, STATSTG, STGC, STGTY_STREAM,
+    STREAM_SEEK, STREAM_SEEK_CUR, STREAM_SEEK_END, STREAM_SEEK_SET,
+};
+use windows_core::HRESULT;
+
+#[derive(Clone, Copy, Default)]
+pub struct Faults {
+    // Reads stop at this offset with S_FALSE while Stat still reports the full size, like a file
+    // that was truncated after it was opened.
+    pub read_limit: Option<u64>,
+    // Writes reaching past this offset fail with STG_E_MEDIUMFULL.
+    pub write_limit: Option<u64>,
+    pub fail_seek: bool,
+    // Applied to every Read and Write.
+    pub delay: Option<Duration>,
+    // Reads and writes transfer at most this many bytes per call and still return S_OK, like
+    // streams over SMB or WebDAV.
+    pub max_transfer: Option<u32>,
+}
+
+struct State {
+    data: Vec<u8>,
+    position: u64,
+}
+
+#[implement(IStream)]
+pub struct FaultStream {
+    state: Mutex<State>,
+    faults: Faults,
+}
+
+impl FaultStream {
+    pub fn new(data: Vec<u8>, faults: Faults) -> Self {
+        Self {
+            state: Mutex::new(State { data, position: 0 }),
+            faults,
+        }
+    }
+
+    pub fn data(&self) -> Vec<u8> {
+        self.state.lock().unwrap().data.clone()
+    }
+
+    fn delay(&self) {
+        if let Some(delay) = self.faults.delay {
+            std::thread::sleep(delay);
+        }
+    }
+}
+
+impl ISequentialStream_Impl for FaultStream_Impl {
+    fn Read(&self, buffer: *mut c_void, size: u32, read: *mut u32) -> HRESULT {
+        self.delay();
+
+        let size = self.faults.max_transfer.map_or(size, |max| size.min(max));
+
+        let mut state = self.state.lock().unwrap();
+        let end = self
+            .faults
+            .read_limit
+            .map_or(state.data.len() as u64, |limit| {
+                limit.min(state.data.len() as u64)
+            });
+
+        // Seeking past the end is allowed, so the position can be beyond the data.
+        let start = state.position.min(end) as usize;
+        let count = (end as usize - start).min(size as usize);
+
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                state.data[start..start + count].as_ptr(),
+                buffer.cast(),
+                count,
+            );
+
+            if !read.is_null() {
+                *read = count as u32;
+            }
+        }
+
+        state.position += count as u64;
+
+        if count < size as usize {
+            S_FALSE
+        } else {
+            S_OK
+        }
+    }
+
+    fn Write(&self, buffer: *const c_void, size: u32, written: *mut u32) -> HRESULT {
+        self.delay();
+
+        let size = self.faults.max_transfer.map_or(size, |max| size.min(max));
+
+        let mut state = self.state.lock().unwrap();
+        let start = state.position as usize;
+        let end = start + size as usize;
+
+        if self
+            .faults
+            .write_limit
+            .is_some_and(|limit| end as u64 > limit)
+        {
+            if !written.is_null() {
+                unsafe { *written = 0 };
+            }
+
+            return STG_E_MEDIUMFULL;
+        }
+
+        if state.data.len() < end {
+            state.data.resize(end, 0);
+        }
+
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                buffer.cast(),
+                state.data[start..end].as_mut_ptr(),
+                size as usize,
+            );
+
+            if !written.is_null() {
+                *written = size;
+            }
+        }
+
+        state.position = end as u64;
+        S_OK
+    }
+}
+
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+impl IStream_Impl for FaultStream_Impl {
+    fn Seek(
+        &self,
+        offset: i64,
+        origin: STREAM_SEEK,
+        new_position: *mut u64,
+    ) -> windows::core::Result<()> {
+        if self.faults.fail_seek {
+            return Err(STG_E_INVALIDFUNCTION.into());
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let base = match origin {
+            STREAM_SEEK_SET => 0,
+            STREAM_SEEK_CUR => state.position as i64,
+            STREAM_SEEK_END => state.data.len() as i64,
+            _ => return Err(STG_E_INVALIDFUNCTION.into()),
+        };
+
+        let position = u64::try_from(base + offset)
+            .map_err(|_| windows::core::Error::from(STG_E_INVALIDFUNCTION))?;
+        state.position = position;
+
+        if !new_position.is_null() {
+            unsafe { *new_position = position };
+        }
+
+        Ok(())
+    }
+
+    fn SetSize(&self, size: u64) -> windows::core::Result<()> {
+        self.state.lock().unwrap().data.resize(size as usize, 0);
+        Ok(())
+    }
+
+    fn CopyTo(
+        &self,
+        _stream: Option<&IStream>,
+        _size: u64,
+        _read: *mut u64,
+        _written: *mut u64,
+    ) -> windows::core::Result<()> {
+        Err(E_NOTIMPL.into())
+    }
+
+    fn Commit(&self, _flags: &STGC) -> windows::core::Result<()> {
+        Ok(())
+    }
+
+    fn Revert(&self) -> windows::core::Result<()> {
+        Ok(())
+    }
+
+    fn LockRegion(
+        &self,
+        _offset: u64,
+        _size: u64,
+        _lock_type: &LOCKTYPE,
+    ) -> windows::core::Result<()> {
+        Err(STG_E_INVALIDFUNCTION.into())
+    }
+
+    fn UnlockRegion(&self, _offset: u64, _size: u64, _lock_type: u32) -> windows::core::Result<()> {
+        Err(STG_E_INVALIDFUNCTION.into())
+    }
+
+    fn Stat(&self, stat: *mut STATSTG, _flags: &STATFLAG) -> windows::core::Result<()> {
+        unsafe {
+            *stat = STATSTG {
+                r#type: STGTY_STREAM.0 as u32,
+                cbSize: self.state.lock().unwrap().data.len() as u64,
+                ..Default::default()
+            };
+        }
+
+        Ok(())
+    }
+
+    // Not cloneable, which also covers the decoder's fallback of sharing the source stream.
+    fn Clone(&self) -> windows::core::Result<IStream> {
+        Err(E_NOTIMPL.into())
+    }
+}
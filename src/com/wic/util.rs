@@ -1,3 +1,4 @@
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::num::NonZeroU8;
 
 use windows::Win32::{
@@ -5,10 +6,12 @@ use windows::Win32::{
         GUID_WICPixelFormat1bppIndexed, GUID_WICPixelFormat2bppIndexed,
         GUID_WICPixelFormat4bppIndexed, GUID_WICPixelFormat8bppIndexed,
     },
-    System::Com::{IStream, STGC_DEFAULT, STREAM_SEEK_CUR, STREAM_SEEK_SET},
+    System::Com::{IStream, STGC_DEFAULT, STREAM_SEEK_CUR, STREAM_SEEK_END, STREAM_SEEK_SET},
 };
 use windows_core::GUID;
 
+use crate::com::hresult::Condition;
+
 pub struct StreamPositionPreserver {
     stream: IStream,
     pub position: u64,
@@ -35,56 +38,127 @@ impl Drop for StreamPositionPreserver {
     }
 }
 
+fn to_io_error(err: windows::core::Error) -> std::io::Error {
+    std::io::Error::from_raw_os_error(err.code().0)
+}
+
+// std::io access to an IStream, so code that only deals in Read, Write and Seek can work on the
+// streams WIC and the shell hand us. Errors carry the HRESULT as their raw OS error.
+#[derive(Debug)]
 pub struct StreamReadWriteWrapper<'a> {
     stream: &'a IStream,
 }
 
-impl<'a> std::io::Read for StreamReadWriteWrapper<'a> {
+impl<'a> StreamReadWriteWrapper<'a> {
+    pub fn new(stream: &'a IStream) -> Self {
+        Self { stream }
+    }
+
+    // Every unbuffered read and write is a COM call, which adds up for small ones. Buffered readers
+    // read ahead, so the stream position no longer matches what has been consumed.
+    pub fn buffered_reader(stream: &'a IStream) -> BufReader<Self> {
+        BufReader::new(Self::new(stream))
+    }
+
+    pub fn buffered_writer(stream: &'a IStream) -> BufWriter<Self> {
+        BufWriter::new(Self::new(stream))
+    }
+
+    pub fn stream(&self) -> &'a IStream {
+        self.stream
+    }
+}
+
+impl<'a> Read for StreamReadWriteWrapper<'a> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         let mut read = 0;
         unsafe {
-            self.stream
-                .Read(
-                    buf.as_mut_ptr().cast(),
-                    buf.len().try_into().unwrap(),
-                    Some(&raw mut read),
-                )
-                .ok()
-                .map_or_else(
-                    |err| Err(std::io::Error::from_raw_os_error(err.code().0)),
-                    |_| Ok(read as _),
-                )
+            self.stream.Read(
+                buf.as_mut_ptr().cast(),
+                buf.len().min(u32::MAX as usize) as u32,
+                Some(&raw mut read),
+            )
         }
+        .ok()
+        .map_err(to_io_error)?;
+
+        Ok(read as _)
     }
 }
 
-impl<'a> std::io::Write for StreamReadWriteWrapper<'a> {
+impl<'a> Write for StreamReadWriteWrapper<'a> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         let mut written = 0;
         unsafe {
             self.stream.Write(
                 buf.as_ptr().cast(),
-                buf.len().try_into().unwrap(),
+                buf.len().min(u32::MAX as usize) as u32,
                 Some(&raw mut written),
             )
         }
         .ok()
-        .map_or_else(
-            |err| Err(std::io::Error::from_raw_os_error(err.code().0)),
-            |_| Ok(written as _),
-        )
+        .map_err(to_io_error)?;
+
+        Ok(written as _)
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
-        unsafe { self.stream.Commit(STGC_DEFAULT) }
-            .map_err(|err| std::io::Error::from_raw_os_error(err.code().0))
+        unsafe { self.stream.Commit(STGC_DEFAULT) }.map_err(to_io_error)
     }
 }
 
+impl<'a> Seek for StreamReadWriteWrapper<'a> {
+    fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+        let (offset, origin) = match position {
+            SeekFrom::Start(offset) => (offset as i64, STREAM_SEEK_SET),
+            SeekFrom::Current(offset) => (offset, STREAM_SEEK_CUR),
+            SeekFrom::End(offset) => (offset, STREAM_SEEK_END),
+        };
+
+        let mut new_position = 0;
+        unsafe {
+            self.stream
+                .Seek(offset, origin, Some(&raw mut new_position))
+                .map_err(to_io_error)?;
+        }
+
+        Ok(new_position)
+    }
+}
+
+// Only for the bit depths BMX supports, where a line never exceeds the width in bytes.
 pub fn bytes_per_line(width: u16, bit_depth: u8) -> u16 {
+    debug_assert!(bit_depth <= 8);
     ((width as u32 * (bit_depth as u32) + 7) / 8) as u16
 }
 
+// Sizes derived from dimensions that come from files and callers fail with Condition::Overflow
+// rather than wrapping around, wherever usize is narrower than the product.
+pub fn checked_image_size(line_len: usize, lines: usize) -> windows::core::Result<usize> {
+    line_len
+        .checked_mul(lines)
+        .ok_or_else(|| Condition::Overflow.into())
+}
+
+// Bytes spanned by `lines` lines `stride` apart, the last of which only needs `line_len` bytes.
+pub fn checked_buffer_size(
+    stride: u32,
+    lines: usize,
+    line_len: usize,
+) -> windows::core::Result<usize> {
+    match lines {
+        0 => Ok(0),
+        _ => checked_image_size(stride as usize, lines - 1)?
+            .checked_add(line_len)
+            .ok_or_else(|| Condition::Overflow.into()),
+    }
+}
+
+// For the u32 sizes and strides of the WIC and GDI APIs.
+pub fn checked_u32(value: usize) -> windows::core::Result<u32> {
+    value.try_into().map_err(|_| Condition::Overflow.into())
+}
+
 pub fn bit_depth_to_pixel_format(bit_depth: u8) -> Option<GUID> {
     match bit_depth {
         1 => Some(GUID_WICPixelFormat1bppIndexed),
@@ -105,3 +179,41 @@ pub fn pixel_format_to_bit_depth(pixel_format: &GUID) -> Option<NonZeroU8> {
         _ => None,
     }
 }
+
+#[cfg(all(test, windows))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_sizes_at_the_extremes() {
+        assert_eq!(bytes_per_line(u16::MAX, 8), u16::MAX);
+        assert_eq!(bytes_per_line(u16::MAX, 1), 8192);
+
+        let line_len = bytes_per_line(u16::MAX, 8) as usize;
+        assert_eq!(
+            checked_image_size(line_len, u16::MAX as usize).ok(),
+            (u16::MAX as usize).checked_mul(u16::MAX as usize)
+        );
+        assert_eq!(
+            checked_image_size(usize::MAX, 2).unwrap_err().code(),
+            Condition::Overflow.hresult()
+        );
+
+        assert_eq!(checked_buffer_size(u32::MAX, 0, 4).unwrap(), 0);
+        assert_eq!(checked_buffer_size(8, 3, 5).unwrap(), 21);
+        assert_eq!(
+            checked_buffer_size(u32::MAX, 1, usize::MAX).unwrap(),
+            usize::MAX
+        );
+        assert_eq!(
+            checked_buffer_size(1, 2, usize::MAX).unwrap_err().code(),
+            Condition::Overflow.hresult()
+        );
+
+        assert_eq!(checked_u32(u32::MAX as usize).unwrap(), u32::MAX);
+        assert_eq!(
+            checked_u32(u32::MAX as usize + 1).unwrap_err().code(),
+            Condition::Overflow.hresult()
+        );
+    }
+}
@@ -1,46 +1,95 @@
-use std::os::raw::c_void;
+use std::{os::raw::c_void, path::Path};
 
 use windows::{
     core::{w, HRESULT},
     Win32::{
-        Foundation::{CLASS_E_CLASSNOTAVAILABLE, E_POINTER, S_OK},
-        System::Registry::HKEY_CLASSES_ROOT,
+        Foundation::{
+            BOOL, CLASS_E_CLASSNOTAVAILABLE, E_INVALIDARG, E_POINTER, HMODULE, S_OK, TRUE,
+        },
+        System::{
+            LibraryLoader::DisableThreadLibraryCalls, Registry::HKEY_CLASSES_ROOT,
+            SystemServices::DLL_PROCESS_ATTACH,
+        },
+        UI::Shell::{DLLVERSIONINFO, DLLVERSIONINFO2, DLLVER_PLATFORM_NT},
     },
 };
-use windows_core::{ComObject, IUnknown, Interface, GUID};
+use windows_core::{ComObject, IUnknown, Interface, GUID, PCWSTR};
 
 use crate::{
-    com::{
-        shell::{command::transcode::Transcode, property_store::PropertyStore},
-        wic::{class_factory::ClassFactory, decoder::BitmapDecoder, encoder::BitmapEncoder},
-        CoClass,
-    },
+    com::{inventory::find_class, wic::com::VERSION_PARTS},
     registry::{
-        register_server,
+        find_sibling_module, grant_app_container_access, reg_file, register_server,
+        register_sibling_server, revoke_app_container_access,
         transaction::{Key, Transaction},
-        unregister_server,
+        unregister_server, unregister_sibling_server,
     },
     util::get_this_module_path,
 };
 
+fn register(transaction: &Transaction, siblings: bool) -> windows::core::Result<()> {
+    let module_path = unsafe { get_this_module_path()? };
+
+    if siblings {
+        if let Some((architecture, sibling_path)) = find_sibling_module(&module_path)? {
+            let classes_root = Key::predefined_in_view(
+                transaction,
+                HKEY_CLASSES_ROOT,
+                w!(""),
+                architecture.view(),
+            )?;
+            register_sibling_server(&classes_root, &sibling_path)?;
+
+            if !transaction.is_dry_run() && !transaction.is_test_hive() {
+                grant_app_container_access(&classes_root, &sibling_path)?;
+            }
+        }
+    }
+
+    let classes_root = Key::predefined(transaction, HKEY_CLASSES_ROOT, w!(""))?;
+    register_server(transaction, &classes_root, &module_path)
+}
+
+fn unregister(transaction: &Transaction, siblings: bool) -> windows::core::Result<()> {
+    let module_path = unsafe { get_this_module_path()? };
+
+    if siblings {
+        if let Some((architecture, sibling_path)) = find_sibling_module(&module_path)? {
+            let classes_root = Key::predefined_in_view(
+                transaction,
+                HKEY_CLASSES_ROOT,
+                w!(""),
+                architecture.view(),
+            )?;
+
+            if !transaction.is_dry_run() && !transaction.is_test_hive() {
+                revoke_app_container_access(&classes_root, &sibling_path)?;
+            }
+            unregister_sibling_server(&classes_root)?;
+        }
+    }
+
+    let classes_root = Key::predefined(transaction, HKEY_CLASSES_ROOT, w!(""))?;
+
+    unregister_server(transaction, &classes_root, &module_path)
+}
+
 #[allow(non_snake_case)]
 #[unsafe(no_mangle)]
-unsafe extern "system" fn DllRegisterServer() -> HRESULT {
-    fn do_register() -> windows::core::Result<()> {
-        let transaction = Transaction::new(true)?;
-
-        let classes_root = Key::predefined(&transaction, HKEY_CLASSES_ROOT, w!(""))?;
-        /*let classes_root = Key::predefined(
-            &transaction,
-            HKEY_CURRENT_USER,
-            w!("Software\\X16BMX\\BMX\\DryRun"),
-        )?;*/
-        register_server(&transaction, &classes_root, unsafe {
-            &get_this_module_path()?
-        })
+unsafe extern "system" fn DllMain(module: HMODULE, reason: u32, _reserved: *mut c_void) -> BOOL {
+    // Nothing is torn down on detach. During process exit other threads are already gone and the
+    // OS reclaims everything; on FreeLibrary every object has been released and no pinned worker
+    // threads are left.
+    if reason == DLL_PROCESS_ATTACH {
+        let _ = unsafe { DisableThreadLibraryCalls(module) };
     }
 
-    match do_register() {
+    TRUE
+}
+
+#[allow(non_snake_case)]
+#[unsafe(no_mangle)]
+unsafe extern "system" fn DllRegisterServer() -> HRESULT {
+    match Transaction::new(true).and_then(|transaction| register(&transaction, false)) {
         Ok(()) => S_OK,
         Err(err) => err.into(),
     }
@@ -49,20 +98,109 @@ unsafe extern "system" fn DllRegisterServer() -> HRESULT {
 #[allow(non_snake_case)]
 #[unsafe(no_mangle)]
 unsafe extern "system" fn DllUnregisterServer() -> HRESULT {
-    fn do_unregister() -> windows::core::Result<()> {
-        let transaction = Transaction::new(true)?;
+    match Transaction::new(true).and_then(|transaction| unregister(&transaction, false)) {
+        Ok(()) => S_OK,
+        Err(err) => err.into(),
+    }
+}
 
-        let classes_root = Key::predefined(&transaction, HKEY_CLASSES_ROOT, w!(""))?;
+// regsvr32 [/u] /n /i:"dryrun:<path>.reg" bmx_shell.dll
+// regsvr32 [/u] /n /i:siblings bmx_shell.dll
+// regsvr32 [/u] /n /i:"testhive:Software\X16BMX\TestHive" bmx_shell.dll
+#[allow(non_snake_case)]
+#[unsafe(no_mangle)]
+unsafe extern "system" fn DllInstall(install: BOOL, command_line: PCWSTR) -> HRESULT {
+    fn do_install(install: bool, command_line: &str) -> windows::core::Result<()> {
+        match command_line.split_once(':') {
+            Some(("dryrun", path)) => {
+                let transaction = Transaction::dry_run();
+
+                if install {
+                    register(&transaction, true)?;
+                } else {
+                    unregister(&transaction, true)?;
+                }
+
+                reg_file::write(&transaction.operations(), Path::new(path))?;
+                Ok(())
+            }
+            Some(("testhive", root)) if !root.is_empty() => {
+                let transaction = Transaction::new(false)?.with_test_hive(root);
+
+                if install {
+                    register(&transaction, true)
+                } else {
+                    unregister(&transaction, true)
+                }
+            }
+            None if command_line == "siblings" => {
+                let transaction = Transaction::new(true)?;
+
+                if install {
+                    register(&transaction, true)
+                } else {
+                    unregister(&transaction, true)
+                }
+            }
+            _ => Err(E_INVALIDARG.into()),
+        }
+    }
 
-        unregister_server(&transaction, &classes_root)
+    if command_line.is_null() {
+        return E_INVALIDARG;
     }
 
-    match do_unregister() {
+    let Ok(command_line) = (unsafe { command_line.to_string() }) else {
+        return E_INVALIDARG;
+    };
+
+    match do_install(install.as_bool(), &command_line) {
         Ok(()) => S_OK,
         Err(err) => err.into(),
     }
 }
 
+// Lets hosts and support tools identify the installed build without going through the registry.
+#[allow(non_snake_case)]
+#[unsafe(no_mangle)]
+unsafe extern "system" fn DllGetVersion(info: *mut DLLVERSIONINFO) -> HRESULT {
+    if info.is_null() {
+        return E_POINTER;
+    }
+
+    let size = unsafe { (*info).cbSize };
+    if size != std::mem::size_of::<DLLVERSIONINFO>() as u32
+        && size != std::mem::size_of::<DLLVERSIONINFO2>() as u32
+    {
+        return E_INVALIDARG;
+    }
+
+    let [major, minor, build, revision] = VERSION_PARTS;
+
+    unsafe {
+        info.write(DLLVERSIONINFO {
+            cbSize: size,
+            dwMajorVersion: major as u32,
+            dwMinorVersion: minor as u32,
+            dwBuildNumber: build as u32,
+            dwPlatformID: DLLVER_PLATFORM_NT,
+        })
+    };
+
+    if size == std::mem::size_of::<DLLVERSIONINFO2>() as u32 {
+        let info = info.cast::<DLLVERSIONINFO2>();
+        unsafe {
+            (*info).dwFlags = 0;
+            (*info).ullVersion = (major as u64) << 48
+                | (minor as u64) << 32
+                | (build as u64) << 16
+                | revision as u64;
+        }
+    }
+
+    S_OK
+}
+
 #[allow(non_snake_case)]
 #[unsafe(no_mangle)]
 unsafe extern "system" fn DllGetClassObject(
@@ -82,29 +220,10 @@ unsafe extern "system" fn DllGetClassObject(
         return E_POINTER;
     }
 
-    let class_factory = match unsafe { *clsid } {
-        BitmapDecoder::CLSID => ClassFactory::new(|iid, ppv| unsafe {
-            ComObject::new(BitmapDecoder::new())
-                .as_interface::<IUnknown>()
-                .query(iid, ppv)
-        }),
-        BitmapEncoder::CLSID => ClassFactory::new(|iid, ppv| unsafe {
-            ComObject::new(BitmapEncoder::new())
-                .as_interface::<IUnknown>()
-                .query(iid, ppv)
-        }),
-        PropertyStore::CLSID => ClassFactory::new(|iid, ppv| unsafe {
-            ComObject::new(PropertyStore::new())
-                .as_interface::<IUnknown>()
-                .query(iid, ppv)
-        }),
-        Transcode::CLSID => ClassFactory::new(|iid, ppv| unsafe {
-            ComObject::new(Transcode::new())
-                .as_interface::<IUnknown>()
-                .query(iid, ppv)
-        }),
-        _ => return CLASS_E_CLASSNOTAVAILABLE,
+    let Some(class) = find_class(unsafe { &*clsid }) else {
+        return CLASS_E_CLASSNOTAVAILABLE;
     };
+    let class_factory = (class.class_factory)();
 
     unsafe {
         ComObject::new(class_factory)
@@ -0,0 +1,343 @@
+#![allow(clippy::missing_safety_doc)]
+
+use std::num::NonZeroU8;
+
+use crate::{
+    bmx::{BmxImage, BmxImageError, FileHeader, FileHeaderError, PaletteEntry},
+    lzsa::LzsaError,
+};
+
+#[repr(i32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BmxStatus {
+    Ok = 0,
+    NullPointer = -1,
+    BufferTooSmall = -2,
+    InvalidHeaderSize = -3,
+    InvalidFileId = -4,
+    InvalidVersion = -5,
+    InvalidBitDepth = -6,
+    InvalidVeraColorDepthRegister = -7,
+    BitDepthMismatch = -8,
+    InvalidDataStart = -9,
+    InvalidVeraBorderColor = -10,
+    InvalidPalette = -11,
+    InvalidPixelDataLength = -12,
+    Truncated = -13,
+    Compression = -14,
+    InvalidPaletteLength = -15,
+}
+
+impl From<FileHeaderError> for BmxStatus {
+    fn from(err: FileHeaderError) -> Self {
+        match err {
+            FileHeaderError::InvalidHeaderSize => BmxStatus::InvalidHeaderSize,
+            FileHeaderError::InvalidFileId => BmxStatus::InvalidFileId,
+            FileHeaderError::InvalidVersion => BmxStatus::InvalidVersion,
+            FileHeaderError::InvalidBitDepth => BmxStatus::InvalidBitDepth,
+            FileHeaderError::InvalidVeraColorDepthRegister => {
+                BmxStatus::InvalidVeraColorDepthRegister
+            }
+            FileHeaderError::BitDepthMismatch => BmxStatus::BitDepthMismatch,
+            FileHeaderError::InvalidDataStart => BmxStatus::InvalidDataStart,
+            FileHeaderError::InvalidVeraBorderColor => BmxStatus::InvalidVeraBorderColor,
+            FileHeaderError::InvalidPaletteLength => BmxStatus::InvalidPaletteLength,
+        }
+    }
+}
+
+impl From<LzsaError> for BmxStatus {
+    fn from(_: LzsaError) -> Self {
+        BmxStatus::Compression
+    }
+}
+
+impl From<BmxImageError> for BmxStatus {
+    fn from(err: BmxImageError) -> Self {
+        match err {
+            BmxImageError::Header(err) => err.into(),
+            BmxImageError::Compression(err) => err.into(),
+            BmxImageError::InvalidPalette => BmxStatus::InvalidPalette,
+            BmxImageError::InvalidPixelDataLength => BmxStatus::InvalidPixelDataLength,
+            BmxImageError::Truncated => BmxStatus::Truncated,
+        }
+    }
+}
+
+// FileHeader as C sees it. The file ID is NonZeroU8 on the Rust side, which C code could break
+// just by zeroing a header, so headers cross the boundary as plain integers and are parsed again
+// on the way back in.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BmxFileHeader {
+    pub file_id: [u8; 3],
+    pub version: u8,
+    pub bit_depth: u8,
+    pub vera_color_depth_register: u8,
+    pub width: u16,
+    pub height: u16,
+    pub pal_used: u8,
+    pub pal_start: u8,
+    pub data_start: u16,
+    pub compressed: i8,
+    pub vera_border_color: u8,
+    pub reserved: [u8; 16],
+}
+
+const _: () = assert!(std::mem::size_of::<BmxFileHeader>() == FileHeader::SIZE);
+
+impl BmxFileHeader {
+    fn to_bytes(self) -> [u8; FileHeader::SIZE] {
+        let mut bytes = [0; FileHeader::SIZE];
+        bytes[..3].copy_from_slice(&self.file_id);
+        bytes[3] = self.version;
+        bytes[4] = self.bit_depth;
+        bytes[5] = self.vera_color_depth_register;
+        bytes[6..8].copy_from_slice(&self.width.to_le_bytes());
+        bytes[8..10].copy_from_slice(&self.height.to_le_bytes());
+        bytes[10] = self.pal_used;
+        bytes[11] = self.pal_start;
+        bytes[12..14].copy_from_slice(&self.data_start.to_le_bytes());
+        bytes[14] = self.compressed as u8;
+        bytes[15] = self.vera_border_color;
+        bytes[16..].copy_from_slice(&self.reserved);
+        bytes
+    }
+}
+
+impl From<&FileHeader> for BmxFileHeader {
+    fn from(header: &FileHeader) -> Self {
+        Self {
+            file_id: header.file_id.map(NonZeroU8::get),
+            version: header.version,
+            bit_depth: header.bit_depth,
+            vera_color_depth_register: header.vera_color_depth_register,
+            width: header.width,
+            height: header.height,
+            pal_used: header.pal_used,
+            pal_start: header.pal_start,
+            data_start: header.data_start,
+            compressed: header.compressed,
+            vera_border_color: header.vera_border_color,
+            reserved: header.reserved,
+        }
+    }
+}
+
+impl TryFrom<&BmxFileHeader> for FileHeader {
+    type Error = FileHeaderError;
+
+    fn try_from(header: &BmxFileHeader) -> Result<Self, Self::Error> {
+        FileHeader::from_bytes(&header.to_bytes())
+    }
+}
+
+unsafe fn slice_from_raw<'a, T>(data: *const T, len: usize) -> Result<&'a [T], BmxStatus> {
+    if len == 0 {
+        Ok(&[])
+    } else if data.is_null() {
+        Err(BmxStatus::NullPointer)
+    } else {
+        Ok(unsafe { std::slice::from_raw_parts(data, len) })
+    }
+}
+
+fn copy_to_raw<T: Copy>(source: &[T], target: *mut T, target_len: usize) -> Result<(), BmxStatus> {
+    if source.is_empty() {
+        Ok(())
+    } else if target_len < source.len() {
+        Err(BmxStatus::BufferTooSmall)
+    } else if target.is_null() {
+        Err(BmxStatus::NullPointer)
+    } else {
+        unsafe { target.copy_from_nonoverlapping(source.as_ptr(), source.len()) };
+        Ok(())
+    }
+}
+
+fn into_status(result: Result<(), BmxStatus>) -> BmxStatus {
+    match result {
+        Ok(()) => BmxStatus::Ok,
+        Err(status) => status,
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bmx_parse_header(
+    data: *const u8,
+    len: usize,
+    header: *mut BmxFileHeader,
+) -> BmxStatus {
+    into_status((|| {
+        if header.is_null() {
+            return Err(BmxStatus::NullPointer);
+        }
+
+        let data = unsafe { slice_from_raw(data, len)? };
+        let parsed = FileHeader::from_bytes(data.get(..FileHeader::SIZE).unwrap_or(data))?;
+
+        unsafe { header.write(BmxFileHeader::from(&parsed)) };
+        Ok(())
+    })())
+}
+
+// 0 for headers that don't parse.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bmx_pixel_data_len(header: *const BmxFileHeader) -> usize {
+    unsafe { header.as_ref() }
+        .and_then(|header| FileHeader::try_from(header).ok())
+        .map_or(0, |header| header.pixel_data_len())
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn bmx_decode(
+    data: *const u8,
+    len: usize,
+    header: *mut BmxFileHeader,
+    palette: *mut PaletteEntry,
+    palette_len: *mut usize,
+    pixels: *mut u8,
+    pixels_len: usize,
+) -> BmxStatus {
+    into_status((|| {
+        let image = BmxImage::from_bytes(unsafe { slice_from_raw(data, len)? })?;
+
+        if !palette_len.is_null() {
+            let available = unsafe { palette_len.replace(image.palette.len()) };
+            copy_to_raw(&image.palette, palette, available)?;
+        }
+
+        copy_to_raw(&image.data, pixels, pixels_len)?;
+
+        if !header.is_null() {
+            unsafe { header.write(BmxFileHeader::from(&image.header)) };
+        }
+
+        Ok(())
+    })())
+}
+
+#[unsafe(no_mangle)]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn bmx_encode(
+    width: u16,
+    height: u16,
+    bit_depth: u8,
+    palette: *const PaletteEntry,
+    palette_len: usize,
+    pixels: *const u8,
+    pixels_len: usize,
+    compress: bool,
+    output: *mut u8,
+    output_len: usize,
+    written: *mut usize,
+) -> BmxStatus {
+    into_status((|| {
+        let image = BmxImage::new(
+            width,
+            height,
+            bit_depth,
+            unsafe { slice_from_raw(palette, palette_len)? }.to_vec(),
+            unsafe { slice_from_raw(pixels, pixels_len)? }.to_vec(),
+        )?;
+
+        let bytes = image.to_bytes(compress)?;
+
+        if !written.is_null() {
+            unsafe { written.write(bytes.len()) };
+        }
+
+        copy_to_raw(&bytes, output, output_len)
+    })())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_exports() {
+        let palette = (0..4)
+            .map(|i| PaletteEntry::from_rgb(i * 0x40, 0, 0))
+            .collect::<Vec<_>>();
+        let pixels = [0b00_01_10_11, 0b11_10_01_00, 0b01_01_01_01];
+
+        let mut written = 0;
+        let status = unsafe {
+            bmx_encode(
+                4,
+                3,
+                2,
+                palette.as_ptr(),
+                palette.len(),
+                pixels.as_ptr(),
+                pixels.len(),
+                true,
+                std::ptr::null_mut(),
+                0,
+                &raw mut written,
+            )
+        };
+        assert_eq!(status, BmxStatus::BufferTooSmall);
+
+        let mut bytes = vec![0; written];
+        let status = unsafe {
+            bmx_encode(
+                4,
+                3,
+                2,
+                palette.as_ptr(),
+                palette.len(),
+                pixels.as_ptr(),
+                pixels.len(),
+                true,
+                bytes.as_mut_ptr(),
+                bytes.len(),
+                &raw mut written,
+            )
+        };
+        assert_eq!(status, BmxStatus::Ok);
+
+        let mut header = BmxFileHeader::default();
+        let status = unsafe { bmx_parse_header(bytes.as_ptr(), bytes.len(), &raw mut header) };
+        assert_eq!(status, BmxStatus::Ok);
+        assert_eq!((header.width, header.height, header.bit_depth), (4, 3, 2));
+        assert_eq!(header.compressed, 1);
+        assert_eq!(
+            unsafe { bmx_pixel_data_len(&raw const header) },
+            pixels.len()
+        );
+
+        let mut decoded_header = BmxFileHeader::default();
+        let mut decoded_palette = [PaletteEntry::default(); 256];
+        let mut palette_len = decoded_palette.len();
+        let mut decoded_pixels = [0; 3];
+        let status = unsafe {
+            bmx_decode(
+                bytes.as_ptr(),
+                bytes.len(),
+                &raw mut decoded_header,
+                decoded_palette.as_mut_ptr(),
+                &raw mut palette_len,
+                decoded_pixels.as_mut_ptr(),
+                decoded_pixels.len(),
+            )
+        };
+        assert_eq!(status, BmxStatus::Ok);
+        assert_eq!(decoded_header, header);
+        assert_eq!(&decoded_palette[..palette_len], palette.as_slice());
+        assert_eq!(decoded_pixels, pixels);
+    }
+
+    #[test]
+    fn rejects_headers_from_c() {
+        assert_eq!(unsafe { bmx_pixel_data_len(std::ptr::null()) }, 0);
+
+        // A zeroed header, as C code would start with, has no valid file ID.
+        let header = BmxFileHeader::default();
+        assert_eq!(unsafe { bmx_pixel_data_len(&raw const header) }, 0);
+
+        let status = unsafe { bmx_parse_header(std::ptr::null(), 0, std::ptr::null_mut()) };
+        assert_eq!(status, BmxStatus::NullPointer);
+    }
+}
@@ -0,0 +1,282 @@
+use std::io::{Read, Write};
+
+use image::{
+    error::{
+        DecodingError, EncodingError, ImageFormatHint, ParameterError, ParameterErrorKind,
+        UnsupportedError, UnsupportedErrorKind,
+    },
+    hooks::{register_decoding_hook, register_format_detection_hook},
+    ColorType, ExtendedColorType, ImageDecoder, ImageEncoder, ImageError, ImageResult,
+};
+
+use crate::bmx::{BmxImage, BmxImageError, FileHeader, PaletteEntry};
+
+const EXTENSION: &str = "bmx";
+
+fn format_hint() -> ImageFormatHint {
+    ImageFormatHint::Name("BMX".to_owned())
+}
+
+fn decoding_error(err: BmxImageError) -> ImageError {
+    ImageError::Decoding(DecodingError::new(format_hint(), err.to_string()))
+}
+
+fn encoding_error(err: BmxImageError) -> ImageError {
+    ImageError::Encoding(EncodingError::new(format_hint(), err.to_string()))
+}
+
+fn dimension_mismatch() -> ImageError {
+    ImageError::Parameter(ParameterError::from_kind(
+        ParameterErrorKind::DimensionMismatch,
+    ))
+}
+
+fn unsupported(message: &str) -> ImageError {
+    ImageError::Unsupported(UnsupportedError::from_format_and_kind(
+        format_hint(),
+        UnsupportedErrorKind::GenericFeature(message.to_owned()),
+    ))
+}
+
+pub fn register_hooks() -> bool {
+    register_format_detection_hook(
+        EXTENSION.into(),
+        &FileHeader::PATTERN,
+        Some(&FileHeader::PATTERN_MASK),
+    );
+
+    register_decoding_hook(
+        EXTENSION.into(),
+        Box::new(|reader| Ok(Box::new(BmxDecoder::new(reader)?) as Box<dyn ImageDecoder>)),
+    )
+}
+
+pub struct BmxDecoder {
+    image: BmxImage,
+}
+
+impl BmxDecoder {
+    pub fn new<R: Read>(mut reader: R) -> ImageResult<Self> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+
+        Ok(Self {
+            image: BmxImage::from_bytes(&bytes).map_err(decoding_error)?,
+        })
+    }
+
+    pub fn into_inner(self) -> BmxImage {
+        self.image
+    }
+}
+
+impl ImageDecoder for BmxDecoder {
+    fn dimensions(&self) -> (u32, u32) {
+        (self.image.header.width as _, self.image.header.height as _)
+    }
+
+    fn color_type(&self) -> ColorType {
+        ColorType::Rgb8
+    }
+
+    fn read_image(self, buf: &mut [u8]) -> ImageResult<()> {
+        let width = self.image.header.width as usize;
+
+        // Valid headers can describe an empty image, which has no rows to chunk the buffer into.
+        if width == 0 {
+            return Ok(());
+        }
+
+        for (row, target) in self.image.rows().zip(buf.chunks_exact_mut(width * 3)) {
+            for (index, pixel) in row.into_iter().zip(target.chunks_exact_mut(3)) {
+                let (r, g, b) = self
+                    .image
+                    .palette
+                    .get(index as usize)
+                    .map_or((0, 0, 0), PaletteEntry::to_rgb);
+
+                pixel.copy_from_slice(&[r, g, b]);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_image_boxed(self: Box<Self>, buf: &mut [u8]) -> ImageResult<()> {
+        (*self).read_image(buf)
+    }
+}
+
+pub struct BmxEncoder<W: Write> {
+    writer: W,
+    compress: bool,
+    version: u8,
+}
+
+impl<W: Write> BmxEncoder<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            compress: false,
+            version: FileHeader::VERSION,
+        }
+    }
+
+    pub fn new_compressed(writer: W) -> Self {
+        Self {
+            writer,
+            compress: true,
+            version: FileHeader::VERSION,
+        }
+    }
+
+    pub fn with_version(mut self, version: u8) -> Self {
+        self.version = version;
+        self
+    }
+}
+
+impl<W: Write> ImageEncoder for BmxEncoder<W> {
+    fn write_image(
+        mut self,
+        buf: &[u8],
+        width: u32,
+        height: u32,
+        color_type: ExtendedColorType,
+    ) -> ImageResult<()> {
+        let (Ok(width), Ok(height)) = (u16::try_from(width), u16::try_from(height)) else {
+            return Err(dimension_mismatch());
+        };
+
+        if width == 0 || height == 0 {
+            return Err(unsupported("Images without pixels can't be written"));
+        }
+
+        let to_entry: fn(&[u8]) -> PaletteEntry = match color_type {
+            ExtendedColorType::L8 | ExtendedColorType::La8 => {
+                |pixel| PaletteEntry::from_rgb(pixel[0], pixel[0], pixel[0])
+            }
+            ExtendedColorType::Rgb8 | ExtendedColorType::Rgba8 => {
+                |pixel| PaletteEntry::from_rgb(pixel[0], pixel[1], pixel[2])
+            }
+            _ => {
+                return Err(unsupported(
+                    "Only 8-bit gray and RGB(A) images are supported",
+                ))
+            }
+        };
+
+        let bytes_per_pixel = color_type.bits_per_pixel() as usize / 8;
+
+        if buf.len() != width as usize * height as usize * bytes_per_pixel {
+            return Err(dimension_mismatch());
+        }
+
+        let mut palette: Vec<PaletteEntry> = Vec::new();
+        let mut indices = Vec::with_capacity(width as usize * height as usize);
+
+        for pixel in buf.chunks_exact(bytes_per_pixel) {
+            let entry = to_entry(pixel);
+
+            let index = match palette.iter().position(|existing| *existing == entry) {
+                Some(index) => index,
+                None if palette.len() < 256 => {
+                    palette.push(entry);
+                    palette.len() - 1
+                }
+                None => return Err(unsupported("Image has more than 256 colors")),
+            };
+
+            indices.push(index as u8);
+        }
+
+        let bit_depth = match palette.len() {
+            0..=2 => 1,
+            3..=4 => 2,
+            5..=16 => 4,
+            _ => 8,
+        };
+
+        let pixels_per_byte = 8 / bit_depth as usize;
+        let mut data = Vec::new();
+
+        for row in indices.chunks_exact(width as usize) {
+            for pixels in row.chunks(pixels_per_byte) {
+                let mut byte = 0;
+
+                for (i, index) in pixels.iter().enumerate() {
+                    byte |= index << (8 - bit_depth * (i as u8 + 1));
+                }
+
+                data.push(byte);
+            }
+        }
+
+        let mut image =
+            BmxImage::new(width, height, bit_depth, palette, data).map_err(encoding_error)?;
+
+        image.header.version = self.version;
+        image
+            .header
+            .validate()
+            .map_err(|err| encoding_error(err.into()))?;
+
+        self.writer
+            .write_all(&image.to_bytes(self.compress).map_err(encoding_error)?)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(buf: &[u8], width: u32, height: u32) -> ImageResult<Vec<u8>> {
+        let mut bytes = Vec::new();
+        BmxEncoder::new(&mut bytes).write_image(buf, width, height, ExtendedColorType::L8)?;
+        Ok(bytes)
+    }
+
+    #[test]
+    fn round_trip() {
+        let gray = [0x00, 0x40, 0x80, 0xC0, 0xF0, 0x00];
+        let bytes = encode(&gray, 3, 2).unwrap();
+
+        let decoder = BmxDecoder::new(bytes.as_slice()).unwrap();
+        assert_eq!(decoder.dimensions(), (3, 2));
+
+        let mut rgb = vec![0; 18];
+        decoder.read_image(&mut rgb).unwrap();
+        assert!(rgb.chunks_exact(3).map(|pixel| pixel[0]).eq(gray));
+    }
+
+    #[test]
+    fn decodes_empty_images() {
+        let palette = vec![PaletteEntry::default()];
+
+        for (width, height) in [(0, 3), (3, 0)] {
+            let image = BmxImage::new(width, height, 1, palette.clone(), Vec::new()).unwrap();
+            let bytes = image.to_bytes(false).unwrap();
+
+            let decoder = BmxDecoder::new(bytes.as_slice()).unwrap();
+            assert_eq!(decoder.dimensions(), (width as u32, height as u32));
+            decoder.read_image(&mut []).unwrap();
+        }
+    }
+
+    #[test]
+    fn rejects_empty_and_mismatched_buffers() {
+        assert!(matches!(encode(&[], 0, 3), Err(ImageError::Unsupported(_))));
+        assert!(matches!(encode(&[], 3, 0), Err(ImageError::Unsupported(_))));
+
+        assert!(matches!(
+            encode(&[0; 5], 3, 2),
+            Err(ImageError::Parameter(_))
+        ));
+        assert!(matches!(
+            encode(&[0; 7], 3, 2),
+            Err(ImageError::Parameter(_))
+        ));
+    }
+}
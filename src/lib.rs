@@ -6,10 +6,18 @@
 
 pub mod bmx;
 pub mod com;
+pub mod crc32;
 pub mod export;
+pub mod ffi;
+#[cfg(feature = "image")]
+pub mod image_codec;
+pub mod lzsa;
 pub mod registry;
+pub mod settings;
 mod util;
 
+pub use util::guid;
+
 pub fn add(left: u64, right: u64) -> u64 {
     left + right
 }
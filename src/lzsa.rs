@@ -0,0 +1,484 @@
+use std::fmt::Display;
+
+// Raw LZSA2 blocks, as used for compressed BMX pixel data.
+
+const MIN_MATCH_LENGTH: usize = 2;
+const MAX_LENGTH: usize = u16::MAX as usize;
+const MAX_OFFSET: usize = u16::MAX as usize;
+
+const LITERALS_RUN_LENGTH: usize = 3;
+const MATCH_RUN_LENGTH: usize = 7;
+
+const END_OF_DATA: u8 = 232;
+const MATCH_LENGTH_16_BIT: u8 = 233;
+const LITERALS_LENGTH_16_BIT: u8 = 239;
+
+const HASH_BITS: u32 = 16;
+const MAX_CHAIN_LENGTH: usize = 64;
+
+#[derive(Clone, Copy, Debug)]
+pub enum LzsaError {
+    UnexpectedEndOfInput,
+    InvalidOffset,
+    OutputTooLarge,
+}
+
+impl Display for LzsaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            LzsaError::UnexpectedEndOfInput => write!(f, "Unexpected end of compressed data"),
+            LzsaError::InvalidOffset => write!(f, "Match offset points before start of data"),
+            LzsaError::OutputTooLarge => write!(f, "Decompressed data exceeds expected size"),
+        }
+    }
+}
+
+impl std::error::Error for LzsaError {}
+
+struct Reader<'a> {
+    input: &'a [u8],
+    position: usize,
+    nibble: Option<u8>,
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Result<u8, LzsaError> {
+        let byte = *self
+            .input
+            .get(self.position)
+            .ok_or(LzsaError::UnexpectedEndOfInput)?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn nibble(&mut self) -> Result<u8, LzsaError> {
+        match self.nibble.take() {
+            Some(nibble) => Ok(nibble),
+            None => {
+                let byte = self.byte()?;
+                self.nibble = Some(byte & 0x0F);
+                Ok(byte >> 4)
+            }
+        }
+    }
+
+    fn u16(&mut self) -> Result<u16, LzsaError> {
+        Ok(u16::from_le_bytes([self.byte()?, self.byte()?]))
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&[u8], LzsaError> {
+        let bytes = self
+            .input
+            .get(self.position..self.position + count)
+            .ok_or(LzsaError::UnexpectedEndOfInput)?;
+        self.position += count;
+        Ok(bytes)
+    }
+}
+
+pub fn decompress(input: &[u8], max_output_len: usize) -> Result<Vec<u8>, LzsaError> {
+    let mut output = Vec::with_capacity(max_output_len);
+    decompress_into(input, max_output_len, &mut output)?;
+    Ok(output)
+}
+
+// Like decompress, but input that ends early yields what was decoded up to there.
+pub fn decompress_truncated(input: &[u8], max_output_len: usize) -> Result<Vec<u8>, LzsaError> {
+    let mut output = Vec::with_capacity(max_output_len);
+
+    match decompress_into(input, max_output_len, &mut output) {
+        Ok(()) | Err(LzsaError::UnexpectedEndOfInput) => Ok(output),
+        Err(err) => Err(err),
+    }
+}
+
+fn decompress_into(
+    input: &[u8],
+    max_output_len: usize,
+    output: &mut Vec<u8>,
+) -> Result<(), LzsaError> {
+    let mut reader = Reader {
+        input,
+        position: 0,
+        nibble: None,
+    };
+
+    let mut offset = 0usize;
+
+    loop {
+        let token = reader.byte()?;
+
+        let mut literals = ((token >> 3) & 0x03) as usize;
+        if literals == LITERALS_RUN_LENGTH {
+            literals += reader.nibble()? as usize;
+
+            if literals == LITERALS_RUN_LENGTH + 15 {
+                literals = match reader.byte()? {
+                    LITERALS_LENGTH_16_BIT => reader.u16()? as usize,
+                    byte => LITERALS_RUN_LENGTH + 15 + byte as usize,
+                };
+            }
+        }
+
+        if output.len() + literals > max_output_len {
+            return Err(LzsaError::OutputTooLarge);
+        }
+
+        output.extend_from_slice(reader.bytes(literals)?);
+
+        let z = ((token >> 5) & 1) ^ 1;
+        offset = match token >> 6 {
+            0b00 => 0xFFE0 | ((reader.nibble()? as usize) << 1) | z as usize,
+            0b01 => 0xFE00 | ((z as usize) << 8) | reader.byte()? as usize,
+            0b10 => {
+                let high = ((reader.nibble()? as usize) << 9) | ((z as usize) << 8);
+                (0xE000 | high | reader.byte()? as usize).wrapping_sub(512) & 0xFFFF
+            }
+            _ if token & 0x20 == 0 => ((reader.byte()? as usize) << 8) | reader.byte()? as usize,
+            _ => offset,
+        };
+
+        let mut length = (token & 0x07) as usize;
+        if length == MATCH_RUN_LENGTH {
+            length += reader.nibble()? as usize;
+
+            if length == MATCH_RUN_LENGTH + 15 {
+                length = match reader.byte()? {
+                    END_OF_DATA => return Ok(()),
+                    MATCH_LENGTH_16_BIT => reader.u16()? as usize,
+                    byte => MATCH_RUN_LENGTH + 15 + MIN_MATCH_LENGTH + byte as usize,
+                };
+            } else {
+                length += MIN_MATCH_LENGTH;
+            }
+        } else {
+            length += MIN_MATCH_LENGTH;
+        }
+
+        let distance = 0x10000 - offset;
+        if distance == 0x10000 || distance > output.len() {
+            return Err(LzsaError::InvalidOffset);
+        }
+
+        if output.len() + length > max_output_len {
+            return Err(LzsaError::OutputTooLarge);
+        }
+
+        let start = output.len() - distance;
+        for i in 0..length {
+            output.push(output[start + i]);
+        }
+    }
+}
+
+struct Writer {
+    output: Vec<u8>,
+    nibble_position: Option<usize>,
+}
+
+impl Writer {
+    fn byte(&mut self, byte: u8) {
+        self.output.push(byte);
+    }
+
+    fn nibble(&mut self, nibble: u8) {
+        match self.nibble_position.take() {
+            Some(position) => self.output[position] |= nibble & 0x0F,
+            None => {
+                self.nibble_position = Some(self.output.len());
+                self.output.push(nibble << 4);
+            }
+        }
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.output.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn literals(&mut self, literals: &[u8]) {
+        if literals.len() >= LITERALS_RUN_LENGTH {
+            let extra = literals.len() - LITERALS_RUN_LENGTH;
+
+            if extra < 15 {
+                self.nibble(extra as u8);
+            } else {
+                self.nibble(15);
+
+                if literals.len() - (LITERALS_RUN_LENGTH + 15) < LITERALS_LENGTH_16_BIT as usize {
+                    self.byte((literals.len() - (LITERALS_RUN_LENGTH + 15)) as u8);
+                } else {
+                    self.byte(LITERALS_LENGTH_16_BIT);
+                    self.u16(literals.len() as u16);
+                }
+            }
+        }
+
+        self.output.extend_from_slice(literals);
+    }
+
+    // Tokens always end in a match, so literal runs longer than one token can carry are split by
+    // matches of length 0, which only the 16-bit match length can express. Returns the rest of the
+    // run for the next token.
+    fn long_literals<'a>(&mut self, mut literals: &'a [u8]) -> &'a [u8] {
+        while literals.len() > MAX_LENGTH {
+            let (run, rest) = literals.split_at(MAX_LENGTH);
+
+            // A 5-bit offset for a distance of 1.
+            self.byte(literals_bits(run.len()) | MATCH_RUN_LENGTH as u8);
+            self.literals(run);
+            self.nibble(0x0F);
+
+            self.nibble(15);
+            self.byte(MATCH_LENGTH_16_BIT);
+            self.u16(0);
+
+            literals = rest;
+        }
+
+        literals
+    }
+
+    fn match_length(&mut self, length: usize) {
+        let encoded = length - MIN_MATCH_LENGTH;
+
+        if encoded >= MATCH_RUN_LENGTH {
+            let extra = encoded - MATCH_RUN_LENGTH;
+
+            if extra < 15 {
+                self.nibble(extra as u8);
+            } else {
+                self.nibble(15);
+
+                let extra = encoded - (MATCH_RUN_LENGTH + 15);
+                if extra < END_OF_DATA as usize {
+                    self.byte(extra as u8);
+                } else {
+                    self.byte(MATCH_LENGTH_16_BIT);
+                    self.u16(length as u16);
+                }
+            }
+        }
+    }
+}
+
+const fn literals_bits(count: usize) -> u8 {
+    (if count >= LITERALS_RUN_LENGTH {
+        LITERALS_RUN_LENGTH
+    } else {
+        count
+    } as u8)
+        << 3
+}
+
+const fn match_bits(length: usize) -> u8 {
+    let encoded = length - MIN_MATCH_LENGTH;
+
+    if encoded >= MATCH_RUN_LENGTH {
+        MATCH_RUN_LENGTH as u8
+    } else {
+        encoded as u8
+    }
+}
+
+fn hash(data: &[u8], position: usize) -> usize {
+    (u16::from_le_bytes([data[position], data[position + 1]]) as u32).wrapping_mul(0x9E37) as usize
+        & ((1 << HASH_BITS) - 1)
+}
+
+fn find_match(
+    data: &[u8],
+    position: usize,
+    head: &[usize],
+    chain: &[usize],
+) -> Option<(usize, usize)> {
+    if position + MIN_MATCH_LENGTH > data.len() {
+        return None;
+    }
+
+    let max_length = (data.len() - position).min(MAX_LENGTH);
+
+    let mut best: Option<(usize, usize)> = None;
+    let mut candidate = head[hash(data, position)];
+
+    for _ in 0..MAX_CHAIN_LENGTH {
+        if candidate == usize::MAX || position - candidate > MAX_OFFSET {
+            break;
+        }
+
+        let length = data[candidate..]
+            .iter()
+            .zip(&data[position..position + max_length])
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        if length >= MIN_MATCH_LENGTH && best.is_none_or(|(_, best_length)| length > best_length) {
+            best = Some((position - candidate, length));
+
+            if length == max_length {
+                break;
+            }
+        }
+
+        candidate = chain[candidate];
+    }
+
+    best
+}
+
+pub fn compress(input: &[u8]) -> Result<Vec<u8>, LzsaError> {
+    let mut writer = Writer {
+        output: Vec::with_capacity(input.len() / 2),
+        nibble_position: None,
+    };
+
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut chain = vec![usize::MAX; input.len()];
+
+    let insert = |position: usize, head: &mut [usize], chain: &mut [usize]| {
+        if position + MIN_MATCH_LENGTH <= input.len() {
+            let hash = hash(input, position);
+            chain[position] = head[hash];
+            head[hash] = position;
+        }
+    };
+
+    let mut literals_start = 0;
+    let mut position = 0;
+    let mut previous_offset = None;
+
+    while position < input.len() {
+        let Some((distance, length)) = find_match(input, position, &head, &chain) else {
+            insert(position, &mut head, &mut chain);
+            position += 1;
+            continue;
+        };
+
+        let mut literals = &input[literals_start..position];
+        if literals.len() > MAX_LENGTH {
+            literals = writer.long_literals(literals);
+            previous_offset = Some(1);
+        }
+
+        let offset = (0x10000 - distance) as u16;
+
+        let mut token = literals_bits(literals.len()) | match_bits(length);
+        token |= if previous_offset == Some(distance) {
+            0xE0
+        } else if distance <= 32 {
+            (((offset & 1) as u8) ^ 1) << 5
+        } else if distance <= 512 {
+            0x40 | ((((offset >> 8) & 1) as u8) ^ 1) << 5
+        } else if distance <= 8704 {
+            0x80 | ((((offset.wrapping_add(512) >> 8) & 1) as u8) ^ 1) << 5
+        } else {
+            0xC0
+        };
+
+        writer.byte(token);
+        writer.literals(literals);
+
+        match token >> 5 {
+            0b000 | 0b001 => writer.nibble(((offset >> 1) & 0x0F) as u8),
+            0b010 | 0b011 => writer.byte(offset as u8),
+            0b100 | 0b101 => {
+                let offset = offset.wrapping_add(512);
+                writer.nibble(((offset >> 9) & 0x0F) as u8);
+                writer.byte(offset as u8);
+            }
+            0b110 => {
+                writer.byte((offset >> 8) as u8);
+                writer.byte(offset as u8);
+            }
+            _ => {}
+        }
+
+        writer.match_length(length);
+
+        for i in position..position + length {
+            insert(i, &mut head, &mut chain);
+        }
+
+        position += length;
+        literals_start = position;
+        previous_offset = Some(distance);
+    }
+
+    let literals = writer.long_literals(&input[literals_start..]);
+
+    writer.byte(0xE0 | literals_bits(literals.len()) | MATCH_RUN_LENGTH as u8);
+    writer.literals(literals);
+    writer.nibble(15);
+    writer.byte(END_OF_DATA);
+
+    Ok(writer.output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(data: &[u8]) {
+        let compressed = compress(data).unwrap();
+        assert_eq!(decompress(&compressed, data.len()).unwrap(), data);
+    }
+
+    #[test]
+    fn keeps_output_of_truncated_input() {
+        let data = (0..1000u32).map(|i| (i / 7) as u8).collect::<Vec<_>>();
+        let compressed = compress(&data).unwrap();
+        let truncated = &compressed[..compressed.len() / 2];
+
+        assert!(matches!(
+            decompress(truncated, data.len()),
+            Err(LzsaError::UnexpectedEndOfInput)
+        ));
+
+        let prefix = decompress_truncated(truncated, data.len()).unwrap();
+        assert!(!prefix.is_empty() && prefix.len() < data.len());
+        assert_eq!(prefix, data[..prefix.len()]);
+    }
+
+    #[test]
+    fn round_trips_random_data() {
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        let data: Vec<u8> = (0..200_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state >> 32) as u8
+            })
+            .collect();
+
+        round_trip(&data);
+    }
+
+    #[test]
+    fn splits_literal_runs_longer_than_a_token() {
+        // A de Bruijn sequence over bytes: no pair of bytes repeats, so nothing can be matched.
+        fn extend(data: &mut Vec<u8>, a: &mut [usize; 3], t: usize, p: usize) {
+            if t > 2 {
+                if 2 % p == 0 {
+                    data.extend(a[1..=p].iter().map(|&byte| byte as u8));
+                }
+            } else {
+                a[t] = a[t - p];
+                extend(data, a, t + 1, p);
+                for byte in a[t - p] + 1..=u8::MAX as usize {
+                    a[t] = byte;
+                    extend(data, a, t + 1, t);
+                }
+            }
+        }
+
+        let mut data = Vec::with_capacity(0x10000 + 1000);
+        extend(&mut data, &mut [0; 3], 1, 1);
+        assert_eq!(data.len(), 0x10000);
+
+        round_trip(&data);
+
+        // Followed by a match rather than the end of the data.
+        data.extend_from_within(..1000);
+        round_trip(&data);
+    }
+}
@@ -1,65 +1,170 @@
-use std::ops::Deref;
+use std::{
+    ffi::c_void,
+    ops::Deref,
+    path::{Path, PathBuf},
+};
 
-use transaction::{Key, Transaction};
+use transaction::{Key, Transaction, View};
+use windows::core::{Owned, PWSTR};
 use windows::Win32::{
-    Foundation::E_BLUETOOTH_ATT_ATTRIBUTE_NOT_FOUND,
+    Foundation::{
+        ERROR_NOT_SUPPORTED, E_BLUETOOTH_ATT_ATTRIBUTE_NOT_FOUND, GENERIC_EXECUTE, GENERIC_READ,
+        HLOCAL,
+    },
     Graphics::Imaging::{
-        CATID_WICBitmapDecoders, CATID_WICBitmapEncoders, GUID_WICPixelFormat1bppIndexed,
-        GUID_WICPixelFormat2bppIndexed, GUID_WICPixelFormat4bppIndexed,
-        GUID_WICPixelFormat8bppIndexed,
+        CATID_WICBitmapDecoders, CATID_WICBitmapEncoders, CATID_WICMetadataReader,
+        CATID_WICMetadataWriter,
+    },
+    Security::{
+        Authorization::{
+            ConvertStringSidToSidW, GetEffectiveRightsFromAclW, GetNamedSecurityInfoW,
+            SetEntriesInAclW, SetNamedSecurityInfoW, ACCESS_MODE, EXPLICIT_ACCESS_W, GRANT_ACCESS,
+            REVOKE_ACCESS, SE_FILE_OBJECT, TRUSTEE_IS_SID, TRUSTEE_IS_WELL_KNOWN_GROUP, TRUSTEE_W,
+        },
+        DACL_SECURITY_INFORMATION, NO_INHERITANCE, PSECURITY_DESCRIPTOR, PSID,
+    },
+    Storage::{
+        FileSystem::{FILE_GENERIC_EXECUTE, FILE_GENERIC_READ},
+        IndexServer::IFilter,
+    },
+    System::{
+        Registry::{
+            RegGetValueW, HKEY_CLASSES_ROOT, HKEY_LOCAL_MACHINE, RRF_RT_REG_DWORD, RRF_RT_REG_SZ,
+        },
+        SystemInformation::{
+            IMAGE_FILE_MACHINE, IMAGE_FILE_MACHINE_AMD64, IMAGE_FILE_MACHINE_ARM64,
+            IMAGE_FILE_MACHINE_I386,
+        },
+        Threading::{GetCurrentProcess, IsWow64Process2},
+    },
+    UI::Shell::{
+        IExtractImage, IThumbnailProvider,
+        PropertiesSystem::{PSRegisterPropertySchema, PSUnregisterPropertySchema},
+        SHChangeNotify, SHCNE_ASSOCCHANGED, SHCNE_UPDATEIMAGE, SHCNF_DWORD, SHCNF_FLAGS,
     },
-    System::Registry::HKEY_LOCAL_MACHINE,
-    UI::Shell::{IThumbnailProvider, SHChangeNotify, SHCNE_ASSOCCHANGED, SHCNF_FLAGS},
 };
-use windows_core::{w, Interface, PCWSTR};
+use windows_core::{w, Interface, GUID, HSTRING, PCWSTR};
 
 use crate::{
+    bmx::FileHeader,
     com::{
-        shell::{command::transcode::Transcode, property_store::PropertyStore},
+        inventory::CLASSES,
+        shell::{
+            command::{
+                paste_as_bmx::PasteAsBmx, send_to_emulator::SendToEmulator, transcode::Transcode,
+                vera_preview::VeraPreview, ExplorerCommandClass,
+            },
+            data_handler::DataHandler,
+            filter::Filter,
+            property_store::{PropertyStore, SCHEMA, SCHEMA_FILE_NAME},
+            thumbnail_provider::{ThumbnailProvider, TREATMENT, TREATMENT_NONE, TYPE_OVERLAY},
+        },
         wic::{
-            com::{CONTAINER_FORMAT, EXTENSION, MIME_TYPE, PREVIEW_DETAILS, PROG_ID, VENDOR},
+            com::{
+                APPLICATION_DESCRIPTION, APPLICATION_NAME, ARBITRATION_PRIORITY, AUTHOR,
+                BITS_PER_PIXEL, CAPABILITIES, COLOR_MANAGEMENT_VERSION, CONTAINER_FORMAT,
+                EXTENSION, FULL_DETAILS, INFO_TIP, MIME_TYPE, PHOTOS_PROG_ID,
+                PHOTO_VIEWER_FILE_ASSOCIATIONS, PHOTO_VIEWER_PROG_ID, PIXEL_FORMATS,
+                PREVIEW_DETAILS, PROG_ID, RAW_CONTAINER_FORMAT, RAW_EXTENSION,
+                RESERVED_METADATA_FORMAT, SPEC_VERSION, SUPPORTS_ANIMATION, SUPPORTS_CHROMAKEY,
+                SUPPORTS_LOSSLESS, SUPPORTS_MULTIFRAME, VENDOR, VERSION,
+            },
             decoder::BitmapDecoder,
             encoder::BitmapEncoder,
+            raw::RawDecoder,
+            reserved::{ReservedMetadataReader, ReservedMetadataWriter},
         },
-        CoClass,
+        CoClass, IoErrorExt,
+    },
+    util::{
+        guid::{self, Guid, GuidExt},
+        is_low_privilege_process, wstr,
     },
-    util::guid::GuidExt,
 };
 
 pub mod transaction {
-    use std::cell::Cell;
+    use std::cell::{Cell, RefCell};
 
     use crate::util::guid::GuidExt;
 
     use windows::{
-        core::{w, Owned, GUID, PCWSTR},
+        core::{w, Owned, GUID, HSTRING, PCWSTR, PWSTR},
         Win32::{
             Foundation::{
-                ERROR_FILE_NOT_FOUND, ERROR_SUCCESS, E_ILLEGAL_STATE_CHANGE, HANDLE, WIN32_ERROR,
+                ERROR_DATATYPE_MISMATCH, ERROR_FILE_NOT_FOUND, ERROR_INVALID_DATA,
+                ERROR_NO_MORE_ITEMS, ERROR_SUCCESS, E_ILLEGAL_STATE_CHANGE, E_INVALIDARG, HANDLE,
+                WIN32_ERROR,
             },
             Storage::FileSystem::{CommitTransaction, CreateTransaction, RollbackTransaction},
             System::{
+                Diagnostics::Debug::OutputDebugStringW,
                 Registry::{
-                    RegCreateKeyTransactedW, RegDeleteTreeW, RegDeleteValueW,
-                    RegOpenKeyTransactedW, HKEY, KEY_READ, KEY_WRITE, REG_BINARY, REG_DWORD,
+                    RegCreateKeyTransactedW, RegDeleteTreeW, RegDeleteValueW, RegEnumKeyExW,
+                    RegEnumValueW, RegOpenKeyTransactedW, RegQueryValueExW, HKEY,
+                    HKEY_CLASSES_ROOT, HKEY_CURRENT_USER, HKEY_LOCAL_MACHINE, HKEY_USERS, KEY_READ,
+                    KEY_WOW64_32KEY, KEY_WOW64_64KEY, KEY_WRITE, REG_BINARY, REG_DWORD,
                     REG_EXPAND_SZ, REG_OPEN_CREATE_OPTIONS, REG_OPTION_NON_VOLATILE,
-                    REG_OPTION_VOLATILE, REG_QWORD, REG_SZ, REG_VALUE_TYPE,
+                    REG_OPTION_VOLATILE, REG_QWORD, REG_SAM_FLAGS, REG_SZ, REG_VALUE_TYPE,
                 },
                 Threading::INFINITE,
             },
         },
     };
 
+    #[derive(Clone, Debug)]
+    pub enum Operation {
+        CreateKey {
+            path: String,
+        },
+        SetValue {
+            path: String,
+            name: Option<String>,
+            value_type: REG_VALUE_TYPE,
+            data: Vec<u8>,
+        },
+        DeleteKey {
+            path: String,
+        },
+        DeleteValue {
+            path: String,
+            name: Option<String>,
+        },
+        // Something registration noticed, e.g. a conflicting codec, but didn't write.
+        Note {
+            text: String,
+        },
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum View {
+        Native,
+        Registry32,
+        Registry64,
+    }
+
+    impl View {
+        const fn sam_flags(self) -> REG_SAM_FLAGS {
+            match self {
+                View::Native => REG_SAM_FLAGS(0),
+                View::Registry32 => KEY_WOW64_32KEY,
+                View::Registry64 => KEY_WOW64_64KEY,
+            }
+        }
+    }
+
     pub struct Transaction {
-        handle: Owned<HANDLE>,
+        handle: Option<Owned<HANDLE>>,
         key_options: REG_OPEN_CREATE_OPTIONS,
         committed: Cell<bool>,
+        log: Option<RefCell<Vec<Operation>>>,
+        // Predefined keys are redirected below HKEY_CURRENT_USER\<test_hive> when set.
+        test_hive: Option<String>,
     }
 
     impl Transaction {
         pub fn new(volatile: bool) -> windows::core::Result<Self> {
             Ok(Self {
-                handle: unsafe {
+                handle: Some(unsafe {
                     Owned::new(CreateTransaction(
                         std::ptr::null_mut(),
                         std::ptr::null_mut(),
@@ -69,7 +174,7 @@ pub mod transaction {
                         INFINITE,
                         w!("bmx-shell"),
                     )?)
-                },
+                }),
                 key_options: if volatile {
                     REG_OPTION_VOLATILE
                 } else {
@@ -77,16 +182,64 @@ pub mod transaction {
                 },
 
                 committed: Cell::new(false),
+                log: None,
+                test_hive: None,
             })
         }
 
+        pub fn dry_run() -> Self {
+            Self {
+                handle: None,
+                key_options: REG_OPTION_NON_VOLATILE,
+                committed: Cell::new(false),
+                log: Some(RefCell::new(Vec::new())),
+                test_hive: None,
+            }
+        }
+
+        pub fn with_test_hive(mut self, root: &str) -> Self {
+            self.test_hive = Some(root.to_owned());
+            self
+        }
+
+        pub fn is_dry_run(&self) -> bool {
+            self.handle.is_none()
+        }
+
+        pub fn is_test_hive(&self) -> bool {
+            self.test_hive.is_some()
+        }
+
+        pub fn operations(&self) -> Vec<Operation> {
+            self.log
+                .as_ref()
+                .map_or_else(Vec::new, |log| log.borrow().clone())
+        }
+
+        fn record(&self, operation: Operation) {
+            if let Some(ref log) = self.log {
+                log.borrow_mut().push(operation);
+            }
+        }
+
+        // Shows up as a comment in dry runs and in the debugger otherwise.
+        pub fn note(&self, text: &str) {
+            let message = HSTRING::from(format!("bmx-shell: {}\n", text));
+            unsafe { OutputDebugStringW(PCWSTR::from_raw(message.as_ptr())) };
+            self.record(Operation::Note {
+                text: text.to_owned(),
+            });
+        }
+
         pub fn commit(&self) -> windows::core::Result<()> {
             if self.committed.get() {
                 return Err(E_ILLEGAL_STATE_CHANGE.into());
             }
 
-            unsafe {
-                CommitTransaction(*self.handle)?;
+            if let Some(ref handle) = self.handle {
+                unsafe {
+                    CommitTransaction(**handle)?;
+                }
             }
 
             self.committed.replace(true);
@@ -97,8 +250,10 @@ pub mod transaction {
     impl Drop for Transaction {
         fn drop(&mut self) {
             if !self.committed.get() {
-                unsafe {
-                    let _ = RollbackTransaction(*self.handle);
+                if let Some(ref handle) = self.handle {
+                    unsafe {
+                        let _ = RollbackTransaction(**handle);
+                    }
                 }
             }
         }
@@ -108,6 +263,7 @@ pub mod transaction {
         key: HKEY,
         sub_key: PCWSTR,
         options: REG_OPEN_CREATE_OPTIONS,
+        view: View,
         transaction: HANDLE,
     ) -> windows::core::Result<HKEY> {
         let mut result = HKEY::default();
@@ -119,7 +275,7 @@ pub mod transaction {
                 0,
                 None,
                 options,
-                KEY_READ | KEY_WRITE,
+                KEY_READ | KEY_WRITE | view.sam_flags(),
                 None,
                 &raw mut result,
                 None,
@@ -132,10 +288,10 @@ pub mod transaction {
         Ok(result)
     }
 
-    #[allow(unused)]
     unsafe fn open_key_transacted(
         key: HKEY,
         sub_key: PCWSTR,
+        view: View,
         transaction: HANDLE,
     ) -> windows::core::Result<HKEY> {
         let mut result = HKEY::default();
@@ -145,7 +301,7 @@ pub mod transaction {
                 key,
                 sub_key,
                 0,
-                KEY_READ | KEY_WRITE,
+                KEY_READ | KEY_WRITE | view.sam_flags(),
                 &raw mut result,
                 transaction,
                 None,
@@ -156,9 +312,53 @@ pub mod transaction {
         Ok(result)
     }
 
+    fn predefined_key_name(key: HKEY) -> windows::core::Result<&'static str> {
+        if key == HKEY_CLASSES_ROOT {
+            Ok("HKEY_CLASSES_ROOT")
+        } else if key == HKEY_CURRENT_USER {
+            Ok("HKEY_CURRENT_USER")
+        } else if key == HKEY_LOCAL_MACHINE {
+            Ok("HKEY_LOCAL_MACHINE")
+        } else if key == HKEY_USERS {
+            Ok("HKEY_USERS")
+        } else {
+            Err(windows::core::Error::new(
+                E_INVALIDARG,
+                "Not a predefined registry key",
+            ))
+        }
+    }
+
+    fn predefined_key_path(key: HKEY, view: View) -> windows::core::Result<String> {
+        let name = predefined_key_name(key)?;
+
+        if view == View::Registry32 && key == HKEY_CLASSES_ROOT {
+            Ok(format!("{}\\WOW6432Node", name))
+        } else {
+            Ok(name.to_owned())
+        }
+    }
+
+    fn pcwstr_to_string(value: PCWSTR) -> Option<String> {
+        if value.is_null() {
+            None
+        } else {
+            Some(String::from_utf16_lossy(unsafe { value.as_wide() }))
+        }
+    }
+
+    fn join_path(path: &str, sub_key: PCWSTR) -> String {
+        match pcwstr_to_string(sub_key) {
+            Some(sub_key) if !sub_key.is_empty() => format!("{}\\{}", path, sub_key),
+            _ => path.to_owned(),
+        }
+    }
+
     pub struct Key<'a> {
         transaction: &'a Transaction,
-        key: Owned<HKEY>,
+        key: Option<Owned<HKEY>>,
+        view: View,
+        path: String,
     }
 
     impl<'a> Key<'a> {
@@ -167,63 +367,97 @@ pub mod transaction {
             key: HKEY,
             sub_key: PCWSTR,
         ) -> windows::core::Result<Self> {
-            let mut result = HKEY::default();
+            Self::predefined_in_view(transaction, key, sub_key, View::Native)
+        }
 
-            unsafe {
-                RegCreateKeyTransactedW(
-                    key,
+        pub fn predefined_in_view(
+            transaction: &'a Transaction,
+            key: HKEY,
+            sub_key: PCWSTR,
+            view: View,
+        ) -> windows::core::Result<Self> {
+            let redirected = match transaction.test_hive {
+                Some(ref hive) => Some(HSTRING::from(join_path(
+                    &format!("{}\\{}", hive, predefined_key_path(key, view)?),
                     sub_key,
-                    0,
-                    None,
-                    transaction.key_options,
-                    KEY_READ | KEY_WRITE,
-                    None,
-                    &raw mut result,
-                    None,
-                    *transaction.handle,
-                    None,
-                )
-                .ok()?;
-            }
+                ))),
+                None => None,
+            };
+
+            let (key, sub_key) = match redirected {
+                Some(ref redirected) => (HKEY_CURRENT_USER, PCWSTR::from_raw(redirected.as_ptr())),
+                None => (key, sub_key),
+            };
+
+            let path = join_path(&predefined_key_path(key, view)?, sub_key);
+            transaction.record(Operation::CreateKey { path: path.clone() });
 
             Ok(Self {
                 transaction,
-                key: unsafe {
-                    Owned::new(reg_create_key_transacted(
-                        key,
-                        sub_key,
-                        transaction.key_options,
-                        *transaction.handle,
-                    )?)
+                key: match transaction.handle {
+                    Some(ref handle) => Some(unsafe {
+                        Owned::new(reg_create_key_transacted(
+                            key,
+                            sub_key,
+                            transaction.key_options,
+                            view,
+                            **handle,
+                        )?)
+                    }),
+                    None => None,
                 },
+                view,
+                path,
             })
         }
 
+        pub fn view(&self) -> View {
+            self.view
+        }
+
+        pub fn path(&self) -> &str {
+            &self.path
+        }
+
+        pub fn note(&self, text: &str) {
+            self.transaction.note(text);
+        }
+
         pub fn create_subkey(&self, sub_key: PCWSTR) -> windows::core::Result<Key<'a>> {
+            let path = join_path(&self.path, sub_key);
+            self.transaction
+                .record(Operation::CreateKey { path: path.clone() });
+
             Ok(Self {
                 transaction: self.transaction,
-                key: unsafe {
-                    Owned::new(reg_create_key_transacted(
-                        *self.key,
-                        sub_key,
-                        self.transaction.key_options,
-                        *self.transaction.handle,
-                    )?)
+                key: match (self.key.as_ref(), self.transaction.handle.as_ref()) {
+                    (Some(key), Some(handle)) => Some(unsafe {
+                        Owned::new(reg_create_key_transacted(
+                            **key,
+                            sub_key,
+                            self.transaction.key_options,
+                            self.view,
+                            **handle,
+                        )?)
+                    }),
+                    _ => None,
                 },
+                view: self.view,
+                path,
             })
         }
 
-        #[allow(unused)]
         pub fn open_subkey(&self, sub_key: PCWSTR) -> windows::core::Result<Key<'a>> {
             Ok(Self {
                 transaction: self.transaction,
-                key: unsafe {
-                    Owned::new(open_key_transacted(
-                        *self.key,
-                        sub_key,
-                        *self.transaction.handle,
-                    )?)
+                key: match (self.key.as_ref(), self.transaction.handle.as_ref()) {
+                    (Some(key), Some(handle)) => Some(unsafe {
+                        Owned::new(open_key_transacted(**key, sub_key, self.view, **handle)?)
+                    }),
+                    _ => None,
                 },
+                view: self.view,
+                path: join_path(&self.path, sub_key),
             })
         }
 
@@ -236,12 +470,196 @@ pub mod transaction {
         }
 
         fn delete_tree_internal(&self, subkey: PCWSTR) -> windows::core::Result<()> {
-            match unsafe { RegDeleteTreeW(*self.key, subkey) } {
+            self.transaction.record(Operation::DeleteKey {
+                path: join_path(&self.path, subkey),
+            });
+
+            let Some(ref key) = self.key else {
+                return Ok(());
+            };
+
+            match unsafe { RegDeleteTreeW(**key, subkey) } {
                 ERROR_SUCCESS | ERROR_FILE_NOT_FOUND => Ok(()),
                 e => e.ok(),
             }
         }
 
+        pub fn try_open_subkey(&self, sub_key: PCWSTR) -> windows::core::Result<Option<Key<'a>>> {
+            match self.open_subkey(sub_key) {
+                Ok(key) => Ok(Some(key)),
+                Err(err) if err.code() == ERROR_FILE_NOT_FOUND.to_hresult() => Ok(None),
+                Err(err) => Err(err),
+            }
+        }
+
+        pub fn subkey_names(&self) -> windows::core::Result<Vec<String>> {
+            let Some(ref key) = self.key else {
+                return Ok(Vec::new());
+            };
+
+            let mut names = Vec::new();
+            let mut buffer = [0u16; 256];
+
+            for index in 0.. {
+                let mut length = buffer.len() as u32;
+
+                match unsafe {
+                    RegEnumKeyExW(
+                        **key,
+                        index,
+                        PWSTR::from_raw(buffer.as_mut_ptr()),
+                        &raw mut length,
+                        None,
+                        PWSTR::null(),
+                        None,
+                        None,
+                    )
+                } {
+                    ERROR_SUCCESS => {
+                        names.push(String::from_utf16_lossy(&buffer[..length as usize]))
+                    }
+                    ERROR_NO_MORE_ITEMS => break,
+                    e => e.ok()?,
+                }
+            }
+
+            Ok(names)
+        }
+
+        fn query_value(
+            &self,
+            name: PCWSTR,
+        ) -> windows::core::Result<Option<(REG_VALUE_TYPE, Vec<u8>)>> {
+            let Some(ref key) = self.key else {
+                return Ok(None);
+            };
+
+            let mut value_type = REG_VALUE_TYPE::default();
+            let mut size = 0;
+
+            match unsafe {
+                RegQueryValueExW(
+                    **key,
+                    name,
+                    None,
+                    Some(&raw mut value_type),
+                    None,
+                    Some(&raw mut size),
+                )
+            } {
+                ERROR_SUCCESS => {}
+                ERROR_FILE_NOT_FOUND => return Ok(None),
+                e => e.ok()?,
+            }
+
+            let mut data = vec![0u8; size as usize];
+
+            unsafe {
+                RegQueryValueExW(
+                    **key,
+                    name,
+                    None,
+                    Some(&raw mut value_type),
+                    Some(data.as_mut_ptr()),
+                    Some(&raw mut size),
+                )
+                .ok()?;
+            }
+
+            data.truncate(size as usize);
+            Ok(Some((value_type, data)))
+        }
+
+        pub fn get_string(&self, name: PCWSTR) -> windows::core::Result<Option<String>> {
+            match self.query_value(name)? {
+                Some((REG_SZ | REG_EXPAND_SZ, data)) => {
+                    let wide = data
+                        .chunks_exact(2)
+                        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                        .take_while(|c| *c != 0)
+                        .collect::<Vec<_>>();
+
+                    Ok(Some(String::from_utf16_lossy(&wide)))
+                }
+                Some(_) => Err(ERROR_DATATYPE_MISMATCH.into()),
+                None => Ok(None),
+            }
+        }
+
+        pub fn get_u32(&self, name: PCWSTR) -> windows::core::Result<Option<u32>> {
+            match self.query_value(name)? {
+                Some((REG_DWORD, data)) => Ok(Some(u32::from_le_bytes(
+                    data.as_slice()
+                        .try_into()
+                        .map_err(|_| windows::core::Error::from(ERROR_INVALID_DATA))?,
+                ))),
+                Some(_) => Err(ERROR_DATATYPE_MISMATCH.into()),
+                None => Ok(None),
+            }
+        }
+
+        pub fn get_u64(&self, name: PCWSTR) -> windows::core::Result<Option<u64>> {
+            match self.query_value(name)? {
+                Some((REG_QWORD, data)) => Ok(Some(u64::from_le_bytes(
+                    data.as_slice()
+                        .try_into()
+                        .map_err(|_| windows::core::Error::from(ERROR_INVALID_DATA))?,
+                ))),
+                Some(_) => Err(ERROR_DATATYPE_MISMATCH.into()),
+                None => Ok(None),
+            }
+        }
+
+        pub fn get_binary(&self, name: PCWSTR) -> windows::core::Result<Option<Vec<u8>>> {
+            match self.query_value(name)? {
+                Some((REG_BINARY, data)) => Ok(Some(data)),
+                Some(_) => Err(ERROR_DATATYPE_MISMATCH.into()),
+                None => Ok(None),
+            }
+        }
+
+        pub fn value_names(&self) -> windows::core::Result<Vec<String>> {
+            let Some(ref key) = self.key else {
+                return Ok(Vec::new());
+            };
+
+            let mut names = Vec::new();
+            let mut buffer = vec![0u16; 16384];
+
+            for index in 0.. {
+                let mut length = buffer.len() as u32;
+
+                match unsafe {
+                    RegEnumValueW(
+                        **key,
+                        index,
+                        PWSTR::from_raw(buffer.as_mut_ptr()),
+                        &raw mut length,
+                        None,
+                        None,
+                        None,
+                        None,
+                    )
+                } {
+                    ERROR_SUCCESS => {
+                        names.push(String::from_utf16_lossy(&buffer[..length as usize]))
+                    }
+                    ERROR_NO_MORE_ITEMS => break,
+                    e => e.ok()?,
+                }
+            }
+
+            Ok(names)
+        }
+
+        pub fn has_subkey(&self, sub_key: PCWSTR) -> windows::core::Result<bool> {
+            Ok(self.try_open_subkey(sub_key)?.is_some())
+        }
+
+        pub fn has_value(&self, name: PCWSTR) -> windows::core::Result<bool> {
+            Ok(self.query_value(name)?.is_some())
+        }
+
         pub fn set_u32(&self, name: PCWSTR, value: u32) -> windows::core::Result<()> {
             self.set_value(name, Some(&value.to_le_bytes()), REG_DWORD)
         }
@@ -307,6 +725,25 @@ pub mod transaction {
             value: Option<&[T]>,
             value_type: REG_VALUE_TYPE,
         ) -> windows::core::Result<()> {
+            self.transaction.record(Operation::SetValue {
+                path: self.path.clone(),
+                name: pcwstr_to_string(name),
+                value_type,
+                data: value.map_or_else(Vec::new, |v| {
+                    unsafe {
+                        std::slice::from_raw_parts(
+                            v.as_ptr().cast::<u8>(),
+                            std::mem::size_of_val(v),
+                        )
+                    }
+                    .to_vec()
+                }),
+            });
+
+            let Some(ref key) = self.key else {
+                return Ok(());
+            };
+
             unsafe extern "system" {
                 #[allow(unused)]
                 fn RegSetValueExW(
@@ -321,7 +758,7 @@ pub mod transaction {
 
             unsafe {
                 RegSetValueExW(
-                    *self.key,
+                    **key,
                     name,
                     0,
                     value_type,
@@ -333,7 +770,16 @@ pub mod transaction {
         }
 
         pub fn delete_value(&self, name: PCWSTR) -> windows::core::Result<()> {
-            match unsafe { RegDeleteValueW(*self.key, name) } {
+            self.transaction.record(Operation::DeleteValue {
+                path: self.path.clone(),
+                name: pcwstr_to_string(name),
+            });
+
+            let Some(ref key) = self.key else {
+                return Ok(());
+            };
+
+            match unsafe { RegDeleteValueW(**key, name) } {
                 ERROR_SUCCESS | ERROR_FILE_NOT_FOUND => Ok(()),
                 e => e.ok(),
             }
@@ -341,8 +787,243 @@ pub mod transaction {
     }
 }
 
+pub mod reg_file {
+    use std::{fmt::Write, path::Path};
+
+    use windows::Win32::System::Registry::{
+        REG_BINARY, REG_DWORD, REG_EXPAND_SZ, REG_MULTI_SZ, REG_QWORD, REG_SZ, REG_VALUE_TYPE,
+    };
+
+    use super::transaction::Operation;
+
+    fn escape(value: &str) -> String {
+        value.replace('\\', "\\\\").replace('"', "\\\"")
+    }
+
+    fn value_name(name: &Option<String>) -> String {
+        match name {
+            Some(name) if !name.is_empty() => format!("\"{}\"", escape(name)),
+            _ => "@".to_owned(),
+        }
+    }
+
+    fn utf16_to_string(data: &[u8]) -> String {
+        let wide = data
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|c| *c != 0)
+            .collect::<Vec<_>>();
+
+        String::from_utf16_lossy(&wide)
+    }
+
+    fn hex(data: &[u8]) -> String {
+        data.iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    fn with_terminator(data: &[u8]) -> Vec<u8> {
+        let mut data = data.to_vec();
+        if !data.ends_with(&[0, 0]) {
+            data.extend_from_slice(&[0, 0]);
+        }
+        data
+    }
+
+    fn value_data(value_type: REG_VALUE_TYPE, data: &[u8]) -> String {
+        match value_type {
+            REG_SZ => format!("\"{}\"", escape(&utf16_to_string(data))),
+            REG_DWORD if data.len() == 4 => format!(
+                "dword:{:08x}",
+                u32::from_le_bytes([data[0], data[1], data[2], data[3]])
+            ),
+            REG_BINARY => format!("hex:{}", hex(data)),
+            REG_EXPAND_SZ => format!("hex(2):{}", hex(&with_terminator(data))),
+            REG_MULTI_SZ => format!("hex(7):{}", hex(data)),
+            REG_QWORD => format!("hex(b):{}", hex(data)),
+            value_type => format!("hex({:x}):{}", value_type.0, hex(data)),
+        }
+    }
+
+    fn display_data(value_type: REG_VALUE_TYPE, data: &[u8]) -> String {
+        match value_type {
+            REG_SZ | REG_EXPAND_SZ => format!("\"{}\"", utf16_to_string(data)),
+            _ => value_data(value_type, data),
+        }
+    }
+
+    pub fn to_reg(operations: &[Operation]) -> String {
+        let mut output = String::from("Windows Registry Editor Version 5.00\r\n");
+        let mut current_key: Option<&str> = None;
+
+        for operation in operations {
+            match operation {
+                Operation::CreateKey { path } => {
+                    if current_key != Some(path) {
+                        _ = write!(output, "\r\n[{}]\r\n", path);
+                        current_key = Some(path);
+                    }
+                }
+                Operation::SetValue {
+                    path,
+                    name,
+                    value_type,
+                    data,
+                } => {
+                    if current_key != Some(path) {
+                        _ = write!(output, "\r\n[{}]\r\n", path);
+                        current_key = Some(path);
+                    }
+
+                    _ = write!(
+                        output,
+                        "{}={}\r\n",
+                        value_name(name),
+                        value_data(*value_type, data)
+                    );
+                }
+                Operation::DeleteKey { path } => {
+                    _ = write!(output, "\r\n[-{}]\r\n", path);
+                    current_key = None;
+                }
+                Operation::DeleteValue { path, name } => {
+                    if current_key != Some(path) {
+                        _ = write!(output, "\r\n[{}]\r\n", path);
+                        current_key = Some(path);
+                    }
+
+                    _ = write!(output, "{}=-\r\n", value_name(name));
+                }
+                Operation::Note { text } => {
+                    _ = write!(output, "; {}\r\n", text);
+                }
+            }
+        }
+
+        output
+    }
+
+    pub fn to_diff(operations: &[Operation]) -> String {
+        let mut output = String::new();
+
+        for operation in operations {
+            match operation {
+                Operation::CreateKey { path } => {
+                    _ = writeln!(output, "+ {}", path);
+                }
+                Operation::SetValue {
+                    path,
+                    name,
+                    value_type,
+                    data,
+                } => {
+                    _ = writeln!(
+                        output,
+                        "+ {}\\{} = {}",
+                        path,
+                        value_name(name),
+                        display_data(*value_type, data)
+                    );
+                }
+                Operation::DeleteKey { path } => {
+                    _ = writeln!(output, "- {}", path);
+                }
+                Operation::DeleteValue { path, name } => {
+                    _ = writeln!(output, "- {}\\{}", path, value_name(name));
+                }
+                Operation::Note { text } => {
+                    _ = writeln!(output, "# {}", text);
+                }
+            }
+        }
+
+        output
+    }
+
+    pub fn write(operations: &[Operation], reg_path: &Path) -> std::io::Result<()> {
+        let mut bytes = vec![0xFF, 0xFE];
+        bytes.extend(
+            to_reg(operations)
+                .encode_utf16()
+                .flat_map(|c| c.to_le_bytes()),
+        );
+
+        std::fs::write(reg_path, bytes)?;
+        std::fs::write(reg_path.with_extension("txt"), to_diff(operations))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn utf16(value: &str) -> Vec<u8> {
+            value
+                .encode_utf16()
+                .chain([0])
+                .flat_map(u16::to_le_bytes)
+                .collect()
+        }
+
+        #[test]
+        fn writes_reg_file() {
+            let key = r"HKEY_CLASSES_ROOT\.bmx".to_owned();
+            let operations = [
+                Operation::Note {
+                    text: "Conflicting codec".to_owned(),
+                },
+                Operation::CreateKey { path: key.clone() },
+                Operation::SetValue {
+                    path: key.clone(),
+                    name: None,
+                    value_type: REG_SZ,
+                    data: utf16(r#"C:\Program Files\"X16"\bmx.dll"#),
+                },
+                Operation::SetValue {
+                    path: key.clone(),
+                    name: Some(r#"Say "hi"\"#.to_owned()),
+                    value_type: REG_DWORD,
+                    data: 0xBEEFu32.to_le_bytes().to_vec(),
+                },
+                Operation::SetValue {
+                    path: key.clone(),
+                    name: Some("Reserved".to_owned()),
+                    value_type: REG_BINARY,
+                    data: vec![0x00, 0x0A, 0xFF],
+                },
+                Operation::DeleteValue {
+                    path: key.clone(),
+                    name: Some("Old".to_owned()),
+                },
+                Operation::DeleteKey {
+                    path: format!(r"{}\Stale", key),
+                },
+            ];
+
+            assert_eq!(
+                to_reg(&operations),
+                concat!(
+                    "Windows Registry Editor Version 5.00\r\n",
+                    "; Conflicting codec\r\n",
+                    "\r\n",
+                    "[HKEY_CLASSES_ROOT\\.bmx]\r\n",
+                    r#"@="C:\\Program Files\\\"X16\"\\bmx.dll""#,
+                    "\r\n",
+                    r#""Say \"hi\"\\"=dword:0000beef"#,
+                    "\r\n",
+                    "\"Reserved\"=hex:00,0a,ff\r\n",
+                    "\"Old\"=-\r\n",
+                    "\r\n",
+                    "[-HKEY_CLASSES_ROOT\\.bmx\\Stale]\r\n",
+                )
+            );
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
-struct NullTerminatedSlice<'a>(&'a [u16]);
+pub(crate) struct NullTerminatedSlice<'a>(&'a [u16]);
 
 impl<'a> NullTerminatedSlice<'a> {
     pub fn new(slice: &'a [u16]) -> Result<Self, ()> {
@@ -362,49 +1043,208 @@ impl Deref for NullTerminatedSlice<'_> {
     }
 }
 
-fn register_com_extension<'a, T: CoClass>(
-    classes: &'a Key,
-    module_path: NullTerminatedSlice,
-    description: PCWSTR,
-    apartment_type: PCWSTR,
-) -> windows::core::Result<Key<'a>> {
-    let clsid_string = T::CLSID.to_wide();
-    let com_object = classes
-        .create_subkey(w!("CLSID"))?
-        .create_subkey(PCWSTR::from_raw(clsid_string.as_ptr()))?;
-
-    com_object.set_pcwstr(PCWSTR::null(), description)?;
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Architecture {
+    X86,
+    X64,
+    Arm64,
+}
 
-    com_object
-        .create_subkey(w!("ProgId"))?
-        .set_pcwstr(PCWSTR::null(), T::PROG_ID)?;
+impl Architecture {
+    #[cfg(target_arch = "x86")]
+    pub const CURRENT: Self = Architecture::X86;
+    #[cfg(target_arch = "x86_64")]
+    pub const CURRENT: Self = Architecture::X64;
+    #[cfg(target_arch = "aarch64")]
+    pub const CURRENT: Self = Architecture::Arm64;
 
-    com_object
-        .create_subkey(w!("VersionIndependentProgId"))?
-        .set_pcwstr(PCWSTR::null(), T::VERSION_INDEPENDENT_PROG_ID)?;
+    pub fn native() -> windows::core::Result<Self> {
+        let mut process_machine = IMAGE_FILE_MACHINE::default();
+        let mut native_machine = IMAGE_FILE_MACHINE::default();
 
-    let inproc = com_object.create_subkey(w!("InprocServer32"))?;
-    inproc.set_pcwstr(PCWSTR::null(), PCWSTR::from_raw(module_path.as_ptr()))?;
-    inproc.set_pcwstr(w!("ThreadingModel"), apartment_type)?;
+        unsafe {
+            IsWow64Process2(
+                GetCurrentProcess(),
+                &raw mut process_machine,
+                Some(&raw mut native_machine),
+            )?;
+        }
 
-    classes
-        .create_subkey(T::PROG_ID)?
-        .create_subkey(w!("CLSID"))?
-        .set_guid(PCWSTR::null(), &T::CLSID)?;
+        match native_machine {
+            IMAGE_FILE_MACHINE_I386 => Ok(Architecture::X86),
+            IMAGE_FILE_MACHINE_AMD64 => Ok(Architecture::X64),
+            IMAGE_FILE_MACHINE_ARM64 => Ok(Architecture::Arm64),
+            _ => Err(ERROR_NOT_SUPPORTED.to_hresult().into()),
+        }
+    }
 
-    classes
-        .create_subkey(T::VERSION_INDEPENDENT_PROG_ID)?
-        .create_subkey(w!("CLSID"))?
-        .set_guid(PCWSTR::null(), &T::CLSID)?;
+    pub const fn directory_name(self) -> &'static str {
+        match self {
+            Architecture::X86 => "x86",
+            Architecture::X64 => "x64",
+            Architecture::Arm64 => "arm64",
+        }
+    }
 
-    Ok(com_object)
+    pub const fn view(self) -> View {
+        match self {
+            Architecture::X86 => View::Registry32,
+            Architecture::X64 | Architecture::Arm64 => View::Registry64,
+        }
+    }
 }
 
-fn unregister_com_extension<T: CoClass>(classes: &Key) -> windows::core::Result<()> {
-    let mut buffer = [0u16; 39 + 6];
-    unsafe {
-        buffer[..6]
-            .as_mut_ptr()
+pub fn find_sibling_module(
+    module_path: &[u16],
+) -> windows::core::Result<Option<(Architecture, Vec<u16>)>> {
+    let native = Architecture::native()?;
+
+    let sibling = match Architecture::CURRENT.view() {
+        View::Registry64 => Architecture::X86,
+        _ => native,
+    };
+
+    if sibling.view() == Architecture::CURRENT.view() {
+        return Ok(None);
+    }
+
+    let module_path = String::from_utf16(module_path.strip_suffix(&[0]).unwrap_or(module_path))?;
+    let module_path = Path::new(&module_path);
+
+    let (Some(directory), Some(file_name)) = (module_path.parent(), module_path.file_name()) else {
+        return Ok(None);
+    };
+
+    let sibling_path = directory.join(sibling.directory_name()).join(file_name);
+    if !sibling_path.is_file() {
+        return Ok(None);
+    }
+
+    Ok(sibling_path
+        .to_str()
+        .map(|path| (sibling, wstr::encode_with_nul(path))))
+}
+
+fn class_settings_key<T: CoClass>() -> Vec<u16> {
+    "CLSID\\"
+        .encode_utf16()
+        .chain(T::CLSID.to_wide())
+        .collect::<Vec<_>>()
+}
+
+pub fn get_class_setting<T: CoClass>(name: PCWSTR) -> Option<u32> {
+    if is_low_privilege_process() {
+        return None;
+    }
+
+    let sub_key = class_settings_key::<T>();
+
+    let mut value = 0u32;
+    let mut size = std::mem::size_of_val(&value) as u32;
+
+    unsafe {
+        RegGetValueW(
+            HKEY_CLASSES_ROOT,
+            PCWSTR::from_raw(sub_key.as_ptr()),
+            name,
+            RRF_RT_REG_DWORD,
+            None,
+            Some((&raw mut value).cast()),
+            Some(&raw mut size),
+        )
+    }
+    .ok()
+    .ok()?;
+
+    Some(value)
+}
+
+// Like get_class_setting, for REG_SZ values. Empty strings count as unset.
+pub fn get_class_string_setting<T: CoClass>(name: PCWSTR) -> Option<String> {
+    if is_low_privilege_process() {
+        return None;
+    }
+
+    let sub_key = class_settings_key::<T>();
+    let mut size = 0u32;
+
+    unsafe {
+        RegGetValueW(
+            HKEY_CLASSES_ROOT,
+            PCWSTR::from_raw(sub_key.as_ptr()),
+            name,
+            RRF_RT_REG_SZ,
+            None,
+            None,
+            Some(&raw mut size),
+        )
+    }
+    .ok()
+    .ok()?;
+
+    let mut buffer = vec![0u16; (size as usize).div_ceil(2)];
+
+    unsafe {
+        RegGetValueW(
+            HKEY_CLASSES_ROOT,
+            PCWSTR::from_raw(sub_key.as_ptr()),
+            name,
+            RRF_RT_REG_SZ,
+            None,
+            Some(buffer.as_mut_ptr().cast()),
+            Some(&raw mut size),
+        )
+    }
+    .ok()
+    .ok()?;
+
+    let len = (size as usize / 2).saturating_sub(1);
+    String::from_utf16(&buffer[..len.min(buffer.len())])
+        .ok()
+        .filter(|value| !value.is_empty())
+}
+
+fn register_com_extension<'a, T: CoClass>(
+    classes: &'a Key,
+    module_path: NullTerminatedSlice,
+) -> windows::core::Result<Key<'a>> {
+    let clsid_string = T::CLSID.to_wide();
+    let com_object = classes
+        .create_subkey(w!("CLSID"))?
+        .create_subkey(PCWSTR::from_raw(clsid_string.as_ptr()))?;
+
+    com_object.set_pcwstr(PCWSTR::null(), T::DESCRIPTION)?;
+
+    com_object
+        .create_subkey(w!("ProgId"))?
+        .set_pcwstr(PCWSTR::null(), T::PROG_ID)?;
+
+    com_object
+        .create_subkey(w!("VersionIndependentProgId"))?
+        .set_pcwstr(PCWSTR::null(), T::VERSION_INDEPENDENT_PROG_ID)?;
+
+    let inproc = com_object.create_subkey(w!("InprocServer32"))?;
+    inproc.set_pcwstr(PCWSTR::null(), PCWSTR::from_raw(module_path.as_ptr()))?;
+    inproc.set_str(w!("ThreadingModel"), T::THREADING.name())?;
+
+    classes
+        .create_subkey(T::PROG_ID)?
+        .create_subkey(w!("CLSID"))?
+        .set_guid(PCWSTR::null(), &T::CLSID)?;
+
+    classes
+        .create_subkey(T::VERSION_INDEPENDENT_PROG_ID)?
+        .create_subkey(w!("CLSID"))?
+        .set_guid(PCWSTR::null(), &T::CLSID)?;
+
+    Ok(com_object)
+}
+
+fn unregister_com_extension<T: CoClass>(classes: &Key) -> windows::core::Result<()> {
+    let mut buffer = [0u16; 39 + 6];
+    unsafe {
+        buffer[..6]
+            .as_mut_ptr()
             .copy_from_nonoverlapping(w!("CLSID\\").as_ptr(), 6);
     }
 
@@ -417,6 +1257,585 @@ fn unregister_com_extension<T: CoClass>(classes: &Key) -> windows::core::Result<
     Ok(())
 }
 
+fn register_codec<'a, T: CoClass>(
+    classes: &'a Key,
+    module_path: NullTerminatedSlice,
+) -> windows::core::Result<Key<'a>> {
+    register_codec_for::<T>(
+        classes,
+        module_path,
+        &CONTAINER_FORMAT,
+        EXTENSION,
+        MIME_TYPE,
+    )
+}
+
+// Lowercase, with a leading dot and separated by commas only, which is what hosts like Photos match
+// against when they build their list of openable files from the installed decoders.
+fn canonical_file_extensions(extensions: &str) -> String {
+    let mut canonical: Vec<String> = Vec::new();
+
+    for extension in extensions.split([',', ';']).map(str::trim) {
+        if extension.is_empty() {
+            continue;
+        }
+
+        let extension = format!(".{}", extension.trim_start_matches('.').to_lowercase());
+        if !canonical.contains(&extension) {
+            canonical.push(extension);
+        }
+    }
+
+    canonical.join(",")
+}
+
+fn register_codec_for<'a, T: CoClass>(
+    classes: &'a Key,
+    module_path: NullTerminatedSlice,
+    container_format: &GUID,
+    file_extensions: PCWSTR,
+    mime_types: PCWSTR,
+) -> windows::core::Result<Key<'a>> {
+    let codec = register_com_extension::<T>(classes, module_path)?;
+
+    codec.set_pcwstr(w!("Author"), AUTHOR)?;
+    codec.set_guid(w!("ContainerFormat"), container_format)?;
+    codec.set_pcwstr(w!("Description"), T::FRIENDLY_NAME)?;
+    codec.set_str(
+        w!("FileExtensions"),
+        &canonical_file_extensions(&unsafe { file_extensions.to_string() }?),
+    )?;
+    codec.set_pcwstr(w!("FriendlyName"), T::FRIENDLY_NAME)?;
+    codec.set_pcwstr(w!("MimeTypes"), mime_types)?;
+    codec.set_pcwstr(w!("Version"), VERSION)?;
+    codec.set_pcwstr(w!("SpecVersion"), SPEC_VERSION)?;
+    codec.set_pcwstr(w!("ColorManagementVersion"), COLOR_MANAGEMENT_VERSION)?;
+    codec.set_u32(w!("SupportAnimation"), SUPPORTS_ANIMATION as u32)?;
+    codec.set_u32(w!("SupportChromakey"), SUPPORTS_CHROMAKEY as u32)?;
+    codec.set_u32(w!("SupportLossless"), SUPPORTS_LOSSLESS as u32)?;
+    codec.set_u32(w!("SupportMultiframe"), SUPPORTS_MULTIFRAME as u32)?;
+    codec.set_guid(w!("VendorGUID"), &VENDOR)?;
+
+    codec.set_u32(w!("BitsPerPixel"), BITS_PER_PIXEL)?;
+    codec.set_str(
+        w!("ColorFormats"),
+        &PIXEL_FORMATS
+            .iter()
+            .map(|pixel_format| Guid(*pixel_format).to_string())
+            .collect::<Vec<_>>()
+            .join(","),
+    )?;
+
+    let formats = codec.create_subkey(w!("Formats"))?;
+    for pixel_format in PIXEL_FORMATS {
+        _ = formats.create_subkey(PCWSTR::from_raw(pixel_format.to_wide().as_ptr()))?;
+    }
+
+    Ok(codec)
+}
+
+fn register_metadata_handler<'a, T: CoClass>(
+    classes: &'a Key,
+    module_path: NullTerminatedSlice,
+) -> windows::core::Result<Key<'a>> {
+    let handler = register_com_extension::<T>(classes, module_path)?;
+
+    handler.set_pcwstr(w!("Author"), AUTHOR)?;
+    handler.set_pcwstr(w!("Description"), T::FRIENDLY_NAME)?;
+    handler.set_pcwstr(w!("FriendlyName"), T::FRIENDLY_NAME)?;
+    handler.set_guid(w!("Vendor"), &VENDOR)?;
+    handler.set_pcwstr(w!("Version"), VERSION)?;
+    handler.set_pcwstr(w!("SpecVersion"), SPEC_VERSION)?;
+    handler.set_guid(w!("MetadataFormat"), &RESERVED_METADATA_FORMAT)?;
+    handler.set_u32(w!("RequiresFullStream"), 0)?;
+    handler.set_u32(w!("SupportsPadding"), 0)?;
+    handler.set_u32(w!("FixedSize"), 16)?;
+
+    // The block has no signature of its own; it's simply the reserved bytes of the header.
+    handler
+        .create_subkey(w!("ContainerFormats"))?
+        .create_subkey(PCWSTR::from_raw(CONTAINER_FORMAT.to_wide().as_ptr()))
+}
+
+fn register_category_instance<T: CoClass>(
+    classes_root: &Key,
+    category: GUID,
+) -> windows::core::Result<()> {
+    let instance = classes_root
+        .create_subkey(w!("CLSID"))?
+        .create_subkey(PCWSTR::from_raw(category.to_wide().as_ptr()))?
+        .create_subkey(w!("Instance"))?
+        .create_subkey(PCWSTR::from_raw(T::CLSID.to_wide().as_ptr()))?;
+
+    instance.set_guid(w!("CLSID"), &T::CLSID)?;
+    instance.set_pcwstr(w!("FriendlyName"), T::FRIENDLY_NAME)
+}
+
+fn register_explorer_command_verb<T: ExplorerCommandClass>(
+    classes: &Key,
+) -> windows::core::Result<()> {
+    let verb = classes
+        .create_subkey(T::FILE_TYPE)?
+        .create_subkey(w!("shell"))?
+        .create_subkey(T::VERB)?;
+    //verb.set_pcwstr(w!("AppliesTo"), w!("System.Kind:picture"))?;
+    verb.set_guid(w!("ExplorerCommandHandler"), &T::CLSID)?;
+
+    Ok(())
+}
+
+fn unregister_explorer_command_verb<T: ExplorerCommandClass>(
+    classes: &Key,
+) -> windows::core::Result<()> {
+    if let Some(shell) = classes
+        .try_open_subkey(T::FILE_TYPE)?
+        .map(|file_type| file_type.try_open_subkey(w!("shell")))
+        .transpose()?
+        .flatten()
+    {
+        shell.delete_subkey(T::VERB)?;
+    }
+
+    Ok(())
+}
+
+// Another decoder listed in the WIC category that claims our container format or extension, e.g.
+// a leftover from an older install or a third-party plugin.
+struct ConflictingDecoder {
+    clsid: GUID,
+    friendly_name: Option<String>,
+    arbitration_priority: Option<u32>,
+}
+
+fn claims_extension(file_extensions: &str, extension: &str) -> bool {
+    let extension = canonical_file_extensions(extension);
+    canonical_file_extensions(file_extensions)
+        .split(',')
+        .any(|claimed| claimed == extension)
+}
+
+fn find_conflicting_decoders(classes_root: &Key) -> windows::core::Result<Vec<ConflictingDecoder>> {
+    let extension = unsafe { EXTENSION.to_string() }?;
+    let mut conflicting = Vec::new();
+
+    let Some(clsid) = classes_root.try_open_subkey(w!("CLSID"))? else {
+        return Ok(conflicting);
+    };
+
+    let Some(instances) = clsid
+        .try_open_subkey(PCWSTR::from_raw(CATID_WICBitmapDecoders.to_wide().as_ptr()))?
+        .map(|category| category.try_open_subkey(w!("Instance")))
+        .transpose()?
+        .flatten()
+    else {
+        return Ok(conflicting);
+    };
+
+    for name in instances.subkey_names()? {
+        let Ok(decoder_clsid) = guid::parse(&name) else {
+            continue;
+        };
+
+        if decoder_clsid == BitmapDecoder::CLSID {
+            continue;
+        }
+
+        let Some(decoder) =
+            clsid.try_open_subkey(PCWSTR::from_raw(decoder_clsid.to_wide().as_ptr()))?
+        else {
+            continue;
+        };
+
+        // Values of the wrong type are as good as missing; this is someone else's registration.
+        let claims_container_format = decoder
+            .get_string(w!("ContainerFormat"))
+            .ok()
+            .flatten()
+            .is_some_and(|value| guid::parse(&value) == Ok(CONTAINER_FORMAT));
+        let claims_extension = decoder
+            .get_string(w!("FileExtensions"))
+            .ok()
+            .flatten()
+            .is_some_and(|value| claims_extension(&value, &extension));
+
+        if claims_container_format || claims_extension {
+            conflicting.push(ConflictingDecoder {
+                clsid: decoder_clsid,
+                friendly_name: decoder.get_string(w!("FriendlyName")).ok().flatten(),
+                arbitration_priority: decoder.get_u32(w!("ArbitrationPriority")).ok().flatten(),
+            });
+        }
+    }
+
+    Ok(conflicting)
+}
+
+// Ours unless a conflicting decoder asks for as much or more, in which case we outbid it. Unset
+// counts as zero, like WIC does.
+fn arbitration_priority(conflicting: impl IntoIterator<Item = Option<u32>>) -> u32 {
+    conflicting
+        .into_iter()
+        .map(|priority| priority.unwrap_or(0).saturating_add(1))
+        .fold(ARBITRATION_PRIORITY, u32::max)
+}
+
+// Registration fragments for the class inventory; see com::inventory.
+pub(crate) fn register_decoder(
+    classes_root: &Key,
+    module_path: NullTerminatedSlice,
+) -> windows::core::Result<()> {
+    let conflicting = find_conflicting_decoders(classes_root)?;
+    for decoder in &conflicting {
+        classes_root.note(&format!(
+            "Conflicting decoder {} ({}), ArbitrationPriority {}",
+            Guid(decoder.clsid),
+            decoder.friendly_name.as_deref().unwrap_or("unnamed"),
+            decoder
+                .arbitration_priority
+                .map_or_else(|| "unset".to_owned(), |priority| priority.to_string())
+        ));
+    }
+
+    let bmx_decoder = register_codec::<BitmapDecoder>(classes_root, module_path)?;
+    bmx_decoder.set_u32(
+        w!("ArbitrationPriority"),
+        arbitration_priority(
+            conflicting
+                .iter()
+                .map(|decoder| decoder.arbitration_priority),
+        ),
+    )?;
+    let patterns = bmx_decoder.create_subkey(w!("Patterns"))?;
+    let first_pattern = patterns.create_subkey(w!("0"))?;
+    first_pattern.set_u32(w!("Position"), 0)?;
+
+    first_pattern.set_binary(w!("Pattern"), &FileHeader::PATTERN)?;
+    first_pattern.set_binary(w!("Mask"), &FileHeader::PATTERN_MASK)?;
+    first_pattern.set_u32(w!("Length"), FileHeader::PATTERN.len() as u32)?;
+    // Only the start of the stream counts; see bmx::sniff for the same check in code.
+    first_pattern.set_u32(w!("EndOfStream"), 0)?;
+
+    register_category_instance::<BitmapDecoder>(classes_root, CATID_WICBitmapDecoders)
+}
+
+pub(crate) fn register_encoder(
+    classes_root: &Key,
+    module_path: NullTerminatedSlice,
+) -> windows::core::Result<()> {
+    _ = register_codec::<BitmapEncoder>(classes_root, module_path)?;
+
+    register_category_instance::<BitmapEncoder>(classes_root, CATID_WICBitmapEncoders)
+}
+
+// Listed in the decoder category so WIC describes it through CreateComponentInfo. It has no
+// patterns, and WIC asks every decoder when no pattern matches, so it's QueryCapability declining
+// every stream that keeps it from claiming other formats or unrelated .bin files.
+pub(crate) fn register_raw_decoder(
+    classes_root: &Key,
+    module_path: NullTerminatedSlice,
+) -> windows::core::Result<()> {
+    _ = register_codec_for::<RawDecoder>(
+        classes_root,
+        module_path,
+        &RAW_CONTAINER_FORMAT,
+        RAW_EXTENSION,
+        w!("application/octet-stream"),
+    )?;
+
+    register_category_instance::<RawDecoder>(classes_root, CATID_WICBitmapDecoders)
+}
+
+pub(crate) fn register_metadata_reader(
+    classes_root: &Key,
+    module_path: NullTerminatedSlice,
+) -> windows::core::Result<()> {
+    let reserved_offset = std::mem::offset_of!(FileHeader, reserved) as u32;
+
+    let container = register_metadata_handler::<ReservedMetadataReader>(classes_root, module_path)?;
+    let pattern = container.create_subkey(w!("0"))?;
+    pattern.set_u32(w!("Position"), reserved_offset)?;
+    pattern.set_binary(w!("Pattern"), &[])?;
+    pattern.set_binary(w!("Mask"), &[])?;
+    pattern.set_u32(w!("DataOffset"), 0)?;
+
+    register_category_instance::<ReservedMetadataReader>(classes_root, CATID_WICMetadataReader)
+}
+
+pub(crate) fn register_metadata_writer(
+    classes_root: &Key,
+    module_path: NullTerminatedSlice,
+) -> windows::core::Result<()> {
+    let container = register_metadata_handler::<ReservedMetadataWriter>(classes_root, module_path)?;
+    container.set_u32(
+        w!("WritePosition"),
+        std::mem::offset_of!(FileHeader, reserved) as u32,
+    )?;
+    container.set_binary(w!("WriteHeader"), &[])?;
+    container.set_u32(w!("WriteOffset"), 0)?;
+
+    register_category_instance::<ReservedMetadataWriter>(classes_root, CATID_WICMetadataWriter)
+}
+
+pub(crate) fn register_extension<T: CoClass>(
+    classes_root: &Key,
+    module_path: NullTerminatedSlice,
+) -> windows::core::Result<()> {
+    register_com_extension::<T>(classes_root, module_path).map(|_| ())
+}
+
+pub(crate) fn unregister_extension<T: CoClass>(classes_root: &Key) -> windows::core::Result<()> {
+    unregister_com_extension::<T>(classes_root)
+}
+
+// Also removes the class from the WIC component category it was listed in.
+pub(crate) fn unregister_component<T: CoClass>(
+    classes_root: &Key,
+    category: GUID,
+) -> windows::core::Result<()> {
+    unregister_com_extension::<T>(classes_root)?;
+
+    let Some(clsid) = classes_root.try_open_subkey(w!("CLSID"))? else {
+        return Ok(());
+    };
+
+    if let Some(instance) = clsid
+        .try_open_subkey(PCWSTR::from_raw(category.to_wide().as_ptr()))?
+        .map(|category| category.try_open_subkey(w!("Instance")))
+        .transpose()?
+        .flatten()
+    {
+        instance.delete_subkey(PCWSTR::from_raw(T::CLSID.to_wide().as_ptr()))?;
+    }
+
+    Ok(())
+}
+
+fn register_com_classes(
+    classes_root: &Key,
+    module_path: NullTerminatedSlice,
+) -> windows::core::Result<()> {
+    for class in CLASSES {
+        (class.register)(classes_root, module_path)?;
+    }
+
+    Ok(())
+}
+
+fn unregister_com_classes(classes_root: &Key) -> windows::core::Result<()> {
+    for class in CLASSES {
+        (class.unregister)(classes_root)?;
+    }
+
+    Ok(())
+}
+
+pub(crate) fn manifest_com_class<T: CoClass>() -> String {
+    let clsid = T::CLSID.to_ascii_with_nul();
+
+    format!(
+        "    <comClass clsid=\"{}\" progid=\"{}\" threadingModel=\"{}\" description=\"{}\"/>\n",
+        std::str::from_utf8(&clsid[..clsid.len() - 1]).unwrap(),
+        unsafe { T::PROG_ID.display() },
+        T::THREADING.name(),
+        unsafe { T::FRIENDLY_NAME.display() }
+    )
+}
+
+// Side-by-side manifest describing the same classes as register_com_classes, so the module can be
+// activated through CreateActCtx without touching the registry.
+pub fn activation_manifest(module_name: &str) -> String {
+    let mut manifest = String::from(concat!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n",
+        "<assembly xmlns=\"urn:schemas-microsoft-com:asm.v1\" manifestVersion=\"1.0\">\n",
+        "  <assemblyIdentity type=\"win32\" name=\"",
+        env!("CARGO_PKG_NAME"),
+        "\" version=\"",
+        env!("CARGO_PKG_VERSION_MAJOR"),
+        ".",
+        env!("CARGO_PKG_VERSION_MINOR"),
+        ".",
+        env!("CARGO_PKG_VERSION_PATCH"),
+        ".0\"/>\n",
+    ));
+
+    manifest += &format!("  <file name=\"{}\">\n", module_name);
+    for class in CLASSES {
+        manifest += &(class.manifest)();
+    }
+    manifest += "  </file>\n</assembly>\n";
+
+    manifest
+}
+
+const APP_CONTAINER_ACCESS_GRANTED: PCWSTR = w!("AppContainerAccessGranted");
+
+// Modules outside of Program Files aren't readable from AppContainers by default, which makes
+// hosts like Photos silently skip the codec. Everything else works without it, so failing to grant
+// access is only noted. Whether an entry was added is kept with the decoder's class, so
+// unregistering only takes away what registering granted.
+pub fn grant_app_container_access(
+    classes_root: &Key,
+    module_path: &[u16],
+) -> windows::core::Result<()> {
+    let class = class_settings_key::<BitmapDecoder>();
+
+    match set_app_container_access(module_path, GRANT_ACCESS) {
+        Ok(true) => classes_root
+            .open_subkey(PCWSTR::from_raw(class.as_ptr()))?
+            .set_u32(APP_CONTAINER_ACCESS_GRANTED, 1),
+        Ok(false) => Ok(()),
+        Err(err) => {
+            classes_root.note(&format!(
+                "Could not grant AppContainers access to the module: {}",
+                err
+            ));
+            Ok(())
+        }
+    }
+}
+
+// Must be called before the decoder's class is removed.
+pub fn revoke_app_container_access(
+    classes_root: &Key,
+    module_path: &[u16],
+) -> windows::core::Result<()> {
+    let class = class_settings_key::<BitmapDecoder>();
+    let granted = classes_root
+        .try_open_subkey(PCWSTR::from_raw(class.as_ptr()))?
+        .map(|class| class.get_u32(APP_CONTAINER_ACCESS_GRANTED))
+        .transpose()?
+        .flatten();
+
+    if granted == Some(1) {
+        if let Err(err) = set_app_container_access(module_path, REVOKE_ACCESS) {
+            classes_root.note(&format!(
+                "Could not revoke AppContainers access to the module: {}",
+                err
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// Returns whether the DACL was changed, which granting skips if AppContainers can already read
+// and execute the module, such as through the entry Program Files passes down.
+fn set_app_container_access(
+    module_path: &[u16],
+    access_mode: ACCESS_MODE,
+) -> windows::core::Result<bool> {
+    const ALL_APPLICATION_PACKAGES: PCWSTR = w!("S-1-15-2-1");
+    const READ_AND_EXECUTE: u32 = FILE_GENERIC_READ.0 | FILE_GENERIC_EXECUTE.0;
+
+    let module_path = NullTerminatedSlice::new(module_path)
+        .map_err(|_| windows::core::Error::from(E_BLUETOOTH_ATT_ATTRIBUTE_NOT_FOUND))?;
+    let module_path = PCWSTR::from_raw(module_path.as_ptr());
+
+    let mut sid = PSID::default();
+    unsafe { ConvertStringSidToSidW(ALL_APPLICATION_PACKAGES, &raw mut sid)? };
+    let _sid = unsafe { Owned::new(HLOCAL(sid.0)) };
+
+    let mut dacl = std::ptr::null_mut();
+    let mut security_descriptor = PSECURITY_DESCRIPTOR::default();
+
+    unsafe {
+        GetNamedSecurityInfoW(
+            module_path,
+            SE_FILE_OBJECT,
+            DACL_SECURITY_INFORMATION,
+            None,
+            None,
+            Some(&raw mut dacl),
+            None,
+            &raw mut security_descriptor,
+        )
+    }
+    .ok()?;
+    let _security_descriptor = unsafe { Owned::new(HLOCAL(security_descriptor.0)) };
+
+    let trustee = TRUSTEE_W {
+        TrusteeForm: TRUSTEE_IS_SID,
+        TrusteeType: TRUSTEE_IS_WELL_KNOWN_GROUP,
+        ptstrName: PWSTR::from_raw(sid.0.cast()),
+        ..Default::default()
+    };
+
+    if access_mode == GRANT_ACCESS {
+        let mut rights = 0;
+        unsafe { GetEffectiveRightsFromAclW(dacl, &raw const trustee, &raw mut rights) }.ok()?;
+
+        if rights & READ_AND_EXECUTE == READ_AND_EXECUTE {
+            return Ok(false);
+        }
+    }
+
+    let access = EXPLICIT_ACCESS_W {
+        grfAccessPermissions: (GENERIC_READ | GENERIC_EXECUTE).0,
+        grfAccessMode: access_mode,
+        grfInheritance: NO_INHERITANCE,
+        Trustee: trustee,
+    };
+
+    let mut new_dacl = std::ptr::null_mut();
+    unsafe { SetEntriesInAclW(Some(&[access]), Some(dacl), &raw mut new_dacl) }.ok()?;
+    let _new_dacl = unsafe { Owned::new(HLOCAL(new_dacl.cast())) };
+
+    unsafe {
+        SetNamedSecurityInfoW(
+            module_path,
+            SE_FILE_OBJECT,
+            DACL_SECURITY_INFORMATION,
+            PSID::default(),
+            PSID::default(),
+            Some(new_dacl),
+            None,
+        )
+    }
+    .ok()?;
+
+    Ok(true)
+}
+
+pub fn register_sibling_server(
+    classes_root: &Key,
+    module_path: &[u16],
+) -> windows::core::Result<()> {
+    let module_path = NullTerminatedSlice::new(module_path)
+        .map_err(|_| windows::core::Error::from(E_BLUETOOTH_ATT_ATTRIBUTE_NOT_FOUND))?;
+
+    register_com_classes(classes_root, module_path)
+}
+
+pub fn unregister_sibling_server(classes_root: &Key) -> windows::core::Result<()> {
+    unregister_com_classes(classes_root)
+}
+
+fn property_schema_path(module_path: &[u16]) -> PathBuf {
+    Path::new(&String::from_utf16_lossy(wstr::until_nul(module_path)))
+        .with_file_name(SCHEMA_FILE_NAME)
+}
+
+// The property system keeps its own list of schema files rather than reading them from the
+// registry, so the schema for the BMX columns is written next to the module and handed to it.
+fn register_property_schema(module_path: &[u16]) -> windows::core::Result<()> {
+    let path = property_schema_path(module_path);
+    std::fs::write(&path, SCHEMA).map_err(IoErrorExt::to_win_error)?;
+
+    unsafe { PSRegisterPropertySchema(&HSTRING::from(path.as_os_str())) }
+}
+
+fn unregister_property_schema(module_path: &[u16]) -> windows::core::Result<()> {
+    let path = property_schema_path(module_path);
+    if !path.exists() {
+        return Ok(());
+    }
+
+    let result = unsafe { PSUnregisterPropertySchema(&HSTRING::from(path.as_os_str())) };
+    _ = std::fs::remove_file(&path);
+
+    result
+}
+
 pub fn register_server<'a>(
     transaction: &'a Transaction,
     classes_root: &'a Key,
@@ -428,6 +1847,8 @@ pub fn register_server<'a>(
     {
         let prog_id = classes_root.create_subkey(PROG_ID)?;
         prog_id.set_pcwstr(PCWSTR::null(), w!("BMX File"))?;
+        prog_id.set_u32(TREATMENT, TREATMENT_NONE)?;
+        prog_id.set_pcwstr(TYPE_OVERLAY, w!(""))?;
 
         let drop_target = prog_id.create_subkey(w!("DropTarget"))?;
         drop_target.set_pcwstr(PCWSTR::null(), w!("{FFE2A43C-56B9-4bf5-9A79-CC6D4285608A}"))?;
@@ -452,133 +1873,65 @@ pub fn register_server<'a>(
         }
 
         let shellex = prog_id.create_subkey(w!("ShellEx"))?;
-        let thumbnail_provider =
-            shellex.create_subkey(PCWSTR::from_raw(IThumbnailProvider::IID.to_wide().as_ptr()))?;
-        thumbnail_provider
-            .set_pcwstr(PCWSTR::null(), w!("{C7657C4A-9F68-40fa-A4DF-96BC08EB3551}"))?;
+        shellex
+            .create_subkey(PCWSTR::from_raw(IThumbnailProvider::IID.to_wide().as_ptr()))?
+            .set_guid(PCWSTR::null(), &ThumbnailProvider::CLSID)?;
     }
 
     {
-        let bmx_decoder = register_com_extension::<BitmapDecoder>(
-            classes_root,
-            module_path,
-            w!("BMX File"),
-            w!("Both"),
-        )?;
+        let bmx = classes_root.create_subkey(EXTENSION)?;
+        bmx.set_pcwstr(PCWSTR::null(), PROG_ID)?;
+        bmx.set_pcwstr(w!("Content Type"), MIME_TYPE)?;
+        bmx.set_pcwstr(w!("PerceivedType"), w!("image"))?;
 
-        bmx_decoder.set_pcwstr(w!("Author"), w!("Fulgen"))?;
-        bmx_decoder.set_guid(w!("ContainerFormat"), &CONTAINER_FORMAT)?;
-        bmx_decoder.set_pcwstr(w!("Description"), w!("BMX Decoder"))?;
-        bmx_decoder.set_pcwstr(w!("FileExtensions"), EXTENSION)?;
-        bmx_decoder.set_pcwstr(w!("FriendlyName"), w!("BMX Decoder"))?;
-        bmx_decoder.set_pcwstr(w!("MimeTypes"), MIME_TYPE)?;
-        bmx_decoder.set_u32(w!("SupportLossless"), 1)?;
-        bmx_decoder.set_guid(w!("VendorGUID"), &VENDOR)?;
-
-        let formats = bmx_decoder.create_subkey(w!("Formats"))?;
-        _ = formats.create_subkey(PCWSTR::from_raw(
-            GUID_WICPixelFormat1bppIndexed.to_wide().as_ptr(),
-        ))?;
-        _ = formats.create_subkey(PCWSTR::from_raw(
-            GUID_WICPixelFormat2bppIndexed.to_wide().as_ptr(),
-        ))?;
-        _ = formats.create_subkey(PCWSTR::from_raw(
-            GUID_WICPixelFormat4bppIndexed.to_wide().as_ptr(),
-        ))?;
-        _ = formats.create_subkey(PCWSTR::from_raw(
-            GUID_WICPixelFormat8bppIndexed.to_wide().as_ptr(),
-        ))?;
+        let open_with_list = bmx.create_subkey(w!("OpenWithList"))?;
+        _ = open_with_list.create_subkey(w!("PhotoViewer.dll"))?;
 
-        let patterns = bmx_decoder.create_subkey(w!("Patterns"))?;
-        let first_pattern = patterns.create_subkey(w!("0"))?;
-        first_pattern.set_u32(w!("Position"), 0)?;
+        let open_with_prog_ids = bmx.create_subkey(w!("OpenWithProgids"))?;
+        open_with_prog_ids.set_pcwstr(PROG_ID, w!(""))?;
+        open_with_prog_ids.set_pcwstr(PHOTOS_PROG_ID, w!(""))?;
 
-        first_pattern.set_binary(w!("Pattern"), b"BMX\x01")?;
-        first_pattern.set_binary(w!("Mask"), &[0xFF, 0xFF, 0xFF, 0xFF])?;
-        first_pattern.set_u32(w!("Length"), 4)?;
+        bmx.create_subkey(w!("PersistentHandler"))?
+            .set_guid(PCWSTR::null(), &Filter::PERSISTENT_HANDLER)?;
     }
 
+    // The indexer looks up the filter through the persistent handler instead of the extension.
     {
-        let category = classes_root
+        let clsid_string = Filter::PERSISTENT_HANDLER.to_wide();
+        let persistent_handler = classes_root
             .create_subkey(w!("CLSID"))?
-            .create_subkey(PCWSTR::from_raw(CATID_WICBitmapDecoders.to_wide().as_ptr()))?;
+            .create_subkey(PCWSTR::from_raw(clsid_string.as_ptr()))?;
+        persistent_handler.set_pcwstr(PCWSTR::null(), w!("BMX Persistent Handler"))?;
 
-        let instance = category.create_subkey(w!("Instance"))?;
-
-        let bmx_decoder =
-            instance.create_subkey(PCWSTR::from_raw(BitmapDecoder::CLSID.to_wide().as_ptr()))?;
-        bmx_decoder.set_guid(w!("CLSID"), &BitmapDecoder::CLSID)?;
-        bmx_decoder.set_pcwstr(w!("FriendlyName"), w!("BMX Decoder"))?;
-    }
-
-    {
-        let bmx_encoder = register_com_extension::<BitmapEncoder>(
-            classes_root,
-            module_path,
-            w!("BMX File"),
-            w!("Both"),
-        )?;
-
-        bmx_encoder.set_pcwstr(w!("Author"), w!("Fulgen"))?;
-        bmx_encoder.set_guid(w!("ContainerFormat"), &CONTAINER_FORMAT)?;
-        bmx_encoder.set_pcwstr(w!("Description"), w!("BMX Encoder"))?;
-        bmx_encoder.set_pcwstr(w!("FileExtensions"), EXTENSION)?;
-        bmx_encoder.set_pcwstr(w!("FriendlyName"), w!("BMX Encoder"))?;
-        bmx_encoder.set_pcwstr(w!("MimeTypes"), MIME_TYPE)?;
-        bmx_encoder.set_u32(w!("SupportLossless"), 1)?;
-        bmx_encoder.set_guid(w!("VendorGUID"), &VENDOR)?;
-
-        let formats = bmx_encoder.create_subkey(w!("Formats"))?;
-        _ = formats.create_subkey(PCWSTR::from_raw(
-            GUID_WICPixelFormat1bppIndexed.to_wide().as_ptr(),
-        ))?;
-        _ = formats.create_subkey(PCWSTR::from_raw(
-            GUID_WICPixelFormat2bppIndexed.to_wide().as_ptr(),
-        ))?;
-        _ = formats.create_subkey(PCWSTR::from_raw(
-            GUID_WICPixelFormat4bppIndexed.to_wide().as_ptr(),
-        ))?;
-        _ = formats.create_subkey(PCWSTR::from_raw(
-            GUID_WICPixelFormat8bppIndexed.to_wide().as_ptr(),
-        ))?;
-    }
-
-    {
-        let category = classes_root
-            .create_subkey(w!("CLSID"))?
-            .create_subkey(PCWSTR::from_raw(CATID_WICBitmapEncoders.to_wide().as_ptr()))?;
-
-        let instance = category.create_subkey(w!("Instance"))?;
-
-        let bmx_encoder =
-            instance.create_subkey(PCWSTR::from_raw(BitmapEncoder::CLSID.to_wide().as_ptr()))?;
-        bmx_encoder.set_guid(w!("CLSID"), &BitmapEncoder::CLSID)?;
-        bmx_encoder.set_pcwstr(w!("FriendlyName"), w!("BMX Encoder"))?;
-    }
-
-    {
-        let bmx = classes_root.create_subkey(EXTENSION)?;
-        bmx.set_pcwstr(PCWSTR::null(), PROG_ID)?;
-        bmx.set_pcwstr(w!("Content Type"), MIME_TYPE)?;
-        bmx.set_pcwstr(w!("PerceivedType"), w!("image"))?;
-
-        let open_with_list = bmx.create_subkey(w!("OpenWithList"))?;
-        _ = open_with_list.create_subkey(w!("PhotoViewer.dll"))?;
+        persistent_handler
+            .create_subkey(w!("PersistentAddinsRegistered"))?
+            .create_subkey(PCWSTR::from_raw(IFilter::IID.to_wide().as_ptr()))?
+            .set_guid(PCWSTR::null(), &Filter::CLSID)?;
     }
 
     {
         let systems_file_associations = classes_root.create_subkey(w!("SystemFileAssociations"))?;
         let bmx = systems_file_associations.create_subkey(EXTENSION)?;
         bmx.set_pcwstr(w!("PreviewDetails"), PREVIEW_DETAILS)?;
+        bmx.set_pcwstr(w!("FullDetails"), FULL_DETAILS)?;
+        bmx.set_pcwstr(w!("InfoTip"), INFO_TIP)?;
 
         let open_with_list = bmx.create_subkey(w!("OpenWithList"))?;
         _ = open_with_list.create_subkey(w!("PhotoViewer.dll"))?;
 
         let shellex = bmx.create_subkey(w!("ShellEx"))?;
-        let thumbnail_provider =
-            shellex.create_subkey(PCWSTR::from_raw(IThumbnailProvider::IID.to_wide().as_ptr()))?;
-        thumbnail_provider
-            .set_pcwstr(PCWSTR::null(), w!("{C7657C4A-9F68-40fa-A4DF-96BC08EB3551}"))?;
+        shellex
+            .create_subkey(PCWSTR::from_raw(IThumbnailProvider::IID.to_wide().as_ptr()))?
+            .set_guid(PCWSTR::null(), &ThumbnailProvider::CLSID)?;
+
+        // Adds CF_DIB to dragged and copied files, and the image for hosts still using
+        // IExtractImage.
+        shellex
+            .create_subkey(w!("DataHandler"))?
+            .set_guid(PCWSTR::null(), &DataHandler::CLSID)?;
+        shellex
+            .create_subkey(PCWSTR::from_raw(IExtractImage::IID.to_wide().as_ptr()))?
+            .set_guid(PCWSTR::null(), &DataHandler::CLSID)?;
 
         let context_menu_handlers = bmx.create_subkey(w!("ContextMenuHandlers"))?;
         let shell_image_preview = context_menu_handlers.create_subkey(w!("ShellImagePreview"))?;
@@ -598,13 +1951,33 @@ pub fn register_server<'a>(
     }
 
     {
-        let _property_store = register_com_extension::<PropertyStore>(
-            classes_root,
-            module_path,
-            w!("BMXPropertyStore"),
-            w!("Both"),
-        );
+        let capabilities = Key::predefined(transaction, HKEY_LOCAL_MACHINE, CAPABILITIES)?;
+        capabilities.set_pcwstr(w!("ApplicationName"), APPLICATION_NAME)?;
+        capabilities.set_pcwstr(w!("ApplicationDescription"), APPLICATION_DESCRIPTION)?;
+
+        let file_associations = capabilities.create_subkey(w!("FileAssociations"))?;
+        file_associations.set_pcwstr(EXTENSION, PROG_ID)?;
+
+        let mime_associations = capabilities.create_subkey(w!("MimeAssociations"))?;
+        mime_associations.set_pcwstr(MIME_TYPE, PROG_ID)?;
+
+        let registered_applications = Key::predefined(
+            transaction,
+            HKEY_LOCAL_MACHINE,
+            w!("Software\\RegisteredApplications"),
+        )?;
+        registered_applications.set_pcwstr(APPLICATION_NAME, CAPABILITIES)?;
+    }
 
+    // Windows Photo Viewer only offers itself for the extensions it lists, and hands them to WIC.
+    Key::predefined(
+        transaction,
+        HKEY_LOCAL_MACHINE,
+        PHOTO_VIEWER_FILE_ASSOCIATIONS,
+    )?
+    .set_pcwstr(EXTENSION, PHOTO_VIEWER_PROG_ID)?;
+
+    {
         let property_handlers = Key::predefined(
             transaction,
             HKEY_LOCAL_MACHINE,
@@ -615,24 +1988,80 @@ pub fn register_server<'a>(
         bmx.set_guid(PCWSTR::null(), &PropertyStore::CLSID)?;
     }
 
-    {
-        let _transcode = register_com_extension::<Transcode>(
-            classes_root,
-            module_path,
-            w!("Transcode"),
-            w!("Both"),
-        );
+    register_com_classes(classes_root, module_path)?;
+    register_explorer_command_verb::<Transcode>(classes_root)?;
+    register_explorer_command_verb::<VeraPreview>(classes_root)?;
+    register_explorer_command_verb::<SendToEmulator>(classes_root)?;
+    register_explorer_command_verb::<PasteAsBmx>(classes_root)?;
 
-        let file = classes_root.create_subkey(w!("*"))?;
-        let shell = file.create_subkey(w!("shell"))?;
-        let transcode = shell.create_subkey(w!("Transcode"))?;
-        //transcode.set_pcwstr(w!("AppliesTo"), w!("System.Kind:picture"))?;
-        transcode.set_guid(w!("ExplorerCommandHandler"), &Transcode::CLSID)?;
+    if !transaction.is_dry_run() && !transaction.is_test_hive() {
+        grant_app_container_access(classes_root, &module_path)?;
+        register_property_schema(&module_path)?;
     }
 
-    transaction
-        .commit()
-        .map(|_| unsafe { SHChangeNotify(SHCNE_ASSOCCHANGED, SHCNF_FLAGS(0), None, None) })?;
+    transaction.commit()?;
+
+    if !transaction.is_dry_run() && !transaction.is_test_hive() {
+        unsafe { SHChangeNotify(SHCNE_ASSOCCHANGED, SHCNF_FLAGS(0), None, None) };
+
+        // Thumbnails Explorer already shows were made by whatever codec was registered before, so
+        // re-registering after an update has every image refreshed.
+        unsafe {
+            SHChangeNotify(
+                SHCNE_UPDATEIMAGE,
+                SHCNF_DWORD,
+                Some(usize::MAX as *const c_void),
+                None,
+            )
+        };
+    }
+
+    Ok(())
+}
+
+const STALE_LOCAL_MACHINE_VALUES: [(PCWSTR, PCWSTR); 1] = [(
+    w!("Software\\Microsoft\\Windows\\CurrentVersion\\KindMap"),
+    EXTENSION,
+)];
+
+fn remove_stale_registrations(
+    transaction: &Transaction,
+    classes_root: &Key,
+    module_path: NullTerminatedSlice,
+) -> windows::core::Result<()> {
+    for (sub_key, value) in STALE_LOCAL_MACHINE_VALUES {
+        if let Some(key) =
+            Key::predefined(transaction, HKEY_LOCAL_MACHINE, w!(""))?.try_open_subkey(sub_key)?
+        {
+            key.delete_value(value)?;
+        }
+    }
+
+    let module_path = String::from_utf16_lossy(wstr::until_nul(&module_path)).to_lowercase();
+
+    let Some(clsid) = classes_root.try_open_subkey(w!("CLSID"))? else {
+        return Ok(());
+    };
+
+    for name in clsid.subkey_names()? {
+        let name = HSTRING::from(name);
+
+        let Some(inproc) = clsid
+            .try_open_subkey(PCWSTR::from_raw(name.as_ptr()))?
+            .map(|class| class.try_open_subkey(w!("InprocServer32")))
+            .transpose()?
+            .flatten()
+        else {
+            continue;
+        };
+
+        if inproc
+            .get_string(PCWSTR::null())?
+            .is_some_and(|path| path.to_lowercase() == module_path)
+        {
+            clsid.delete_subkey(PCWSTR::from_raw(name.as_ptr()))?;
+        }
+    }
 
     Ok(())
 }
@@ -640,27 +2069,31 @@ pub fn register_server<'a>(
 pub fn unregister_server<'a>(
     transaction: &'a Transaction,
     classes_root: &'a Key,
+    module_path: &[u16],
 ) -> windows::core::Result<()> {
-    classes_root.delete_subkey(PROG_ID)?;
-
-    unregister_com_extension::<BitmapDecoder>(classes_root)?;
-    unregister_com_extension::<BitmapEncoder>(classes_root)?;
-    unregister_com_extension::<PropertyStore>(classes_root)?;
+    let module_path = NullTerminatedSlice::new(module_path)
+        .map_err(|_| windows::core::Error::from(E_BLUETOOTH_ATT_ATTRIBUTE_NOT_FOUND))?;
 
-    let clsid = classes_root.open_subkey(w!("CLSID"))?;
+    if !transaction.is_dry_run() && !transaction.is_test_hive() {
+        revoke_app_container_access(classes_root, &module_path)?;
+    }
 
-    clsid
-        .open_subkey(PCWSTR::from_raw(CATID_WICBitmapDecoders.to_wide().as_ptr()))?
-        .open_subkey(w!("Instance"))?
-        .delete_subkey(PCWSTR::from_raw(BitmapDecoder::CLSID.to_wide().as_ptr()))?;
+    classes_root.delete_subkey(PROG_ID)?;
 
-    clsid
-        .open_subkey(PCWSTR::from_raw(CATID_WICBitmapEncoders.to_wide().as_ptr()))?
-        .open_subkey(w!("Instance"))?
-        .delete_subkey(PCWSTR::from_raw(BitmapEncoder::CLSID.to_wide().as_ptr()))?;
+    unregister_com_classes(classes_root)?;
+    unregister_explorer_command_verb::<Transcode>(classes_root)?;
+    unregister_explorer_command_verb::<VeraPreview>(classes_root)?;
+    unregister_explorer_command_verb::<SendToEmulator>(classes_root)?;
+    unregister_explorer_command_verb::<PasteAsBmx>(classes_root)?;
 
     classes_root.delete_subkey(EXTENSION)?;
 
+    if let Some(clsid) = classes_root.try_open_subkey(w!("CLSID"))? {
+        clsid.delete_subkey(PCWSTR::from_raw(
+            Filter::PERSISTENT_HANDLER.to_wide().as_ptr(),
+        ))?;
+    }
+
     classes_root
         .open_subkey(w!("SystemFileAssociations"))?
         .delete_subkey(EXTENSION)?;
@@ -668,7 +2101,7 @@ pub fn unregister_server<'a>(
     Key::predefined(
         transaction,
         HKEY_LOCAL_MACHINE,
-        w!("Software\\Microsoft\\Windows\\CurrentVersion\\KindMap"),
+        w!("Software\\Microsoft\\Windows\\CurrentVersion\\Explorer\\KindMap"),
     )?
     .delete_value(EXTENSION)?;
 
@@ -679,9 +2112,323 @@ pub fn unregister_server<'a>(
     )?
     .delete_subkey(EXTENSION)?;
 
-    transaction
-        .commit()
-        .map(|_| unsafe { SHChangeNotify(SHCNE_ASSOCCHANGED, SHCNF_FLAGS(0), None, None) })?;
+    Key::predefined(
+        transaction,
+        HKEY_LOCAL_MACHINE,
+        w!("Software\\RegisteredApplications"),
+    )?
+    .delete_value(APPLICATION_NAME)?;
+
+    Key::predefined(transaction, HKEY_LOCAL_MACHINE, w!(""))?.delete_subkey(CAPABILITIES)?;
+
+    Key::predefined(
+        transaction,
+        HKEY_LOCAL_MACHINE,
+        PHOTO_VIEWER_FILE_ASSOCIATIONS,
+    )?
+    .delete_value(EXTENSION)?;
+
+    remove_stale_registrations(transaction, classes_root, module_path)?;
+
+    if !transaction.is_dry_run() && !transaction.is_test_hive() {
+        unregister_property_schema(&module_path)?;
+    }
+
+    transaction.commit()?;
+
+    if !transaction.is_dry_run() && !transaction.is_test_hive() {
+        unsafe { SHChangeNotify(SHCNE_ASSOCCHANGED, SHCNF_FLAGS(0), None, None) };
+    }
 
     Ok(())
 }
+
+#[cfg(all(test, windows))]
+mod tests {
+    use windows::Win32::System::Registry::HKEY_CURRENT_USER;
+
+    use super::*;
+
+    // Registers into HKEY_CURRENT_USER\<hive> and reads the result back with a plain transaction.
+    struct TestHive(String);
+
+    impl TestHive {
+        fn new() -> Self {
+            Self(format!(
+                "Software\\X16BMX\\TestHive\\{}",
+                std::process::id()
+            ))
+        }
+
+        fn transaction(&self) -> Transaction {
+            Transaction::new(false).unwrap().with_test_hive(&self.0)
+        }
+
+        fn read<T>(&self, read: impl FnOnce(&Key) -> T) -> T {
+            let transaction = Transaction::new(false).unwrap();
+            let name = HSTRING::from(self.0.as_str());
+            let root = Key::predefined(
+                &transaction,
+                HKEY_CURRENT_USER,
+                PCWSTR::from_raw(name.as_ptr()),
+            )
+            .unwrap();
+
+            read(&root)
+        }
+    }
+
+    impl Drop for TestHive {
+        fn drop(&mut self) {
+            let transaction = Transaction::new(false).unwrap();
+            let name = HSTRING::from(self.0.as_str());
+
+            if let Ok(root) = Key::predefined(
+                &transaction,
+                HKEY_CURRENT_USER,
+                PCWSTR::from_raw(name.as_ptr()),
+            ) {
+                let _ = root.delete_tree();
+                let _ = transaction.commit();
+            }
+        }
+    }
+
+    #[test]
+    fn canonicalizes_file_extensions() {
+        assert_eq!(canonical_file_extensions(".bmx"), ".bmx");
+        assert_eq!(canonical_file_extensions("BMX; .Bin,,.bmx , "), ".bmx,.bin");
+        assert_eq!(canonical_file_extensions(""), "");
+    }
+
+    #[test]
+    fn outbids_conflicting_decoders() {
+        assert!(claims_extension(".png,.BMX", ".bmx"));
+        assert!(!claims_extension(".bmxx,.bin", ".bmx"));
+
+        assert_eq!(arbitration_priority([]), ARBITRATION_PRIORITY);
+        assert_eq!(arbitration_priority([None, Some(3)]), ARBITRATION_PRIORITY);
+        assert_eq!(
+            arbitration_priority([Some(ARBITRATION_PRIORITY), None]),
+            ARBITRATION_PRIORITY + 1
+        );
+        assert_eq!(arbitration_priority([Some(u32::MAX)]), u32::MAX);
+    }
+
+    #[test]
+    fn outbids_conflicting_decoder_in_test_hive() {
+        const OTHER_DECODER: GUID = guid::from_str("0b2c4e51-7a1d-4f6e-9c3b-5d8e2f1a6b7c");
+
+        let hive = TestHive::new();
+        let module_path_wide = wstr::encode_with_nul("C:\\bmx-shell\\bmx_shell.dll");
+
+        {
+            let transaction = hive.transaction();
+            let clsid = Key::predefined(&transaction, HKEY_CLASSES_ROOT, w!("CLSID")).unwrap();
+            clsid
+                .create_subkey(PCWSTR::from_raw(CATID_WICBitmapDecoders.to_wide().as_ptr()))
+                .unwrap()
+                .create_subkey(w!("Instance"))
+                .unwrap()
+                .create_subkey(PCWSTR::from_raw(OTHER_DECODER.to_wide().as_ptr()))
+                .unwrap();
+
+            let other = clsid
+                .create_subkey(PCWSTR::from_raw(OTHER_DECODER.to_wide().as_ptr()))
+                .unwrap();
+            other.set_pcwstr(w!("FileExtensions"), w!(".BMX")).unwrap();
+            other.set_u32(w!("ArbitrationPriority"), 50).unwrap();
+            transaction.commit().unwrap();
+        }
+
+        {
+            let transaction = hive.transaction();
+            let classes_root = Key::predefined(&transaction, HKEY_CLASSES_ROOT, w!("")).unwrap();
+            assert_eq!(
+                find_conflicting_decoders(&classes_root).unwrap()[0].clsid,
+                OTHER_DECODER
+            );
+            register_server(&transaction, &classes_root, &module_path_wide).unwrap();
+        }
+
+        let decoder = HSTRING::from(format!(
+            "HKEY_CLASSES_ROOT\\CLSID\\{}",
+            Guid(BitmapDecoder::CLSID)
+        ));
+        hive.read(|root| {
+            let decoder = root
+                .open_subkey(PCWSTR::from_raw(decoder.as_ptr()))
+                .unwrap();
+            assert_eq!(
+                decoder.get_u32(w!("ArbitrationPriority")).unwrap(),
+                Some(51)
+            );
+            assert_eq!(
+                decoder.get_string(w!("Version")).unwrap(),
+                unsafe { VERSION.to_string() }.ok()
+            );
+            assert_eq!(
+                decoder.get_u32(w!("BitsPerPixel")).unwrap(),
+                Some(BITS_PER_PIXEL)
+            );
+            assert_eq!(
+                decoder
+                    .get_string(w!("ColorFormats"))
+                    .unwrap()
+                    .map(|formats| formats
+                        .split(',')
+                        .map(guid::parse)
+                        .collect::<Result<Vec<_>, _>>()),
+                Some(Ok(PIXEL_FORMATS.to_vec()))
+            );
+        });
+    }
+
+    #[test]
+    fn register_and_unregister_in_test_hive() {
+        let hive = TestHive::new();
+        let module_path = "C:\\bmx-shell\\bmx_shell.dll";
+        let module_path_wide = wstr::encode_with_nul(module_path);
+
+        let clsid = HSTRING::from(format!(
+            "HKEY_CLASSES_ROOT\\CLSID\\{}\\InprocServer32",
+            Guid(BitmapDecoder::CLSID)
+        ));
+
+        let filter = HSTRING::from(format!(
+            "HKEY_CLASSES_ROOT\\CLSID\\{}\\PersistentAddinsRegistered\\{}",
+            Guid(Filter::PERSISTENT_HANDLER),
+            Guid(IFilter::IID)
+        ));
+
+        let thumbnail_provider = HSTRING::from(format!(
+            "HKEY_CLASSES_ROOT\\SystemFileAssociations\\.bmx\\ShellEx\\{}",
+            Guid(IThumbnailProvider::IID)
+        ));
+
+        let metadata_reader = HSTRING::from(format!(
+            "HKEY_CLASSES_ROOT\\CLSID\\{}\\Instance\\{}",
+            Guid(CATID_WICMetadataReader),
+            Guid(ReservedMetadataReader::CLSID)
+        ));
+
+        {
+            let transaction = hive.transaction();
+            let classes_root = Key::predefined(&transaction, HKEY_CLASSES_ROOT, w!("")).unwrap();
+            register_server(&transaction, &classes_root, &module_path_wide).unwrap();
+        }
+
+        hive.read(|root| {
+            let inproc = root
+                .open_subkey(PCWSTR::from_raw(clsid.as_ptr()))
+                .unwrap();
+            assert_eq!(
+                inproc.get_string(PCWSTR::null()).unwrap().as_deref(),
+                Some(module_path)
+            );
+            assert_eq!(
+                inproc.get_string(w!("ThreadingModel")).unwrap().as_deref(),
+                Some(BitmapDecoder::THREADING.name())
+            );
+
+            let kind_map = root
+                .open_subkey(w!(
+                    "HKEY_LOCAL_MACHINE\\Software\\Microsoft\\Windows\\CurrentVersion\\Explorer\\KindMap"
+                ))
+                .unwrap();
+            assert_eq!(
+                kind_map.get_string(EXTENSION).unwrap().as_deref(),
+                Some("Picture")
+            );
+
+            let thumbnail_provider = root
+                .open_subkey(PCWSTR::from_raw(thumbnail_provider.as_ptr()))
+                .unwrap();
+            assert_eq!(
+                thumbnail_provider
+                    .get_string(PCWSTR::null())
+                    .unwrap()
+                    .map(|value| guid::parse(&value)),
+                Some(Ok(ThumbnailProvider::CLSID))
+            );
+
+            let photo_viewer = root
+                .open_subkey(w!("HKEY_LOCAL_MACHINE\\Software\\Microsoft\\Windows Photo Viewer\\Capabilities\\FileAssociations"))
+                .unwrap();
+            assert_eq!(
+                photo_viewer.get_string(EXTENSION).unwrap().as_deref(),
+                Some("PhotoViewer.FileAssoc.Tiff")
+            );
+
+            let file_associations = root
+                .open_subkey(w!("HKEY_CLASSES_ROOT\\SystemFileAssociations\\.bmx"))
+                .unwrap();
+            assert!(file_associations
+                .get_string(w!("FullDetails"))
+                .unwrap()
+                .is_some_and(|value| value.contains("X16BMX.Image.PaletteSize")));
+
+            let data_handler = root
+                .open_subkey(w!(
+                    "HKEY_CLASSES_ROOT\\SystemFileAssociations\\.bmx\\ShellEx\\DataHandler"
+                ))
+                .unwrap();
+            assert_eq!(
+                data_handler
+                    .get_string(PCWSTR::null())
+                    .unwrap()
+                    .map(|value| guid::parse(&value)),
+                Some(Ok(DataHandler::CLSID))
+            );
+
+            let prog_id = root
+                .open_subkey(w!("HKEY_CLASSES_ROOT\\bmxfile"))
+                .unwrap();
+            assert_eq!(prog_id.get_u32(TREATMENT).unwrap(), Some(TREATMENT_NONE));
+
+            let metadata_reader = root
+                .open_subkey(PCWSTR::from_raw(metadata_reader.as_ptr()))
+                .unwrap();
+            assert_eq!(
+                metadata_reader.get_string(w!("FriendlyName")).unwrap().as_deref(),
+                Some("BMX Reserved Metadata Reader")
+            );
+
+            let filter = root
+                .open_subkey(PCWSTR::from_raw(filter.as_ptr()))
+                .unwrap();
+            assert_eq!(
+                filter
+                    .get_string(PCWSTR::null())
+                    .unwrap()
+                    .map(|value| guid::parse(&value)),
+                Some(Ok(Filter::CLSID))
+            );
+        });
+
+        {
+            let transaction = hive.transaction();
+            let classes_root = Key::predefined(&transaction, HKEY_CLASSES_ROOT, w!("")).unwrap();
+            unregister_server(&transaction, &classes_root, &module_path_wide).unwrap();
+        }
+
+        hive.read(|root| {
+            assert!(root
+                .try_open_subkey(PCWSTR::from_raw(clsid.as_ptr()))
+                .unwrap()
+                .is_none());
+            assert!(root
+                .try_open_subkey(PCWSTR::from_raw(metadata_reader.as_ptr()))
+                .unwrap()
+                .is_none());
+            assert!(root
+                .try_open_subkey(PCWSTR::from_raw(filter.as_ptr()))
+                .unwrap()
+                .is_none());
+            assert!(root
+                .try_open_subkey(w!("HKEY_CLASSES_ROOT\\.bmx"))
+                .unwrap()
+                .is_none());
+        });
+    }
+}
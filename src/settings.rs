@@ -0,0 +1,286 @@
+use std::path::{Path, PathBuf};
+
+use windows::Win32::Foundation::ERROR_FILE_NOT_FOUND;
+use windows::Win32::Graphics::Imaging::{
+    WICBitmapDitherType, WICBitmapDitherTypeErrorDiffusion, WICBitmapDitherTypeNone,
+    WICBitmapDitherTypeOrdered8x8,
+};
+use windows::Win32::System::Registry::{
+    RegDeleteKeyValueW, RegGetValueW, RegSetKeyValueW, HKEY_CURRENT_USER, REG_DWORD, REG_SZ,
+    RRF_RT_REG_DWORD, RRF_RT_REG_SZ,
+};
+use windows_core::{w, PCWSTR};
+
+use crate::com::shell::command::send_to_emulator::SendToEmulator;
+use crate::com::wic::decoder::{BitmapDecoder, EXPAND_TO_8BPP, TOLERATE_TRUNCATION};
+use crate::registry::{get_class_setting, get_class_string_setting};
+use crate::util::{is_low_privilege_process, wstr};
+
+// Per-user settings, as changed from the options dialog. Values that aren't set here fall back to
+// the ones under the CLSID keys, which remain the place for machine-wide defaults.
+const KEY: PCWSTR = w!("Software\\X16BMX");
+
+// A Dithering value.
+pub const DITHERING: PCWSTR = w!("Dithering");
+// A ThumbnailBackground value.
+pub const THUMBNAIL_BACKGROUND: PCWSTR = w!("ThumbnailBackground");
+// Leaves the format of a single selected file out of the Transcode submenu. On by default.
+pub const HIDE_SOURCE_FORMAT: PCWSTR = w!("HideSourceFormat");
+// Path of x16emu.exe, for Send to X16 emulator.
+pub const EMULATOR_PATH: PCWSTR = w!("EmulatorPath");
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Dithering {
+    None,
+    Ordered,
+    #[default]
+    ErrorDiffusion,
+}
+
+impl Dithering {
+    pub const ALL: [Self; 3] = [Self::None, Self::Ordered, Self::ErrorDiffusion];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::None => "None",
+            Self::Ordered => "Ordered",
+            Self::ErrorDiffusion => "Error diffusion",
+        }
+    }
+}
+
+impl From<Dithering> for WICBitmapDitherType {
+    fn from(dithering: Dithering) -> Self {
+        match dithering {
+            Dithering::None => WICBitmapDitherTypeNone,
+            Dithering::Ordered => WICBitmapDitherTypeOrdered8x8,
+            Dithering::ErrorDiffusion => WICBitmapDitherTypeErrorDiffusion,
+        }
+    }
+}
+
+// What fills the space around thumbnails and previews that don't match their aspect ratio.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ThumbnailBackground {
+    // The border color, as it would look on the X16.
+    #[default]
+    BorderColor,
+    // Nothing; the image keeps its own aspect ratio and the host decides.
+    None,
+}
+
+impl ThumbnailBackground {
+    pub const ALL: [Self; 2] = [Self::BorderColor, Self::None];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::BorderColor => "Border color",
+            Self::None => "None",
+        }
+    }
+}
+
+fn from_index<T: Copy + Default, const N: usize>(all: [T; N], value: Option<u32>) -> T {
+    value
+        .and_then(|value| all.get(value as usize).copied())
+        .unwrap_or_default()
+}
+
+fn index_of<T: PartialEq, const N: usize>(all: [T; N], value: T) -> u32 {
+    all.iter().position(|v| *v == value).unwrap_or_default() as u32
+}
+
+pub fn get_u32(name: PCWSTR) -> Option<u32> {
+    if is_low_privilege_process() {
+        return None;
+    }
+
+    let mut value = 0u32;
+    let mut size = std::mem::size_of_val(&value) as u32;
+
+    unsafe {
+        RegGetValueW(
+            HKEY_CURRENT_USER,
+            KEY,
+            name,
+            RRF_RT_REG_DWORD,
+            None,
+            Some((&raw mut value).cast()),
+            Some(&raw mut size),
+        )
+    }
+    .ok()
+    .ok()?;
+
+    Some(value)
+}
+
+// Empty strings count as unset.
+pub fn get_string(name: PCWSTR) -> Option<String> {
+    if is_low_privilege_process() {
+        return None;
+    }
+
+    let mut size = 0u32;
+
+    unsafe {
+        RegGetValueW(
+            HKEY_CURRENT_USER,
+            KEY,
+            name,
+            RRF_RT_REG_SZ,
+            None,
+            None,
+            Some(&raw mut size),
+        )
+    }
+    .ok()
+    .ok()?;
+
+    let mut buffer = vec![0u16; (size as usize).div_ceil(2)];
+
+    unsafe {
+        RegGetValueW(
+            HKEY_CURRENT_USER,
+            KEY,
+            name,
+            RRF_RT_REG_SZ,
+            None,
+            Some(buffer.as_mut_ptr().cast()),
+            Some(&raw mut size),
+        )
+    }
+    .ok()
+    .ok()?;
+
+    let len = buffer.iter().position(|&c| c == 0).unwrap_or(buffer.len());
+    Some(String::from_utf16_lossy(&buffer[..len])).filter(|value| !value.is_empty())
+}
+
+pub fn set_u32(name: PCWSTR, value: u32) -> windows::core::Result<()> {
+    unsafe {
+        RegSetKeyValueW(
+            HKEY_CURRENT_USER,
+            KEY,
+            name,
+            REG_DWORD.0,
+            Some((&raw const value).cast()),
+            std::mem::size_of_val(&value) as u32,
+        )
+    }
+    .ok()
+}
+
+// An empty string removes the value, so the machine-wide default applies again.
+pub fn set_string(name: PCWSTR, value: &str) -> windows::core::Result<()> {
+    if value.is_empty() {
+        return delete(name);
+    }
+
+    let value = wstr::encode_with_nul(value);
+
+    unsafe {
+        RegSetKeyValueW(
+            HKEY_CURRENT_USER,
+            KEY,
+            name,
+            REG_SZ.0,
+            Some(value.as_ptr().cast()),
+            (value.len() * 2) as u32,
+        )
+    }
+    .ok()
+}
+
+pub fn delete(name: PCWSTR) -> windows::core::Result<()> {
+    match unsafe { RegDeleteKeyValueW(HKEY_CURRENT_USER, KEY, name) } {
+        ERROR_FILE_NOT_FOUND => Ok(()),
+        result => result.ok(),
+    }
+}
+
+pub fn tolerate_truncation() -> bool {
+    get_u32(TOLERATE_TRUNCATION)
+        .or_else(|| get_class_setting::<BitmapDecoder>(TOLERATE_TRUNCATION))
+        .unwrap_or(0)
+        != 0
+}
+
+pub fn set_tolerate_truncation(value: bool) -> windows::core::Result<()> {
+    set_u32(TOLERATE_TRUNCATION, value as u32)
+}
+
+pub fn expand_to_8bpp() -> bool {
+    get_u32(EXPAND_TO_8BPP)
+        .or_else(|| get_class_setting::<BitmapDecoder>(EXPAND_TO_8BPP))
+        .unwrap_or(0)
+        != 0
+}
+
+pub fn set_expand_to_8bpp(value: bool) -> windows::core::Result<()> {
+    set_u32(EXPAND_TO_8BPP, value as u32)
+}
+
+// Used whenever images are reduced to a palette, e.g. when transcoding to BMX.
+pub fn dithering() -> Dithering {
+    from_index(Dithering::ALL, get_u32(DITHERING))
+}
+
+pub fn set_dithering(value: Dithering) -> windows::core::Result<()> {
+    set_u32(DITHERING, index_of(Dithering::ALL, value))
+}
+
+pub fn emulator_path() -> Option<PathBuf> {
+    get_string(EMULATOR_PATH)
+        .or_else(|| get_class_string_setting::<SendToEmulator>(EMULATOR_PATH))
+        .map(PathBuf::from)
+}
+
+pub fn set_emulator_path(value: Option<&Path>) -> windows::core::Result<()> {
+    set_string(
+        EMULATOR_PATH,
+        &value.map(|path| path.to_string_lossy()).unwrap_or_default(),
+    )
+}
+
+pub fn thumbnail_background() -> ThumbnailBackground {
+    from_index(ThumbnailBackground::ALL, get_u32(THUMBNAIL_BACKGROUND))
+}
+
+pub fn set_thumbnail_background(value: ThumbnailBackground) -> windows::core::Result<()> {
+    set_u32(
+        THUMBNAIL_BACKGROUND,
+        index_of(ThumbnailBackground::ALL, value),
+    )
+}
+
+pub fn hide_source_format() -> bool {
+    get_u32(HIDE_SOURCE_FORMAT).is_none_or(|value| value != 0)
+}
+
+pub fn set_hide_source_format(value: bool) -> windows::core::Result<()> {
+    set_u32(HIDE_SOURCE_FORMAT, value as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn enum_values() {
+        for dithering in Dithering::ALL {
+            let index = index_of(Dithering::ALL, dithering);
+            assert_eq!(from_index(Dithering::ALL, Some(index)), dithering);
+        }
+
+        assert_eq!(from_index(Dithering::ALL, None), Dithering::ErrorDiffusion);
+        assert_eq!(
+            from_index(Dithering::ALL, Some(7)),
+            Dithering::ErrorDiffusion
+        );
+        assert_eq!(
+            from_index(ThumbnailBackground::ALL, Some(1)),
+            ThumbnailBackground::None
+        );
+    }
+}
@@ -1,114 +1,31 @@
+use std::sync::OnceLock;
+
 use windows::{
-    core::PCWSTR,
+    core::{Owned, PCWSTR},
     Win32::{
-        Foundation::HMODULE,
-        System::LibraryLoader::{
-            GetModuleFileNameW, GetModuleHandleExW, GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS,
-            GET_MODULE_HANDLE_EX_FLAG_UNCHANGED_REFCOUNT,
+        Foundation::{FreeLibrary, HANDLE, HMODULE},
+        Security::{
+            GetSidSubAuthority, GetSidSubAuthorityCount, GetTokenInformation, TokenIntegrityLevel,
+            TokenIsAppContainer, TOKEN_MANDATORY_LABEL, TOKEN_QUERY,
+        },
+        System::{
+            LibraryLoader::{
+                FreeLibraryAndExitThread, GetModuleFileNameW, GetModuleHandleExW,
+                GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS,
+                GET_MODULE_HANDLE_EX_FLAG_UNCHANGED_REFCOUNT,
+            },
+            SystemServices::SECURITY_MANDATORY_MEDIUM_RID,
+            Threading::{GetCurrentProcess, OpenProcessToken},
+        },
+        UI::HiDpi::{
+            SetThreadDpiAwarenessContext, DPI_AWARENESS_CONTEXT,
+            DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE_V2,
         },
     },
 };
 
-pub mod guid {
-    use core::str;
-    use std::{
-        fmt::Display,
-        io::{Cursor, Write},
-    };
-
-    use windows::core::GUID;
-
-    struct GuidWrapper<'a>(&'a GUID);
-
-    impl<'a> Display for GuidWrapper<'a> {
-        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-            write!(
-                f,
-                "{{{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}}}",
-                self.0.data1,
-                self.0.data2,
-                self.0.data3,
-                self.0.data4[0],
-                self.0.data4[1],
-                self.0.data4[2],
-                self.0.data4[3],
-                self.0.data4[4],
-                self.0.data4[5],
-                self.0.data4[6],
-                self.0.data4[7]
-            )
-        }
-    }
-
-    pub const fn from_str(value: &str) -> GUID {
-        if !value.is_ascii() {
-            panic!("Invalid GUID characters");
-        }
-
-        let value = value.as_bytes();
-
-        const fn slice_to_str(slice: &[u8]) -> &str {
-            unsafe { str::from_utf8_unchecked(slice) }
-        }
-
-        const fn to_hex(value1: u8, value2: u8) -> u8 {
-            match u8::from_str_radix(slice_to_str(&[value1, value2]), 16) {
-                Ok(data4) => data4,
-                Err(_) => panic!("Invalid GUID data4"),
-            }
-        }
-
-        match value {
-            [a0, a1, a2, a3, a4, a5, a6, a7, b'-', b0, b1, b2, b3, b'-', c0, c1, c2, c3, b'-', d0, d1, d2, d3, b'-', e0, e1, e2, e3, e4, e5, e6, e7, e8, e9, e10, e11]
-            | [b'{', a0, a1, a2, a3, a4, a5, a6, a7, b'-', b0, b1, b2, b3, b'-', c0, c1, c2, c3, b'-', d0, d1, d2, d3, b'-', e0, e1, e2, e3, e4, e5, e6, e7, e8, e9, e10, e11, b'}'] => {
-                GUID {
-                    data1: match u32::from_str_radix(
-                        slice_to_str(&[*a0, *a1, *a2, *a3, *a4, *a5, *a6, *a7]),
-                        16,
-                    ) {
-                        Ok(data1) => data1,
-                        Err(_) => panic!("Invalid GUID data1"),
-                    },
-                    data2: match u16::from_str_radix(slice_to_str(&[*b0, *b1, *b2, *b3]), 16) {
-                        Ok(data2) => data2,
-                        Err(_) => panic!("Invalid GUID data2"),
-                    },
-                    data3: match u16::from_str_radix(slice_to_str(&[*c0, *c1, *c2, *c3]), 16) {
-                        Ok(data3) => data3,
-                        Err(_) => panic!("Invalid GUID data3"),
-                    },
-                    data4: [
-                        to_hex(*d0, *d1),
-                        to_hex(*d2, *d3),
-                        to_hex(*e0, *e1),
-                        to_hex(*e2, *e3),
-                        to_hex(*e4, *e5),
-                        to_hex(*e6, *e7),
-                        to_hex(*e8, *e9),
-                        to_hex(*e10, *e11),
-                    ],
-                }
-            }
-            _ => panic!("Invalid GUID"),
-        }
-    }
-
-    pub trait GuidExt {
-        fn to_ascii_with_nul(&self) -> [u8; 39];
-        fn to_wide(&self) -> [u16; 39] {
-            self.to_ascii_with_nul().map(|value| value as u16)
-        }
-    }
-
-    impl GuidExt for GUID {
-        fn to_ascii_with_nul(&self) -> [u8; 39] {
-            let mut cursor = Cursor::new([0u8; 39]);
-            write!(cursor, "{}", GuidWrapper(self)).unwrap();
-            assert!(cursor.position() == 38);
-            cursor.into_inner()
-        }
-    }
-}
+pub mod guid;
+pub mod wstr;
 
 #[inline(never)]
 pub unsafe fn get_this_module_handle() -> windows::core::Result<HMODULE> {
@@ -145,3 +62,120 @@ pub fn get_module_path(module: HMODULE) -> windows::core::Result<Vec<u16>> {
 pub unsafe fn get_this_module_path() -> windows::core::Result<Vec<u16>> {
     get_module_path(unsafe { get_this_module_handle()? })
 }
+
+// Keeps this module loaded while background threads run code from it, since Explorer unloads
+// shell extensions as soon as DllCanUnloadNow allows. A thread that owns the last pin must release
+// it with `exit_thread`, as returning into an unloaded module would crash.
+pub struct ModulePin(HMODULE);
+
+// Module handles are valid in every thread of the process.
+unsafe impl Send for ModulePin {}
+
+impl ModulePin {
+    pub fn new() -> windows::core::Result<Self> {
+        let mut module = HMODULE::default();
+
+        unsafe {
+            GetModuleHandleExW(
+                GET_MODULE_HANDLE_EX_FLAG_FROM_ADDRESS,
+                PCWSTR::from_raw(get_this_module_path as *const () as *const _),
+                &raw mut module,
+            )?;
+        }
+
+        Ok(Self(module))
+    }
+
+    pub fn exit_thread(self, exit_code: u32) -> ! {
+        let module = self.0;
+        std::mem::forget(self);
+
+        unsafe { FreeLibraryAndExitThread(module, exit_code) }
+    }
+}
+
+impl Drop for ModulePin {
+    fn drop(&mut self) {
+        let _ = unsafe { FreeLibrary(self.0) };
+    }
+}
+
+// Makes windows created on this thread per-monitor DPI aware while it lives, whatever the awareness
+// of the host process is. Dialogs then get scaled by the system on every monitor instead of being
+// stretched as bitmaps.
+pub struct PerMonitorDpiAwareness(DPI_AWARENESS_CONTEXT);
+
+impl PerMonitorDpiAwareness {
+    pub fn new() -> Self {
+        Self(unsafe { SetThreadDpiAwarenessContext(DPI_AWARENESS_CONTEXT_PER_MONITOR_AWARE_V2) })
+    }
+}
+
+impl Default for PerMonitorDpiAwareness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for PerMonitorDpiAwareness {
+    fn drop(&mut self) {
+        // Null if the awareness couldn't be changed, e.g. before Windows 10 1703.
+        if !self.0.is_invalid() {
+            unsafe { SetThreadDpiAwarenessContext(self.0) };
+        }
+    }
+}
+
+fn query_low_privilege() -> windows::core::Result<bool> {
+    let token = unsafe {
+        let mut token = HANDLE::default();
+        OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &raw mut token)?;
+        Owned::new(token)
+    };
+
+    let mut is_app_container = 0u32;
+    let mut size = 0;
+
+    unsafe {
+        GetTokenInformation(
+            *token,
+            TokenIsAppContainer,
+            Some((&raw mut is_app_container).cast()),
+            std::mem::size_of_val(&is_app_container) as u32,
+            &raw mut size,
+        )?;
+    }
+
+    if is_app_container != 0 {
+        return Ok(true);
+    }
+
+    // TOKEN_MANDATORY_LABEL followed by the SID it points to.
+    let mut buffer = [0usize; 8];
+
+    unsafe {
+        GetTokenInformation(
+            *token,
+            TokenIntegrityLevel,
+            Some(buffer.as_mut_ptr().cast()),
+            std::mem::size_of_val(&buffer) as u32,
+            &raw mut size,
+        )?;
+    }
+
+    let sid = unsafe { &*buffer.as_ptr().cast::<TOKEN_MANDATORY_LABEL>() }
+        .Label
+        .Sid;
+
+    let integrity_level =
+        unsafe { *GetSidSubAuthority(sid, *GetSidSubAuthorityCount(sid) as u32 - 1) };
+
+    Ok(integrity_level < SECURITY_MANDATORY_MEDIUM_RID as u32)
+}
+
+// AppContainer and low integrity hosts (Photos, browsers) get virtualized or denied registry
+// access, so code that runs at decode time must not depend on it there.
+pub fn is_low_privilege_process() -> bool {
+    static LOW_PRIVILEGE: OnceLock<bool> = OnceLock::new();
+    *LOW_PRIVILEGE.get_or_init(|| query_low_privilege().unwrap_or(true))
+}
@@ -0,0 +1,191 @@
+use std::{
+    fmt::Display,
+    io::{Cursor, Write},
+    str::FromStr,
+};
+
+use windows::core::GUID;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GuidParseError {
+    // Neither 36 characters, nor 38 including braces.
+    Length,
+    // The byte offset of a character that isn't a hex digit, or of a misplaced dash.
+    Character(usize),
+}
+
+impl Display for GuidParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GuidParseError::Length => write!(f, "Invalid GUID length"),
+            GuidParseError::Character(offset) => {
+                write!(f, "Invalid GUID character at offset {}", offset)
+            }
+        }
+    }
+}
+
+impl std::error::Error for GuidParseError {}
+
+const fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+// Parses "xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx", with or without surrounding braces, in any case.
+pub const fn parse(value: &str) -> Result<GUID, GuidParseError> {
+    let bytes = value.as_bytes();
+
+    let start = match bytes {
+        [b'{', .., b'}'] => 1,
+        _ => 0,
+    };
+
+    if bytes.len() - 2 * start != 36 {
+        return Err(GuidParseError::Length);
+    }
+
+    let mut result = 0u128;
+    let mut i = 0;
+
+    while i < 36 {
+        let c = bytes[start + i];
+
+        if matches!(i, 8 | 13 | 18 | 23) {
+            if c != b'-' {
+                return Err(GuidParseError::Character(start + i));
+            }
+        } else {
+            match hex_digit(c) {
+                Some(digit) => result = result << 4 | digit as u128,
+                None => return Err(GuidParseError::Character(start + i)),
+            }
+        }
+
+        i += 1;
+    }
+
+    Ok(GUID::from_u128(result))
+}
+
+// For constants; panics at compile time on invalid input.
+pub const fn from_str(value: &str) -> GUID {
+    match parse(value) {
+        Ok(guid) => guid,
+        Err(_) => panic!("Invalid GUID"),
+    }
+}
+
+// A GUID that formats in registry form, "{xxxxxxxx-...}", or without braces with `{:#}`, and parses
+// from either.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Guid(pub GUID);
+
+impl Display for Guid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let braced = !f.alternate();
+
+        if braced {
+            write!(f, "{{")?;
+        }
+
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+            self.0.data1,
+            self.0.data2,
+            self.0.data3,
+            self.0.data4[0],
+            self.0.data4[1],
+            self.0.data4[2],
+            self.0.data4[3],
+            self.0.data4[4],
+            self.0.data4[5],
+            self.0.data4[6],
+            self.0.data4[7]
+        )?;
+
+        if braced {
+            write!(f, "}}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Guid {
+    type Err = GuidParseError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        parse(value).map(Guid)
+    }
+}
+
+impl From<GUID> for Guid {
+    fn from(guid: GUID) -> Self {
+        Guid(guid)
+    }
+}
+
+impl From<Guid> for GUID {
+    fn from(guid: Guid) -> Self {
+        guid.0
+    }
+}
+
+pub trait GuidExt {
+    fn to_ascii_with_nul(&self) -> [u8; 39];
+    fn to_wide(&self) -> [u16; 39] {
+        self.to_ascii_with_nul().map(|value| value as u16)
+    }
+}
+
+impl GuidExt for GUID {
+    fn to_ascii_with_nul(&self) -> [u8; 39] {
+        let mut cursor = Cursor::new([0u8; 39]);
+        write!(cursor, "{}", Guid(*self)).unwrap();
+        assert!(cursor.position() == 38);
+        cursor.into_inner()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLSID: GUID = GUID::from_u128(0x5c8a66da_1c32_4d8e_8ead_c579214a6522);
+
+    #[test]
+    fn parses_both_forms() {
+        assert_eq!(parse("5c8a66da-1c32-4d8e-8ead-c579214a6522"), Ok(CLSID));
+        assert_eq!(parse("{5C8A66DA-1C32-4D8E-8EAD-C579214A6522}"), Ok(CLSID));
+        assert_eq!(from_str("{5c8a66da-1c32-4d8e-8ead-c579214a6522}"), CLSID);
+
+        assert_eq!(parse(""), Err(GuidParseError::Length));
+        assert_eq!(
+            parse("{5c8a66da-1c32-4d8e-8ead-c579214a6522"),
+            Err(GuidParseError::Length)
+        );
+        assert_eq!(
+            parse("5c8a66da-1c32-4d8e-8ead+c579214a6522"),
+            Err(GuidParseError::Character(23))
+        );
+        assert_eq!(
+            parse("{5c8a66da-1c32-4d8e-8ead-c579214a652g}"),
+            Err(GuidParseError::Character(36))
+        );
+    }
+
+    #[test]
+    fn round_trips() {
+        let braced = Guid(CLSID).to_string();
+        assert_eq!(braced, "{5c8a66da-1c32-4d8e-8ead-c579214a6522}");
+        assert_eq!(format!("{:#}", Guid(CLSID)), &braced[1..37]);
+        assert_eq!(braced.parse(), Ok(Guid(CLSID)));
+        assert_eq!(&CLSID.to_ascii_with_nul()[..38], braced.as_bytes());
+    }
+}
@@ -0,0 +1,154 @@
+// Helpers for the UTF-16 strings Windows hands out, which may or may not be null-terminated.
+// Unpaired surrogates are compared and kept as they are, as file names can contain them.
+
+// The string up to, but not including, the first nul.
+pub fn until_nul(s: &[u16]) -> &[u16] {
+    s.iter().position(|&c| c == 0).map_or(s, |len| &s[..len])
+}
+
+// Copies `s` up to its first nul and terminates it.
+pub fn with_nul(s: &[u16]) -> Vec<u16> {
+    concat_with_nul(&[s])
+}
+
+// Concatenates `parts`, each up to its first nul, and terminates the result.
+pub fn concat_with_nul(parts: &[&[u16]]) -> Vec<u16> {
+    let mut result = Vec::with_capacity(parts.iter().map(|part| part.len()).sum::<usize>() + 1);
+
+    for part in parts {
+        result.extend_from_slice(until_nul(part));
+    }
+
+    result.push(0);
+    result
+}
+
+pub fn encode_with_nul(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(std::iter::once(0)).collect()
+}
+
+// Like w!, but for strings only known as constants, e.g. from env!. `N` counts the nul.
+pub const fn encode_ascii_with_nul<const N: usize>(s: &str) -> [u16; N] {
+    let bytes = s.as_bytes();
+    assert!(bytes.len() + 1 == N);
+
+    let mut result = [0; N];
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(bytes[i].is_ascii());
+        result[i] = bytes[i] as u16;
+        i += 1;
+    }
+
+    result
+}
+
+fn lowercase(s: &[u16]) -> impl Iterator<Item = u32> + '_ {
+    char::decode_utf16(until_nul(s).iter().copied()).flat_map(|c| {
+        let (lower, unpaired) = match c {
+            Ok(c) => (Some(c.to_lowercase()), None),
+            Err(err) => (None, Some(err.unpaired_surrogate() as u32)),
+        };
+
+        lower.into_iter().flatten().map(u32::from).chain(unpaired)
+    })
+}
+
+// Compares two strings up to their first nul, ignoring case.
+pub fn eq_ignore_case(first: &[u16], second: &[u16]) -> bool {
+    lowercase(first).eq(lowercase(second))
+}
+
+// Splits `s` up to its first nul at every `separator`. Empty parts are skipped, so lists with
+// trailing separators like ".jpg,.jpeg," work as expected.
+pub fn split(s: &[u16], separator: char) -> impl Iterator<Item = &[u16]> {
+    let mut buffer = [0u16; 2];
+    let separator = separator.encode_utf16(&mut buffer)[0];
+
+    until_nul(s)
+        .split(move |&c| c == separator)
+        .filter(|part| !part.is_empty())
+}
+
+pub fn join<'a>(parts: impl IntoIterator<Item = &'a [u16]>, separator: char) -> Vec<u16> {
+    let mut buffer = [0u16; 2];
+    let separator = separator.encode_utf16(&mut buffer);
+
+    let mut result = Vec::new();
+
+    for (i, part) in parts.into_iter().enumerate() {
+        if i > 0 {
+            result.extend_from_slice(separator);
+        }
+
+        result.extend_from_slice(until_nul(part));
+    }
+
+    result
+}
+
+// The name without its last extension. Names that only start with a dot, like ".bmx", are kept
+// as they are.
+pub fn file_stem(name: &[u16]) -> &[u16] {
+    let name = until_nul(name);
+
+    match name.iter().rposition(|&c| c == b'.' as u16) {
+        Some(0) | None => name,
+        Some(dot) => &name[..dot],
+    }
+}
+
+// Replaces the last extension of `name`, or appends one if there is none. `extension` includes
+// the dot, as in the extension lists of WIC codecs.
+pub fn replace_extension(name: &[u16], extension: &[u16]) -> Vec<u16> {
+    [file_stem(name), until_nul(extension)].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn w(s: &str) -> Vec<u16> {
+        s.encode_utf16().collect()
+    }
+
+    #[test]
+    fn nul_handling() {
+        assert_eq!(until_nul(&w("a.png\0junk")), w("a.png"));
+        assert_eq!(until_nul(&w("a.png")), w("a.png"));
+        assert_eq!(with_nul(&w("a\0b")), w("a\0"));
+        assert_eq!(concat_with_nul(&[&w("a\0"), &w(".png")]), w("a.png\0"));
+        assert_eq!(encode_with_nul("ä"), w("ä\0"));
+    }
+
+    #[test]
+    fn compares_ignoring_case() {
+        assert!(eq_ignore_case(&w("Image/PNG\0"), &w("image/png")));
+        assert!(eq_ignore_case(&w("ÄRGER"), &w("ärger")));
+        assert!(!eq_ignore_case(&w("image/png"), &w("image/pngx")));
+        assert!(!eq_ignore_case(&w("image/png"), &w("image/pn")));
+        assert!(eq_ignore_case(
+            &[0xD800, b'a' as u16],
+            &[0xD800, b'A' as u16]
+        ));
+    }
+
+    #[test]
+    fn splits_and_joins() {
+        let list = w(".jpg,.jpeg,\0");
+        let parts = split(&list, ',').collect::<Vec<_>>();
+
+        assert_eq!(parts, [&w(".jpg")[..], &w(".jpeg")[..]]);
+        assert_eq!(join(parts, ';'), w(".jpg;.jpeg"));
+        assert_eq!(split(&w(""), ',').count(), 0);
+    }
+
+    #[test]
+    fn extensions() {
+        assert_eq!(file_stem(&w("a.b.png\0")), w("a.b"));
+        assert_eq!(file_stem(&w("noext")), w("noext"));
+        assert_eq!(file_stem(&w(".bmx")), w(".bmx"));
+        assert_eq!(replace_extension(&w("a.png"), &w(".bmx\0")), w("a.bmx"));
+        assert_eq!(replace_extension(&w("a"), &w(".bmx")), w("a.bmx"));
+    }
+}
@@ -0,0 +1,305 @@
+#!/usr/bin/env python3
+"""Regenerates the BMX golden files in this directory.
+
+The files are assembled byte by byte from the BMX format description and the LZSA2 block format,
+without going through the Rust implementation. They are still written by this script rather than
+by the reference X16 tools, so they only check the decoder against this reading of the formats.
+Prints the expectations used by the `golden_files` test in src/bmx.rs, and which LZSA2 match
+forms the compressed files use; between them, they have to use all of them.
+
+Files exported by other BMX tools, or compressed with the reference `lzsa -f2 -r`, can be dropped
+in next to these; they only need a matching entry in `GOLDEN_FILES`.
+"""
+
+import struct
+import zlib
+from pathlib import Path
+
+HERE = Path(__file__).parent
+
+
+def palette_entry(r, g, b):
+    # 12-bit VERA color: byte 0 is GGGGBBBB, byte 1 is 0000RRRR.
+    return bytes([(g & 0xF) << 4 | (b & 0xF), r & 0xF])
+
+
+def pack(indices, width, bit_depth):
+    rows = [indices[i : i + width] for i in range(0, len(indices), width)]
+    data = bytearray()
+
+    for row in rows:
+        bits = 0
+        count = 0
+        line = bytearray()
+
+        for index in row:
+            bits = (bits << bit_depth) | index
+            count += bit_depth
+
+            if count == 8:
+                line.append(bits)
+                bits = 0
+                count = 0
+
+        if count:
+            line.append(bits << (8 - count))
+
+        data += line
+
+    return bytes(data)
+
+
+MATCH_FORMS = ("5-bit", "9-bit", "13-bit", "16-bit", "repeat")
+
+
+def lzsa2_compress(data, forms=None):
+    # Greedy raw LZSA2 block: literal runs plus matches, each with the shortest offset form that
+    # holds its distance, or a repeat match when it has the same distance as the previous one.
+    # The forms used are counted into `forms`.
+    out = bytearray()
+    nibble_pos = None
+    previous_distance = None
+
+    def nibble(value):
+        nonlocal nibble_pos
+        if nibble_pos is None:
+            nibble_pos = len(out)
+            out.append(value << 4)
+        else:
+            out[nibble_pos] |= value
+            nibble_pos = None
+
+    def command(literals, match_distance, match_length):
+        lit_len = len(literals)
+        lit_field = min(lit_len, 3)
+
+        nonlocal previous_distance
+
+        if match_distance is None:
+            # End of data marker: match length 7 + nibble 15 + byte 232.
+            form = None
+            token = 0b11100000 | (lit_field << 3) | 7
+        else:
+            # The token's top three bits select the offset form. For the short forms, the third
+            # one is the inverse of the offset bit right above the ones read from the stream.
+            if match_distance == previous_distance:
+                form, xyz, value = "repeat", 0b111, 0
+            elif match_distance <= 32:
+                form, value = "5-bit", 32 - match_distance
+                xyz = 0b000 | (value & 1 ^ 1)
+            elif match_distance <= 512:
+                form, value = "9-bit", 512 - match_distance
+                xyz = 0b010 | (value >> 8 ^ 1)
+            elif match_distance <= 8704:
+                form, value = "13-bit", 8704 - match_distance
+                xyz = 0b100 | (value >> 8 & 1 ^ 1)
+            else:
+                form, xyz, value = "16-bit", 0b110, 0x10000 - match_distance
+
+            previous_distance = match_distance
+            token = (xyz << 5) | (lit_field << 3) | min(match_length - 2, 7)
+
+            if forms is not None:
+                forms[form] = forms.get(form, 0) + 1
+
+        out.append(token)
+
+        if lit_len >= 3:
+            extra = lit_len - 3
+            if extra < 15:
+                nibble(extra)
+            else:
+                nibble(15)
+                if lit_len - 18 < 239:
+                    out.append(lit_len - 18)
+                else:
+                    out.append(239)
+                    out.extend(struct.pack("<H", lit_len))
+
+        out.extend(literals)
+
+        if match_distance is None:
+            nibble(15)
+            out.append(232)
+            return
+
+        if form == "5-bit":
+            nibble(value >> 1)
+        elif form == "9-bit":
+            out.append(value & 0xFF)
+        elif form == "13-bit":
+            nibble(value >> 9)
+            out.append(value & 0xFF)
+        elif form == "16-bit":
+            out.extend(struct.pack(">H", value))
+
+        extra = match_length - 2
+        if extra >= 7:
+            extra -= 7
+            if extra < 15:
+                nibble(extra)
+            else:
+                nibble(15)
+                if match_length - 24 < 232:
+                    out.append(match_length - 24)
+                else:
+                    out.append(233)
+                    out.extend(struct.pack("<H", match_length))
+
+    position = 0
+    literal_start = 0
+
+    while position < len(data):
+        best_length = 0
+        best_distance = 0
+
+        # Nearest first, so that the shortest offset wins between equally long matches.
+        for candidate in reversed(range(max(0, position - 0xFF00), position)):
+            length = 0
+            while (
+                position + length < len(data)
+                and data[candidate + length] == data[position + length]
+                and length < 0xFFFF
+            ):
+                length += 1
+
+            if length > best_length:
+                best_length = length
+                best_distance = position - candidate
+
+        if best_length >= 4:
+            command(data[literal_start:position], best_distance, best_length)
+            position += best_length
+            literal_start = position
+        else:
+            position += 1
+
+    command(data[literal_start:], None, 0)
+    return bytes(out)
+
+
+def bmx(width, height, bit_depth, palette, indices, pal_start=0, border=0, compressed=False,
+        data_start=None, crc=False, forms=None):
+    pal_used = 0 if len(palette) == 256 else len(palette)
+    palette_bytes = b"".join(palette_entry(*color) for color in palette)
+    pixels = pack(indices, width, bit_depth)
+
+    if data_start is None:
+        data_start = 32 + len(palette_bytes)
+
+    reserved = bytearray(16)
+    if crc:
+        reserved[8:12] = b"CRC1"
+        reserved[12:16] = struct.pack("<I", zlib.crc32(pixels))
+
+    header = struct.pack(
+        "<3sBBBHHBBHbB16s",
+        b"BMX",
+        1,
+        bit_depth,
+        {1: 0, 2: 1, 4: 2, 8: 3}[bit_depth],
+        width,
+        height,
+        pal_used,
+        pal_start,
+        data_start,
+        1 if compressed else 0,
+        # The border is a VERA palette index, so it's offset like the palette.
+        pal_start + border,
+        bytes(reserved),
+    )
+
+    body = header + palette_bytes
+    body += bytes(data_start - len(body))
+    body += lzsa2_compress(pixels, forms) if compressed else pixels
+
+    return body, palette_bytes, pixels
+
+
+def gradient(count):
+    return [(i % 16, (i // 16) % 16, (i * 7) % 16) for i in range(count)]
+
+
+def pattern(width, height, colors, seed):
+    return [(x * 3 + y * 5 + seed) % colors for y in range(height) for x in range(width)]
+
+
+def runs(width, height, colors):
+    return [(x // 4 + y // 2) % colors for y in range(height) for x in range(width)]
+
+
+def repeats(width, height):
+    # Noise, which has no matches of its own, with copies of earlier stretches at distances for
+    # every offset form. Each copy is repeated at the same distance after a few literals.
+    state = 0x1234
+    indices = []
+    for _ in range(width * height):
+        state = (state * 1103515245 + 12345) & 0x7FFFFFFF
+        indices.append(state >> 16 & 0xFF)
+
+    # Far enough apart that no copy's source is also the source of another one.
+    position = 64
+    for i, distance in enumerate([1, 7, 32, 33, 200, 512, 513, 3000, 8704, 8705, 10000]):
+        position = max(position, distance + 100 * i)
+        for start in (position, position + 24):
+            indices[start : start + 16] = indices[start - distance : start - distance + 16]
+        position += 64
+
+    return indices
+
+
+FIXTURES = {
+    "1bpp_8x4.bmx": dict(width=8, height=4, bit_depth=1, palette=[(0, 0, 0), (15, 15, 15)],
+                         indices=pattern(8, 4, 2, 0)),
+    "1bpp_11x3_odd_width.bmx": dict(width=11, height=3, bit_depth=1,
+                                    palette=[(2, 4, 6), (8, 10, 12)],
+                                    indices=pattern(11, 3, 2, 1), border=1),
+    "2bpp_5x3_pal_start_4.bmx": dict(width=5, height=3, bit_depth=2, palette=gradient(4),
+                                     indices=pattern(5, 3, 4, 2), pal_start=4, border=2),
+    "4bpp_9x3_pal_start_16.bmx": dict(width=9, height=3, bit_depth=4, palette=gradient(16),
+                                      indices=pattern(9, 3, 16, 3), pal_start=16, border=3),
+    "4bpp_12x6_data_start_512.bmx": dict(width=12, height=6, bit_depth=4, palette=gradient(16),
+                                         indices=pattern(12, 6, 16, 4), data_start=512),
+    "8bpp_16x8_full_palette.bmx": dict(width=16, height=8, bit_depth=8, palette=gradient(256),
+                                       indices=pattern(16, 8, 256, 5)),
+    "8bpp_10x4_short_palette.bmx": dict(width=10, height=4, bit_depth=8, palette=gradient(32),
+                                        indices=pattern(10, 4, 32, 6), pal_start=32),
+    "4bpp_32x16_compressed.bmx": dict(width=32, height=16, bit_depth=4, palette=gradient(16),
+                                      indices=runs(32, 16, 16), compressed=True),
+    "8bpp_64x32_compressed_crc.bmx": dict(width=64, height=32, bit_depth=8,
+                                          palette=gradient(256), indices=runs(64, 32, 256),
+                                          compressed=True, crc=True),
+    "2bpp_40x10_compressed_pal_start_8.bmx": dict(width=40, height=10, bit_depth=2,
+                                                    palette=gradient(4),
+                                                    indices=pattern(40, 10, 4, 7),
+                                                    compressed=True, pal_start=8),
+    "8bpp_128x96_compressed_offsets.bmx": dict(width=128, height=96, bit_depth=8,
+                                               palette=gradient(256), indices=repeats(128, 96),
+                                               compressed=True),
+}
+
+
+def main():
+    all_forms = {}
+
+    for name, fixture in FIXTURES.items():
+        forms = {}
+        body, palette_bytes, pixels = bmx(**fixture, forms=forms)
+        (HERE / name).write_bytes(body)
+
+        for form, count in forms.items():
+            all_forms[form] = all_forms.get(form, 0) + count
+
+        print(
+            f'{name}: {fixture["width"]}x{fixture["height"]}, {fixture["bit_depth"]} bpp, '
+            f'{len(fixture["palette"])} colors, pal_start {fixture.get("pal_start", 0)}, '
+            f'palette crc {zlib.crc32(palette_bytes):#010x}, pixel crc {zlib.crc32(pixels):#010x}'
+            + (f', matches {forms}' if forms else '')
+        )
+
+    missing = [form for form in MATCH_FORMS if form not in all_forms]
+    assert not missing, f"No fixture uses {missing}"
+
+
+if __name__ == "__main__":
+    main()